@@ -0,0 +1,210 @@
+//! Support for asset contracts as used by the Liquid asset registry.
+//!
+//! The registry identifies an asset by the hash of its contract, which is
+//! committed to in the issuance. This module can craft and validate such
+//! contracts and calculate the asset id the issuance is expected to produce.
+
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::PublicKey;
+use liquid_rpc::elements::{self, OutPoint};
+use liquid_rpc::AssetId;
+
+use crate::error::Error;
+
+/// The contract version supported by the registry.
+pub const CONTRACT_VERSION: u8 = 0;
+
+/// The maximum precision of an asset.
+pub const MAX_PRECISION: u8 = 8;
+
+/// The entity issuing the asset.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entity {
+	/// The domain on which the issuer proves ownership of the asset.
+	pub domain: String,
+}
+
+/// An asset contract in the format of the Liquid asset registry.
+///
+/// Fields are declared in alphabetical order so that the serialization
+/// is the canonical form the contract hash is calculated over.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contract {
+	pub entity: Entity,
+	pub issuer_pubkey: PublicKey,
+	pub name: String,
+	pub precision: u8,
+	pub ticker: String,
+	pub version: u8,
+}
+
+/// Check if the domain name looks like a valid domain.
+fn is_valid_domain(domain: &str) -> bool {
+	let labels = domain.split('.').collect::<Vec<_>>();
+	labels.len() >= 2
+		&& domain.len() <= 255
+		&& labels.iter().all(|l| {
+			!l.is_empty()
+				&& l.len() <= 63
+				&& !l.starts_with('-')
+				&& !l.ends_with('-')
+				&& l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+		})
+}
+
+impl Contract {
+	/// Create a new contract with the current contract version.
+	pub fn new(
+		domain: String,
+		issuer_pubkey: PublicKey,
+		name: String,
+		ticker: String,
+		precision: u8,
+	) -> Contract {
+		Contract {
+			entity: Entity {
+				domain,
+			},
+			issuer_pubkey,
+			name,
+			precision,
+			ticker,
+			version: CONTRACT_VERSION,
+		}
+	}
+
+	/// Check that the contract will be accepted by the registry.
+	pub fn validate(&self) -> Result<(), Error> {
+		if self.version != CONTRACT_VERSION {
+			return Err(Error::Config("unsupported asset contract version"));
+		}
+		if !is_valid_domain(&self.entity.domain) {
+			return Err(Error::Config("invalid asset contract domain"));
+		}
+		if !self.issuer_pubkey.compressed {
+			return Err(Error::Config("asset contract issuer pubkey should be compressed"));
+		}
+		if self.name.is_empty()
+			|| self.name.len() > 255
+			|| !self.name.chars().all(|c| c.is_ascii() && !c.is_ascii_control())
+		{
+			return Err(Error::Config("asset name should be 1 to 255 printable ASCII characters"));
+		}
+		if self.ticker.len() < 3
+			|| self.ticker.len() > 24
+			|| !self.ticker.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+		{
+			return Err(Error::Config(
+				"asset ticker should be 3 to 24 characters of [a-zA-Z0-9.-]",
+			));
+		}
+		if self.precision > MAX_PRECISION {
+			return Err(Error::Config("asset precision can be at most 8"));
+		}
+		Ok(())
+	}
+
+	/// The canonical JSON serialization of the contract.
+	pub fn to_json(&self) -> String {
+		serde_json::to_string(self).expect("contract serialization can't fail")
+	}
+
+	/// The hash of the contract that is committed to in the issuance.
+	pub fn contract_hash(&self) -> sha256::Hash {
+		sha256::Hash::hash(self.to_json().as_bytes())
+	}
+
+	/// The contract hash in the hex format expected by the `issueasset` RPC.
+	///
+	/// The RPC interprets the argument as a uint256, so the bytes are reversed.
+	pub fn contract_hash_rpc_hex(&self) -> String {
+		let mut bytes = self.contract_hash().into_inner();
+		bytes.reverse();
+		bytes.iter().map(|b| format!("{:02x}", b)).collect()
+	}
+
+	/// The issuance entropy for an issuance spending the given prevout.
+	pub fn entropy(&self, prevout: OutPoint) -> sha256::Midstate {
+		elements::issuance::AssetId::generate_asset_entropy(prevout, self.contract_hash())
+	}
+
+	/// The asset id of an issuance of this contract spending the given prevout.
+	pub fn asset_id(&self, prevout: OutPoint) -> AssetId {
+		elements::issuance::AssetId::from_entropy(self.entropy(prevout)).into_inner()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::str::FromStr;
+
+	use bitcoin::hashes::hex::FromHex;
+
+	fn example_contract() -> Contract {
+		Contract::new(
+			"example.com".into(),
+			PublicKey::from_str(
+				"0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+			)
+			.unwrap(),
+			"Example Asset".into(),
+			"EXMPL".into(),
+			8,
+		)
+	}
+
+	#[test]
+	fn to_json() {
+		assert_eq!(
+			example_contract().to_json(),
+			r#"{"entity":{"domain":"example.com"},"issuer_pubkey":"0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798","name":"Example Asset","precision":8,"ticker":"EXMPL","version":0}"#,
+		);
+	}
+
+	#[test]
+	fn contract_hash() {
+		let contract = example_contract();
+		assert_eq!(
+			contract.contract_hash().to_string(),
+			"b8d0168931a8f5e074fc3760d7735d3bf82eb76f52283eea41952db47575f1db",
+		);
+		assert_eq!(
+			contract.contract_hash_rpc_hex(),
+			"dbf17575b42d9541ea3e28526fb72ef83b5d73d76037fc74e0f5a8318916d0b8",
+		);
+	}
+
+	#[test]
+	fn asset_id() {
+		let prevout = OutPoint {
+			txid: bitcoin::Txid::from_hex(
+				"aabbccddeeff00112233445566778899aabbccddeeff00112233445566778899",
+			)
+			.unwrap(),
+			vout: 1,
+		};
+		assert_eq!(
+			example_contract().asset_id(prevout).to_string(),
+			"7890d465d6fd184c230c39992af7aa8914f9d8c38a3781f3fc6938358902c063",
+		);
+	}
+
+	#[test]
+	fn validate() {
+		assert!(example_contract().validate().is_ok());
+
+		let mut contract = example_contract();
+		contract.entity.domain = "localhost".into();
+		assert!(contract.validate().is_err());
+
+		let mut contract = example_contract();
+		contract.ticker = "EX".into();
+		assert!(contract.validate().is_err());
+
+		let mut contract = example_contract();
+		contract.precision = MAX_PRECISION + 1;
+		assert!(contract.validate().is_err());
+	}
+}
@@ -0,0 +1,194 @@
+//! An async variant of [DaemonRunner] based on tokio.
+//!
+//! Instead of using two threads per daemon to process its output,
+//! the output is processed in tasks on the current tokio runtime.
+
+use std::sync::{Arc, Mutex};
+use std::{fmt, process, time};
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+
+use crate::error::Error;
+use crate::runner::{ExitKind, RunnerHelper, RuntimeData, StartupStage, Status};
+use crate::utils;
+
+/// Runs a daemon asynchronously.
+///
+/// The methods that spawn the daemon should be called from within a tokio runtime.
+/// The process is kept in the runtime data of the daemon, so that the
+/// [DaemonRunner] methods of [AsyncRunner::daemon] see it as well.
+pub struct AsyncRunner<D: RunnerHelper> {
+	daemon: D,
+	runtime_data: Option<Arc<Mutex<RuntimeData<D::State>>>>,
+}
+
+impl<D> AsyncRunner<D>
+where
+	D: RunnerHelper + fmt::Debug + 'static,
+	D::State: Send + 'static,
+{
+	pub fn new(daemon: D) -> AsyncRunner<D> {
+		AsyncRunner {
+			daemon,
+			runtime_data: None,
+		}
+	}
+
+	/// The daemon that is being run.
+	pub fn daemon(&self) -> &D {
+		&self.daemon
+	}
+
+	pub fn daemon_mut(&mut self) -> &mut D {
+		&mut self.daemon
+	}
+
+	/// Start the daemon.
+	///
+	/// If the daemon was previously stopped, it is restarted.
+	pub async fn start(&mut self) -> Result<(), Error> {
+		let first_start = match self.status()? {
+			Status::Running => return Ok(()),
+			Status::Stopped(_) => false,
+			Status::Init => {
+				self.daemon._prepare()?;
+				let rt = RuntimeData::new(self.daemon._init_state());
+				self.runtime_data = Some(Arc::new(Mutex::new(rt)));
+				true
+			}
+		};
+		let rt = self.runtime_data.clone().unwrap();
+
+		info!("Starting daemon {:?}...", self.daemon);
+		let std_cmd = self.daemon._command();
+		let redacted = utils::redacted_command(&std_cmd);
+		let mut cmd = Command::from(std_cmd);
+		cmd.stdout(process::Stdio::piped());
+		cmd.stderr(process::Stdio::piped());
+		cmd.kill_on_drop(true);
+		debug!("Launching daemon {:?} with command: {:?}", self.daemon, redacted);
+		let mut child = cmd.spawn().map_err(|e| Error::RunCommand(e, Box::new(redacted)))?;
+
+		let stdout = child.stdout.take().unwrap();
+		let rt_cloned = rt.clone();
+		let name = format!("{:?}_stdout", self.daemon);
+		tokio::spawn(async move {
+			let mut lines = BufReader::new(stdout).lines();
+			while let Ok(Some(line)) = lines.next_line().await {
+				let mut lock = rt_cloned.lock().unwrap();
+				lock.record_startup(StartupStage::FirstLogLine);
+				D::_process_stdout(&name, &mut lock.state, &line);
+			}
+			trace!("Task {} stopped", name);
+		});
+
+		let stderr = child.stderr.take().unwrap();
+		let rt_cloned = rt.clone();
+		tokio::spawn(async move {
+			let mut lines = BufReader::new(stderr).lines();
+			while let Ok(Some(line)) = lines.next_line().await {
+				let mut lock = rt_cloned.lock().unwrap();
+				lock.record_startup(StartupStage::FirstLogLine);
+				D::_process_stderr(&mut lock.state, &line);
+			}
+		});
+
+		info!("Daemon {:?} started. PID: {:?}", self.daemon, child.id());
+		rt.lock().unwrap().started_async(child);
+		if first_start {
+			self.daemon._notif_started(rt);
+		}
+		Ok(())
+	}
+
+	/// Stop the daemon.
+	///
+	/// State is preserved so that it can be restarted with [start].
+	/// If the daemon already stopped, this is a no-op.
+	pub async fn stop(&mut self) -> Result<(), Error> {
+		match self.status()? {
+			Status::Init => return Err(Error::InvalidState(Status::Init)),
+			Status::Running => {}
+			Status::Stopped(_) => return Ok(()),
+		}
+
+		info!("Stopping daemon {:?}...", self.daemon);
+		self.runtime_data.as_ref().unwrap().lock().unwrap().kill(false);
+		let exit = self.wait_for_exit().await?;
+		info!("Daemon {:?} stopped: {}", self.daemon, ExitKind::from(exit));
+		Ok(())
+	}
+
+	/// Wait for the daemon to exit.
+	pub async fn wait_for_exit(&mut self) -> Result<process::ExitStatus, Error> {
+		// We poll so that we don't hold the lock the output tasks need.
+		loop {
+			match self.status()? {
+				Status::Init => return Err(Error::InvalidState(Status::Init)),
+				Status::Running => tokio::time::sleep(time::Duration::from_millis(50)).await,
+				Status::Stopped(s) => return Ok(s),
+			}
+		}
+	}
+
+	/// The running status of the daemon.
+	pub fn status(&self) -> Result<Status, Error> {
+		match self.runtime_data {
+			None => Ok(Status::Init),
+			Some(ref rt) => Ok(rt.lock().unwrap().status()?),
+		}
+	}
+
+	/// Get the OS process ID of the daemon.
+	pub fn pid(&self) -> Option<u32> {
+		self.runtime_data.as_ref().and_then(|rt| rt.lock().unwrap().pid())
+	}
+}
+
+impl<D: RunnerHelper + fmt::Debug> fmt::Debug for AsyncRunner<D> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		fmt::Debug::fmt(&self.daemon, f)
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+
+	use crate::runner::tests::Sleeper;
+	use crate::runner::DaemonRunner;
+
+	#[test]
+	fn daemon_sees_process() {
+		let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+		rt.block_on(async {
+			let mut runner = AsyncRunner::new(Sleeper::default());
+			assert_eq!(runner.status().unwrap(), Status::Init);
+			assert_eq!(runner.daemon().pid(), None);
+
+			runner.start().await.unwrap();
+			assert_eq!(runner.status().unwrap(), Status::Running);
+			assert_eq!(runner.daemon().status().unwrap(), Status::Running);
+			assert!(runner.pid().is_some());
+			assert_eq!(runner.daemon().pid(), runner.pid());
+
+			// The output is processed by tasks on the runtime.
+			while runner.daemon().with_state(|s| s.is_empty()).unwrap() {
+				tokio::time::sleep(time::Duration::from_millis(10)).await;
+			}
+			assert_eq!(runner.daemon().startup_timings().len(), 1);
+
+			runner.stop().await.unwrap();
+			assert!(matches!(runner.status().unwrap(), Status::Stopped(_)));
+			assert!(matches!(runner.daemon().status().unwrap(), Status::Stopped(_)));
+
+			// Restarting keeps the runtime data.
+			runner.start().await.unwrap();
+			assert_eq!(runner.daemon().status().unwrap(), Status::Running);
+			assert_eq!(runner.daemon().startup_timings().len(), 2);
+			runner.daemon().stop().unwrap();
+			assert!(runner.wait_for_exit().await.unwrap().code().is_none());
+		});
+	}
+}
@@ -1,22 +1,153 @@
+use std::any::Any;
+use std::collections::BTreeMap;
 use std::fmt::Write;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::{fmt, fs, io, mem, process};
+use std::{cmp, fmt, fs, io, mem, process, thread, time};
 
-use bitcoin;
 use bitcoincore_rpc::{self as rpc, RpcApi};
 use regex::Regex;
 
-use error::Error;
-use runner::{DaemonRunner, RunnerHelper, RuntimeData};
-use utils;
+use crate::chain_params::{self, ChainParams};
+use crate::error::Error;
+use crate::logs::{self, LogEvent};
+use crate::runner::{
+	self, Common, CommonDaemon, DaemonRunner, ExitKind, RunnerConfig, RunnerHelper, RuntimeData,
+	StartupStage, Status,
+};
+use crate::utils;
+use crate::utxo::{self, UtxoHashType, UtxoSetInfo};
 
 pub const CONFIG_FILENAME: &str = "bitcoin.conf";
 
 pub const DEFAULT_VERSION: u64 = 21_00_00;
 
-#[derive(Debug, Clone, Deserialize, Default)]
+/// The fallback fee rate in BTC/kvB applied by [Config::apply_test_defaults].
+pub const TEST_FALLBACKFEE: f64 = 0.0002;
+
+/// The environment variable that can be used to point to the bitcoind executable.
+pub const EXECUTABLE_ENV_VAR: &str = "BITCOIND_EXE";
+
+/// How long the wallet is unlocked for after [Daemon::encrypt_wallet].
+const WALLET_UNLOCK_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// The time to wait for a daemon created with [Daemon::regtest_tmp] to be ready.
+const REGTEST_TMP_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// How a daemon participates in the p2p network, as derived from its config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum P2pMode {
+	/// Only the configured `connect` peers are connected to.
+	/// Peer discovery is disabled and other peers can only connect
+	/// when listening was enabled explicitly.
+	ConnectOnly,
+	/// Other peers can connect to the daemon.
+	Listening,
+	/// The daemon doesn't accept connections from other peers.
+	/// It only connects out to added or discovered peers.
+	Isolated,
+}
+
+impl P2pMode {
+	/// Derive the mode from the relevant config options.
+	pub(crate) fn from_config(connect: &[String], listen: bool) -> P2pMode {
+		if !connect.is_empty() {
+			P2pMode::ConnectOnly
+		} else if listen {
+			P2pMode::Listening
+		} else {
+			P2pMode::Isolated
+		}
+	}
+}
+
+/// Whether the RPC server responded to a request, even if with an error.
+pub(crate) fn rpc_responded<T>(res: &Result<T, rpc::Error>) -> bool {
+	match *res {
+		Ok(_) => true,
+		Err(rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(_))) => true,
+		Err(_) => false,
+	}
+}
+
+/// Wait for RPC to become available and initialize the tip from RPC
+/// if no tip has been seen in the logs yet.
+///
+/// This makes sure the tip is known when starting on an existing chain.
+/// The [tip] closure gives access to the tip and the time it was set in the state.
+/// The startup stages are recorded as soon as the RPC interface responds.
+///
+/// The client is built again for every attempt because the daemon only writes
+/// its cookie file once it started, replacing the one from any previous run.
+pub(crate) fn init_tip_from_rpc<S>(
+	url: String,
+	auth: rpc::Auth,
+	rt: Arc<Mutex<RuntimeData<S>>>,
+	tip: impl Fn(&mut S) -> (&mut Option<(u64, bitcoin::BlockHash)>, &mut Option<time::Instant>),
+) {
+	let query = || -> Result<(u64, bitcoin::BlockHash), rpc::Error> {
+		let timeout = Some(utils::HEALTH_CHECK_RPC_TIMEOUT);
+		let rpc = utils::TimeoutClient::new(&url, auth.clone(), timeout)?;
+		let height = rpc.get_block_count()?;
+		let hash = rpc.get_block_hash(height)?;
+		Ok((height, hash))
+	};
+
+	while rt.lock().unwrap().is_running() {
+		let res = query();
+		if rpc_responded(&res) {
+			rt.lock().unwrap().record_startup(StartupStage::RpcListening);
+		}
+		if let Ok(rpc_tip) = res {
+			let mut lock = rt.lock().unwrap();
+			lock.record_startup(StartupStage::Ready);
+			let (tip, tip_time) = tip(&mut lock.state);
+			if tip.is_none() {
+				trace!("Initializing tip from RPC: {:?}", rpc_tip);
+				*tip = Some(rpc_tip);
+				*tip_time = Some(time::Instant::now());
+			}
+			return;
+		}
+		thread::sleep(time::Duration::from_millis(100));
+	}
+}
+
+/// Load the wallet with the given name, creating it if it doesn't exist.
+pub(crate) fn load_or_create_wallet(rpc: &impl RpcApi, name: &str) -> Result<(), Error> {
+	let loaded = rpc.call::<Vec<String>>("listwallets", &[])?;
+	if loaded.iter().any(|w| w == name) {
+		return Ok(());
+	}
+	if rpc.call::<serde_json::Value>("loadwallet", &[name.into()]).is_err() {
+		debug!("Creating wallet {:?}", name);
+		rpc.call::<serde_json::Value>("createwallet", &[name.into()])?;
+	}
+	Ok(())
+}
+
+/// The compact block filters to index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockFilterIndex {
+	/// All supported filter types.
+	All,
+	/// The BIP158 basic filters.
+	Basic,
+}
+
+impl fmt::Display for BlockFilterIndex {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			BlockFilterIndex::All => f.write_str("1"),
+			BlockFilterIndex::Basic => f.write_str("basic"),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct Config {
 	/// This field is not present in the config but is necessary to
 	/// know the config file format that needs to be written.
@@ -25,6 +156,14 @@ pub struct Config {
 
 	pub datadir: PathBuf,
 	pub network: Option<bitcoin::Network>,
+	/// Run on signet instead of [network].
+	/// Only supported since version 0.21.
+	pub signet: bool,
+	/// The block signing challenge of a custom signet.
+	/// When not set, the default signet is used.
+	pub signetchallenge: Option<bitcoin::Script>,
+	/// The seed nodes of a custom signet.
+	pub signetseednodes: Vec<String>,
 	pub fdefaultconsistencychecks: Option<bool>,
 	pub debug: bool,
 	pub printtoconsole: bool,
@@ -32,24 +171,127 @@ pub struct Config {
 	pub listen: bool,
 	pub listenonion: bool,
 	pub discover: bool,
+	pub upnp: bool,
+	/// Only supported since version 22.0.
+	pub natpmp: bool,
+	/// When not set, a free port is allocated when the daemon is started.
 	pub port: Option<u16>,
+	pub externalip: Option<String>,
 	pub proxy: Option<String>,
+	/// The tor control port to create an onion service through.
+	pub torcontrol: Option<String>,
+	/// The addresses to listen on for p2p connections.
+	/// Addresses with an `=onion` suffix are meant for incoming tor
+	/// connections, which is only supported since version 0.21.
+	pub bind: Vec<String>,
+	pub onlynet: Vec<String>,
+	pub dns: Option<bool>,
+	pub dnsseed: Option<bool>,
+	/// Only supported since version 22.0.
+	pub fixedseeds: Option<bool>,
 	pub txindex: bool,
+	/// Only supported since version 0.19.
+	pub blockfilterindex: Option<BlockFilterIndex>,
+	/// Only supported since version 22.0.
+	pub coinstatsindex: bool,
 	pub connect: Vec<String>,
 	pub addnodes: Vec<String>,
 
 	pub rpccookie: Option<String>,
+	/// When not set, a free port is allocated when the daemon is started.
 	pub rpcport: Option<u16>,
+	/// The IPs or subnets allowed to connect to the RPC interface.
+	/// When empty and [rpcport] is set, only localhost is allowed.
+	pub rpcallowip: Vec<String>,
+	/// The addresses the RPC interface listens on.
+	/// When empty and [rpcport] is set, only localhost is used.
+	pub rpcbind: Vec<String>,
 	pub rpcuser: Option<String>,
+	/// Not written to the config when there is an [rpcauth] entry for [rpcuser].
 	pub rpcpass: Option<String>,
+	/// Hashed credentials, in the format of [utils::generate_rpcauth].
+	pub rpcauth: Vec<String>,
+	/// Deprecated RPC methods or fields to keep enabled.
+	pub deprecatedrpc: Vec<String>,
 
 	pub disablewallet: Option<bool>,
+	/// The wallets to load at startup.
+	pub wallets: Vec<String>,
 	pub dbcache: Option<u32>,
+	/// The number of blocks to verify at startup. With 0, all blocks are verified.
+	pub checkblocks: Option<u32>,
+	/// How thorough the block verification at startup is, from 0 to 4.
+	pub checklevel: Option<u8>,
+	/// The target size of the block files in MiB, at least 550.
+	/// When set to 1, blocks are only pruned with the `pruneblockchain` RPC.
+	/// Pruning is incompatible with [txindex].
+	pub prune: Option<u64>,
+	/// The target for outbound traffic in MiB per 24 hours.
+	pub maxuploadtarget: Option<u64>,
 	//TODO(stevenroose) enum?
 	pub addresstype: Option<String>,
 	pub blockmintxfee: Option<f64>,
 	pub minrelaytxfee: Option<f64>,
 	pub fallbackfee: Option<f64>,
+	/// The external signer command, like the one of [crate::signer::Signer].
+	/// Only supported since version 22.
+	pub signer: Option<PathBuf>,
+
+	/// ZMQ notification endpoints, f.e. `tcp://127.0.0.1:28332`.
+	pub zmqpubhashblock: Option<String>,
+	pub zmqpubrawblock: Option<String>,
+	pub zmqpubhashtx: Option<String>,
+	pub zmqpubrawtx: Option<String>,
+	/// Only supported since version 0.21.
+	pub zmqpubsequence: Option<String>,
+
+	// The options below are not present in the config file.
+	/// When set, the daemon is isolated from the outside world: it only
+	/// listens on loopback and doesn't discover peers or map ports.
+	/// Only explicitly configured peers are connected to.
+	pub hermetic: bool,
+
+	/// When set, [Daemon::wait_for_ready] fails when the daemon warned about
+	/// options it doesn't understand, f.e. because of a version mismatch.
+	pub fail_on_unknown_options: bool,
+
+	/// When set, [Daemon::wait_for_ready] loads the wallet with this name,
+	/// creating it if it doesn't exist yet.
+	pub create_wallet: Option<String>,
+
+	/// When set, [ConfigBuilder::build] fills in options that tests
+	/// otherwise trip over. See [Config::apply_test_defaults].
+	pub test_defaults: bool,
+
+	/// The timeout of the RPC calls made by the convenience methods of
+	/// [Daemon]. Without a timeout, calls wait for as long as it takes.
+	/// See [utils::TimeoutClient].
+	pub rpc_timeout: Option<time::Duration>,
+
+	/// When set, stdout output is kept as well, up to this many bytes.
+	/// Only the most recent output is kept. See [Daemon::take_stdout].
+	pub max_stdout_bytes: Option<usize>,
+
+	/// When set, all captured stdout and stderr output is written to
+	/// a log file in the datadir that is rotated by size.
+	pub log_file: Option<logs::LogFileConfig>,
+
+	/// When set, the daemon doesn't print to the console and its `debug.log`
+	/// is followed instead, so that messages that are only logged to file
+	/// are processed as well.
+	pub tail_debug_log: bool,
+
+	/// When set, starting the daemon fails if the filesystem of the datadir
+	/// has less free space than [Config::required_disk_space].
+	pub check_disk_space: bool,
+
+	/// The free disk space in bytes required by [Config::check_disk_space].
+	/// Defaults to an estimate for the network, taking pruning into account.
+	pub min_free_disk_space: Option<u64>,
+
+	/// The options that are only used by the runner.
+	#[serde(flatten)]
+	pub runner: RunnerConfig,
 }
 impl Config {
 	pub fn write_into<W: io::Write>(&self, mut w: W) -> Result<(), io::Error> {
@@ -59,12 +301,30 @@ impl Config {
 			DEFAULT_VERSION
 		};
 
-		let datadir = self.datadir.as_path().to_str().unwrap_or("");
-		if datadir.len() > 0 {
+		let datadir = utils::config_path(&self.datadir);
+		if !datadir.is_empty() {
 			writeln!(w, "datadir={}", datadir)?;
 		}
 
 		match self.network {
+			_ if self.signet => {
+				// Versions before 0.21 abort on the signet options, and
+				// silently ignoring them would run the daemon on mainnet.
+				if version < 21_00_00 {
+					return Err(io::Error::new(
+						io::ErrorKind::InvalidInput,
+						"signet is only supported since version 0.21",
+					));
+				}
+				writeln!(w, "signet=1")?;
+				writeln!(w, "[signet]")?;
+				if let Some(ref v) = self.signetchallenge {
+					writeln!(w, "signetchallenge={:x}", v)?;
+				}
+				for v in &self.signetseednodes {
+					writeln!(w, "signetseednode={}", v)?;
+				}
+			}
 			Some(bitcoin::Network::Bitcoin) | None => {}
 			Some(bitcoin::Network::Testnet) => {
 				writeln!(w, "testnet=1")?;
@@ -92,8 +352,15 @@ impl Config {
 		writeln!(w, "printtoconsole={}", self.printtoconsole as u8)?;
 		writeln!(w, "daemon={}", self.daemon as u8)?;
 		writeln!(w, "listen={}", self.listen as u8)?;
-		writeln!(w, "listenonion={}", self.listenonion as u8)?;
-		writeln!(w, "discover={}", self.discover as u8)?;
+		writeln!(w, "listenonion={}", (self.listenonion && !self.hermetic) as u8)?;
+		writeln!(w, "discover={}", (self.discover && !self.hermetic) as u8)?;
+		writeln!(w, "upnp={}", (self.upnp && !self.hermetic) as u8)?;
+		if version >= 22_00_00 {
+			writeln!(w, "natpmp={}", (self.natpmp && !self.hermetic) as u8)?;
+		}
+		if self.hermetic && self.listen && self.bind.is_empty() {
+			writeln!(w, "bind=127.0.0.1")?;
+		}
 
 		if let Some(p) = self.port {
 			writeln!(w, "port={}", p)?;
@@ -101,7 +368,54 @@ impl Config {
 		if let Some(ref v) = self.proxy {
 			writeln!(w, "proxy={}", v)?;
 		}
+		if let Some(ref v) = self.torcontrol {
+			writeln!(w, "torcontrol={}", v)?;
+		}
+		for bind in &self.bind {
+			if bind.ends_with("=onion") && version < 21_00_00 {
+				// versions before 0.21 don't support onion binds
+				// leave the reference, but commented out
+				write!(w, ";")?;
+			}
+			writeln!(w, "bind={}", bind)?;
+		}
+		if let Some(ref v) = self.externalip {
+			let torv3 = v.len() == crate::elementsd::TORV3_ADDR_LEN && v.ends_with(".onion");
+			if torv3 && version < 21_00_00 {
+				// versions before 0.21 don't support torv3 externalip
+				// leave the reference, but commented out
+				write!(w, ";")?;
+			}
+			writeln!(w, "externalip={}", v)?;
+		}
+		for onlynet in &self.onlynet {
+			writeln!(w, "onlynet={}", onlynet)?;
+		}
+		if let Some(v) = self.dns {
+			writeln!(w, "dns={}", v as u8)?;
+		}
+		let (dnsseed, fixedseeds) = if self.hermetic {
+			(Some(false), Some(false))
+		} else {
+			(self.dnsseed, self.fixedseeds)
+		};
+		if let Some(v) = dnsseed {
+			writeln!(w, "dnsseed={}", v as u8)?;
+		}
+		if let Some(v) = fixedseeds {
+			if version >= 22_00_00 {
+				writeln!(w, "fixedseeds={}", v as u8)?;
+			}
+		}
 		writeln!(w, "txindex={}", self.txindex as u8)?;
+		if let Some(v) = self.blockfilterindex {
+			if version >= 19_00_00 {
+				writeln!(w, "blockfilterindex={}", v)?;
+			}
+		}
+		if version >= 22_00_00 {
+			writeln!(w, "coinstatsindex={}", self.coinstatsindex as u8)?;
+		}
 
 		for connect in &self.connect {
 			writeln!(w, "connect={}", connect)?;
@@ -111,31 +425,78 @@ impl Config {
 		}
 
 		// RPC details
-		if self.rpccookie.is_some() || self.rpcuser.is_some() {
+		if self.rpccookie.is_some() || self.rpcuser.is_some() || !self.rpcauth.is_empty() {
 			writeln!(w, "server=1")?;
 		}
 		if let Some(ref cf) = self.rpccookie {
-			writeln!(w, "rpccookiefile={}", cf)?;
+			writeln!(w, "rpccookiefile={}", utils::config_path(Path::new(cf)))?;
+		}
+		let localhost = ["127.0.0.1".to_owned()];
+		let default = if self.rpcport.is_some() {
+			&localhost[..]
+		} else {
+			&[]
+		};
+		let rpcallowip = if self.rpcallowip.is_empty() {
+			default
+		} else {
+			&self.rpcallowip[..]
+		};
+		for ip in rpcallowip {
+			writeln!(w, "rpcallowip={}", ip)?;
+		}
+		let rpcbind = if self.rpcbind.is_empty() {
+			default
+		} else {
+			&self.rpcbind[..]
+		};
+		for addr in rpcbind {
+			writeln!(w, "rpcbind={}", addr)?;
 		}
 		if let Some(p) = self.rpcport {
-			writeln!(w, "rpcallowip=127.0.0.1")?;
-			writeln!(w, "rpcbind=127.0.0.1")?;
 			writeln!(w, "rpcport={}", p)?;
 		}
-		if let Some(ref u) = self.rpcuser {
-			writeln!(w, "rpcuser={}", u)?;
+		let hashed = match self.rpcuser {
+			Some(ref u) => self.rpcauth.iter().any(|a| a.starts_with(&format!("{}:", u))),
+			None => false,
+		};
+		if !hashed {
+			if let Some(ref u) = self.rpcuser {
+				writeln!(w, "rpcuser={}", u)?;
+			}
+			if let Some(ref p) = self.rpcpass {
+				writeln!(w, "rpcpassword={}", p)?;
+			}
+		}
+		for v in &self.rpcauth {
+			writeln!(w, "rpcauth={}", v)?;
 		}
-		if let Some(ref p) = self.rpcpass {
-			writeln!(w, "rpcpassword={}", p)?;
+		for v in &self.deprecatedrpc {
+			writeln!(w, "deprecatedrpc={}", v)?;
 		}
 
 		if let Some(p) = self.disablewallet {
 			writeln!(w, "disablewallet={}", p as u8)?;
 		}
+		for wallet in &self.wallets {
+			writeln!(w, "wallet={}", wallet)?;
+		}
 
 		if let Some(p) = self.dbcache {
 			writeln!(w, "dbcache={}", p)?;
 		}
+		if let Some(v) = self.checkblocks {
+			writeln!(w, "checkblocks={}", v)?;
+		}
+		if let Some(v) = self.checklevel {
+			writeln!(w, "checklevel={}", v)?;
+		}
+		if let Some(v) = self.prune {
+			writeln!(w, "prune={}", v)?;
+		}
+		if let Some(v) = self.maxuploadtarget {
+			writeln!(w, "maxuploadtarget={}", v)?;
+		}
 
 		if let Some(ref v) = self.addresstype {
 			writeln!(w, "addresstype={}", v)?;
@@ -149,180 +510,1638 @@ impl Config {
 		if let Some(v) = self.fallbackfee {
 			writeln!(w, "fallbackfee={:.8}", v)?;
 		}
+		if let Some(ref v) = self.signer {
+			if version >= 22_00_00 {
+				writeln!(w, "signer={}", utils::config_path(v))?;
+			}
+		}
+
+		for (topic, endpoint) in self.zmq_endpoints() {
+			writeln!(w, "zmqpub{}={}", topic, endpoint)?;
+		}
 		Ok(())
 	}
-}
 
-#[derive(Default)]
-pub struct State {
-	/// Buffer holding all stderr output.
-	pub stderr: String,
+	/// The configured ZMQ notification topics with their endpoints.
+	pub fn zmq_endpoints(&self) -> Vec<(&'static str, &str)> {
+		let topics = [
+			("hashblock", &self.zmqpubhashblock),
+			("rawblock", &self.zmqpubrawblock),
+			("hashtx", &self.zmqpubhashtx),
+			("rawtx", &self.zmqpubrawtx),
+			("sequence", &self.zmqpubsequence),
+		];
+		topics.iter().filter_map(|(t, e)| e.as_ref().map(|e| (*t, e.as_str()))).collect()
+	}
 
-	/// For older versions, write stdout to this file.
-	pub stdout_file: Option<File>,
+	/// How the daemon participates in the p2p network.
+	pub fn p2p_mode(&self) -> P2pMode {
+		P2pMode::from_config(&self.connect, self.listen)
+	}
 
-	/// Error messages produced during runtime.
-	error_msgs: Vec<String>,
-}
+	/// Fill in options that are easily forgotten in tests.
+	///
+	/// - On regtest with the wallet enabled, [fallbackfee] is set to
+	///   [TEST_FALLBACKFEE] because sending fails without it since 0.20.
+	/// - The deprecated RPCs that the RPC client of this crate relies on
+	///   are kept enabled for the versions that deprecated them.
+	///
+	/// Options that are already set are left alone. Every applied default
+	/// is logged.
+	pub fn apply_test_defaults(&mut self) {
+		let version = if self.version > 0 {
+			self.version
+		} else {
+			DEFAULT_VERSION
+		};
 
-pub struct Daemon {
-	name: String,
-	executable: PathBuf,
-	config: Config,
+		let regtest = self.network == Some(bitcoin::Network::Regtest) && !self.signet;
+		let wallet = self.disablewallet != Some(true);
+		if regtest && wallet && version >= 20_00_00 && self.fallbackfee.is_none() {
+			info!("Test default for {}: fallbackfee={}", self.datadir.display(), TEST_FALLBACKFEE);
+			self.fallbackfee = Some(TEST_FALLBACKFEE);
+		}
 
-	/// The path of the written config file.
-	/// [None] before it has been written.
-	config_file: Option<PathBuf>,
+		let deprecated: &[&str] = match version {
+			// The generate method was replaced by generatetoaddress.
+			18_00_00..=18_99_99 => &["generate"],
+			// The addresses field of scriptPubKey results was replaced by address.
+			22_00_00..=22_99_99 => &["addresses"],
+			_ => &[],
+		};
+		for rpc in deprecated {
+			if !self.deprecatedrpc.iter().any(|d| d == rpc) {
+				info!("Test default for {}: deprecatedrpc={}", self.datadir.display(), rpc);
+				self.deprecatedrpc.push(rpc.to_string());
+			}
+		}
+	}
 
-	runtime_data: Option<Arc<Mutex<RuntimeData<State>>>>,
+	/// The free disk space in bytes needed to run the daemon.
+	///
+	/// This is [Config::min_free_disk_space] or an estimate for the network.
+	/// For pruned nodes, the estimate is based on the prune target.
+	pub fn required_disk_space(&self) -> u64 {
+		if let Some(v) = self.min_free_disk_space {
+			return v;
+		}
+		let chain = if self.signet {
+			10 * utils::GB
+		} else {
+			match self.network.unwrap_or(bitcoin::Network::Bitcoin) {
+				bitcoin::Network::Bitcoin => 700 * utils::GB,
+				bitcoin::Network::Testnet => 200 * utils::GB,
+				bitcoin::Network::Regtest => 100 * utils::MB,
+			}
+		};
+		match self.prune {
+			// The chainstate needs space on top of the block files.
+			Some(mib) if mib > 1 => chain.min(mib * utils::MB + 15 * utils::GB),
+			_ => chain,
+		}
+	}
 }
 
-impl Daemon {
-	pub fn new<P: Into<PathBuf>>(executable: P, config: Config) -> Result<Daemon, Error> {
-		if !config.datadir.is_absolute() {
-			return Err(Error::Config("datadir should be an absolute path"));
+impl utils::ConfigFile for Config {}
+
+/// A builder for [Config].
+///
+/// Use [ConfigBuilder::regtest] to start from sensible regtest defaults.
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+	config: Config,
+}
+
+impl ConfigBuilder {
+	/// Create a builder for a config without any options set.
+	pub fn new<P: Into<PathBuf>>(datadir: P) -> ConfigBuilder {
+		ConfigBuilder {
+			config: Config {
+				datadir: datadir.into(),
+				..Default::default()
+			},
 		}
+	}
 
-		Ok(Daemon {
-			name: "".into(),
-			executable: executable.into(),
-			config: config,
+	/// Create a builder with defaults for a regtest node.
+	///
+	/// The RPC interface listens on a free port and uses cookie authentication
+	/// with a cookie file in the datadir. The transaction index is enabled.
+	pub fn regtest<P: Into<PathBuf>>(datadir: P) -> ConfigBuilder {
+		let datadir = datadir.into();
+		let cookie = datadir.join(".cookie").to_string_lossy().into_owned();
+		let ports = utils::find_free_ports(2);
+		ConfigBuilder::new(datadir)
+			.network(bitcoin::Network::Regtest)
+			.port(ports[0])
+			.rpcport(ports[1])
+			.rpccookie(cookie)
+			.txindex(true)
+	}
 
-			config_file: None,
-			runtime_data: None,
-		})
+	/// The version the config is written for. See [Config::version].
+	pub fn version(mut self, version: u64) -> Self {
+		self.config.version = version;
+		self
 	}
 
-	pub fn set_name(&mut self, name: String) {
-		self.name = name;
+	pub fn network(mut self, network: bitcoin::Network) -> Self {
+		self.config.network = Some(network);
+		self
 	}
 
-	pub fn datadir(&self) -> &Path {
-		self.config.datadir.as_path()
+	/// Run on signet. See [Config::signet].
+	pub fn signet(mut self, v: bool) -> Self {
+		self.config.signet = v;
+		self
 	}
 
-	/// Get the RPC info.
+	/// Run on a custom signet with the given block signing challenge.
+	pub fn signetchallenge(mut self, script: bitcoin::Script) -> Self {
+		self.config.signet = true;
+		self.config.signetchallenge = Some(script);
+		self
+	}
+
+	pub fn signetseednode(mut self, addr: impl Into<String>) -> Self {
+		self.config.signetseednodes.push(addr.into());
+		self
+	}
+
+	pub fn fdefaultconsistencychecks(mut self, v: bool) -> Self {
+		self.config.fdefaultconsistencychecks = Some(v);
+		self
+	}
+
+	pub fn debug(mut self, v: bool) -> Self {
+		self.config.debug = v;
+		self
+	}
+
+	pub fn printtoconsole(mut self, v: bool) -> Self {
+		self.config.printtoconsole = v;
+		self
+	}
+
+	pub fn listen(mut self, v: bool) -> Self {
+		self.config.listen = v;
+		self
+	}
+
+	pub fn listenonion(mut self, v: bool) -> Self {
+		self.config.listenonion = v;
+		self
+	}
+
+	pub fn discover(mut self, v: bool) -> Self {
+		self.config.discover = v;
+		self
+	}
+
+	pub fn upnp(mut self, v: bool) -> Self {
+		self.config.upnp = v;
+		self
+	}
+
+	pub fn natpmp(mut self, v: bool) -> Self {
+		self.config.natpmp = v;
+		self
+	}
+
+	pub fn port(mut self, port: u16) -> Self {
+		self.config.port = Some(port);
+		self
+	}
+
+	pub fn externalip(mut self, ip: impl Into<String>) -> Self {
+		self.config.externalip = Some(ip.into());
+		self
+	}
+
+	pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+		self.config.proxy = Some(proxy.into());
+		self
+	}
+
+	pub fn torcontrol(mut self, addr: impl Into<String>) -> Self {
+		self.config.torcontrol = Some(addr.into());
+		self
+	}
+
+	pub fn bind(mut self, addr: impl Into<String>) -> Self {
+		self.config.bind.push(addr.into());
+		self
+	}
+
+	/// Listen for incoming tor connections on the given address.
 	///
-	/// Don't call this method before calling [start].
-	pub fn rpc_info(&self) -> Option<(String, rpc::Auth)> {
-		let url = format!("http://127.0.0.1:{}", self.config.rpcport?);
-		let auth = if let Some(ref c) = self.config.rpccookie {
-			rpc::Auth::CookieFile(c.clone().into())
-		} else if let Some(ref u) = self.config.rpcuser {
-			let pass = self.config.rpcpass.as_ref()?.clone();
-			rpc::Auth::UserPass(u.clone(), pass)
-		} else {
-			return None;
-		};
-		Some((url, auth))
+	/// See [Config::bind].
+	pub fn bind_onion(mut self, addr: impl fmt::Display) -> Self {
+		self.config.bind.push(format!("{}=onion", addr));
+		self
 	}
 
-	/// Get an RPC client.
+	/// Only make outgoing connections to the given network.
+	pub fn onlynet(mut self, net: impl Into<String>) -> Self {
+		self.config.onlynet.push(net.into());
+		self
+	}
+
+	pub fn dns(mut self, v: bool) -> Self {
+		self.config.dns = Some(v);
+		self
+	}
+
+	pub fn dnsseed(mut self, v: bool) -> Self {
+		self.config.dnsseed = Some(v);
+		self
+	}
+
+	pub fn fixedseeds(mut self, v: bool) -> Self {
+		self.config.fixedseeds = Some(v);
+		self
+	}
+
+	pub fn txindex(mut self, v: bool) -> Self {
+		self.config.txindex = v;
+		self
+	}
+
+	pub fn blockfilterindex(mut self, v: BlockFilterIndex) -> Self {
+		self.config.blockfilterindex = Some(v);
+		self
+	}
+
+	pub fn coinstatsindex(mut self, v: bool) -> Self {
+		self.config.coinstatsindex = v;
+		self
+	}
+
+	/// Add a node to connect to exclusively.
+	pub fn connect(mut self, addr: impl Into<String>) -> Self {
+		self.config.connect.push(addr.into());
+		self
+	}
+
+	/// Add a node to connect to.
+	pub fn addnode(mut self, addr: impl Into<String>) -> Self {
+		self.config.addnodes.push(addr.into());
+		self
+	}
+
+	/// Use cookie authentication with the given cookie file.
 	///
-	/// Don't call this method before calling [start].
-	pub fn rpc_client(&self) -> Option<Result<rpc::Client, rpc::Error>> {
-		let (url, port) = self.rpc_info()?;
-		Some(rpc::Client::new(url, port))
+	/// This unsets any user and password that were set before.
+	pub fn rpccookie(mut self, path: impl Into<String>) -> Self {
+		self.config.rpccookie = Some(path.into());
+		self.config.rpcuser = None;
+		self.config.rpcpass = None;
+		self
 	}
 
-	pub fn take_stderr(&self) -> String {
-		self.runtime_data
-			.as_ref()
-			.map(|rt| mem::replace(&mut rt.lock().unwrap().state.stderr, String::new()))
-			.unwrap_or_default()
+	/// Use user and password authentication.
+	///
+	/// This unsets any cookie file that was set before.
+	pub fn rpcauth(mut self, user: impl Into<String>, pass: impl Into<String>) -> Self {
+		self.config.rpcuser = Some(user.into());
+		self.config.rpcpass = Some(pass.into());
+		self.config.rpccookie = None;
+		self
 	}
 
-	pub fn take_error_msgs(&self) -> Vec<String> {
-		self.runtime_data
-			.as_ref()
-			.map(|rt| mem::replace(&mut rt.lock().unwrap().state.error_msgs, Vec::new()))
-			.unwrap_or_default()
+	/// Use user and password authentication with generated hashed credentials.
+	///
+	/// Only the hash of the password is written to the config.
+	/// This unsets any cookie file that was set before.
+	pub fn hashed_rpcauth(mut self, user: impl Into<String>) -> Self {
+		let user = user.into();
+		let (rpcauth, pass) = utils::generate_rpcauth(&user);
+		self.config.rpcauth.push(rpcauth);
+		self.rpcauth(user, pass)
 	}
-}
 
-impl RunnerHelper for Daemon {
-	type State = State;
+	/// Add hashed credentials, like the ones of [utils::generate_rpcauth].
+	pub fn rpcauth_line(mut self, rpcauth: impl Into<String>) -> Self {
+		self.config.rpcauth.push(rpcauth.into());
+		self
+	}
 
-	fn _prepare(&mut self) -> Result<(), Error> {
-		if self.config_file.is_some() {
-			return Ok(());
-		}
+	pub fn rpcport(mut self, port: u16) -> Self {
+		self.config.rpcport = Some(port);
+		self
+	}
 
-		// Make sure the datadir exists.
-		fs::create_dir_all(&self.config.datadir)?;
+	/// Allow RPC connections from the given IP or subnet.
+	///
+	/// Once set, localhost is no longer allowed by default.
+	pub fn rpcallowip(mut self, ip: impl Into<String>) -> Self {
+		self.config.rpcallowip.push(ip.into());
+		self
+	}
 
-		// Write the config file once and store the path.
-		let mut path: PathBuf = self.config.datadir.clone().into();
-		path.push(CONFIG_FILENAME);
-		let mut file = File::create(&path)?;
-		self.config.write_into(&mut file)?;
-		self.config_file = Some(path);
-		Ok(())
+	/// Let the RPC interface listen on the given address.
+	///
+	/// Once set, the RPC interface no longer listens on localhost by default.
+	pub fn rpcbind(mut self, addr: impl Into<String>) -> Self {
+		self.config.rpcbind.push(addr.into());
+		self
 	}
 
-	fn _command(&self) -> process::Command {
-		let mut cmd = process::Command::new(self.executable.clone());
-		cmd.args(&[
-			&format!("-conf={}", self.config_file.as_ref().unwrap().as_path().display()),
-			"-printtoconsole=1",
-		]);
-		cmd
+	pub fn disablewallet(mut self, v: bool) -> Self {
+		self.config.disablewallet = Some(v);
+		self
 	}
 
-	fn _init_state(&self) -> Self::State {
-		State {
-			stderr: String::new(),
+	/// Keep the given deprecated RPC method or field enabled.
+	pub fn deprecatedrpc(mut self, v: impl Into<String>) -> Self {
+		self.config.deprecatedrpc.push(v.into());
+		self
+	}
 
-			stdout_file: if self.config.version < 18_00_00 {
-				let mut path = self.config.datadir.clone();
-				path.push("stdout.log");
-				debug!("Writing bitcoind stdout to {}", path.display());
-				Some(File::create(&path).expect("failed to create stdout log file"))
-			} else {
-				None
-			},
-			error_msgs: Vec::new(),
-		}
+	/// Load the wallet with the given name at startup.
+	pub fn wallet(mut self, name: impl Into<String>) -> Self {
+		self.config.wallets.push(name.into());
+		self
 	}
 
-	fn _notif_started(&mut self, runtime_data: Arc<Mutex<RuntimeData<Self::State>>>) {
-		self.runtime_data.replace(runtime_data);
+	pub fn dbcache(mut self, v: u32) -> Self {
+		self.config.dbcache = Some(v);
+		self
 	}
 
-	fn _get_runtime(&self) -> Option<Arc<Mutex<RuntimeData<Self::State>>>> {
-		self.runtime_data.clone()
+	/// See [Config::checkblocks].
+	pub fn checkblocks(mut self, v: u32) -> Self {
+		self.config.checkblocks = Some(v);
+		self
 	}
 
-	fn _process_stdout(name: &str, state: &mut Self::State, line: &str) {
-		use std::io::Write;
+	/// See [Config::checklevel].
+	pub fn checklevel(mut self, v: u8) -> Self {
+		self.config.checklevel = Some(v);
+		self
+	}
 
-		if let Some(ref mut file) = state.stdout_file {
-			writeln!(file, "{}", line).unwrap();
-		}
+	/// Prune the block files to the given size in MiB. See [Config::prune].
+	///
+	/// This disables the transaction index, which can't be used with pruning.
+	pub fn prune(mut self, mib: u64) -> Self {
+		self.config.prune = Some(mib);
+		self.config.txindex = false;
+		self
+	}
 
-		lazy_static! {
-			/// Regular expression to match for error messages.
-			static ref ERROR_REGEX: Regex = Regex::new(r"(?i)ERROR").unwrap();
-		}
-		if ERROR_REGEX.is_match(line) {
-			debug!("{}: found error: {}", name, line);
-			state.error_msgs.push(line.to_string());
-		}
+	/// Limit outbound traffic to the given number of MiB per 24 hours.
+	pub fn maxuploadtarget(mut self, v: u64) -> Self {
+		self.config.maxuploadtarget = Some(v);
+		self
 	}
 
-	fn _process_stderr(state: &mut Self::State, line: &str) {
-		writeln!(&mut state.stderr, "{}", line).unwrap();
+	pub fn addresstype(mut self, v: impl Into<String>) -> Self {
+		self.config.addresstype = Some(v.into());
+		self
 	}
-}
 
-impl DaemonRunner for Daemon {}
+	pub fn blockmintxfee(mut self, v: f64) -> Self {
+		self.config.blockmintxfee = Some(v);
+		self
+	}
+
+	pub fn minrelaytxfee(mut self, v: f64) -> Self {
+		self.config.minrelaytxfee = Some(v);
+		self
+	}
+
+	pub fn fallbackfee(mut self, v: f64) -> Self {
+		self.config.fallbackfee = Some(v);
+		self
+	}
+
+	/// Use the given command as external signer.
+	pub fn signer<P: Into<PathBuf>>(mut self, cmd: P) -> Self {
+		self.config.signer = Some(cmd.into());
+		self
+	}
+
+	pub fn zmqpubhashblock(mut self, endpoint: impl Into<String>) -> Self {
+		self.config.zmqpubhashblock = Some(endpoint.into());
+		self
+	}
+
+	pub fn zmqpubrawblock(mut self, endpoint: impl Into<String>) -> Self {
+		self.config.zmqpubrawblock = Some(endpoint.into());
+		self
+	}
+
+	pub fn zmqpubhashtx(mut self, endpoint: impl Into<String>) -> Self {
+		self.config.zmqpubhashtx = Some(endpoint.into());
+		self
+	}
+
+	pub fn zmqpubrawtx(mut self, endpoint: impl Into<String>) -> Self {
+		self.config.zmqpubrawtx = Some(endpoint.into());
+		self
+	}
+
+	pub fn zmqpubsequence(mut self, endpoint: impl Into<String>) -> Self {
+		self.config.zmqpubsequence = Some(endpoint.into());
+		self
+	}
+
+	/// See [Config::hermetic].
+	pub fn hermetic(mut self, v: bool) -> Self {
+		self.config.hermetic = v;
+		self
+	}
+
+	/// See [RunnerConfig::block_on_drop].
+	pub fn block_on_drop(mut self, v: bool) -> Self {
+		self.config.runner.block_on_drop = v;
+		self
+	}
+
+	/// See [Config::fail_on_unknown_options].
+	pub fn fail_on_unknown_options(mut self, v: bool) -> Self {
+		self.config.fail_on_unknown_options = v;
+		self
+	}
+
+	/// See [Config::create_wallet].
+	pub fn create_wallet(mut self, name: impl Into<String>) -> Self {
+		self.config.create_wallet = Some(name.into());
+		self
+	}
+
+	/// See [Config::test_defaults].
+	pub fn test_defaults(mut self, v: bool) -> Self {
+		self.config.test_defaults = v;
+		self
+	}
+
+	/// See [Config::rpc_timeout].
+	pub fn rpc_timeout(mut self, v: time::Duration) -> Self {
+		self.config.rpc_timeout = Some(v);
+		self
+	}
+
+	/// See [RunnerConfig::max_stderr_bytes].
+	pub fn max_stderr_bytes(mut self, v: usize) -> Self {
+		self.config.runner.max_stderr_bytes = Some(v);
+		self
+	}
+
+	/// See [Config::check_disk_space].
+	pub fn check_disk_space(mut self, v: bool) -> Self {
+		self.config.check_disk_space = v;
+		self
+	}
+
+	/// See [Config::min_free_disk_space].
+	pub fn min_free_disk_space(mut self, bytes: u64) -> Self {
+		self.config.min_free_disk_space = Some(bytes);
+		self
+	}
+
+	/// See [Config::max_stdout_bytes].
+	pub fn max_stdout_bytes(mut self, v: usize) -> Self {
+		self.config.max_stdout_bytes = Some(v);
+		self
+	}
+
+	/// See [RunnerConfig::max_error_msgs].
+	pub fn max_error_msgs(mut self, v: usize) -> Self {
+		self.config.runner.max_error_msgs = Some(v);
+		self
+	}
+
+	/// See [Config::tail_debug_log].
+	pub fn tail_debug_log(mut self, v: bool) -> Self {
+		self.config.tail_debug_log = v;
+		self
+	}
+
+	/// Write all output to [logs::DEFAULT_LOG_FILENAME] in the datadir,
+	/// keeping [keep] rotated files of at most [max_bytes] bytes.
+	///
+	/// See [Config::log_file].
+	pub fn log_file(mut self, max_bytes: u64, keep: usize) -> Self {
+		self.config.log_file = Some(logs::LogFileConfig {
+			filename: logs::DEFAULT_LOG_FILENAME.to_owned(),
+			max_bytes,
+			keep,
+		});
+		self
+	}
+
+	/// Validate the options and build the config.
+	pub fn build(self) -> Result<Config, Error> {
+		let mut c = self.config;
+		if !c.datadir.is_absolute() {
+			return Err(Error::Config("datadir should be an absolute path"));
+		}
+		if c.test_defaults {
+			c.apply_test_defaults();
+		}
+		if c.rpcuser.is_some() != c.rpcpass.is_some() {
+			return Err(Error::Config("rpcuser and rpcpass should be set together"));
+		}
+		if c.rpcport.is_some() && c.rpccookie.is_none() && c.rpcuser.is_none() {
+			return Err(Error::Config("rpcport is set without RPC authentication"));
+		}
+		if c.port.is_some() && c.port == c.rpcport {
+			return Err(Error::Config("port and rpcport should be different"));
+		}
+		if c.disablewallet == Some(true) && (!c.wallets.is_empty() || c.create_wallet.is_some()) {
+			return Err(Error::Config("wallets are set while the wallet is disabled"));
+		}
+		if let Some(prune) = c.prune {
+			if prune > 1 && prune < 550 {
+				return Err(Error::Config("prune should be 1 or at least 550 MiB"));
+			}
+			if prune > 0 && c.txindex {
+				return Err(Error::Config("prune is incompatible with txindex"));
+			}
+		}
+		if c.signet {
+			if c.network.map(|n| n != bitcoin::Network::Bitcoin).unwrap_or(false) {
+				return Err(Error::Config("signet can't be combined with another network"));
+			}
+			if c.version != 0 && c.version < 21_00_00 {
+				return Err(Error::Config("signet is only supported since version 0.21"));
+			}
+		} else if c.signetchallenge.is_some() || !c.signetseednodes.is_empty() {
+			return Err(Error::Config("signet options are set without signet"));
+		}
+		if c.checklevel.map(|l| l > 4).unwrap_or(false) {
+			return Err(Error::Config("checklevel should be between 0 and 4"));
+		}
+		let fees = [c.blockmintxfee, c.minrelaytxfee, c.fallbackfee];
+		if fees.iter().flatten().any(|f| !f.is_finite() || *f < 0.0) {
+			return Err(Error::Config("fee rates should be non-negative"));
+		}
+		Ok(c)
+	}
+}
+
+#[derive(Default)]
+pub struct State {
+	pub last_update_tip: Option<(u64, bitcoin::BlockHash)>,
+	/// The time at which [last_update_tip] was last updated.
+	pub last_update_tip_time: Option<time::Instant>,
+	/// The sync progress from the last UpdateTip line, between 0 and 1.
+	pub sync_progress: Option<f64>,
+	/// The progress of the block verification at startup, between 0 and 1.
+	pub verification_progress: Option<f64>,
+	/// The number of blocks and the check level of the block verification at startup.
+	pub verification_scope: Option<(u64, u8)>,
+	/// The time at which the block verification started.
+	pub verification_start: Option<time::Instant>,
+	/// How long the block verification took, once it finished.
+	pub verification_time: Option<time::Duration>,
+	/// Buffer holding the most recent stdout output.
+	/// Only present when [Config::max_stdout_bytes] is set.
+	pub stdout: Option<logs::LineBuffer>,
+
+	/// For older versions, write stdout to this file.
+	pub stdout_file: Option<File>,
+
+	/// The log file all output is written to, if configured.
+	pub log_file: Option<logs::RotatingFile>,
+
+	/// The startup arguments the daemon reported to have picked up,
+	/// from both the command line and the config file.
+	pub effective_args: BTreeMap<String, Vec<String>>,
+
+	/// Warnings about options the daemon doesn't understand.
+	pub unknown_options: Vec<String>,
+
+	/// The peers that were disconnected because the upload target was reached.
+	/// Only detected with the `net` debug category enabled.
+	pub upload_limit_disconnects: Vec<u64>,
+
+	/// The wallet passphrase and unlock duration used to unlock
+	/// the wallet before signing.
+	wallet_unlock: Option<(utils::Secret, time::Duration)>,
+
+	/// The user hook for stdout lines.
+	stdout_hook: logs::LogHook,
+
+	/// The captured output of the daemon.
+	pub output: logs::Output,
+}
+
+impl AsRef<logs::Output> for State {
+	fn as_ref(&self) -> &logs::Output {
+		&self.output
+	}
+}
+
+impl AsMut<logs::Output> for State {
+	fn as_mut(&mut self) -> &mut logs::Output {
+		&mut self.output
+	}
+}
+
+pub struct Daemon {
+	common: Common<State>,
+	/// Environment variables set for the process.
+	env: BTreeMap<String, String>,
+	/// Extra command line arguments for the process.
+	args: Vec<String>,
+	/// The user hook for stdout lines, shared with the runtime state.
+	stdout_hook: logs::LogHook,
+	executable: PathBuf,
+	config: Config,
+
+	/// The path of the written config file.
+	/// [None] before it has been written.
+	config_file: Option<PathBuf>,
+	/// The temporary datadir to remove on drop, see [Daemon::new_tempdir].
+	temp_datadir: Option<PathBuf>,
+	/// The log file opened by [_prepare], until it's moved into the state.
+	log_file: Option<logs::RotatingFile>,
+}
+
+impl Daemon {
+	pub fn new<P: Into<PathBuf>>(executable: P, config: Config) -> Result<Daemon, Error> {
+		if !config.datadir.is_absolute() {
+			return Err(Error::Config("datadir should be an absolute path"));
+		}
+
+		Ok(Daemon {
+			common: Common::new(&config.runner),
+			executable: executable.into(),
+			env: BTreeMap::new(),
+			args: Vec::new(),
+			stdout_hook: logs::LogHook::default(),
+			config,
+
+			config_file: None,
+			temp_datadir: None,
+			log_file: None,
+		})
+	}
+
+	/// Create a daemon in a new temporary datadir.
+	///
+	/// The datadir of the config should not be set. The temporary datadir is
+	/// removed when the daemon is dropped, unless [keep_temp_datadir] is called.
+	pub fn new_tempdir(
+		executable: impl Into<PathBuf>,
+		mut config: Config,
+	) -> Result<Daemon, Error> {
+		if config.datadir != PathBuf::new() {
+			return Err(Error::Config("datadir should not be set"));
+		}
+		let datadir = utils::create_temp_dir("bitcoind")?;
+		config.datadir = datadir.clone();
+		match Daemon::new(executable, config) {
+			Ok(mut daemon) => {
+				daemon.temp_datadir = Some(datadir);
+				Ok(daemon)
+			}
+			Err(e) => {
+				utils::remove_temp_dir(&datadir);
+				Err(e)
+			}
+		}
+	}
+
+	/// Don't remove the temporary datadir on drop, for example to inspect it
+	/// after a failure.
+	pub fn keep_temp_datadir(&mut self) {
+		if let Some(dir) = self.temp_datadir.take() {
+			info!("Keeping temporary datadir {} of {:?}", dir.display(), self);
+		}
+	}
+
+	/// Create and start a regtest node in a temporary datadir, which is
+	/// removed when the node is dropped.
+	///
+	/// The bitcoind executable is taken from the `BITCOIND_EXE` environment
+	/// variable or searched in `PATH`. The node has its wallet enabled and
+	/// a fallback fee set so that it can be used for sending right away.
+	/// This method returns once the node's RPC interface is ready.
+	pub fn regtest_tmp() -> Result<Daemon, Error> {
+		let executable = utils::find_executable("bitcoind", EXECUTABLE_ENV_VAR)
+			.ok_or(Error::Config("bitcoind executable not found"))?;
+		let ports = utils::find_free_ports(2);
+		let mut daemon = Daemon::new_tempdir(
+			executable,
+			Config {
+				network: Some(bitcoin::Network::Regtest),
+				port: Some(ports[0]),
+				rpcport: Some(ports[1]),
+				rpcuser: Some("user".into()),
+				rpcpass: Some(format!("{:016x}", rand::random::<u64>())),
+				disablewallet: Some(false),
+				fallbackfee: Some(TEST_FALLBACKFEE),
+				..Default::default()
+			},
+		)?;
+		daemon.start()?;
+		daemon.wait_for_ready(REGTEST_TMP_TIMEOUT)?;
+		Ok(daemon)
+	}
+
+	/// Wait until the daemon is ready to handle RPC requests.
+	///
+	/// The RPC interface is polled with an increasing interval. This fails when
+	/// the daemon exits or when it isn't ready after [timeout].
+	///
+	/// When [Config::fail_on_unknown_options] is set, this also fails if the
+	/// daemon warned about options it doesn't understand.
+	/// When [Config::create_wallet] is set, the wallet is loaded or created.
+	pub fn wait_for_ready(&self, timeout: time::Duration) -> Result<(), Error> {
+		runner::wait_until(self, "daemon to become ready", timeout, || {
+			let rpc = match self.rpc_client_with_timeout(Some(utils::HEALTH_CHECK_RPC_TIMEOUT)) {
+				Some(Ok(rpc)) => rpc,
+				_ => return false,
+			};
+			let res = rpc.call::<serde_json::Value>("getblockchaininfo", &[]);
+			if rpc_responded(&res) {
+				self.record_startup(StartupStage::RpcListening);
+			}
+			res.is_ok()
+		})?;
+		self.record_startup(StartupStage::Ready);
+
+		let unknown = self.unknown_options();
+		if self.config.fail_on_unknown_options && !unknown.is_empty() {
+			return Err(Error::UnknownOptions(unknown));
+		}
+		if let Some(ref name) = self.config.create_wallet {
+			load_or_create_wallet(&self.rpc()?, name)?;
+		}
+		Ok(())
+	}
+
+	/// Detect the version of the executable and use it for the config.
+	///
+	/// This should be called before the daemon is first started,
+	/// because that is when the config file is written.
+	pub fn detect_version(&mut self) -> Result<u64, Error> {
+		let version = utils::detect_version(&self.executable)?;
+		debug!("Detected version {} for {:?}", utils::format_version(version), self);
+		self.config.version = version;
+		Ok(version)
+	}
+
+	/// Set an environment variable for the process, like `MALLOC_ARENA_MAX`.
+	///
+	/// The process inherits the environment of the current process otherwise.
+	/// Changes take effect the next time the daemon is started.
+	pub fn set_env(&mut self, key: impl Into<String>, value: impl Into<String>) {
+		self.env.insert(key.into(), value.into());
+	}
+
+	/// The environment variables set for the process.
+	pub fn env(&self) -> &BTreeMap<String, String> {
+		&self.env
+	}
+
+	/// Add a command line argument for the process, like `-debug=net`.
+	///
+	/// The arguments are passed after the ones for the config file, so that
+	/// options the config doesn't support can be used. Changes take effect
+	/// the next time the daemon is started.
+	pub fn add_arg(&mut self, arg: impl Into<String>) {
+		self.args.push(arg.into());
+	}
+
+	/// The extra command line arguments for the process.
+	pub fn args(&self) -> &[String] {
+		&self.args
+	}
+
+	pub fn config(&self) -> &Config {
+		&self.config
+	}
+
+	pub fn datadir(&self) -> &Path {
+		self.config.datadir.as_path()
+	}
+
+	/// The directory where the daemon stores network-specific data.
+	pub fn network_datadir(&self) -> PathBuf {
+		match self.config.network {
+			_ if self.config.signet => self.config.datadir.join("signet"),
+			Some(bitcoin::Network::Bitcoin) | None => self.config.datadir.clone(),
+			Some(bitcoin::Network::Testnet) => self.config.datadir.join("testnet3"),
+			Some(bitcoin::Network::Regtest) => self.config.datadir.join("regtest"),
+		}
+	}
+
+	/// The path of the RPC cookie file.
+	///
+	/// This is [None] when user and password authentication is used.
+	/// Relative paths are resolved like the daemon does, against the
+	/// network-specific datadir.
+	pub fn cookie_path(&self) -> Option<PathBuf> {
+		let path = match self.config.rpccookie {
+			Some(ref c) => PathBuf::from(c),
+			None if self.config.rpcuser.is_some() => return None,
+			None => PathBuf::from(".cookie"),
+		};
+		if path.is_absolute() {
+			Some(path)
+		} else {
+			Some(self.network_datadir().join(path))
+		}
+	}
+
+	/// Wait for the daemon to create its RPC cookie file and return its path.
+	///
+	/// The cookie file is written asynchronously after the daemon is started.
+	pub fn wait_for_cookie(&self, timeout: time::Duration) -> Result<PathBuf, Error> {
+		let path = self.cookie_path().ok_or(Error::Config("cookie authentication is not used"))?;
+		let start = time::Instant::now();
+		loop {
+			if fs::metadata(&path).map(|m| m.len() > 0).unwrap_or(false) {
+				return Ok(path);
+			}
+			if let Status::Stopped(s) = self.status()? {
+				return Err(Error::Exited(s.into()));
+			}
+			if start.elapsed() > timeout {
+				return Err(Error::Timeout("cookie file to be created"));
+			}
+			thread::sleep(time::Duration::from_millis(50));
+		}
+	}
+
+	/// Get the RPC info.
+	///
+	/// When neither a cookie file nor a user is configured, the default
+	/// cookie file in the network-specific datadir is used.
+	/// Don't call this method before calling [start].
+	pub fn rpc_info(&self) -> Option<(String, rpc::Auth)> {
+		let url = format!("http://127.0.0.1:{}", self.config.rpcport?);
+		// Without any auth configured, the daemon uses the default cookie file.
+		let auth = match self.cookie_path() {
+			Some(path) => rpc::Auth::CookieFile(path),
+			None => {
+				let user = self.config.rpcuser.as_ref()?.clone();
+				let pass = self.config.rpcpass.as_ref()?.clone();
+				rpc::Auth::UserPass(user, pass)
+			}
+		};
+		Some((url, auth))
+	}
+
+	/// Iterate over the blocks the daemon connects from now on.
+	///
+	/// When built with the `zmq` feature and [Config::zmqpubhashblock] is
+	/// set, the blocks are taken from the ZMQ notifications. Otherwise they
+	/// are taken from the UpdateTip log lines. The iterator ends when the
+	/// daemon stops. See [logs::Blocks].
+	pub fn blocks(&self) -> Result<logs::Blocks<State>, Error> {
+		let observer = self.observer()?;
+		#[cfg(feature = "zmq")]
+		{
+			if let Some(ref endpoint) = self.config.zmqpubhashblock {
+				let socket = zmq::Context::new().socket(zmq::SUB)?;
+				socket.connect(endpoint)?;
+				socket.set_subscribe(b"hashblock")?;
+				let rpc = self.rpc_client().ok_or(Error::Config("RPC is not configured"))??;
+				return Ok(logs::Blocks::from_zmq(socket, rpc, observer));
+			}
+		}
+		Ok(logs::Blocks::from_events(self.subscribe_events(), observer))
+	}
+
+	/// Derive custom state from the stdout lines of the daemon.
+	///
+	/// The callback is called with the given state for every line, before the
+	/// line is processed by the daemon itself. Use [with_hook_state] to access
+	/// the state. This replaces any hook that was set before and can be used
+	/// both before and after the daemon is started.
+	pub fn set_stdout_hook<T, F>(&self, state: T, callback: F)
+	where
+		T: Any + Send,
+		F: FnMut(&mut T, &str) + Send + 'static,
+	{
+		self.stdout_hook.set(state, callback);
+	}
+
+	/// Remove the stdout hook and return its state.
+	///
+	/// Returns [None] if no hook was set or if its state has another type.
+	pub fn clear_stdout_hook<T: Any>(&self) -> Option<T> {
+		self.stdout_hook.clear()
+	}
+
+	/// Run the closure with the state of the stdout hook.
+	///
+	/// Returns [None] if no hook was set or if its state has another type.
+	pub fn with_hook_state<T: Any, R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+		self.stdout_hook.with_state(f)
+	}
+
+	/// Get an RPC client.
+	///
+	/// Don't call this method before calling [start].
+	pub fn rpc_client(&self) -> Option<Result<rpc::Client, rpc::Error>> {
+		let (url, port) = self.rpc_info()?;
+		Some(rpc::Client::new(url, port))
+	}
+
+	/// Get an RPC client for the wallet with the given name.
+	///
+	/// Don't call this method before calling [start].
+	pub fn rpc_client_for_wallet(&self, name: &str) -> Option<Result<rpc::Client, rpc::Error>> {
+		let (url, port) = self.rpc_info()?;
+		let mut path = String::with_capacity(name.len());
+		for b in name.bytes() {
+			match b {
+				b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+					path.push(b as char)
+				}
+				_ => write!(path, "%{:02X}", b).unwrap(),
+			}
+		}
+		Some(rpc::Client::new(format!("{}/wallet/{}", url, path), port))
+	}
+
+	/// Get an RPC client whose calls time out after the given timeout.
+	///
+	/// Use a short timeout for health checks and a long one, or none at all,
+	/// for calls that can take long, like those during a reindex.
+	/// Don't call this method before calling [start].
+	pub fn rpc_client_with_timeout(
+		&self,
+		timeout: Option<time::Duration>,
+	) -> Option<Result<utils::TimeoutClient, rpc::Error>> {
+		let (url, auth) = self.rpc_info()?;
+		Some(utils::TimeoutClient::new(&url, auth, timeout))
+	}
+
+	fn rpc(&self) -> Result<utils::TimeoutClient, Error> {
+		let rpc = self.rpc_client_with_timeout(self.config.rpc_timeout);
+		Ok(rpc.ok_or(Error::Config("RPC is not configured"))??)
+	}
+
+	/// Get the wallet balance of outputs with at least [min_conf] confirmations.
+	pub fn balance(&self, min_conf: u32) -> Result<bitcoin::Amount, Error> {
+		Ok(self.rpc()?.get_balance(Some(min_conf as usize), None)?)
+	}
+
+	/// The height of the first block that has not been pruned.
+	///
+	/// This is [None] when the node is not pruned.
+	pub fn prune_height(&self) -> Result<Option<u64>, Error> {
+		let info = self.rpc()?.call::<serde_json::Value>("getblockchaininfo", &[])?;
+		if info["pruned"].as_bool() != Some(true) {
+			return Ok(None);
+		}
+		Ok(info["pruneheight"].as_u64())
+	}
+
+	/// The last tip the daemon logged, as height and block hash.
+	pub fn last_update_tip(&self) -> Option<(u64, bitcoin::BlockHash)> {
+		self.with_state(|s| s.last_update_tip).flatten()
+	}
+
+	/// The time at which the last known tip was updated.
+	pub fn last_update_tip_time(&self) -> Option<time::Instant> {
+		self.with_state(|s| s.last_update_tip_time).flatten()
+	}
+
+	/// The estimated sync progress from the logs, between 0 and 1.
+	///
+	/// This is [None] until the daemon logged a new tip.
+	pub fn sync_progress(&self) -> Option<f64> {
+		self.with_state(|s| s.sync_progress).flatten()
+	}
+
+	/// The progress of the block verification at startup, between 0 and 1.
+	pub fn verification_progress(&self) -> Option<f64> {
+		self.with_state(|s| s.verification_progress).flatten()
+	}
+
+	/// The number of blocks and the check level of the block verification
+	/// at startup, as logged by the daemon.
+	///
+	/// See [Config::checkblocks] and [Config::checklevel].
+	pub fn verification_scope(&self) -> Option<(u64, u8)> {
+		self.with_state(|s| s.verification_scope).flatten()
+	}
+
+	/// How long the block verification at startup took.
+	///
+	/// This is [None] while the verification is still running.
+	pub fn verification_time(&self) -> Option<time::Duration> {
+		self.with_state(|s| s.verification_time).flatten()
+	}
+
+	/// Stop the daemon such that a snapshot of its datadir is consistent.
+	///
+	/// The chain state is flushed to disk with `gettxoutsetinfo` first. Then the
+	/// daemon is asked to shut down, which also flushes its wallets. This fails
+	/// when the daemon didn't exit cleanly within [timeout] or when its datadir
+	/// is still locked afterwards. If the daemon already stopped, only its exit
+	/// and the lock are checked.
+	pub fn stop_for_snapshot(&self, timeout: time::Duration) -> Result<(), Error> {
+		if self.status()? == Status::Running {
+			let version = if self.config.version > 0 {
+				self.config.version
+			} else {
+				DEFAULT_VERSION
+			};
+			// Older versions can't skip the hash calculation.
+			let hash_type = if version < 21_00_00 {
+				UtxoHashType::HashSerialized
+			} else {
+				UtxoHashType::None
+			};
+			self.utxo_set_info(hash_type)?;
+			self.stop_with_timeout(timeout)?;
+		}
+		match self.status()? {
+			Status::Stopped(s) if ExitKind::from(s).is_clean() => {}
+			Status::Stopped(s) => return Err(Error::Exited(s.into())),
+			s => return Err(Error::InvalidState(s)),
+		}
+		if utils::datadir_locked(&self.network_datadir())? {
+			return Err(Error::Custom("datadir is still locked after shutdown"));
+		}
+		Ok(())
+	}
+
+	/// Get a summary of the UTXO set using `gettxoutsetinfo`.
+	pub fn utxo_set_info(&self, hash_type: UtxoHashType) -> Result<UtxoSetInfo, Error> {
+		let version = if self.config.version > 0 {
+			self.config.version
+		} else {
+			DEFAULT_VERSION
+		};
+		utxo::query_utxo_set_info(&self.rpc()?, version, hash_type)
+	}
+
+	/// The consensus constants of the configured network.
+	pub fn chain_params(&self) -> ChainParams {
+		ChainParams {
+			coinbase_maturity: chain_params::COINBASE_MATURITY,
+			subsidy_halving_interval: match self.config.network {
+				Some(bitcoin::Network::Regtest) => chain_params::REGTEST_HALVING_INTERVAL,
+				_ => chain_params::MAINNET_HALVING_INTERVAL,
+			},
+			initial_subsidy: bitcoin::Amount::from_sat(chain_params::DEFAULT_INITIAL_SUBSIDY_SAT),
+		}
+	}
+
+	/// Mine the given number of blocks to the given address.
+	pub fn generate_to(
+		&self,
+		blocks: u64,
+		address: &bitcoin::Address,
+	) -> Result<Vec<bitcoin::BlockHash>, Error> {
+		Ok(self.rpc()?.generate_to_address(blocks, address)?)
+	}
+
+	/// Mine a block to the wallet and enough blocks on top of it
+	/// for its coinbase to be spendable.
+	///
+	/// Returns the hashes of the mined blocks.
+	pub fn mature_coinbase(&self) -> Result<Vec<bitcoin::BlockHash>, Error> {
+		let address = self.new_address(None)?;
+		self.generate_to(self.chain_params().coinbase_maturity + 1, &address)
+	}
+
+	/// List all unspent outputs in the wallet.
+	pub fn unspent(&self) -> Result<Vec<rpc::json::ListUnspentResultEntry>, Error> {
+		Ok(self.rpc()?.list_unspent(None, None, None, None, None)?)
+	}
+
+	/// Generate a new wallet address of the given type.
+	/// If no type is given, the daemon's default `addresstype` is used.
+	pub fn new_address(
+		&self,
+		kind: Option<rpc::json::AddressType>,
+	) -> Result<bitcoin::Address, Error> {
+		Ok(self.rpc()?.get_new_address(None, kind)?)
+	}
+
+	/// Create a ZMQ socket subscribed to all configured notification topics.
+	///
+	/// Messages are multipart: the topic, the body and a sequence number.
+	#[cfg(feature = "zmq")]
+	pub fn zmq_subscriber(&self) -> Result<zmq::Socket, Error> {
+		let endpoints = self.config.zmq_endpoints();
+		if endpoints.is_empty() {
+			return Err(Error::Config("no ZMQ notifications configured"));
+		}
+
+		let socket = zmq::Context::new().socket(zmq::SUB)?;
+		let mut connected = Vec::new();
+		for (topic, endpoint) in endpoints {
+			if !connected.contains(&endpoint) {
+				socket.connect(endpoint)?;
+				connected.push(endpoint);
+			}
+			socket.set_subscribe(topic.as_bytes())?;
+		}
+		Ok(socket)
+	}
+
+	/// Encrypt the wallet with the given passphrase.
+	///
+	/// The passphrase is remembered so that the wallet can be unlocked
+	/// automatically before signing. See [unlock_wallet].
+	pub fn encrypt_wallet(&self, passphrase: &str) -> Result<(), Error> {
+		let rpc = self.rpc()?;
+		rpc.encrypt_wallet(passphrase)?;
+		self.with_state_mut(|s| {
+			s.wallet_unlock = Some((utils::Secret::new(passphrase), WALLET_UNLOCK_TIMEOUT));
+		});
+		Ok(())
+	}
+
+	/// Unlock the wallet for the given duration.
+	///
+	/// The passphrase is remembered so that the wallet is unlocked again
+	/// for the same duration before the helpers that sign.
+	pub fn unlock_wallet(&self, passphrase: &str, timeout: time::Duration) -> Result<(), Error> {
+		let pass = utils::Secret::new(passphrase);
+		self.unlock_with(&self.rpc()?, &pass, timeout)?;
+		self.with_state_mut(|s| s.wallet_unlock = Some((pass, timeout)));
+		Ok(())
+	}
+
+	/// Lock the wallet and forget the passphrase.
+	pub fn lock_wallet(&self) -> Result<(), Error> {
+		self.with_state_mut(|s| s.wallet_unlock = None);
+		let rpc = self.rpc()?;
+		rpc.call::<()>("walletlock", &[])?;
+		Ok(())
+	}
+
+	fn unlock_with(
+		&self,
+		rpc: &impl RpcApi,
+		pass: &utils::Secret,
+		timeout: time::Duration,
+	) -> Result<(), Error> {
+		let secs = cmp::max(timeout.as_secs(), 1);
+		rpc.call::<()>("walletpassphrase", &[pass.expose().into(), secs.into()])?;
+		Ok(())
+	}
+
+	/// Unlock the wallet if a passphrase is known.
+	fn ensure_unlocked(&self, rpc: &impl RpcApi) -> Result<(), Error> {
+		if let Some((pass, timeout)) = self.with_state(|s| s.wallet_unlock.clone()).flatten() {
+			self.unlock_with(rpc, &pass, timeout)?;
+		}
+		Ok(())
+	}
+
+	/// Send the given amount to the address from the wallet.
+	///
+	/// The wallet is unlocked first if a passphrase is known.
+	pub fn send_to_address(
+		&self,
+		address: &bitcoin::Address,
+		amount: bitcoin::Amount,
+	) -> Result<bitcoin::Txid, Error> {
+		let rpc = self.rpc()?;
+		self.ensure_unlocked(&rpc)?;
+		Ok(rpc.send_to_address(address, amount, None, None, None, None, None, None)?)
+	}
+
+	/// Sign the transaction with the wallet.
+	///
+	/// The wallet is unlocked first if a passphrase is known.
+	pub fn sign_raw_transaction(
+		&self,
+		tx: &bitcoin::Transaction,
+	) -> Result<rpc::json::SignRawTransactionResult, Error> {
+		let rpc = self.rpc()?;
+		self.ensure_unlocked(&rpc)?;
+		Ok(rpc.sign_raw_transaction_with_wallet(tx, None, None)?)
+	}
+
+	/// Take the retained stdout output.
+	///
+	/// This is empty unless [Config::max_stdout_bytes] is set.
+	pub fn take_stdout(&self) -> String {
+		self.with_state_mut(|s| {
+			s.stdout
+				.as_mut()
+				.map(|b| b.take())
+				.unwrap_or_default()
+				.into_iter()
+				.map(|l| l + "\n")
+				.collect()
+		})
+		.unwrap_or_default()
+	}
+
+	/// The lines of the retained stdout and stderr output that match the pattern.
+	///
+	/// The output is not taken. Stdout is only retained when
+	/// [Config::max_stdout_bytes] is set.
+	pub fn grep_logs(&self, pattern: &Regex) -> Vec<String> {
+		self.with_state(|s| {
+			s.stdout
+				.iter()
+				.flat_map(|b| b.iter())
+				.chain(s.output.stderr.iter())
+				.filter(|l| pattern.is_match(l))
+				.cloned()
+				.collect()
+		})
+		.unwrap_or_default()
+	}
+
+	/// The number of stderr lines and error messages that were dropped
+	/// because their buffers were full.
+	///
+	/// See [RunnerConfig::max_stderr_bytes] and [RunnerConfig::max_error_msgs].
+	pub fn dropped_output(&self) -> (usize, usize) {
+		self.with_state(|s| (s.output.stderr.dropped(), s.output.error_msgs().dropped()))
+			.unwrap_or_default()
+	}
+
+	/// The number of stderr lines and error messages that were moved
+	/// to disk by compaction.
+	pub fn compacted_output(&self) -> (usize, usize) {
+		self.with_state(|s| (s.output.stderr.flushed(), s.output.error_msgs().flushed()))
+			.unwrap_or_default()
+	}
+
+	/// Move the buffered stderr output and error messages to archive files
+	/// in the datadir.
+	///
+	/// See [logs::Output::compact].
+	pub fn compact_logs(&self) -> Result<(), Error> {
+		let rt = self.common.runtime_data.as_ref().ok_or(Error::InvalidState(Status::Init))?;
+		rt.lock().unwrap().state.output.compact(&self.config.datadir)?;
+		Ok(())
+	}
+
+	/// Compact the logs every [interval] for as long as the daemon exists.
+	///
+	/// This is useful for long-running tests to keep memory usage flat
+	/// while retaining the full history on disk.
+	pub fn compact_logs_every(&self, interval: time::Duration) -> Result<(), Error> {
+		let rt = self.common.runtime_data.clone().ok_or(Error::InvalidState(Status::Init))?;
+		let dir = self.config.datadir.clone();
+		let name = format!("{:?}", self);
+		thread::Builder::new()
+			.name(format!("{}_compact", name))
+			.spawn(move || loop {
+				thread::sleep(interval);
+				// When we hold the only reference, the daemon was dropped.
+				if Arc::strong_count(&rt) == 1 {
+					break;
+				}
+				if let Err(e) = rt.lock().unwrap().state.output.compact(&dir) {
+					warn!("Failed to compact logs of daemon {}: {}", name, e);
+				}
+			})
+			.expect("failed to start compaction thread");
+		Ok(())
+	}
+
+	/// The startup arguments the daemon reported to have picked up.
+	///
+	/// Arguments that are given multiple times have multiple values.
+	pub fn effective_args(&self) -> BTreeMap<String, Vec<String>> {
+		self.with_state(|s| s.effective_args.clone()).unwrap_or_default()
+	}
+
+	/// Warnings the daemon gave about options it doesn't understand.
+	pub fn unknown_options(&self) -> Vec<String> {
+		self.with_state(|s| s.unknown_options.clone()).unwrap_or_default()
+	}
+
+	/// The peers that were disconnected because the upload target was reached.
+	///
+	/// Disconnects are only logged with the `net` debug category enabled.
+	pub fn upload_limit_disconnects(&self) -> Vec<u64> {
+		self.with_state(|s| s.upload_limit_disconnects.clone()).unwrap_or_default()
+	}
+
+	/// Whether the daemon reports that the upload target has been reached.
+	///
+	/// Only historical blocks are no longer served when this is the case.
+	pub fn upload_target_reached(&self) -> Result<bool, Error> {
+		let totals: serde_json::Value = self.rpc()?.call("getnettotals", &[])?;
+		totals["uploadtarget"]["serve_historical_blocks"]
+			.as_bool()
+			.map(|serving| !serving)
+			.ok_or(Error::Custom("invalid getnettotals response"))
+	}
+}
+
+impl RunnerHelper for Daemon {
+	type State = State;
+
+	fn _prepare(&mut self) -> Result<(), Error> {
+		// Open the log file here so that failing to do so fails the start.
+		if let Some(ref c) = self.config.log_file {
+			fs::create_dir_all(&self.config.datadir)?;
+			let path = self.config.datadir.join(&c.filename);
+			debug!("Writing {:?} output to {}", self, path.display());
+			self.log_file = Some(logs::RotatingFile::open(&path, c.max_bytes, c.keep)?);
+		}
+
+		if self.config_file.is_some() {
+			return Ok(());
+		}
+
+		// Make sure the datadir exists.
+		fs::create_dir_all(&self.config.datadir)?;
+
+		if self.config.check_disk_space {
+			utils::check_disk_space(&self.config.datadir, self.config.required_disk_space())?;
+		}
+
+		// Make sure the directory of a custom cookie file exists.
+		if self.config.rpccookie.is_some() {
+			if let Some(dir) = self.cookie_path().as_ref().and_then(|p| p.parent()) {
+				fs::create_dir_all(dir)?;
+			}
+		}
+
+		// Allocate free ports for the ones that are not set so that
+		// the config always knows where to reach the daemon.
+		let missing =
+			[self.config.port, self.config.rpcport].iter().filter(|p| p.is_none()).count();
+		let mut ports = utils::find_free_ports(missing).into_iter();
+		if self.config.port.is_none() {
+			self.config.port = ports.next();
+		}
+		if self.config.rpcport.is_none() {
+			self.config.rpcport = ports.next();
+		}
+
+		// Write the config file once and store the path.
+		let mut path: PathBuf = self.config.datadir.clone();
+		path.push(CONFIG_FILENAME);
+		let mut file = File::create(&path)?;
+		self.config.write_into(&mut file)?;
+		self.config_file = Some(path);
+		Ok(())
+	}
+
+	fn _reset(&mut self) -> Result<(), Error> {
+		if self.config.datadir.exists() {
+			fs::remove_dir_all(&self.config.datadir)?;
+		}
+		self.config_file = None;
+		Ok(())
+	}
+
+	fn _command(&self) -> process::Command {
+		let mut cmd = process::Command::new(self.executable.clone());
+		cmd.envs(&self.env);
+		cmd.arg(format!("-conf={}", self.config_file.as_ref().unwrap().as_path().display()));
+		if self.config.tail_debug_log {
+			cmd.arg("-printtoconsole=0");
+		} else {
+			cmd.arg("-printtoconsole=1");
+		}
+		cmd.args(&self.args);
+		cmd
+	}
+
+	fn _tail_log_path(&self) -> Option<PathBuf> {
+		if self.config.tail_debug_log {
+			Some(self.network_datadir().join("debug.log"))
+		} else {
+			None
+		}
+	}
+
+	fn _init_state(&mut self) -> Self::State {
+		State {
+			last_update_tip: None,
+			last_update_tip_time: None,
+			sync_progress: None,
+			verification_progress: None,
+			verification_scope: None,
+			verification_start: None,
+			verification_time: None,
+			stdout: self.config.max_stdout_bytes.map(|max| logs::LineBuffer::new(None, Some(max))),
+
+			stdout_file: if self.config.version < 18_00_00 {
+				let mut path = self.config.datadir.clone();
+				path.push("stdout.log");
+				debug!("Writing bitcoind stdout to {}", path.display());
+				Some(File::create(&path).expect("failed to create stdout log file"))
+			} else {
+				None
+			},
+			log_file: self.log_file.take(),
+			effective_args: BTreeMap::new(),
+			unknown_options: Vec::new(),
+			upload_limit_disconnects: Vec::new(),
+			wallet_unlock: None,
+			stdout_hook: self.stdout_hook.clone(),
+			output: self.common.new_output(&self.config.runner),
+		}
+	}
+
+	fn _notif_started(&mut self, runtime_data: Arc<Mutex<RuntimeData<Self::State>>>) {
+		self.common.runtime_data.replace(runtime_data.clone());
+
+		if let Some((url, auth)) = self.rpc_info() {
+			thread::Builder::new()
+				.name(format!("{:?}_init_tip", self))
+				.spawn(move || {
+					init_tip_from_rpc(url, auth, runtime_data, |s| {
+						(&mut s.last_update_tip, &mut s.last_update_tip_time)
+					})
+				})
+				.expect("failed to start tip init thread");
+		}
+	}
+
+	fn _get_runtime(&self) -> Option<Arc<Mutex<RuntimeData<Self::State>>>> {
+		self.common.runtime_data.clone()
+	}
+
+	fn _process_stdout(name: &str, state: &mut Self::State, line: &str) {
+		use std::io::Write;
+
+		if let Some(ref mut file) = state.stdout_file {
+			writeln!(file, "{}", line).unwrap();
+		}
+		if let Some(ref mut file) = state.log_file {
+			if let Err(e) = file.write_line(line) {
+				warn!("{}: failed to write to log file: {}", name, e);
+			}
+		}
+		if let Some(ref mut buf) = state.stdout {
+			buf.push(line.to_owned());
+		}
+		state.stdout_hook.process(line);
+
+		if let Some(tip) = logs::parse_update_tip(line) {
+			trace!("Setting new bitcoind tip: {:?}", tip);
+			state.last_update_tip = Some(tip);
+			state.last_update_tip_time = Some(time::Instant::now());
+			if let Some(progress) = logs::parse_sync_progress(line) {
+				state.sync_progress = Some(progress);
+			}
+			state.output.send(LogEvent::UpdateTip {
+				height: tip.0,
+				hash: tip.1,
+			});
+			return;
+		}
+
+		if let Some(scope) = logs::parse_verification_start(line) {
+			state.verification_scope = Some(scope);
+			state.verification_start = Some(time::Instant::now());
+		}
+		if let Some(progress) = logs::parse_verification_progress(line) {
+			state.verification_progress = Some(progress);
+			if progress >= 1.0 && state.verification_time.is_none() {
+				state.verification_time = state.verification_start.map(|s| s.elapsed());
+			}
+		}
+
+		if let Some(warning) = logs::parse_unknown_option_warning(line) {
+			warn!("{}: unknown option: {}", name, warning);
+			state.unknown_options.push(warning);
+			state.output.send(LogEvent::Warning(line.to_owned()));
+			return;
+		}
+
+		if let Some(peer) = logs::parse_upload_limit_disconnect(line) {
+			debug!("{}: upload target reached, disconnected peer {}", name, peer);
+			state.upload_limit_disconnects.push(peer);
+		}
+
+		if let Some((name, value)) = logs::parse_startup_arg(line) {
+			let values = state.effective_args.entry(name).or_default();
+			// Arguments are logged again on restart.
+			if !values.contains(&value) {
+				values.push(value);
+			}
+			state.output.send(LogEvent::RawLine(line.to_owned()));
+			return;
+		}
+
+		lazy_static! {
+			/// Regular expression to match for error messages.
+			static ref ERROR_REGEX: Regex = Regex::new(r"(?i)ERROR").unwrap();
+			/// Regular expression to match for warning messages.
+			static ref WARNING_REGEX: Regex = Regex::new(r"(?i)WARNING").unwrap();
+		}
+		if ERROR_REGEX.is_match(line) {
+			debug!("{}: found error: {}", name, line);
+		}
+		state.output.process_line(line, &ERROR_REGEX, &WARNING_REGEX);
+	}
+
+	fn _process_stderr(state: &mut Self::State, line: &str) {
+		if let Some(ref mut file) = state.log_file {
+			if let Err(e) = file.write_line(line) {
+				warn!("bitcoind: failed to write to log file: {}", e);
+			}
+		}
+		state.output.stderr.push(line.to_owned());
+		if let Some(warning) = logs::parse_unknown_option_warning(line) {
+			warn!("bitcoind: unknown option: {}", warning);
+			state.unknown_options.push(warning);
+		}
+	}
+}
+
+impl DaemonRunner for Daemon {}
+
+impl CommonDaemon for Daemon {
+	fn _common(&self) -> &Common<State> {
+		&self.common
+	}
+
+	fn _common_mut(&mut self) -> &mut Common<State> {
+		&mut self.common
+	}
+
+	fn _rpc_info(&self) -> Option<(String, rpc::Auth)> {
+		self.rpc_info()
+	}
+}
+
+impl Drop for Daemon {
+	fn drop(&mut self) {
+		if let Some(ref dir) = self.temp_datadir {
+			if let Some(ref rt) = self.common.runtime_data {
+				if let Ok(mut rt) = rt.lock() {
+					// Wait for the process so that the datadir is no longer in use.
+					rt.kill(true);
+				}
+			}
+			utils::remove_temp_dir(dir);
+		}
+	}
+}
 
 impl fmt::Debug for Daemon {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		if self.name.is_empty() {
-			write!(f, "<unnamed> bitcoind")
-		} else {
-			write!(f, "bitcoind \"{}\"", self.name)
+		self.common.fmt_header(f, "bitcoind")?;
+		if f.alternate() {
+			let c = &self.config;
+			let version = if c.version > 0 {
+				c.version
+			} else {
+				DEFAULT_VERSION
+			};
+			let chain = if c.signet {
+				"signet".to_owned()
+			} else {
+				c.network.unwrap_or(bitcoin::Network::Bitcoin).to_string()
+			};
+			utils::fmt_details(
+				f,
+				&[
+					("version", Some(utils::format_version(version))),
+					("chain", Some(chain)),
+					("datadir", Some(c.datadir.display().to_string())),
+					("port", c.port.map(|p| p.to_string())),
+					("rpcport", c.rpcport.map(|p| p.to_string())),
+				],
+			)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn init_tip_on_restart() {
+		let executable = match utils::find_executable("bitcoind", EXECUTABLE_ENV_VAR) {
+			Some(e) => e,
+			None => return,
+		};
+		let datadir = utils::create_temp_dir("bitcoind-restart").unwrap();
+		let config = ConfigBuilder::regtest(&datadir).block_on_drop(true).build().unwrap();
+		let cookie = datadir.join(".cookie");
+
+		let mut daemon = Daemon::new(&executable, config.clone()).unwrap();
+		daemon.start().unwrap();
+		daemon.wait_for_ready(time::Duration::from_secs(30)).unwrap();
+		drop(daemon);
+
+		// The killed daemon left its cookie behind, make sure it is stale.
+		fs::write(&cookie, "__cookie__:stale").unwrap();
+		let mut daemon = Daemon::new(&executable, config).unwrap();
+		daemon.start().unwrap();
+		let start = time::Instant::now();
+		while daemon.last_update_tip().is_none() {
+			assert!(start.elapsed() < time::Duration::from_secs(30), "tip not initialized");
+			thread::sleep(time::Duration::from_millis(50));
 		}
+		assert_eq!(daemon.last_update_tip().unwrap().0, 0);
+		assert!(daemon.startup_timings().last().unwrap().ready.is_some());
+
+		drop(daemon);
+		utils::remove_temp_dir(&datadir);
 	}
 }
@@ -0,0 +1,46 @@
+//! Consensus constants of the chains the daemons run.
+
+use bitcoin::Amount;
+
+/// The number of confirmations a coinbase output needs before it can be spent.
+pub const COINBASE_MATURITY: u64 = 100;
+
+/// The block subsidy in satoshi of the default chains before the first halving.
+pub const DEFAULT_INITIAL_SUBSIDY_SAT: u64 = 50 * 100_000_000;
+
+/// The halving interval of bitcoin mainnet and testnet and of liquidv1.
+pub const MAINNET_HALVING_INTERVAL: u64 = 210_000;
+
+/// The halving interval of regtest and of custom elements chains.
+pub const REGTEST_HALVING_INTERVAL: u64 = 150;
+
+/// Consensus constants that helpers need to know about a chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainParams {
+	/// The number of confirmations a coinbase output needs before it can be spent.
+	pub coinbase_maturity: u64,
+	/// The number of blocks after which the block subsidy halves.
+	/// Zero means the subsidy never halves.
+	pub subsidy_halving_interval: u64,
+	/// The block subsidy before the first halving.
+	pub initial_subsidy: Amount,
+}
+
+impl ChainParams {
+	/// The block subsidy at the given height.
+	pub fn subsidy(&self, height: u64) -> Amount {
+		let halvings = height.checked_div(self.subsidy_halving_interval).unwrap_or(0);
+		if halvings >= 64 {
+			return Amount::from_sat(0);
+		}
+		Amount::from_sat(self.initial_subsidy.as_sat() >> halvings)
+	}
+
+	/// The height of the next halving after the given height.
+	///
+	/// Returns [None] if the subsidy never halves.
+	pub fn next_halving(&self, height: u64) -> Option<u64> {
+		let interval = self.subsidy_halving_interval;
+		Some((height.checked_div(interval)? + 1) * interval)
+	}
+}
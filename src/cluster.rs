@@ -0,0 +1,1175 @@
+//! A cluster is a set of daemons that are managed together.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::{fs, io, thread, time};
+
+use bitcoin::hashes::hex::ToHex;
+use bitcoincore_rpc::RpcApi;
+
+use crate::bitcoind::{self, P2pMode};
+use crate::elementsd;
+use crate::error::Error;
+use crate::genesis::GenesisParams;
+use crate::observer::Observer;
+use crate::runner::{CommonDaemon, DaemonRunner, ExitKind, Status};
+use crate::utils;
+use crate::utxo::{UtxoHashType, UtxoSetInfo};
+
+/// A daemon in a [Cluster].
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Node {
+	Bitcoind(bitcoind::Daemon),
+	Elementsd(elementsd::Daemon),
+}
+
+impl From<bitcoind::Daemon> for Node {
+	fn from(d: bitcoind::Daemon) -> Node {
+		Node::Bitcoind(d)
+	}
+}
+
+impl From<elementsd::Daemon> for Node {
+	fn from(d: elementsd::Daemon) -> Node {
+		Node::Elementsd(d)
+	}
+}
+
+impl Node {
+	/// The type of daemon.
+	pub fn kind(&self) -> &'static str {
+		match *self {
+			Node::Bitcoind(_) => "bitcoind",
+			Node::Elementsd(_) => "elementsd",
+		}
+	}
+
+	pub fn name(&self) -> &str {
+		match *self {
+			Node::Bitcoind(ref d) => CommonDaemon::name(d),
+			Node::Elementsd(ref d) => CommonDaemon::name(d),
+		}
+	}
+
+	/// The version the config is written for.
+	pub fn version(&self) -> u64 {
+		match *self {
+			Node::Bitcoind(ref d) if d.config().version == 0 => bitcoind::DEFAULT_VERSION,
+			Node::Bitcoind(ref d) => d.config().version,
+			Node::Elementsd(ref d) if d.config().version == 0 => elementsd::DEFAULT_VERSION,
+			Node::Elementsd(ref d) => d.config().version,
+		}
+	}
+
+	pub fn datadir(&self) -> &Path {
+		match *self {
+			Node::Bitcoind(ref d) => d.datadir(),
+			Node::Elementsd(ref d) => d.datadir(),
+		}
+	}
+
+	pub fn start(&mut self) -> Result<(), Error> {
+		match *self {
+			Node::Bitcoind(ref mut d) => d.start(),
+			Node::Elementsd(ref mut d) => d.start(),
+		}
+	}
+
+	/// Wait until the daemon is ready to handle RPC requests.
+	pub fn wait_for_ready(&self, timeout: time::Duration) -> Result<(), Error> {
+		match *self {
+			Node::Bitcoind(ref d) => d.wait_for_ready(timeout),
+			Node::Elementsd(ref d) => d.wait_for_ready(timeout),
+		}
+	}
+
+	/// Stop the daemon such that its datadir can be copied consistently.
+	///
+	/// See [bitcoind::Daemon::stop_for_snapshot].
+	pub fn stop_for_snapshot(&self, timeout: time::Duration) -> Result<(), Error> {
+		match *self {
+			Node::Bitcoind(ref d) => d.stop_for_snapshot(timeout),
+			Node::Elementsd(ref d) => d.stop_for_snapshot(timeout),
+		}
+	}
+
+	/// Stop the daemon gracefully, see [DaemonRunner::stop_with_timeout].
+	pub fn stop(&self, timeout: time::Duration) -> Result<(), Error> {
+		match *self {
+			Node::Bitcoind(ref d) => d.stop_with_timeout(timeout),
+			Node::Elementsd(ref d) => d.stop_with_timeout(timeout),
+		}
+	}
+
+	pub fn status(&self) -> Result<Status, Error> {
+		match *self {
+			Node::Bitcoind(ref d) => d.status(),
+			Node::Elementsd(ref d) => d.status(),
+		}
+	}
+
+	pub fn pid(&self) -> Option<u32> {
+		match *self {
+			Node::Bitcoind(ref d) => d.pid(),
+			Node::Elementsd(ref d) => d.pid(),
+		}
+	}
+
+	pub fn p2p_port(&self) -> Option<u16> {
+		match *self {
+			Node::Bitcoind(ref d) => d.config().port,
+			Node::Elementsd(ref d) => d.config().port,
+		}
+	}
+
+	/// How the daemon participates in the p2p network.
+	pub fn p2p_mode(&self) -> bitcoind::P2pMode {
+		match *self {
+			Node::Bitcoind(ref d) => d.config().p2p_mode(),
+			Node::Elementsd(ref d) => d.config().p2p_mode(),
+		}
+	}
+
+	pub fn rpc_port(&self) -> Option<u16> {
+		match *self {
+			Node::Bitcoind(ref d) => d.config().rpcport,
+			Node::Elementsd(ref d) => d.config().rpcport,
+		}
+	}
+
+	/// The last block height the daemon logged.
+	pub fn height(&self) -> Option<u64> {
+		match *self {
+			Node::Bitcoind(ref d) => d.last_update_tip().map(|t| t.0),
+			Node::Elementsd(ref d) => d.last_update_tip().map(|t| t.0),
+		}
+	}
+
+	/// Get a summary of the UTXO set of the node.
+	pub fn utxo_set_info(&self, hash_type: UtxoHashType) -> Result<UtxoSetInfo, Error> {
+		match *self {
+			Node::Bitcoind(ref d) => d.utxo_set_info(hash_type),
+			Node::Elementsd(ref d) => d.utxo_set_info(hash_type),
+		}
+	}
+
+	/// Get a client for the RPC calls that all daemons support.
+	pub fn rpc_client(&self) -> Option<Result<bitcoincore_rpc::Client, Error>> {
+		match *self {
+			Node::Bitcoind(ref d) => d.rpc_client().map(|r| r.map_err(Error::from)),
+			Node::Elementsd(ref d) => d.rpc_client().map(|r| r.map(|c| c.0).map_err(Error::from)),
+		}
+	}
+
+	pub fn labels(&self) -> &BTreeMap<String, String> {
+		match *self {
+			Node::Bitcoind(ref d) => d.labels(),
+			Node::Elementsd(ref d) => d.labels(),
+		}
+	}
+
+	pub fn label(&self, key: &str) -> Option<&str> {
+		self.labels().get(key).map(|v| v.as_str())
+	}
+
+	pub fn error_count(&self) -> usize {
+		match *self {
+			Node::Bitcoind(ref d) => d.error_count(),
+			Node::Elementsd(ref d) => d.error_count(),
+		}
+	}
+
+	/// The retained stdout and the buffered stderr output of the node.
+	fn captured_output(&self) -> (Vec<String>, Vec<String>) {
+		macro_rules! output {
+			($d:expr) => {
+				$d.with_state(|s| {
+					let stdout = s.stdout.iter().flat_map(|b| b.iter()).cloned().collect();
+					(stdout, s.output.stderr.iter().cloned().collect())
+				})
+				.unwrap_or_default()
+			};
+		}
+		match *self {
+			Node::Bitcoind(ref d) => output!(d),
+			Node::Elementsd(ref d) => output!(d),
+		}
+	}
+
+	/// The config values that determine the genesis block of the node.
+	fn genesis_config(&self) -> Vec<(&'static str, String)> {
+		match *self {
+			Node::Bitcoind(ref d) => {
+				let c = d.config();
+				let network = if c.signet {
+					"signet".to_owned()
+				} else {
+					c.network.unwrap_or(bitcoin::Network::Bitcoin).to_string()
+				};
+				let challenge = c.signetchallenge.as_ref().map(|s| s.to_hex());
+				vec![("network", network), ("signetchallenge", challenge.unwrap_or_default())]
+			}
+			Node::Elementsd(ref d) => match GenesisParams::from_config(d.config()) {
+				Ok(p) => vec![
+					("chain", p.chain),
+					("con_genesis_style", p.style.to_string()),
+					("con_signed_blocks", p.signed_blocks.to_string()),
+					("signblockscript", p.signblockscript.to_hex()),
+					("fedpegscript", p.fedpegscript.to_hex()),
+					("parentgenesisblockhash", p.parent_genesis_hash.to_string()),
+					("initialfreecoins", p.initial_free_coins.to_string()),
+					("initialreissuancetokens", p.initial_reissuance_tokens.to_string()),
+				],
+				Err(_) => vec![("chain", d.config().chain.clone())],
+			},
+		}
+	}
+}
+
+/// The time daemons are given to shut down cleanly for checkpoints.
+const CHECKPOINT_STOP_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// The time daemons are given to become ready in [Cluster::start_all].
+const START_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// The time nodes are given to converge on a new tip in [Cluster::force_reorg].
+const REORG_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// The time restarted nodes are given to catch up with the cluster.
+///
+/// Peers retry connections to restarted nodes only about every minute.
+const RESTART_SYNC_TIMEOUT: time::Duration = time::Duration::from_secs(150);
+
+/// How the nodes of a cluster are connected to each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topology {
+	/// Every node is connected to every other node.
+	FullMesh,
+	/// Every node is connected to the next one.
+	Chain,
+	/// Every node is connected to the first one.
+	Star,
+}
+
+impl Topology {
+	/// The connections between [n] nodes, by index.
+	///
+	/// For every pair, the first node connects to the second.
+	pub fn edges(&self, n: usize) -> Vec<(usize, usize)> {
+		match *self {
+			Topology::FullMesh => (0..n).flat_map(|a| (a + 1..n).map(move |b| (a, b))).collect(),
+			Topology::Chain => (1..n).map(|b| (b - 1, b)).collect(),
+			Topology::Star => (1..n).map(|b| (b, 0)).collect(),
+		}
+	}
+}
+
+/// A read-only handle to a node in a [Cluster].
+#[derive(Debug, Clone)]
+pub enum NodeObserver {
+	Bitcoind(Observer<bitcoind::State>),
+	Elementsd(Observer<elementsd::State>),
+}
+
+impl NodeObserver {
+	pub fn name(&self) -> &str {
+		match *self {
+			NodeObserver::Bitcoind(ref o) => o.name(),
+			NodeObserver::Elementsd(ref o) => o.name(),
+		}
+	}
+
+	pub fn status(&self) -> Result<Status, Error> {
+		match *self {
+			NodeObserver::Bitcoind(ref o) => o.status(),
+			NodeObserver::Elementsd(ref o) => o.status(),
+		}
+	}
+
+	/// The number of error messages that have not been taken yet.
+	pub fn error_count(&self) -> usize {
+		match *self {
+			NodeObserver::Bitcoind(ref o) => o.with_state(|s| s.output.error_msgs().len()),
+			NodeObserver::Elementsd(ref o) => o.with_state(|s| s.output.error_msgs().len()),
+		}
+		.unwrap_or(0)
+	}
+
+	/// Get a client for the RPC calls that all daemons support.
+	///
+	/// See [Observer::rpc_client] for its timeout.
+	pub fn rpc_client(&self) -> Option<Result<utils::TimeoutClient, Error>> {
+		match *self {
+			NodeObserver::Bitcoind(ref o) => o.rpc_client(),
+			NodeObserver::Elementsd(ref o) => o.rpc_client(),
+		}
+	}
+}
+
+/// A node with a chain tip, see [wait_for_sync].
+pub trait ChainTip {
+	/// The name of the node to use in reports.
+	fn name(&self) -> &str;
+
+	/// The height and hash of the current tip of the node.
+	///
+	/// The tip is queried over RPC. When RPC is not configured, the last
+	/// tip the node logged is used.
+	fn chain_tip(&self) -> Result<(u64, bitcoin::BlockHash), Error>;
+}
+
+/// Query the tip with `getblockchaininfo`, so that height and hash match.
+fn rpc_chain_tip(rpc: &impl RpcApi) -> Result<(u64, bitcoin::BlockHash), Error> {
+	let info = rpc.call::<serde_json::Value>("getblockchaininfo", &[])?;
+	let invalid = || Error::Custom("invalid getblockchaininfo response");
+	let height = info["blocks"].as_u64().ok_or_else(invalid)?;
+	let hash = info["bestblockhash"].as_str().and_then(|s| s.parse().ok()).ok_or_else(invalid)?;
+	Ok((height, hash))
+}
+
+impl ChainTip for bitcoind::Daemon {
+	fn name(&self) -> &str {
+		CommonDaemon::name(self)
+	}
+
+	fn chain_tip(&self) -> Result<(u64, bitcoin::BlockHash), Error> {
+		match self.rpc_client_with_timeout(Some(utils::HEALTH_CHECK_RPC_TIMEOUT)) {
+			Some(rpc) => rpc_chain_tip(&rpc?),
+			None => self.last_update_tip().ok_or(Error::Custom("no tip logged yet")),
+		}
+	}
+}
+
+impl ChainTip for elementsd::Daemon {
+	fn name(&self) -> &str {
+		CommonDaemon::name(self)
+	}
+
+	fn chain_tip(&self) -> Result<(u64, bitcoin::BlockHash), Error> {
+		match self.rpc_client_with_timeout(Some(utils::HEALTH_CHECK_RPC_TIMEOUT)) {
+			Some(rpc) => rpc_chain_tip(&rpc?),
+			None => self.last_update_tip().ok_or(Error::Custom("no tip logged yet")),
+		}
+	}
+}
+
+impl ChainTip for Node {
+	fn name(&self) -> &str {
+		self.name()
+	}
+
+	fn chain_tip(&self) -> Result<(u64, bitcoin::BlockHash), Error> {
+		match *self {
+			Node::Bitcoind(ref d) => d.chain_tip(),
+			Node::Elementsd(ref d) => d.chain_tip(),
+		}
+	}
+}
+
+impl ChainTip for NodeObserver {
+	fn name(&self) -> &str {
+		self.name()
+	}
+
+	fn chain_tip(&self) -> Result<(u64, bitcoin::BlockHash), Error> {
+		if let Some(rpc) = self.rpc_client() {
+			return rpc_chain_tip(&rpc?);
+		}
+		let tip = match *self {
+			NodeObserver::Bitcoind(ref o) => o.with_state(|s| s.last_update_tip)?,
+			NodeObserver::Elementsd(ref o) => o.with_state(|s| s.last_update_tip)?,
+		};
+		tip.ok_or(Error::Custom("no tip logged yet"))
+	}
+}
+
+/// Wait until all nodes have the same chain tip and return it.
+///
+/// On timeout, an [Error::NotSynced] is returned listing the tip of every node.
+pub fn wait_for_sync(
+	nodes: &[&dyn ChainTip],
+	timeout: time::Duration,
+) -> Result<bitcoin::BlockHash, Error> {
+	if nodes.is_empty() {
+		return Err(Error::Config("no nodes to wait for"));
+	}
+	let start = time::Instant::now();
+	loop {
+		let tips = nodes.iter().map(|n| n.chain_tip()).collect::<Vec<_>>();
+		if let Ok((_, hash)) = tips[0] {
+			if tips.iter().all(|t| t.as_ref().map(|t| t.1 == hash).unwrap_or(false)) {
+				return Ok(hash);
+			}
+		}
+
+		if start.elapsed() > timeout {
+			let mut report = String::new();
+			for (node, tip) in nodes.iter().zip(tips) {
+				match tip {
+					Ok((height, hash)) => {
+						writeln!(report, "{}: {} at height {}", node.name(), hash, height)
+					}
+					Err(e) => writeln!(report, "{}: unknown tip: {:?}", node.name(), e),
+				}
+				.unwrap();
+			}
+			return Err(Error::NotSynced(report));
+		}
+		thread::sleep(time::Duration::from_millis(100));
+	}
+}
+
+/// The number of seconds in a day.
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Settings to make blocks be mined as simulated time passes.
+///
+/// See [Cluster::advance_days].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeScaling {
+	/// The name of the node that mines the blocks.
+	pub miner: String,
+	/// The number of blocks to mine per simulated day.
+	pub blocks_per_day: u64,
+}
+
+/// A set of daemons that are managed together.
+#[derive(Debug, Default)]
+pub struct Cluster {
+	nodes: Vec<Node>,
+
+	/// The directories holding the datadir snapshots per checkpoint.
+	checkpoints: BTreeMap<String, PathBuf>,
+
+	/// The mock time set on all nodes, as a UNIX timestamp.
+	mocktime: Option<u64>,
+	/// How blocks are mined when time is advanced.
+	time_scaling: Option<TimeScaling>,
+}
+
+/// Whether stdout is a terminal that can show colors.
+fn stdout_is_terminal() -> bool {
+	#[cfg(unix)]
+	{
+		unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+	}
+	#[cfg(not(unix))]
+	{
+		false
+	}
+}
+
+impl Cluster {
+	pub fn new() -> Cluster {
+		Cluster::default()
+	}
+
+	/// Create a cluster of [n] bitcoind regtest nodes connected in the given topology.
+	///
+	/// The nodes are named `node0`, `node1`, etc. and have their datadirs in
+	/// [dir]. Ports are allocated automatically and the connections are added
+	/// to the configs, so they are restored when nodes are restarted. The
+	/// bitcoind executable is taken from the `BITCOIND_EXE` environment
+	/// variable or searched in `PATH`. Use [start_all] to start the nodes.
+	pub fn bitcoind_regtest(dir: &Path, n: usize, topology: Topology) -> Result<Cluster, Error> {
+		let executable = utils::find_executable("bitcoind", bitcoind::EXECUTABLE_ENV_VAR)
+			.ok_or(Error::Config("bitcoind executable not found"))?;
+
+		let mut configs = (0..n)
+			.map(|i| {
+				bitcoind::ConfigBuilder::regtest(dir.join(format!("node{}", i)))
+					.listen(true)
+					.hermetic(true)
+					.disablewallet(false)
+					.fallbackfee(0.0002)
+					.build()
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+		for (a, b) in topology.edges(n) {
+			let port = configs[b].port.expect("regtest config has a port");
+			configs[a].addnodes.push(format!("127.0.0.1:{}", port));
+		}
+
+		let mut cluster = Cluster::new();
+		for (i, config) in configs.into_iter().enumerate() {
+			let mut daemon = bitcoind::Daemon::new(executable.clone(), config)?;
+			daemon.set_name(format!("node{}", i));
+			cluster.add(daemon);
+		}
+		Ok(cluster)
+	}
+
+	/// Start all nodes that are not running and wait until they are ready.
+	pub fn start_all(&mut self) -> Result<(), Error> {
+		for node in &mut self.nodes {
+			if node.status()? != Status::Running {
+				node.start()?;
+			}
+		}
+		for node in &self.nodes {
+			node.wait_for_ready(START_TIMEOUT)?;
+		}
+		Ok(())
+	}
+
+	/// Gracefully stop all running nodes.
+	pub fn stop_all(&self) -> Result<(), Error> {
+		for node in &self.nodes {
+			if node.status()? == Status::Running {
+				node.stop(CHECKPOINT_STOP_TIMEOUT)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// The node at the given index.
+	pub fn node(&self, idx: usize) -> Option<&Node> {
+		self.nodes.get(idx)
+	}
+
+	/// The bitcoind at the given index, if that node is a bitcoind.
+	pub fn bitcoind(&self, idx: usize) -> Option<&bitcoind::Daemon> {
+		match self.nodes.get(idx) {
+			Some(Node::Bitcoind(ref d)) => Some(d),
+			_ => None,
+		}
+	}
+
+	/// The elementsd at the given index, if that node is an elementsd.
+	pub fn elementsd(&self, idx: usize) -> Option<&elementsd::Daemon> {
+		match self.nodes.get(idx) {
+			Some(Node::Elementsd(ref d)) => Some(d),
+			_ => None,
+		}
+	}
+
+	/// Connect the nodes in the given topology using [add_peer].
+	///
+	/// The nodes should be running. Connections made this way are not kept
+	/// when nodes are restarted.
+	pub fn connect(&self, topology: Topology) -> Result<(), Error> {
+		for (a, b) in topology.edges(self.nodes.len()) {
+			self.add_peer(a, b)?;
+		}
+		Ok(())
+	}
+
+	/// Add a node to the cluster and return its index.
+	pub fn add(&mut self, node: impl Into<Node>) -> usize {
+		self.nodes.push(node.into());
+		self.nodes.len() - 1
+	}
+
+	pub fn nodes(&self) -> &[Node] {
+		&self.nodes
+	}
+
+	pub fn nodes_mut(&mut self) -> &mut [Node] {
+		&mut self.nodes
+	}
+
+	/// The nodes that have the given label.
+	pub fn nodes_with_label<'a>(
+		&'a self,
+		key: &'a str,
+		value: &'a str,
+	) -> impl Iterator<Item = &'a Node> + 'a {
+		self.nodes.iter().filter(move |n| n.label(key) == Some(value))
+	}
+
+	/// Make the node at index [node] connect to the node at index [peer].
+	///
+	/// The peer is added with the `addnode` RPC. A warning is logged when
+	/// either node is in [P2pMode::ConnectOnly] mode, because the node won't
+	/// connect to other peers and the peer won't accept them.
+	pub fn add_peer(&self, node: usize, peer: usize) -> Result<(), Error> {
+		let (n, p) = match (self.nodes.get(node), self.nodes.get(peer)) {
+			(Some(n), Some(p)) => (n, p),
+			_ => return Err(Error::Config("unknown node index")),
+		};
+		if n.p2p_mode() == P2pMode::ConnectOnly {
+			warn!(
+				"Adding peer {} to {} which only connects to its connect peers",
+				p.name(),
+				n.name()
+			);
+		}
+		match p.p2p_mode() {
+			P2pMode::Listening => {}
+			P2pMode::ConnectOnly => {
+				warn!("Adding peer {} in exclusive-connect mode to {}", p.name(), n.name());
+			}
+			P2pMode::Isolated => {
+				warn!("Adding peer {} that isn't listening to {}", p.name(), n.name())
+			}
+		}
+
+		let port = p.p2p_port().ok_or(Error::Config("peer has no p2p port"))?;
+		let addr = format!("127.0.0.1:{}", port);
+		let rpc = n.rpc_client().ok_or(Error::Config("RPC is not configured"))??;
+		rpc.call::<serde_json::Value>("addnode", &[addr.into(), "add".into()])?;
+		Ok(())
+	}
+
+	/// Replace the last [depth] blocks of the chain with a longer one.
+	///
+	/// The node at index [node] is disconnected from the network, invalidates
+	/// its last [depth] blocks and mines [depth] + 1 blocks on top of the fork
+	/// point to its wallet. It's then reconnected to all listening nodes,
+	/// after which all running nodes should reorg to the new chain. The
+	/// invalidated blocks are reconsidered, so that the node isn't left with
+	/// blocks marked invalid.
+	///
+	/// Returns the new tip once all running nodes converged on it.
+	pub fn force_reorg(&self, node: usize, depth: u64) -> Result<bitcoin::BlockHash, Error> {
+		let n = self.nodes.get(node).ok_or(Error::Config("unknown node index"))?;
+		let rpc = n.rpc_client().ok_or(Error::Config("RPC is not configured"))??;
+		let height = rpc.get_block_count()?;
+		if depth == 0 || depth > height {
+			return Err(Error::Config("reorg depth should be between 1 and the chain height"));
+		}
+		let fork = rpc.get_block_hash(height - depth + 1)?;
+		let address = rpc.call::<String>("getnewaddress", &[])?;
+		info!("Reorging {} blocks on {} from height {}", depth, n.name(), height - depth + 1);
+
+		rpc.call::<serde_json::Value>("setnetworkactive", &[false.into()])?;
+		let mined = rpc.invalidate_block(&fork).and_then(|_| {
+			rpc.call::<Vec<bitcoin::BlockHash>>(
+				"generatetoaddress",
+				&[(depth + 1).into(), address.into()],
+			)
+		});
+		// Always reconnect, also when mining failed.
+		rpc.call::<serde_json::Value>("setnetworkactive", &[true.into()])?;
+		// Don't wait for the node to retry its peers by itself.
+		for (i, peer) in self.nodes.iter().enumerate() {
+			if i == node || peer.p2p_mode() != P2pMode::Listening {
+				continue;
+			}
+			if let Some(port) = peer.p2p_port() {
+				let addr = format!("127.0.0.1:{}", port);
+				rpc.call::<serde_json::Value>("addnode", &[addr.into(), "onetry".into()])?;
+			}
+		}
+		let tip = *mined?.last().ok_or(Error::Custom("no blocks were mined"))?;
+		rpc.reconsider_block(&fork)?;
+
+		self.wait_for_tip(&tip, REORG_TIMEOUT)?;
+		Ok(tip)
+	}
+
+	/// Wait until all running nodes have the same chain tip and return it.
+	///
+	/// See [wait_for_sync].
+	pub fn wait_for_sync(&self, timeout: time::Duration) -> Result<bitcoin::BlockHash, Error> {
+		let mut running = Vec::with_capacity(self.nodes.len());
+		for node in &self.nodes {
+			if node.status()? == Status::Running {
+				running.push(node as &dyn ChainTip);
+			}
+		}
+		wait_for_sync(&running, timeout)
+	}
+
+	/// Restart all running nodes in waves of [batch_size] nodes.
+	///
+	/// Every wave is stopped gracefully and started again, after which all
+	/// running nodes have to agree on the chain tip before the next wave is
+	/// restarted. Nodes that are not running are left alone.
+	pub fn rolling_restart(&mut self, batch_size: usize) -> Result<(), Error> {
+		if batch_size == 0 {
+			return Err(Error::Config("batch size should be at least 1"));
+		}
+		let mut running = Vec::with_capacity(self.nodes.len());
+		for (i, node) in self.nodes.iter().enumerate() {
+			if node.status()? == Status::Running {
+				running.push(i);
+			}
+		}
+
+		for wave in running.chunks(batch_size) {
+			info!("Restarting nodes {:?}...", wave);
+			for &i in wave {
+				self.nodes[i].stop(CHECKPOINT_STOP_TIMEOUT)?;
+			}
+			for &i in wave {
+				self.nodes[i].start()?;
+			}
+			for &i in wave {
+				self.nodes[i].wait_for_ready(START_TIMEOUT)?;
+			}
+			let tip = self.wait_for_sync(RESTART_SYNC_TIMEOUT)?;
+			debug!("Nodes {:?} restarted and synced to {}", wave, tip);
+		}
+		Ok(())
+	}
+
+	/// Wait until all running nodes with RPC have the given tip.
+	fn wait_for_tip(&self, tip: &bitcoin::BlockHash, timeout: time::Duration) -> Result<(), Error> {
+		let start = time::Instant::now();
+		for node in &self.nodes {
+			if node.status()? != Status::Running {
+				continue;
+			}
+			let rpc = match node.rpc_client() {
+				Some(rpc) => rpc?,
+				None => continue,
+			};
+			while rpc.get_best_block_hash()? != *tip {
+				if start.elapsed() > timeout {
+					return Err(Error::Timeout("nodes to converge on the tip"));
+				}
+				thread::sleep(time::Duration::from_millis(100));
+			}
+		}
+		Ok(())
+	}
+
+	/// Stop all running nodes for a snapshot and return which ones were running.
+	fn stop_running(&self) -> Result<Vec<bool>, Error> {
+		let mut running = Vec::with_capacity(self.nodes.len());
+		for node in &self.nodes {
+			let is_running = node.status()? == Status::Running;
+			if is_running {
+				node.stop_for_snapshot(CHECKPOINT_STOP_TIMEOUT)?;
+			}
+			running.push(is_running);
+		}
+		Ok(running)
+	}
+
+	/// Start the nodes that are marked as running.
+	fn start_running(&mut self, running: &[bool]) -> Result<(), Error> {
+		for (node, running) in self.nodes.iter_mut().zip(running) {
+			if *running {
+				node.start()?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Snapshot the datadirs of all nodes under the given name.
+	///
+	/// Running nodes are stopped for the snapshot and started again afterwards,
+	/// also when the snapshot failed. The snapshot only replaces an existing
+	/// one with the same name once all datadirs have been copied.
+	pub fn checkpoint(&mut self, name: &str) -> Result<(), Error> {
+		info!("Creating cluster checkpoint {}...", name);
+		let running = self.stop_running()?;
+		let res = self.copy_datadirs(name);
+		let started = self.start_running(&running);
+		res.and(started)
+	}
+
+	/// Copy the datadirs of all nodes into a new checkpoint with the given name.
+	fn copy_datadirs(&mut self, name: &str) -> Result<(), Error> {
+		let dir = utils::create_temp_dir("cluster-checkpoint")?;
+		for (i, node) in self.nodes.iter().enumerate() {
+			if node.datadir().exists() {
+				if let Err(e) = utils::copy_dir(node.datadir(), &dir.join(i.to_string())) {
+					utils::remove_temp_dir(&dir);
+					return Err(e.into());
+				}
+			}
+		}
+		if let Some(old) = self.checkpoints.insert(name.to_owned(), dir) {
+			fs::remove_dir_all(old)?;
+		}
+		Ok(())
+	}
+
+	/// Restore the datadirs of all nodes from the checkpoint with the given name.
+	///
+	/// All nodes are stopped for the restore and the nodes that were running
+	/// are started again afterwards, also when the restore failed.
+	/// The checkpoint can be rolled back to again.
+	pub fn rollback(&mut self, name: &str) -> Result<(), Error> {
+		let dir = self.checkpoints.get(name).ok_or(Error::Config("unknown checkpoint"))?.clone();
+		info!("Rolling back to cluster checkpoint {}...", name);
+		let running = self.stop_running()?;
+		let res = self.restore_datadirs(&dir);
+		let started = self.start_running(&running);
+		res.and(started)
+	}
+
+	/// Replace the datadirs of all nodes with their copies in the checkpoint dir.
+	fn restore_datadirs(&self, dir: &Path) -> Result<(), Error> {
+		for (i, node) in self.nodes.iter().enumerate() {
+			let snapshot = dir.join(i.to_string());
+			if node.datadir().exists() {
+				fs::remove_dir_all(node.datadir())?;
+			}
+			if snapshot.exists() {
+				utils::copy_dir(&snapshot, node.datadir())?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Remove the checkpoint with the given name.
+	pub fn remove_checkpoint(&mut self, name: &str) -> Result<(), Error> {
+		if let Some(dir) = self.checkpoints.remove(name) {
+			fs::remove_dir_all(dir)?;
+		}
+		Ok(())
+	}
+
+	/// Create read-only handles to all nodes that have been started.
+	///
+	/// The handles can be sent to other threads to monitor the cluster.
+	pub fn observers(&self) -> Vec<NodeObserver> {
+		self.nodes
+			.iter()
+			.filter_map(|n| match *n {
+				Node::Bitcoind(ref d) => d.observer().ok().map(NodeObserver::Bitcoind),
+				Node::Elementsd(ref d) => d.observer().ok().map(NodeObserver::Elementsd),
+			})
+			.collect()
+	}
+
+	/// Run an RPC operation against all nodes in parallel.
+	///
+	/// The results are returned per node name, in the order of the nodes.
+	/// Nodes without RPC configured get an error.
+	pub fn for_each_rpc<F, R>(&self, f: F) -> Vec<(String, Result<R, Error>)>
+	where
+		F: Fn(&str, &bitcoincore_rpc::Client) -> Result<R, Error> + Sync,
+		R: Send,
+	{
+		let clients = self
+			.nodes
+			.iter()
+			.map(|n| {
+				let client = n.rpc_client().unwrap_or(Err(Error::Config("RPC is not configured")));
+				(n.name().to_owned(), client)
+			})
+			.collect::<Vec<_>>();
+
+		let f = &f;
+		thread::scope(|s| {
+			let handles = clients
+				.into_iter()
+				.map(|(name, client)| {
+					s.spawn(move || {
+						let res = client.and_then(|c| f(&name, &c));
+						(name, res)
+					})
+				})
+				.collect::<Vec<_>>();
+			handles.into_iter().map(|h| h.join().expect("RPC thread panicked")).collect()
+		})
+	}
+
+	/// Check that all running nodes of the same type agree on the genesis block.
+	/// Nodes without RPC configured are skipped.
+	///
+	/// This is best done right after starting the nodes. When the genesis
+	/// block hashes differ, an [Error::GenesisMismatch] is returned with a
+	/// report of the genesis hashes and the config values that differ.
+	pub fn check_genesis(&self) -> Result<(), Error> {
+		let hashes = self.for_each_rpc(|_, rpc| Ok(rpc.get_block_hash(0)?));
+		let mut running = Vec::new();
+		for (node, (_, hash)) in self.nodes.iter().zip(hashes) {
+			if node.rpc_client().is_some() && node.status()? == Status::Running {
+				running.push((node, hash?));
+			}
+		}
+
+		let mut report = String::new();
+		for kind in &["bitcoind", "elementsd"] {
+			let nodes = running.iter().filter(|n| n.0.kind() == *kind).collect::<Vec<_>>();
+			if nodes.iter().all(|n| n.1 == nodes[0].1) {
+				continue;
+			}
+
+			writeln!(report, "{} nodes have different genesis blocks:", kind).unwrap();
+			for (node, hash) in &nodes {
+				writeln!(report, "  {}: {}", node.name(), hash).unwrap();
+			}
+			let configs = nodes.iter().map(|n| n.0.genesis_config()).collect::<Vec<_>>();
+			for (i, (key, _)) in configs[0].iter().enumerate() {
+				let differs = configs.iter().any(|c| c.get(i) != configs[0].get(i));
+				if !differs {
+					continue;
+				}
+				writeln!(report, "  {} differs:", key).unwrap();
+				for (node, config) in nodes.iter().zip(configs.iter()) {
+					let value = config.get(i).map(|v| v.1.as_str()).unwrap_or("-");
+					writeln!(report, "    {}: {}", node.0.name(), value).unwrap();
+				}
+			}
+		}
+
+		if report.is_empty() {
+			Ok(())
+		} else {
+			Err(Error::GenesisMismatch(report))
+		}
+	}
+
+	/// Broadcast the serialized transaction to all nodes.
+	///
+	/// The txid returned by each node is returned per node name.
+	pub fn broadcast_tx_everywhere(&self, raw_tx: &[u8]) -> Vec<(String, Result<String, Error>)> {
+		let hex = raw_tx.to_hex();
+		self.for_each_rpc(|_, rpc| {
+			Ok(rpc.call::<String>("sendrawtransaction", &[hex.clone().into()])?)
+		})
+	}
+
+	/// The mock time set on all nodes, if any.
+	pub fn mocktime(&self) -> Option<u64> {
+		self.mocktime
+	}
+
+	/// Set the mock time of all running nodes to the given UNIX timestamp.
+	///
+	/// Nodes that are started later should be given the mock time again.
+	pub fn set_mocktime(&mut self, time: u64) -> Result<(), Error> {
+		for node in &self.nodes {
+			if node.status()? != Status::Running {
+				continue;
+			}
+			let rpc = node.rpc_client().ok_or(Error::Config("RPC is not configured"))??;
+			rpc.call::<serde_json::Value>("setmocktime", &[time.into()])?;
+		}
+		self.mocktime = Some(time);
+		Ok(())
+	}
+
+	/// Mine blocks when time is advanced with [advance_days].
+	///
+	/// Blocks are spread evenly over every simulated day, so that their
+	/// timestamps advance with the mock time.
+	pub fn set_time_scaling(&mut self, scaling: Option<TimeScaling>) {
+		self.time_scaling = scaling;
+	}
+
+	/// Advance the mock time of all running nodes by the given number of days.
+	///
+	/// If no mock time was set yet, time starts from the current time.
+	/// When [set_time_scaling] was used, blocks are mined along the way.
+	pub fn advance_days(&mut self, days: u64) -> Result<(), Error> {
+		let start = match self.mocktime {
+			Some(t) => t,
+			None => time::SystemTime::now()
+				.duration_since(time::UNIX_EPOCH)
+				.expect("system time before UNIX epoch")
+				.as_secs(),
+		};
+		let end = start + days * SECONDS_PER_DAY;
+		info!("Advancing cluster time by {} days...", days);
+
+		let scaling = match self.time_scaling {
+			Some(ref s) if s.blocks_per_day > 0 => s.clone(),
+			_ => return self.set_mocktime(end),
+		};
+		let miner = self
+			.nodes
+			.iter()
+			.find(|n| n.name() == scaling.miner)
+			.ok_or(Error::Config("unknown miner node"))?;
+		let rpc = miner.rpc_client().ok_or(Error::Config("RPC is not configured"))??;
+		let address = rpc.call::<String>("getnewaddress", &[])?;
+
+		let step = SECONDS_PER_DAY / scaling.blocks_per_day;
+		let mut time = start;
+		for _ in 0..days * scaling.blocks_per_day {
+			time += step;
+			self.set_mocktime(time)?;
+			rpc.call::<Vec<String>>("generatetoaddress", &[1.into(), address.clone().into()])?;
+		}
+		self.set_mocktime(end)
+	}
+
+	/// Write the captured output of all nodes to a single file, ordered by time.
+	///
+	/// Every line is prefixed with the name of its node, and stderr lines are
+	/// marked as such. Lines are ordered by their log timestamp. Lines without
+	/// a timestamp stay right after the line preceding them. Stdout is only
+	/// available for nodes with [bitcoind::Config::max_stdout_bytes] or
+	/// [elementsd::Config::max_stdout_bytes] set. The output is not taken.
+	pub fn export_merged_log(&self, path: &Path) -> Result<(), Error> {
+		use std::io::Write;
+
+		let mut lines = Vec::new();
+		for (i, node) in self.nodes.iter().enumerate() {
+			let name = match node.name() {
+				"" => format!("node{}", i),
+				n => n.to_owned(),
+			};
+			let (stdout, stderr) = node.captured_output();
+			for (stream, output) in [("", stdout), (" stderr", stderr)].iter() {
+				let prefix = format!("[{}{}]", name, stream);
+				let mut time = (0, 0);
+				for line in output {
+					if let Some(t) = crate::logs::parse_log_timestamp(line) {
+						time = t;
+					}
+					lines.push((time, format!("{} {}", prefix, line)));
+				}
+			}
+		}
+		// The sort is stable, so the order within every stream is kept.
+		lines.sort_by_key(|l| l.0);
+
+		let mut file = io::BufWriter::new(fs::File::create(path)?);
+		for (_, line) in lines {
+			writeln!(file, "{}", line)?;
+		}
+		file.flush()?;
+		Ok(())
+	}
+
+	/// Create a table describing the state of all nodes.
+	///
+	/// If [colored] is set, ANSI colors are used for the status column.
+	pub fn report(&self, colored: bool) -> String {
+		const HEADER: [&str; 10] = [
+			"NAME", "TYPE", "VERSION", "STATUS", "HEIGHT", "PID", "P2P", "RPC", "ERRORS", "LABELS",
+		];
+
+		let opt = |o: Option<String>| o.unwrap_or_else(|| "-".into());
+		let rows = self
+			.nodes
+			.iter()
+			.map(|n| {
+				let (status, color) = match n.status() {
+					Ok(Status::Init) => ("init".to_owned(), ""),
+					Ok(Status::Running) => ("running".to_owned(), "\x1b[32m"),
+					Ok(Status::Stopped(s)) => {
+						let kind = ExitKind::from(s);
+						let color = if kind.is_clean() {
+							"\x1b[33m"
+						} else {
+							"\x1b[31m"
+						};
+						(format!("stopped ({})", kind), color)
+					}
+					Err(_) => ("unknown".to_owned(), "\x1b[31m"),
+				};
+				let labels = n
+					.labels()
+					.iter()
+					.map(|(k, v)| format!("{}={}", k, v))
+					.collect::<Vec<_>>()
+					.join(",");
+				let cells = [
+					if n.name().is_empty() {
+						"<unnamed>".into()
+					} else {
+						n.name().into()
+					},
+					n.kind().into(),
+					utils::format_version(n.version()),
+					status,
+					opt(n.height().map(|h| h.to_string())),
+					opt(n.pid().map(|p| p.to_string())),
+					opt(n.p2p_port().map(|p| p.to_string())),
+					opt(n.rpc_port().map(|p| p.to_string())),
+					n.error_count().to_string(),
+					opt(Some(labels).filter(|l| !l.is_empty())),
+				];
+				(cells, color)
+			})
+			.collect::<Vec<_>>();
+
+		let mut widths = [0; HEADER.len()];
+		for (i, h) in HEADER.iter().enumerate() {
+			widths[i] = rows.iter().map(|r| r.0[i].len()).chain(Some(h.len())).max().unwrap();
+		}
+
+		let mut ret = String::new();
+		for (i, h) in HEADER.iter().enumerate() {
+			write!(ret, "{:<w$}  ", h, w = widths[i]).unwrap();
+		}
+		ret.truncate(ret.trim_end().len());
+		ret.push('\n');
+		for (cells, color) in rows {
+			let mut line = String::new();
+			for (i, cell) in cells.iter().enumerate() {
+				if i == 3 && colored && !color.is_empty() {
+					write!(line, "{}{:<w$}\x1b[0m  ", color, cell, w = widths[i]).unwrap();
+				} else {
+					write!(line, "{:<w$}  ", cell, w = widths[i]).unwrap();
+				}
+			}
+			ret.push_str(line.trim_end());
+			ret.push('\n');
+		}
+		ret
+	}
+
+	/// Print a table describing the state of all nodes to stdout.
+	///
+	/// Colors are used if stdout is a terminal.
+	pub fn print_report(&self) {
+		print!("{}", self.report(stdout_is_terminal()));
+	}
+}
+
+impl Drop for Cluster {
+	fn drop(&mut self) {
+		for dir in self.checkpoints.values() {
+			let _ = fs::remove_dir_all(dir);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn topology_edges() {
+		assert_eq!(Topology::FullMesh.edges(3), vec![(0, 1), (0, 2), (1, 2)]);
+		assert_eq!(Topology::Chain.edges(3), vec![(0, 1), (1, 2)]);
+		assert_eq!(Topology::Star.edges(3), vec![(1, 0), (2, 0)]);
+
+		assert_eq!(Topology::FullMesh.edges(4).len(), 6);
+		for t in &[Topology::FullMesh, Topology::Chain, Topology::Star] {
+			assert!(t.edges(0).is_empty());
+			assert!(t.edges(1).is_empty());
+		}
+	}
+
+	#[test]
+	fn failed_checkpoint_is_cleaned_up() {
+		let checkpoint_dirs = || {
+			fs::read_dir(std::env::temp_dir())
+				.unwrap()
+				.filter_map(|e| e.ok())
+				.filter(|e| e.file_name().to_string_lossy().starts_with("cluster-checkpoint-"))
+				.count()
+		};
+
+		// A datadir that is a file can't be copied.
+		let dir = utils::create_temp_dir("cluster-test").unwrap();
+		let datadir = dir.join("datadir");
+		fs::write(&datadir, "").unwrap();
+		let config = bitcoind::Config {
+			datadir: datadir.clone(),
+			..Default::default()
+		};
+		let mut cluster = Cluster::new();
+		cluster.add(bitcoind::Daemon::new("bitcoind", config).unwrap());
+
+		let before = checkpoint_dirs();
+		assert!(cluster.checkpoint("a").is_err());
+		assert!(cluster.checkpoints.is_empty());
+		assert_eq!(checkpoint_dirs(), before);
+
+		// Once the datadir can be copied, the checkpoint is kept until the cluster is dropped.
+		fs::remove_file(&datadir).unwrap();
+		fs::create_dir(&datadir).unwrap();
+		cluster.checkpoint("a").unwrap();
+		assert_eq!(checkpoint_dirs(), before + 1);
+		cluster.rollback("a").unwrap();
+
+		drop(cluster);
+		utils::remove_temp_dir(&dir);
+		assert_eq!(checkpoint_dirs(), before);
+	}
+}
@@ -0,0 +1,442 @@
+//! Running electrs Electrum servers.
+//!
+//! electrs indexes the chain of a bitcoind and serves it over the Electrum
+//! protocol. The config file is written in the TOML format of electrs 0.9
+//! and later, which fetches blocks over the p2p interface of bitcoind.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::{fmt, fs, io, process, time};
+
+use bitcoin::hashes::hex::FromHex;
+use regex::Regex;
+
+use crate::bitcoind;
+use crate::error::Error;
+use crate::logs::{self, LogEvent};
+use crate::runner::{
+	self, Common, CommonDaemon, DaemonRunner, RunnerConfig, RunnerHelper, RuntimeData, StartupStage,
+};
+use crate::utils;
+
+pub const CONFIG_FILENAME: &str = "electrs.toml";
+
+/// The environment variable that can be used to point to the electrs executable.
+pub const EXECUTABLE_ENV_VAR: &str = "ELECTRS_EXE";
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+	/// The directory to keep the index database in.
+	pub db_dir: PathBuf,
+	pub network: Option<bitcoin::Network>,
+	pub log_filters: Option<String>,
+
+	/// The port to serve the Electrum protocol on.
+	/// When not set, a free port is allocated when the daemon is started.
+	pub electrum_rpc_port: Option<u16>,
+	/// The port to serve Prometheus metrics on.
+	/// When not set, a free port is allocated when the daemon is started.
+	pub monitoring_port: Option<u16>,
+
+	// Connection to bitcoind:
+	/// The datadir of bitcoind.
+	pub daemon_dir: Option<PathBuf>,
+	/// The address of the RPC interface, f.e. `127.0.0.1:18443`.
+	pub daemon_rpc_addr: Option<String>,
+	/// The address of the p2p interface, f.e. `127.0.0.1:18444`.
+	pub daemon_p2p_addr: Option<String>,
+	pub cookie_file: Option<PathBuf>,
+	/// The RPC credentials in the `user:password` format.
+	pub auth: Option<String>,
+
+	/// The options that are only used by the runner.
+	#[serde(flatten)]
+	pub runner: RunnerConfig,
+}
+
+/// The name electrs uses for the network.
+fn network_name(network: bitcoin::Network) -> &'static str {
+	match network {
+		bitcoin::Network::Bitcoin => "bitcoin",
+		bitcoin::Network::Testnet => "testnet",
+		bitcoin::Network::Regtest => "regtest",
+	}
+}
+
+impl Config {
+	pub fn write_into<W: io::Write>(&self, mut w: W) -> Result<(), io::Error> {
+		writeln!(w, "db_dir = {:?}", utils::config_path(&self.db_dir))?;
+		if let Some(n) = self.network {
+			writeln!(w, "network = {:?}", network_name(n))?;
+		}
+		if let Some(ref v) = self.log_filters {
+			writeln!(w, "log_filters = {:?}", v)?;
+		}
+
+		if let Some(p) = self.electrum_rpc_port {
+			writeln!(w, "electrum_rpc_addr = \"127.0.0.1:{}\"", p)?;
+		}
+		if let Some(p) = self.monitoring_port {
+			writeln!(w, "monitoring_addr = \"127.0.0.1:{}\"", p)?;
+		}
+
+		if let Some(ref v) = self.daemon_dir {
+			writeln!(w, "daemon_dir = {:?}", utils::config_path(v))?;
+		}
+		if let Some(ref v) = self.daemon_rpc_addr {
+			writeln!(w, "daemon_rpc_addr = {:?}", v)?;
+		}
+		if let Some(ref v) = self.daemon_p2p_addr {
+			writeln!(w, "daemon_p2p_addr = {:?}", v)?;
+		}
+		if let Some(ref v) = self.cookie_file {
+			writeln!(w, "cookie_file = {:?}", utils::config_path(v))?;
+		}
+		if let Some(ref v) = self.auth {
+			writeln!(w, "auth = {:?}", v)?;
+		}
+		Ok(())
+	}
+}
+
+impl utils::ConfigFile for Config {}
+
+/// A builder for [Config].
+///
+/// Use [ConfigBuilder::regtest] to start from sensible regtest defaults.
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+	config: Config,
+}
+
+impl ConfigBuilder {
+	/// Create a builder for a config without any options set.
+	pub fn new<P: Into<PathBuf>>(db_dir: P) -> ConfigBuilder {
+		ConfigBuilder {
+			config: Config {
+				db_dir: db_dir.into(),
+				..Default::default()
+			},
+		}
+	}
+
+	/// Create a builder with defaults for a regtest server indexing the given bitcoind.
+	pub fn regtest<P: Into<PathBuf>>(db_dir: P, bitcoind: &bitcoind::Daemon) -> ConfigBuilder {
+		ConfigBuilder::new(db_dir)
+			.network(bitcoin::Network::Regtest)
+			.electrum_rpc_port(utils::find_free_port())
+			.monitoring_port(utils::find_free_port())
+			.bitcoind(bitcoind)
+	}
+
+	pub fn network(mut self, network: bitcoin::Network) -> Self {
+		self.config.network = Some(network);
+		self
+	}
+
+	pub fn log_filters(mut self, v: impl Into<String>) -> Self {
+		self.config.log_filters = Some(v.into());
+		self
+	}
+
+	pub fn electrum_rpc_port(mut self, v: u16) -> Self {
+		self.config.electrum_rpc_port = Some(v);
+		self
+	}
+
+	pub fn monitoring_port(mut self, v: u16) -> Self {
+		self.config.monitoring_port = Some(v);
+		self
+	}
+
+	/// Use the given local bitcoind as chain source.
+	///
+	/// The bitcoind should be listening for p2p connections.
+	pub fn bitcoind(mut self, bitcoind: &bitcoind::Daemon) -> Self {
+		let c = bitcoind.config();
+		self.config.daemon_dir = Some(c.datadir.clone());
+		self.config.daemon_rpc_addr = c.rpcport.map(|p| format!("127.0.0.1:{}", p));
+		self.config.daemon_p2p_addr = c.port.map(|p| format!("127.0.0.1:{}", p));
+		self.config.cookie_file = c.rpccookie.as_ref().map(PathBuf::from);
+		self.config.auth = match (&c.rpcuser, &c.rpcpass) {
+			(Some(u), Some(p)) => Some(format!("{}:{}", u, p)),
+			_ => None,
+		};
+		self
+	}
+
+	/// See [RunnerConfig::block_on_drop].
+	pub fn block_on_drop(mut self, v: bool) -> Self {
+		self.config.runner.block_on_drop = v;
+		self
+	}
+
+	/// Validate the options and build the config.
+	pub fn build(self) -> Result<Config, Error> {
+		let c = self.config;
+		if !c.db_dir.is_absolute() {
+			return Err(Error::Config("db_dir should be an absolute path"));
+		}
+		if c.cookie_file.is_some() && c.auth.is_some() {
+			return Err(Error::Config("cookie_file and auth can't be combined"));
+		}
+		Ok(c)
+	}
+}
+
+#[derive(Default)]
+pub struct State {
+	/// Whether the Electrum RPC server is up.
+	pub rpc_ready: bool,
+
+	/// The height and hash of the last block the index was updated to.
+	pub indexed_tip: Option<(u64, bitcoin::BlockHash)>,
+
+	/// The captured output of the daemon.
+	pub output: logs::Output,
+}
+
+impl AsRef<logs::Output> for State {
+	fn as_ref(&self) -> &logs::Output {
+		&self.output
+	}
+}
+
+impl AsMut<logs::Output> for State {
+	fn as_mut(&mut self) -> &mut logs::Output {
+		&mut self.output
+	}
+}
+
+pub struct Daemon {
+	common: Common<State>,
+	executable: PathBuf,
+	config: Config,
+
+	/// The path of the written config file.
+	/// [None] before it has been written.
+	config_file: Option<PathBuf>,
+}
+
+/// Check if the line is the one electrs logs when its Electrum server is up.
+pub fn parse_rpc_server_started(msg: &str) -> bool {
+	lazy_static! {
+		/// The regular expression for the server started message.
+		static ref RPC_STARTED_REGEX: Regex =
+			Regex::new(r"Electrum RPC server running on|serving Electrum RPC on").unwrap();
+	}
+
+	RPC_STARTED_REGEX.is_match(msg)
+}
+
+/// Parse a line logging an update of the index into the new height and block hash.
+pub fn parse_chain_updated(msg: &str) -> Option<(u64, bitcoin::BlockHash)> {
+	lazy_static! {
+		/// The regular expression for index update messages.
+		static ref CHAIN_UPDATED_REGEX: Regex =
+			Regex::new(r"chain updated: tip=([0-9a-f]{64}), height=([0-9]+)").unwrap();
+	}
+
+	let c = CHAIN_UPDATED_REGEX.captures(msg)?;
+	let hash = bitcoin::BlockHash::from_hex(&c[1]).ok()?;
+	let height = u64::from_str(&c[2]).ok()?;
+	Some((height, hash))
+}
+
+impl Daemon {
+	pub fn new<P: Into<PathBuf>>(executable: P, config: Config) -> Result<Daemon, Error> {
+		if !config.db_dir.is_absolute() {
+			return Err(Error::Config("db_dir should be an absolute path"));
+		}
+
+		Ok(Daemon {
+			common: Common::new(&config.runner),
+			executable: executable.into(),
+			config,
+
+			config_file: None,
+		})
+	}
+
+	/// Wait until the daemon serves Electrum requests.
+	///
+	/// This fails when the daemon exits or when it isn't ready after [timeout].
+	pub fn wait_for_ready(&self, timeout: time::Duration) -> Result<(), Error> {
+		self.wait_for("daemon to become ready", timeout, |s| s.rpc_ready)?;
+		self.record_startup(StartupStage::Ready);
+		Ok(())
+	}
+
+	/// Wait until the index reached the given height.
+	///
+	/// This fails when the daemon exits or when the height isn't reached
+	/// after [timeout].
+	pub fn wait_for_index(&self, height: u64, timeout: time::Duration) -> Result<(), Error> {
+		self.wait_for("index to reach height", timeout, |s| {
+			s.indexed_tip.map(|(h, _)| h >= height).unwrap_or(false)
+		})
+	}
+
+	/// Wait until the condition holds for the state.
+	fn wait_for(
+		&self,
+		what: &'static str,
+		timeout: time::Duration,
+		cond: impl Fn(&State) -> bool,
+	) -> Result<(), Error> {
+		runner::wait_until(self, what, timeout, || self.with_state(|s| cond(s)).unwrap_or(false))
+	}
+
+	pub fn config(&self) -> &Config {
+		&self.config
+	}
+
+	pub fn db_dir(&self) -> &Path {
+		self.config.db_dir.as_path()
+	}
+
+	/// The address of the Electrum server, f.e. `127.0.0.1:60401`.
+	pub fn electrum_address(&self) -> Option<String> {
+		self.config.electrum_rpc_port.map(|p| format!("127.0.0.1:{}", p))
+	}
+
+	/// The URL of the Electrum server, f.e. `tcp://127.0.0.1:60401`.
+	pub fn electrum_url(&self) -> Option<String> {
+		self.electrum_address().map(|a| format!("tcp://{}", a))
+	}
+
+	/// The address of the Prometheus metrics endpoint.
+	pub fn monitoring_address(&self) -> Option<String> {
+		self.config.monitoring_port.map(|p| format!("127.0.0.1:{}", p))
+	}
+
+	/// The height and hash of the last block the index was updated to.
+	pub fn indexed_tip(&self) -> Option<(u64, bitcoin::BlockHash)> {
+		self.with_state(|s| s.indexed_tip).flatten()
+	}
+}
+
+/// Process a line of log output.
+///
+/// electrs logs to stderr, but stdout is processed as well.
+fn process_log_line(state: &mut State, line: &str) {
+	if parse_rpc_server_started(line) {
+		state.rpc_ready = true;
+	}
+	if let Some((height, hash)) = parse_chain_updated(line) {
+		state.indexed_tip = Some((height, hash));
+		state.output.send(LogEvent::UpdateTip {
+			height,
+			hash,
+		});
+		return;
+	}
+
+	lazy_static! {
+		/// Regular expression to match for error messages.
+		static ref ERROR_REGEX: Regex = Regex::new(r"\bERROR\b").unwrap();
+		/// Regular expression to match for warning messages.
+		static ref WARNING_REGEX: Regex = Regex::new(r"\bWARN\b").unwrap();
+	}
+	state.output.process_line(line, &ERROR_REGEX, &WARNING_REGEX);
+}
+
+impl RunnerHelper for Daemon {
+	type State = State;
+
+	fn _prepare(&mut self) -> Result<(), Error> {
+		if self.config_file.is_some() {
+			return Ok(());
+		}
+
+		// Make sure the db dir exists.
+		fs::create_dir_all(&self.config.db_dir)?;
+
+		if self.config.electrum_rpc_port.is_none() {
+			self.config.electrum_rpc_port = Some(utils::find_free_port());
+		}
+		if self.config.monitoring_port.is_none() {
+			self.config.monitoring_port = Some(utils::find_free_port());
+		}
+
+		// Write the config file once and store the path.
+		let path = self.config.db_dir.join(CONFIG_FILENAME);
+		let mut file = File::create(&path)?;
+		self.config.write_into(&mut file)?;
+		self.config_file = Some(path);
+		Ok(())
+	}
+
+	fn _reset(&mut self) -> Result<(), Error> {
+		if self.config.db_dir.exists() {
+			fs::remove_dir_all(&self.config.db_dir)?;
+		}
+		self.config_file = None;
+		Ok(())
+	}
+
+	fn _command(&self) -> process::Command {
+		let mut cmd = process::Command::new(self.executable.clone());
+		cmd.arg("--conf").arg(self.config_file.as_ref().unwrap());
+		cmd
+	}
+
+	fn _init_state(&mut self) -> Self::State {
+		State {
+			rpc_ready: false,
+			indexed_tip: None,
+			output: self.common.new_output(&self.config.runner),
+		}
+	}
+
+	fn _notif_started(&mut self, runtime_data: Arc<Mutex<RuntimeData<Self::State>>>) {
+		self.common.runtime_data.replace(runtime_data);
+	}
+
+	fn _get_runtime(&self) -> Option<Arc<Mutex<RuntimeData<Self::State>>>> {
+		self.common.runtime_data.clone()
+	}
+
+	fn _process_stdout(_name: &str, state: &mut Self::State, line: &str) {
+		process_log_line(state, line);
+	}
+
+	fn _process_stderr(state: &mut Self::State, line: &str) {
+		state.output.stderr.push(line.to_owned());
+		process_log_line(state, line);
+	}
+}
+
+impl DaemonRunner for Daemon {}
+
+impl CommonDaemon for Daemon {
+	fn _common(&self) -> &Common<State> {
+		&self.common
+	}
+
+	fn _common_mut(&mut self) -> &mut Common<State> {
+		&mut self.common
+	}
+}
+
+impl fmt::Debug for Daemon {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		self.common.fmt_header(f, "electrs")?;
+		if f.alternate() {
+			let c = &self.config;
+			utils::fmt_details(
+				f,
+				&[
+					("network", c.network.map(network_name).map(str::to_owned)),
+					("db_dir", Some(c.db_dir.display().to_string())),
+					("electrum_rpc_port", c.electrum_rpc_port.map(|p| p.to_string())),
+					("monitoring_port", c.monitoring_port.map(|p| p.to_string())),
+				],
+			)?;
+		}
+		Ok(())
+	}
+}
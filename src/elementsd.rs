@@ -1,27 +1,53 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Write;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::{fmt, fs, io, mem, process};
+use std::{cmp, fmt, fs, io, mem, process, thread, time};
 
-use bitcoin::hashes::hex::FromHex;
-use bitcoin::{PublicKey, Script};
-use liquid_rpc as rpc;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::{Amount, PublicKey, Script};
+use liquid_rpc::{self as rpc, LiquidRpcApi};
 use regex::Regex;
 
-use error::Error;
-use runner::{DaemonRunner, RunnerHelper, RuntimeData};
-use utils;
+use crate::asset_registry;
+use crate::bitcoind;
+use crate::chain_params::{self, ChainParams};
+use crate::error::Error;
+use crate::genesis::GenesisParams;
+pub use crate::logs::parse_update_tip;
+use crate::logs::{self, LogEvent};
+use crate::runner::{
+	self, Common, CommonDaemon, DaemonRunner, ExitKind, RunnerConfig, RunnerHelper, RuntimeData,
+	StartupStage, Status,
+};
+use crate::utils;
+use crate::utxo::{self, UtxoHashType, UtxoSetInfo};
 
 pub const CONFIG_FILENAME: &str = "elements.conf";
 
 pub const DEFAULT_VERSION: u64 = 21_00_01;
+/// The environment variable that can be used to point to the elementsd executable.
+pub const EXECUTABLE_ENV_VAR: &str = "ELEMENTSD_EXE";
+
+/// How long the wallet is unlocked for after [Daemon::encrypt_wallet].
+const WALLET_UNLOCK_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// The peg-in confirmation depth used by [Daemon::regtest_with_mainchain].
+pub const REGTEST_PEGIN_CONFIRMATION_DEPTH: usize = 1;
+
+/// The time to wait for daemons created with [Daemon::regtest_with_mainchain] to be ready.
+const REGTEST_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// The block interval in seconds that is configured when none is set.
+pub const DEFAULT_POW_TARGET_SPACING: u64 = 60;
+
 /// length of the torv3 address
 pub const TORV3_ADDR_LEN: usize = 62;
 
 //throw std::runtime_error("ElementsVersion bits parameters malformed, expecting deployment:start:end:period:threshold");
-#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub struct EvbParams {
 	pub start: Option<u64>,
 	pub end: Option<u64>,
@@ -50,7 +76,27 @@ impl fmt::Display for EvbParams {
 	}
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
+/// The style of the genesis block of a custom chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GenesisStyle {
+	/// The genesis block of custom elements chains.
+	Elements,
+	/// A genesis block in the style of bitcoin.
+	Bitcoin,
+}
+
+impl fmt::Display for GenesisStyle {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			GenesisStyle::Elements => f.write_str("elements"),
+			GenesisStyle::Bitcoin => f.write_str("bitcoin"),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct Config {
 	/// This field is not present in the config but is necessary to
 	/// know the config file format that needs to be written.
@@ -64,22 +110,46 @@ pub struct Config {
 	pub listen: bool,
 	pub listenonion: bool,
 	pub discover: bool,
+	pub upnp: bool,
+	/// Only supported since version 22.0.
+	pub natpmp: bool,
+	/// When not set, a free port is allocated when the daemon is started.
 	pub port: Option<u16>,
 	pub externalip: Option<String>,
 	pub proxy: Option<String>,
+	/// The tor control port to create an onion service through.
+	pub torcontrol: Option<String>,
 	pub bind: Vec<String>,
 	pub onlynet: Vec<String>,
+	pub dns: Option<bool>,
+	pub dnsseed: Option<bool>,
+	/// Only supported since version 22.0.
+	pub fixedseeds: Option<bool>,
 	pub txindex: bool,
 	pub connect: Vec<String>,
+	pub addnodes: Vec<String>,
 	pub fdefaultconsistencychecks: bool,
 
 	pub rpccookie: Option<String>,
+	/// When not set, a free port is allocated when the daemon is started.
 	pub rpcport: Option<u16>,
+	/// The IPs or subnets allowed to connect to the RPC interface.
+	/// When empty and [rpcport] is set, only localhost is allowed.
+	pub rpcallowip: Vec<String>,
+	/// The addresses the RPC interface listens on.
+	/// When empty and [rpcport] is set, only localhost is used.
+	pub rpcbind: Vec<String>,
 	pub rpcuser: Option<String>,
 	pub rpcpass: Option<String>,
 
 	//TODO(stevenroose) enum?
 	pub addresstype: Option<String>,
+	/// Whether the wallet gives out blinded addresses by default.
+	pub blindedaddresses: Option<bool>,
+	/// The number of bits of the amounts hidden by range proofs.
+	pub ct_bits: Option<u8>,
+	/// The exponent of the amounts hidden by range proofs.
+	pub ct_exponent: Option<i8>,
 	pub blockmintxfee: Option<f64>,
 	pub minrelaytxfee: Option<f64>,
 
@@ -88,11 +158,26 @@ pub struct Config {
 	pub validatepegin: bool,
 	pub anyonecanspendaremine: bool,
 	pub peginconfirmationdepth: Option<usize>,
+	/// Whether blocks are signed by the [signblockscript] instead of mined
+	/// with proof of work. Custom chains use signed blocks by default.
+	pub con_signed_blocks: Option<bool>,
 	pub signblockscript: Option<Script>,
 	pub con_max_block_sig_size: Option<usize>,
+	pub con_genesis_style: Option<GenesisStyle>,
 	pub con_mandatorycoinbase: Option<String>,
 	pub fedpegscript: Option<Script>,
-	#[serde(default)]
+	/// The genesis block hash of the parent chain.
+	/// It's committed to by the pegged asset.
+	pub parentgenesisblockhash: Option<bitcoin::BlockHash>,
+	/// The amount of the pegged asset issued in the genesis block.
+	pub initialfreecoins: Option<u64>,
+	/// The amount of reissuance tokens of the pegged asset issued in the genesis block.
+	pub initialreissuancetokens: Option<u64>,
+	/// The block subsidy in satoshis.
+	pub con_blocksubsidy: Option<u64>,
+	/// Whether the outputs of the genesis block can be spent.
+	/// Needed to spend the [initialfreecoins].
+	pub con_connect_genesis_outputs: Option<bool>,
 	pub pak_pubkeys: Vec<(PublicKey, PublicKey)>,
 	pub evbparams_dynafed: Option<EvbParams>,
 	pub evbparams_taproot: Option<EvbParams>,
@@ -101,12 +186,65 @@ pub struct Config {
 	pub con_dyna_deploy_signal: Option<bool>,
 	pub con_nminerconfirmationwindow: Option<u64>,
 	pub con_nrulechangeactivationthreshold: Option<u64>,
+	/// The target block interval in seconds.
+	/// Defaults to [DEFAULT_POW_TARGET_SPACING].
+	pub con_npowtargetspacing: Option<u64>,
+	/// The difficulty adjustment interval in seconds.
+	pub con_npowtargettimespan: Option<u64>,
+	pub con_nsubsidyhalvinginterval: Option<u64>,
+	pub con_fpowallowmindifficultyblocks: Option<bool>,
+	pub con_fpownoretargeting: Option<bool>,
 	pub dynamic_epoch_length: Option<u64>,
+	/// The network magic bytes in hex.
+	/// It can't be set for liquidv1, which has a fixed magic.
+	/// When not set, the magic of newer versions is configured for older
+	/// versions of the pre-defined test chains.
+	pub pchmessagestart: Option<String>,
 	pub blockmaxweight: Option<u64>,
 	pub mainchain_rpchost: Option<String>,
 	pub mainchain_rpcport: Option<u16>,
 	pub mainchain_rpcuser: Option<String>,
 	pub mainchain_rpcpass: Option<String>,
+
+	// The options below are not present in the config file.
+	/// When set, the daemon is isolated from the outside world: it only
+	/// listens on loopback and doesn't discover peers or map ports.
+	/// Only explicitly configured peers are connected to.
+	pub hermetic: bool,
+
+	/// When set, [Daemon::wait_for_ready] fails when the daemon warned about
+	/// options it doesn't understand, f.e. because of a version mismatch.
+	pub fail_on_unknown_options: bool,
+
+	/// The timeout of the RPC calls made by the convenience methods of
+	/// [Daemon]. Without a timeout, calls wait for as long as it takes.
+	/// See [utils::TimeoutClient].
+	pub rpc_timeout: Option<time::Duration>,
+
+	/// When set, stdout output is kept as well, up to this many bytes.
+	/// Only the most recent output is kept. See [Daemon::take_stdout].
+	pub max_stdout_bytes: Option<usize>,
+
+	/// When set, all captured stdout and stderr output is written to
+	/// a log file in the datadir that is rotated by size.
+	pub log_file: Option<logs::LogFileConfig>,
+
+	/// When set, the daemon doesn't print to the console and its `debug.log`
+	/// is followed instead, so that messages that are only logged to file
+	/// are processed as well.
+	pub tail_debug_log: bool,
+
+	/// When set, starting the daemon fails if the filesystem of the datadir
+	/// has less free space than [Config::required_disk_space].
+	pub check_disk_space: bool,
+
+	/// The free disk space in bytes required by [Config::check_disk_space].
+	/// Defaults to an estimate for the chain.
+	pub min_free_disk_space: Option<u64>,
+
+	/// The options that are only used by the runner.
+	#[serde(flatten)]
+	pub runner: RunnerConfig,
 }
 impl Config {
 	pub fn write_into(&self, mut w: impl io::Write) -> Result<(), io::Error> {
@@ -119,8 +257,8 @@ impl Config {
 			DEFAULT_VERSION
 		};
 
-		let datadir = self.datadir.as_path().to_str().unwrap_or("");
-		if datadir.len() > 0 {
+		let datadir = utils::config_path(&self.datadir);
+		if !datadir.is_empty() {
 			writeln!(w, "datadir={}", datadir)?;
 		}
 
@@ -133,14 +271,40 @@ impl Config {
 		writeln!(w, "printtoconsole={}", self.printtoconsole as u8)?;
 		writeln!(w, "daemon={}", self.daemon as u8)?;
 		writeln!(w, "listen={}", self.listen as u8)?;
-		writeln!(w, "listenonion={}", self.listenonion as u8)?;
-		writeln!(w, "discover={}", self.discover as u8)?;
+		writeln!(w, "listenonion={}", (self.listenonion && !self.hermetic) as u8)?;
+		writeln!(w, "discover={}", (self.discover && !self.hermetic) as u8)?;
+		writeln!(w, "upnp={}", (self.upnp && !self.hermetic) as u8)?;
+		if version >= 22_00_00 {
+			writeln!(w, "natpmp={}", (self.natpmp && !self.hermetic) as u8)?;
+		}
+		if self.hermetic && self.listen && self.bind.is_empty() {
+			writeln!(w, "bind=127.0.0.1")?;
+		}
+		if let Some(v) = self.dns {
+			writeln!(w, "dns={}", v as u8)?;
+		}
+		let (dnsseed, fixedseeds) = if self.hermetic {
+			(Some(false), Some(false))
+		} else {
+			(self.dnsseed, self.fixedseeds)
+		};
+		if let Some(v) = dnsseed {
+			writeln!(w, "dnsseed={}", v as u8)?;
+		}
+		if let Some(v) = fixedseeds {
+			if version >= 22_00_00 {
+				writeln!(w, "fixedseeds={}", v as u8)?;
+			}
+		}
 		if let Some(p) = self.port {
 			writeln!(w, "port={}", p)?;
 		}
 		if let Some(ref v) = self.proxy {
 			writeln!(w, "proxy={}", v)?;
 		}
+		if let Some(ref v) = self.torcontrol {
+			writeln!(w, "torcontrol={}", v)?;
+		}
 		for bind in &self.bind {
 			writeln!(w, "bind={}", bind)?;
 		}
@@ -159,6 +323,12 @@ impl Config {
 
 		// Consensus variables have no effect for pre-defined chains.
 		if self.chain != "liquidv1" {
+			if let Some(v) = self.con_signed_blocks {
+				writeln!(w, "con_signed_blocks={}", v as u8)?;
+			}
+			if let Some(v) = self.con_genesis_style {
+				writeln!(w, "con_genesis_style={}", v)?;
+			}
 			if let Some(ref v) = self.signblockscript {
 				writeln!(w, "signblockscript={:x}", v)?;
 			}
@@ -168,6 +338,21 @@ impl Config {
 			if let Some(ref v) = self.fedpegscript {
 				writeln!(w, "fedpegscript={:x}", v)?;
 			}
+			if let Some(v) = self.parentgenesisblockhash {
+				writeln!(w, "parentgenesisblockhash={}", v)?;
+			}
+			if let Some(v) = self.initialfreecoins {
+				writeln!(w, "initialfreecoins={}", v)?;
+			}
+			if let Some(v) = self.initialreissuancetokens {
+				writeln!(w, "initialreissuancetokens={}", v)?;
+			}
+			if let Some(v) = self.con_blocksubsidy {
+				writeln!(w, "con_blocksubsidy={}", v)?;
+			}
+			if let Some(v) = self.con_connect_genesis_outputs {
+				writeln!(w, "con_connect_genesis_outputs={}", v as u8)?;
+			}
 
 			for pair in &self.pak_pubkeys {
 				writeln!(w, "pak={}{}", pair.0, pair.1)?;
@@ -176,7 +361,20 @@ impl Config {
 			if let Some(ref v) = self.con_mandatorycoinbase {
 				writeln!(w, "con_mandatorycoinbase={}", v)?;
 			}
-			writeln!(w, "con_npowtargetspacing=60")?;
+			let spacing = self.con_npowtargetspacing.unwrap_or(DEFAULT_POW_TARGET_SPACING);
+			writeln!(w, "con_npowtargetspacing={}", spacing)?;
+			if let Some(v) = self.con_npowtargettimespan {
+				writeln!(w, "con_npowtargettimespan={}", v)?;
+			}
+			if let Some(v) = self.con_nsubsidyhalvinginterval {
+				writeln!(w, "con_nsubsidyhalvinginterval={}", v)?;
+			}
+			if let Some(v) = self.con_fpowallowmindifficultyblocks {
+				writeln!(w, "con_fpowallowmindifficultyblocks={}", v as u8)?;
+			}
+			if let Some(v) = self.con_fpownoretargeting {
+				writeln!(w, "con_fpownoretargeting={}", v as u8)?;
+			}
 			if let Some(v) = self.con_nminerconfirmationwindow {
 				writeln!(w, "con_nminerconfirmationwindow={}", v)?;
 			}
@@ -186,7 +384,9 @@ impl Config {
 			if let Some(v) = self.dynamic_epoch_length {
 				writeln!(w, "dynamic_epoch_length={}", v)?;
 			}
-			if version < 21_00_00 {
+			if let Some(ref v) = self.pchmessagestart {
+				writeln!(w, "pchmessagestart={}", v)?;
+			} else if version < 21_00_00 {
 				if self.chain == "elementsregtest" {
 					// make older versions compatible with
 					// https://github.com/ElementsProject/elements/pull/1040
@@ -227,17 +427,40 @@ impl Config {
 		for connect in &self.connect {
 			writeln!(w, "connect={}", connect)?;
 		}
+		for addnode in &self.addnodes {
+			writeln!(w, "addnode={}", addnode)?;
+		}
 
 		// RPC details
 		if self.rpccookie.is_some() || self.rpcuser.is_some() {
 			writeln!(w, "server=1")?;
 		}
 		if let Some(ref cf) = self.rpccookie {
-			writeln!(w, "rpccookiefile={}", cf)?;
+			writeln!(w, "rpccookiefile={}", utils::config_path(Path::new(cf)))?;
+		}
+		let localhost = ["127.0.0.1".to_owned()];
+		let default = if self.rpcport.is_some() {
+			&localhost[..]
+		} else {
+			&[]
+		};
+		let rpcallowip = if self.rpcallowip.is_empty() {
+			default
+		} else {
+			&self.rpcallowip[..]
+		};
+		for ip in rpcallowip {
+			writeln!(w, "rpcallowip={}", ip)?;
+		}
+		let rpcbind = if self.rpcbind.is_empty() {
+			default
+		} else {
+			&self.rpcbind[..]
+		};
+		for addr in rpcbind {
+			writeln!(w, "rpcbind={}", addr)?;
 		}
 		if let Some(p) = self.rpcport {
-			writeln!(w, "rpcallowip=127.0.0.1")?;
-			writeln!(w, "rpcbind=127.0.0.1")?;
 			writeln!(w, "rpcport={}", p)?;
 		}
 		if let Some(ref u) = self.rpcuser {
@@ -270,6 +493,15 @@ impl Config {
 		if let Some(ref v) = self.addresstype {
 			writeln!(w, "addresstype={}", v)?;
 		}
+		if let Some(v) = self.blindedaddresses {
+			writeln!(w, "blindedaddresses={}", v as u8)?;
+		}
+		if let Some(v) = self.ct_bits {
+			writeln!(w, "ct_bits={}", v)?;
+		}
+		if let Some(v) = self.ct_exponent {
+			writeln!(w, "ct_exponent={}", v)?;
+		}
 		if let Some(v) = self.blockmintxfee {
 			writeln!(w, "blockmintxfee={:.8}", v)?;
 		}
@@ -278,50 +510,675 @@ impl Config {
 		}
 		Ok(())
 	}
+
+	/// How the daemon participates in the p2p network.
+	pub fn p2p_mode(&self) -> bitcoind::P2pMode {
+		bitcoind::P2pMode::from_config(&self.connect, self.listen)
+	}
+
+	/// The free disk space in bytes needed to run the daemon.
+	///
+	/// This is [Config::min_free_disk_space] or an estimate for the chain.
+	pub fn required_disk_space(&self) -> u64 {
+		if let Some(v) = self.min_free_disk_space {
+			return v;
+		}
+		match self.chain.as_str() {
+			"liquidv1" => 100 * utils::GB,
+			"liquidtestnet" => 20 * utils::GB,
+			_ => 100 * utils::MB,
+		}
+	}
+}
+
+impl utils::ConfigFile for Config {}
+
+/// A builder for [Config].
+///
+/// Use one of the chain presets to start from the right defaults for a chain.
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+	config: Config,
+}
+
+impl ConfigBuilder {
+	/// Create a builder for a config for the given chain without any other options set.
+	pub fn new<P: Into<PathBuf>>(datadir: P, chain: impl Into<String>) -> ConfigBuilder {
+		ConfigBuilder {
+			config: Config {
+				datadir: datadir.into(),
+				chain: chain.into(),
+				..Default::default()
+			},
+		}
+	}
+
+	/// Create a builder with defaults for an `elementsregtest` node.
+	///
+	/// The RPC interface listens on a free port and uses cookie authentication
+	/// with a cookie file in the datadir. The fedpegscript is `OP_TRUE` and
+	/// pegins are not validated unless a mainchain is configured.
+	pub fn elementsregtest<P: Into<PathBuf>>(datadir: P) -> ConfigBuilder {
+		let datadir = datadir.into();
+		let cookie = datadir.join(".cookie").to_string_lossy().into_owned();
+		let ports = utils::find_free_ports(2);
+		ConfigBuilder::new(datadir, "elementsregtest")
+			.port(ports[0])
+			.rpcport(ports[1])
+			.rpccookie(cookie)
+			.txindex(true)
+			.fedpegscript(Script::from(vec![0x51]))
+			.peginconfirmationdepth(REGTEST_PEGIN_CONFIRMATION_DEPTH)
+	}
+
+	/// Create a builder with defaults for a Liquid mainnet node.
+	///
+	/// The consensus parameters of this chain are fixed, so they are not written.
+	/// Pegins are validated against a mainchain bitcoind on the default port.
+	pub fn liquidv1<P: Into<PathBuf>>(datadir: P) -> ConfigBuilder {
+		let datadir = datadir.into();
+		let cookie = datadir.join(".cookie").to_string_lossy().into_owned();
+		let mut builder = ConfigBuilder::new(datadir, "liquidv1").rpcport(7041).rpccookie(cookie);
+		builder.config.validatepegin = true;
+		builder.config.mainchain_rpchost = Some("127.0.0.1".into());
+		builder.config.mainchain_rpcport = Some(8332);
+		builder
+	}
+
+	/// Create a builder with defaults for a `liquidv1test` node.
+	///
+	/// This chain mimics Liquid mainnet, but its consensus parameters can be
+	/// overridden. Pegins are not validated unless a mainchain is configured.
+	pub fn liquidv1test<P: Into<PathBuf>>(datadir: P) -> ConfigBuilder {
+		let datadir = datadir.into();
+		let cookie = datadir.join(".cookie").to_string_lossy().into_owned();
+		let ports = utils::find_free_ports(2);
+		ConfigBuilder::new(datadir, "liquidv1test")
+			.port(ports[0])
+			.rpcport(ports[1])
+			.rpccookie(cookie)
+	}
+
+	/// The version the config is written for. See [Config::version].
+	pub fn version(mut self, version: u64) -> Self {
+		self.config.version = version;
+		self
+	}
+
+	pub fn debug(mut self, v: bool) -> Self {
+		self.config.debug = v;
+		self
+	}
+
+	pub fn printtoconsole(mut self, v: bool) -> Self {
+		self.config.printtoconsole = v;
+		self
+	}
+
+	pub fn listen(mut self, v: bool) -> Self {
+		self.config.listen = v;
+		self
+	}
+
+	pub fn upnp(mut self, v: bool) -> Self {
+		self.config.upnp = v;
+		self
+	}
+
+	pub fn natpmp(mut self, v: bool) -> Self {
+		self.config.natpmp = v;
+		self
+	}
+
+	pub fn port(mut self, port: u16) -> Self {
+		self.config.port = Some(port);
+		self
+	}
+
+	pub fn externalip(mut self, ip: impl Into<String>) -> Self {
+		self.config.externalip = Some(ip.into());
+		self
+	}
+
+	pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+		self.config.proxy = Some(proxy.into());
+		self
+	}
+
+	pub fn torcontrol(mut self, addr: impl Into<String>) -> Self {
+		self.config.torcontrol = Some(addr.into());
+		self
+	}
+
+	pub fn bind(mut self, addr: impl Into<String>) -> Self {
+		self.config.bind.push(addr.into());
+		self
+	}
+
+	pub fn onlynet(mut self, net: impl Into<String>) -> Self {
+		self.config.onlynet.push(net.into());
+		self
+	}
+
+	pub fn dns(mut self, v: bool) -> Self {
+		self.config.dns = Some(v);
+		self
+	}
+
+	pub fn dnsseed(mut self, v: bool) -> Self {
+		self.config.dnsseed = Some(v);
+		self
+	}
+
+	pub fn fixedseeds(mut self, v: bool) -> Self {
+		self.config.fixedseeds = Some(v);
+		self
+	}
+
+	pub fn txindex(mut self, v: bool) -> Self {
+		self.config.txindex = v;
+		self
+	}
+
+	/// Add a node to connect to exclusively.
+	pub fn connect(mut self, addr: impl Into<String>) -> Self {
+		self.config.connect.push(addr.into());
+		self
+	}
+
+	/// Add a node to connect to.
+	pub fn addnode(mut self, addr: impl Into<String>) -> Self {
+		self.config.addnodes.push(addr.into());
+		self
+	}
+
+	/// Use cookie authentication with the given cookie file.
+	///
+	/// This unsets any user and password that were set before.
+	pub fn rpccookie(mut self, path: impl Into<String>) -> Self {
+		self.config.rpccookie = Some(path.into());
+		self.config.rpcuser = None;
+		self.config.rpcpass = None;
+		self
+	}
+
+	/// Use user and password authentication.
+	///
+	/// This unsets any cookie file that was set before.
+	pub fn rpcauth(mut self, user: impl Into<String>, pass: impl Into<String>) -> Self {
+		self.config.rpcuser = Some(user.into());
+		self.config.rpcpass = Some(pass.into());
+		self.config.rpccookie = None;
+		self
+	}
+
+	pub fn rpcport(mut self, port: u16) -> Self {
+		self.config.rpcport = Some(port);
+		self
+	}
+
+	/// Allow RPC connections from the given IP or subnet.
+	///
+	/// Once set, localhost is no longer allowed by default.
+	pub fn rpcallowip(mut self, ip: impl Into<String>) -> Self {
+		self.config.rpcallowip.push(ip.into());
+		self
+	}
+
+	/// Let the RPC interface listen on the given address.
+	///
+	/// Once set, the RPC interface no longer listens on localhost by default.
+	pub fn rpcbind(mut self, addr: impl Into<String>) -> Self {
+		self.config.rpcbind.push(addr.into());
+		self
+	}
+
+	pub fn addresstype(mut self, v: impl Into<String>) -> Self {
+		self.config.addresstype = Some(v.into());
+		self
+	}
+
+	pub fn blindedaddresses(mut self, v: bool) -> Self {
+		self.config.blindedaddresses = Some(v);
+		self
+	}
+
+	pub fn ct_bits(mut self, v: u8) -> Self {
+		self.config.ct_bits = Some(v);
+		self
+	}
+
+	pub fn ct_exponent(mut self, v: i8) -> Self {
+		self.config.ct_exponent = Some(v);
+		self
+	}
+
+	pub fn blockmintxfee(mut self, v: f64) -> Self {
+		self.config.blockmintxfee = Some(v);
+		self
+	}
+
+	pub fn minrelaytxfee(mut self, v: f64) -> Self {
+		self.config.minrelaytxfee = Some(v);
+		self
+	}
+
+	pub fn anyonecanspendaremine(mut self, v: bool) -> Self {
+		self.config.anyonecanspendaremine = v;
+		self
+	}
+
+	pub fn peginconfirmationdepth(mut self, v: usize) -> Self {
+		self.config.peginconfirmationdepth = Some(v);
+		self
+	}
+
+	/// Validate pegins against the mainchain node with the given RPC details.
+	pub fn mainchain_rpc(
+		mut self,
+		host: impl Into<String>,
+		port: u16,
+		user: impl Into<String>,
+		pass: impl Into<String>,
+	) -> Self {
+		self.config.validatepegin = true;
+		self.config.mainchain_rpchost = Some(host.into());
+		self.config.mainchain_rpcport = Some(port);
+		self.config.mainchain_rpcuser = Some(user.into());
+		self.config.mainchain_rpcpass = Some(pass.into());
+		self
+	}
+
+	/// Validate pegins against the given local bitcoind.
+	///
+	/// The bitcoind should use user and password authentication.
+	pub fn mainchain(mut self, bitcoind: &bitcoind::Daemon) -> Self {
+		let c = bitcoind.config();
+		self.config.validatepegin = true;
+		self.config.mainchain_rpchost = Some("127.0.0.1".into());
+		self.config.mainchain_rpcport = c.rpcport;
+		self.config.mainchain_rpcuser = c.rpcuser.clone();
+		self.config.mainchain_rpcpass = c.rpcpass.clone();
+		self
+	}
+
+	/// Don't validate pegins.
+	pub fn no_validatepegin(mut self) -> Self {
+		self.config.validatepegin = false;
+		self
+	}
+
+	/// See [Config::con_signed_blocks].
+	pub fn con_signed_blocks(mut self, v: bool) -> Self {
+		self.config.con_signed_blocks = Some(v);
+		self
+	}
+
+	/// Mine blocks with proof of work instead of signing them.
+	///
+	/// This unsets any [signblockscript] that was set before.
+	pub fn proof_of_work(mut self) -> Self {
+		self.config.con_signed_blocks = Some(false);
+		self.config.signblockscript = None;
+		self.config.con_max_block_sig_size = None;
+		self
+	}
+
+	pub fn signblockscript(mut self, script: Script) -> Self {
+		self.config.signblockscript = Some(script);
+		self
+	}
+
+	pub fn con_genesis_style(mut self, v: GenesisStyle) -> Self {
+		self.config.con_genesis_style = Some(v);
+		self
+	}
+
+	pub fn con_max_block_sig_size(mut self, v: usize) -> Self {
+		self.config.con_max_block_sig_size = Some(v);
+		self
+	}
+
+	pub fn con_mandatorycoinbase(mut self, v: impl Into<String>) -> Self {
+		self.config.con_mandatorycoinbase = Some(v.into());
+		self
+	}
+
+	/// Set [Config::con_mandatorycoinbase] to the script of the given address.
+	pub fn mandatory_coinbase_address(self, address: &rpc::elements::Address) -> Self {
+		self.con_mandatorycoinbase(mandatory_coinbase_script(address).to_hex())
+	}
+
+	pub fn fedpegscript(mut self, script: Script) -> Self {
+		self.config.fedpegscript = Some(script);
+		self
+	}
+
+	pub fn parentgenesisblockhash(mut self, v: bitcoin::BlockHash) -> Self {
+		self.config.parentgenesisblockhash = Some(v);
+		self
+	}
+
+	pub fn initialfreecoins(mut self, v: u64) -> Self {
+		self.config.initialfreecoins = Some(v);
+		self
+	}
+
+	pub fn initialreissuancetokens(mut self, v: u64) -> Self {
+		self.config.initialreissuancetokens = Some(v);
+		self
+	}
+
+	pub fn con_blocksubsidy(mut self, v: u64) -> Self {
+		self.config.con_blocksubsidy = Some(v);
+		self
+	}
+
+	pub fn con_connect_genesis_outputs(mut self, v: bool) -> Self {
+		self.config.con_connect_genesis_outputs = Some(v);
+		self
+	}
+
+	/// Configure all parameters that determine the genesis block.
+	///
+	/// Use [GenesisParams::genesis_hash] to know the hash of the resulting
+	/// genesis block in advance.
+	pub fn genesis(mut self, params: &GenesisParams) -> Self {
+		let c = &mut self.config;
+		c.chain = params.chain.clone();
+		c.con_genesis_style = Some(params.style);
+		c.con_signed_blocks = Some(params.signed_blocks);
+		if params.signed_blocks {
+			c.signblockscript = Some(params.signblockscript.clone());
+		} else {
+			c.signblockscript = None;
+			c.con_max_block_sig_size = None;
+		}
+		c.fedpegscript = Some(params.fedpegscript.clone());
+		c.parentgenesisblockhash = Some(params.parent_genesis_hash);
+		c.initialfreecoins = Some(params.initial_free_coins);
+		c.initialreissuancetokens = Some(params.initial_reissuance_tokens);
+		self
+	}
+
+	/// Add a PAK pubkey pair.
+	pub fn pak(mut self, online: PublicKey, offline: PublicKey) -> Self {
+		self.config.pak_pubkeys.push((online, offline));
+		self
+	}
+
+	pub fn evbparams_dynafed(mut self, p: EvbParams) -> Self {
+		self.config.evbparams_dynafed = Some(p);
+		self
+	}
+
+	pub fn evbparams_taproot(mut self, p: EvbParams) -> Self {
+		self.config.evbparams_taproot = Some(p);
+		self
+	}
+
+	pub fn con_npowtargetspacing(mut self, v: u64) -> Self {
+		self.config.con_npowtargetspacing = Some(v);
+		self
+	}
+
+	/// Set the network magic bytes in hex. See [Config::pchmessagestart].
+	pub fn pchmessagestart(mut self, v: impl Into<String>) -> Self {
+		self.config.pchmessagestart = Some(v.into());
+		self
+	}
+
+	pub fn dynamic_epoch_length(mut self, v: u64) -> Self {
+		self.config.dynamic_epoch_length = Some(v);
+		self
+	}
+
+	pub fn blockmaxweight(mut self, v: u64) -> Self {
+		self.config.blockmaxweight = Some(v);
+		self
+	}
+
+	/// See [Config::hermetic].
+	pub fn hermetic(mut self, v: bool) -> Self {
+		self.config.hermetic = v;
+		self
+	}
+
+	/// See [RunnerConfig::block_on_drop].
+	pub fn block_on_drop(mut self, v: bool) -> Self {
+		self.config.runner.block_on_drop = v;
+		self
+	}
+
+	/// See [Config::fail_on_unknown_options].
+	pub fn fail_on_unknown_options(mut self, v: bool) -> Self {
+		self.config.fail_on_unknown_options = v;
+		self
+	}
+
+	/// See [Config::rpc_timeout].
+	pub fn rpc_timeout(mut self, v: time::Duration) -> Self {
+		self.config.rpc_timeout = Some(v);
+		self
+	}
+
+	/// See [RunnerConfig::max_stderr_bytes].
+	pub fn max_stderr_bytes(mut self, v: usize) -> Self {
+		self.config.runner.max_stderr_bytes = Some(v);
+		self
+	}
+
+	/// See [Config::check_disk_space].
+	pub fn check_disk_space(mut self, v: bool) -> Self {
+		self.config.check_disk_space = v;
+		self
+	}
+
+	/// See [Config::min_free_disk_space].
+	pub fn min_free_disk_space(mut self, bytes: u64) -> Self {
+		self.config.min_free_disk_space = Some(bytes);
+		self
+	}
+
+	/// See [Config::max_stdout_bytes].
+	pub fn max_stdout_bytes(mut self, v: usize) -> Self {
+		self.config.max_stdout_bytes = Some(v);
+		self
+	}
+
+	/// See [RunnerConfig::max_error_msgs].
+	pub fn max_error_msgs(mut self, v: usize) -> Self {
+		self.config.runner.max_error_msgs = Some(v);
+		self
+	}
+
+	/// See [Config::tail_debug_log].
+	pub fn tail_debug_log(mut self, v: bool) -> Self {
+		self.config.tail_debug_log = v;
+		self
+	}
+
+	/// Write all output to [logs::DEFAULT_LOG_FILENAME] in the datadir,
+	/// keeping [keep] rotated files of at most [max_bytes] bytes.
+	///
+	/// See [Config::log_file].
+	pub fn log_file(mut self, max_bytes: u64, keep: usize) -> Self {
+		self.config.log_file = Some(logs::LogFileConfig {
+			filename: logs::DEFAULT_LOG_FILENAME.to_owned(),
+			max_bytes,
+			keep,
+		});
+		self
+	}
+
+	/// Validate the options and build the config.
+	pub fn build(self) -> Result<Config, Error> {
+		let c = self.config;
+		if c.chain.is_empty() {
+			return Err(Error::Config("chain should be set"));
+		}
+		if !c.datadir.is_absolute() {
+			return Err(Error::Config("datadir should be an absolute path"));
+		}
+		if c.rpcuser.is_some() != c.rpcpass.is_some() {
+			return Err(Error::Config("rpcuser and rpcpass should be set together"));
+		}
+		if c.rpcport.is_some() && c.rpccookie.is_none() && c.rpcuser.is_none() {
+			return Err(Error::Config("rpcport is set without RPC authentication"));
+		}
+		if c.port.is_some() && c.port == c.rpcport {
+			return Err(Error::Config("port and rpcport should be different"));
+		}
+		if c.validatepegin && c.mainchain_rpcport.is_none() {
+			return Err(Error::Config("validatepegin requires mainchain RPC details"));
+		}
+		if let Some(ref v) = c.pchmessagestart {
+			if v.len() != 8 || !v.chars().all(|c| c.is_ascii_hexdigit()) {
+				return Err(Error::Config("pchmessagestart should be 4 bytes in hex"));
+			}
+			if c.chain == "liquidv1" {
+				return Err(Error::Config("pchmessagestart can't be set for liquidv1"));
+			}
+		}
+		if c.ct_bits.map(|b| b > 64).unwrap_or(false) {
+			return Err(Error::Config("ct_bits should be at most 64"));
+		}
+		if c.ct_exponent.map(|e| !(-1..=18).contains(&e)).unwrap_or(false) {
+			return Err(Error::Config("ct_exponent should be between -1 and 18"));
+		}
+		if c.con_signed_blocks == Some(false)
+			&& (c.signblockscript.is_some() || c.con_max_block_sig_size.is_some())
+		{
+			return Err(Error::Config("signblockscript is set for a chain without signed blocks"));
+		}
+		if c.con_npowtargetspacing == Some(0) {
+			return Err(Error::Config("con_npowtargetspacing should be positive"));
+		}
+		if c.con_nsubsidyhalvinginterval == Some(0) {
+			return Err(Error::Config("con_nsubsidyhalvinginterval should be positive"));
+		}
+		let fees = [c.blockmintxfee, c.minrelaytxfee];
+		if fees.iter().flatten().any(|f| !f.is_finite() || *f < 0.0) {
+			return Err(Error::Config("fee rates should be non-negative"));
+		}
+		Ok(c)
+	}
 }
 
 #[derive(Default)]
 pub struct State {
 	pub last_update_tip: Option<(u64, bitcoin::BlockHash)>,
-	/// Buffer holding all stderr output.
-	pub stderr: String,
+	/// The time at which [last_update_tip] was last updated.
+	pub last_update_tip_time: Option<time::Instant>,
+	/// The sync progress from the last UpdateTip line, between 0 and 1.
+	pub sync_progress: Option<f64>,
+	/// The progress of the block verification at startup, between 0 and 1.
+	pub verification_progress: Option<f64>,
+	/// The number of block files at startup, to calculate the reindex progress.
+	pub block_files: usize,
+	/// The progress of importing the block files during a reindex, between 0 and 1.
+	pub reindex_progress: Option<f64>,
+	/// Whether importing the block files of a reindex finished.
+	pub reindex_finished: bool,
+	/// Buffer holding the most recent stdout output.
+	/// Only present when [Config::max_stdout_bytes] is set.
+	pub stdout: Option<logs::LineBuffer>,
 
 	/// For older versions, write stdout to this file.
 	pub stdout_file: Option<File>,
 
-	/// Error messages produced during runtime.
-	error_msgs: Vec<String>,
+	/// The log file all output is written to, if configured.
+	pub log_file: Option<logs::RotatingFile>,
+
+	/// The startup arguments the daemon reported to have picked up,
+	/// from both the command line and the config file.
+	pub effective_args: BTreeMap<String, Vec<String>>,
+
+	/// Warnings about options the daemon doesn't understand.
+	pub unknown_options: Vec<String>,
+
+	/// Messages about blocks that were rejected because their coinbase
+	/// doesn't pay to the mandatory coinbase destination.
+	pub coinbase_destination_mismatches: Vec<String>,
+
+	/// The wallet passphrase and unlock duration used to unlock
+	/// the wallet before signing.
+	wallet_unlock: Option<(utils::Secret, time::Duration)>,
+
+	/// The captured output of the daemon.
+	pub output: logs::Output,
+}
+
+impl AsRef<logs::Output> for State {
+	fn as_ref(&self) -> &logs::Output {
+		&self.output
+	}
+}
+
+impl AsMut<logs::Output> for State {
+	fn as_mut(&mut self) -> &mut logs::Output {
+		&mut self.output
+	}
 }
 
 pub struct Daemon {
-	name: String,
+	common: Common<State>,
+	/// Environment variables set for the process.
+	env: BTreeMap<String, String>,
+	/// Extra command line arguments for the process.
+	args: Vec<String>,
+	/// Additional patterns of stderr output that are considered benign.
+	benign_stderr: Vec<Regex>,
 	executable: PathBuf,
 	config: Config,
 
 	/// The path of the written config file.
 	/// [None] before it has been written.
 	config_file: Option<PathBuf>,
+	/// The temporary datadir to remove on drop, see [Daemon::new_tempdir].
+	temp_datadir: Option<PathBuf>,
+	/// The log file opened by [_prepare], until it's moved into the state.
+	log_file: Option<logs::RotatingFile>,
+}
+
+/// The number of `blk*.dat` files in the blocks directory.
+fn count_block_files(dir: &Path) -> usize {
+	let entries = match fs::read_dir(dir) {
+		Ok(e) => e,
+		Err(_) => return 0,
+	};
+	entries
+		.filter_map(|e| e.ok())
+		.filter(|e| {
+			let name = e.file_name();
+			let name = name.to_string_lossy();
+			name.starts_with("blk") && name.ends_with(".dat")
+		})
+		.count()
+}
 
-	runtime_data: Option<Arc<Mutex<RuntimeData<State>>>>,
+/// The script to use for [Config::con_mandatorycoinbase] to make all
+/// block rewards pay to the given address.
+pub fn mandatory_coinbase_script(address: &rpc::elements::Address) -> Script {
+	address.script_pubkey()
 }
 
-pub fn parse_update_tip(msg: &str) -> Option<(u64, bitcoin::BlockHash)> {
+/// Parse a line logging a block that was rejected because its coinbase
+/// doesn't pay to the mandatory coinbase destination.
+///
+/// Returns the full message.
+pub fn parse_coinbase_destination_mismatch(msg: &str) -> Option<String> {
 	lazy_static! {
-		/// The regular expression for UpdateTip messages.
-		static ref UPDATE_TIP_REGEX: Regex = Regex::new(
-			r".*UpdateTip: new best=([0-9a-f]+) height=([0-9]+) version=.*$"
-		).unwrap();
-	}
-
-	UPDATE_TIP_REGEX.captures(msg).map(|c| {
-		let blockhash = bitcoin::BlockHash::from_hex(
-			c.get(1).expect("blockhash missing in UpdateTip").as_str(),
-		)
-		.expect("invalid blockhash in UpdateTip");
-		let height = u64::from_str(c.get(2).expect("height missing in UpdateTip").as_str())
-			.expect("invalid height in UpdateTip");
-		(height, blockhash)
-	})
+		/// The regular expression for coinbase destination mismatches.
+		static ref COINBASE_DEST_REGEX: Regex =
+			Regex::new(r"bad-coinbase-txos|mandatory coinbase destination").unwrap();
+	}
+
+	if COINBASE_DEST_REGEX.is_match(msg) {
+		Some(msg.trim().to_owned())
+	} else {
+		None
+	}
 }
 
 impl Daemon {
@@ -338,14 +1195,159 @@ impl Daemon {
 			return Err(Error::Config("datadir should be an absolute path"));
 		}
 
-		Ok(Daemon {
-			name: name,
+		let mut daemon = Daemon {
+			common: Common::new(&config.runner),
 			executable: executable.into(),
-			config: config,
+			env: BTreeMap::new(),
+			args: Vec::new(),
+			benign_stderr: Vec::new(),
+			config,
 
 			config_file: None,
-			runtime_data: None,
-		})
+			temp_datadir: None,
+			log_file: None,
+		};
+		daemon.set_name(name);
+		Ok(daemon)
+	}
+
+	/// Create a daemon in a new temporary datadir.
+	///
+	/// The datadir of the config should not be set. The temporary datadir is
+	/// removed when the daemon is dropped, unless [keep_temp_datadir] is called.
+	pub fn new_tempdir(
+		executable: impl Into<PathBuf>,
+		mut config: Config,
+	) -> Result<Daemon, Error> {
+		if config.datadir != PathBuf::new() {
+			return Err(Error::Config("datadir should not be set"));
+		}
+		let datadir = utils::create_temp_dir("elementsd")?;
+		config.datadir = datadir.clone();
+		match Daemon::new(executable, config) {
+			Ok(mut daemon) => {
+				daemon.temp_datadir = Some(datadir);
+				Ok(daemon)
+			}
+			Err(e) => {
+				utils::remove_temp_dir(&datadir);
+				Err(e)
+			}
+		}
+	}
+
+	/// Don't remove the temporary datadir on drop, for example to inspect it
+	/// after a failure.
+	pub fn keep_temp_datadir(&mut self) {
+		if let Some(dir) = self.temp_datadir.take() {
+			info!("Keeping temporary datadir {} of {:?}", dir.display(), self);
+		}
+	}
+
+	/// Create and start a regtest bitcoind and an elementsregtest elementsd
+	/// that validates peg-ins against it.
+	///
+	/// The executables are taken from the `BITCOIND_EXE` and `ELEMENTSD_EXE`
+	/// environment variables or searched in `PATH`. Both daemons use temporary
+	/// datadirs that are removed on drop. This method returns once both daemons' RPC interfaces are ready.
+	pub fn regtest_with_mainchain() -> Result<(bitcoind::Daemon, Daemon), Error> {
+		let executable = utils::find_executable("elementsd", EXECUTABLE_ENV_VAR)
+			.ok_or(Error::Config("elementsd executable not found"))?;
+		let bitcoind = bitcoind::Daemon::regtest_tmp()?;
+		let mainchain = bitcoind.config();
+
+		let ports = utils::find_free_ports(2);
+		let mut elementsd = Daemon::new_tempdir(
+			executable,
+			Config {
+				chain: "elementsregtest".into(),
+				port: Some(ports[0]),
+				rpcport: Some(ports[1]),
+				rpcuser: Some("user".into()),
+				rpcpass: Some(format!("{:016x}", rand::random::<u64>())),
+				validatepegin: true,
+				peginconfirmationdepth: Some(REGTEST_PEGIN_CONFIRMATION_DEPTH),
+				mainchain_rpchost: Some("127.0.0.1".into()),
+				mainchain_rpcport: mainchain.rpcport,
+				mainchain_rpcuser: mainchain.rpcuser.clone(),
+				mainchain_rpcpass: mainchain.rpcpass.clone(),
+				..Default::default()
+			},
+		)?;
+		elementsd.start()?;
+		elementsd.wait_for_ready(REGTEST_TIMEOUT)?;
+		Ok((bitcoind, elementsd))
+	}
+
+	/// Wait until the daemon is ready to handle RPC requests.
+	///
+	/// The RPC interface is polled with an increasing interval. This fails when
+	/// the daemon exits or when it isn't ready after [timeout].
+	///
+	/// When [Config::fail_on_unknown_options] is set, this also fails if the
+	/// daemon warned about options it doesn't understand.
+	pub fn wait_for_ready(&self, timeout: time::Duration) -> Result<(), Error> {
+		runner::wait_until(self, "daemon to become ready", timeout, || {
+			let rpc = match self.rpc_client_with_timeout(Some(utils::HEALTH_CHECK_RPC_TIMEOUT)) {
+				Some(Ok(rpc)) => rpc,
+				_ => return false,
+			};
+			let res =
+				bitcoincore_rpc::RpcApi::call::<serde_json::Value>(&rpc, "getblockchaininfo", &[]);
+			if bitcoind::rpc_responded(&res) {
+				self.record_startup(StartupStage::RpcListening);
+			}
+			res.is_ok()
+		})?;
+		self.record_startup(StartupStage::Ready);
+
+		let unknown = self.unknown_options();
+		if self.config.fail_on_unknown_options && !unknown.is_empty() {
+			return Err(Error::UnknownOptions(unknown));
+		}
+		Ok(())
+	}
+
+	/// Detect the version of the executable and use it for the config.
+	///
+	/// This should be called before the daemon is first started,
+	/// because that is when the config file is written.
+	pub fn detect_version(&mut self) -> Result<u64, Error> {
+		let version = utils::detect_version(&self.executable)?;
+		debug!("Detected version {} for {:?}", utils::format_version(version), self);
+		self.config.version = version;
+		Ok(version)
+	}
+
+	/// Set an environment variable for the process, like `MALLOC_ARENA_MAX`.
+	///
+	/// The process inherits the environment of the current process otherwise.
+	/// Changes take effect the next time the daemon is started.
+	pub fn set_env(&mut self, key: impl Into<String>, value: impl Into<String>) {
+		self.env.insert(key.into(), value.into());
+	}
+
+	/// The environment variables set for the process.
+	pub fn env(&self) -> &BTreeMap<String, String> {
+		&self.env
+	}
+
+	/// Add a command line argument for the process, like `-debug=net`.
+	///
+	/// The arguments are passed after the ones for the config file, so that
+	/// options the config doesn't support can be used. Changes take effect
+	/// the next time the daemon is started.
+	pub fn add_arg(&mut self, arg: impl Into<String>) {
+		self.args.push(arg.into());
+	}
+
+	/// The extra command line arguments for the process.
+	pub fn args(&self) -> &[String] {
+		&self.args
+	}
+
+	pub fn config(&self) -> &Config {
+		&self.config
 	}
 
 	pub fn datadir(&self) -> &Path {
@@ -353,21 +1355,148 @@ impl Daemon {
 	}
 
 	pub fn last_update_tip(&self) -> Option<(u64, bitcoin::BlockHash)> {
-		self.runtime_data.as_ref().and_then(|rt| rt.lock().unwrap().state.last_update_tip)
+		self.with_state(|s| s.last_update_tip).flatten()
+	}
+
+	/// The time at which the last known tip was updated.
+	pub fn last_update_tip_time(&self) -> Option<time::Instant> {
+		self.with_state(|s| s.last_update_tip_time).flatten()
+	}
+
+	/// The estimated sync progress from the logs, between 0 and 1.
+	///
+	/// This is [None] until the daemon logged a new tip.
+	pub fn sync_progress(&self) -> Option<f64> {
+		self.with_state(|s| s.sync_progress).flatten()
+	}
+
+	/// The progress of the block verification at startup, between 0 and 1.
+	pub fn verification_progress(&self) -> Option<f64> {
+		self.with_state(|s| s.verification_progress).flatten()
+	}
+
+	/// The progress of importing the block files during a reindex, between 0 and 1.
+	///
+	/// The progress is based on the number of block files at startup. This
+	/// is [None] unless the daemon was started with `-reindex`, see
+	/// [Daemon::add_arg].
+	pub fn reindex_progress(&self) -> Option<f64> {
+		self.with_state(|s| s.reindex_progress).flatten()
+	}
+
+	/// Wait until a reindex completed.
+	///
+	/// The reindex is complete when all block files were imported and the
+	/// chain is connected up to the best header. This fails when the daemon
+	/// exits or when the reindex didn't complete after [timeout].
+	pub fn wait_for_reindex_complete(&self, timeout: time::Duration) -> Result<(), Error> {
+		let start = time::Instant::now();
+		loop {
+			if let Status::Stopped(s) = self.status()? {
+				return Err(Error::Exited(s.into()));
+			}
+			if self.with_state(|s| s.reindex_finished).unwrap_or(false) {
+				let rpc = self.rpc_client_with_timeout(Some(utils::HEALTH_CHECK_RPC_TIMEOUT));
+				if let Some(Ok(rpc)) = rpc {
+					if let Ok(info) = rpc.call::<serde_json::Value>("getblockchaininfo", &[]) {
+						if info["blocks"].as_u64().is_some() && info["blocks"] == info["headers"] {
+							info!("{:?}: reindex completed after {:?}", self, start.elapsed());
+							return Ok(());
+						}
+					}
+				}
+			}
+			if start.elapsed() > timeout {
+				return Err(Error::Timeout("reindex to complete"));
+			}
+			thread::sleep(time::Duration::from_millis(500));
+		}
+	}
+
+	/// Update the last known tip with the current tip from RPC.
+	pub fn refresh(&self) -> Result<(u64, bitcoin::BlockHash), Error> {
+		let rt = self.common.runtime_data.as_ref().ok_or(Error::InvalidState(Status::Init))?;
+
+		let rpc = self.rpc()?;
+		let height = bitcoincore_rpc::RpcApi::get_block_count(&rpc)?;
+		let hash = bitcoincore_rpc::RpcApi::get_block_hash(&rpc, height)?;
+
+		let mut lock = rt.lock().unwrap();
+		lock.state.last_update_tip = Some((height, hash));
+		lock.state.last_update_tip_time = Some(time::Instant::now());
+		Ok((height, hash))
+	}
+
+	/// Get the current block count.
+	///
+	/// This uses the last tip seen in the logs and only falls back
+	/// to querying RPC if no tip has been seen yet.
+	/// Use [refresh] to force an update from RPC.
+	pub fn block_count(&self) -> Result<u64, Error> {
+		match self.last_update_tip() {
+			Some((height, _)) => Ok(height),
+			None => Ok(self.refresh()?.0),
+		}
+	}
+
+	/// The directory where the daemon stores chain-specific data.
+	pub fn network_datadir(&self) -> PathBuf {
+		self.config.datadir.join(&self.config.chain)
+	}
+
+	/// The path of the RPC cookie file.
+	///
+	/// This is [None] when user and password authentication is used.
+	/// Relative paths are resolved like the daemon does, against the
+	/// network-specific datadir.
+	pub fn cookie_path(&self) -> Option<PathBuf> {
+		let path = match self.config.rpccookie {
+			Some(ref c) => PathBuf::from(c),
+			None if self.config.rpcuser.is_some() => return None,
+			None => PathBuf::from(".cookie"),
+		};
+		if path.is_absolute() {
+			Some(path)
+		} else {
+			Some(self.network_datadir().join(path))
+		}
+	}
+
+	/// Wait for the daemon to create its RPC cookie file and return its path.
+	///
+	/// The cookie file is written asynchronously after the daemon is started.
+	pub fn wait_for_cookie(&self, timeout: time::Duration) -> Result<PathBuf, Error> {
+		let path = self.cookie_path().ok_or(Error::Config("cookie authentication is not used"))?;
+		let start = time::Instant::now();
+		loop {
+			if fs::metadata(&path).map(|m| m.len() > 0).unwrap_or(false) {
+				return Ok(path);
+			}
+			if let Status::Stopped(s) = self.status()? {
+				return Err(Error::Exited(s.into()));
+			}
+			if start.elapsed() > timeout {
+				return Err(Error::Timeout("cookie file to be created"));
+			}
+			thread::sleep(time::Duration::from_millis(50));
+		}
 	}
 
 	/// Get the RPC info.
 	///
+	/// When neither a cookie file nor a user is configured, the default
+	/// cookie file in the network-specific datadir is used.
 	/// Don't call this method before calling [start].
 	pub fn rpc_info(&self) -> Option<(String, rpc::Auth)> {
 		let url = format!("http://127.0.0.1:{}", self.config.rpcport?);
-		let auth = if let Some(ref c) = self.config.rpccookie {
-			rpc::Auth::CookieFile(c.clone().into())
-		} else if let Some(ref u) = self.config.rpcuser {
-			let pass = self.config.rpcpass.as_ref()?.clone();
-			rpc::Auth::UserPass(u.clone(), pass)
-		} else {
-			return None;
+		// Without any auth configured, the daemon uses the default cookie file.
+		let auth = match self.cookie_path() {
+			Some(path) => rpc::Auth::CookieFile(path),
+			None => {
+				let user = self.config.rpcuser.as_ref()?.clone();
+				let pass = self.config.rpcpass.as_ref()?.clone();
+				rpc::Auth::UserPass(user, pass)
+			}
 		};
 		Some((url, auth))
 	}
@@ -377,25 +1506,431 @@ impl Daemon {
 		Some(rpc::Client::new(url, auth))
 	}
 
-	pub fn take_stderr(&self) -> String {
-		self.runtime_data
-			.as_ref()
-			.map(|rt| mem::replace(&mut rt.lock().unwrap().state.stderr, String::new()))
+	/// Iterate over the blocks the daemon connects from now on.
+	///
+	/// The blocks are taken from the UpdateTip log lines. The iterator ends
+	/// when the daemon stops. See [logs::Blocks].
+	pub fn blocks(&self) -> Result<logs::Blocks<State>, Error> {
+		Ok(logs::Blocks::from_events(self.subscribe_events(), self.observer()?))
+	}
+
+	/// Get an RPC client whose calls time out after the given timeout.
+	///
+	/// Use a short timeout for health checks and a long one, or none at all,
+	/// for calls that can take long, like those during a reindex.
+	/// Don't call this method before calling [start].
+	pub fn rpc_client_with_timeout(
+		&self,
+		timeout: Option<time::Duration>,
+	) -> Option<Result<utils::TimeoutClient, rpc::Error>> {
+		let (url, auth) = self.rpc_info()?;
+		Some(utils::TimeoutClient::new(&url, auth, timeout).map_err(rpc::Error::from))
+	}
+
+	fn rpc(&self) -> Result<utils::TimeoutClient, Error> {
+		let rpc = self.rpc_client_with_timeout(self.config.rpc_timeout);
+		Ok(rpc.ok_or(Error::Config("RPC is not configured"))??)
+	}
+
+	/// Get the wallet balance of outputs with at least [min_conf] confirmations.
+	///
+	/// The balance is returned per asset, keyed by asset label or asset id.
+	pub fn balance(&self, min_conf: u32) -> Result<HashMap<String, Amount>, Error> {
+		Ok(self.rpc()?.get_balance(Some(min_conf), None)?)
+	}
+
+	/// Get the wallet balance of a single asset, given by label or asset id.
+	pub fn balance_asset(&self, asset: &str, min_conf: u32) -> Result<Amount, Error> {
+		Ok(self.rpc()?.get_balance_asset(asset, Some(min_conf), None)?)
+	}
+
+	/// Stop the daemon such that a snapshot of its datadir is consistent.
+	///
+	/// The chain state is flushed to disk with `gettxoutsetinfo` first. Then the
+	/// daemon is asked to shut down, which also flushes its wallets. This fails
+	/// when the daemon didn't exit cleanly within [timeout] or when its datadir
+	/// is still locked afterwards. If the daemon already stopped, only its exit
+	/// and the lock are checked.
+	pub fn stop_for_snapshot(&self, timeout: time::Duration) -> Result<(), Error> {
+		if self.status()? == Status::Running {
+			let version = if self.config.version > 0 {
+				self.config.version
+			} else {
+				DEFAULT_VERSION
+			};
+			// Older versions can't skip the hash calculation.
+			let hash_type = if version < 21_00_00 {
+				UtxoHashType::HashSerialized
+			} else {
+				UtxoHashType::None
+			};
+			self.utxo_set_info(hash_type)?;
+			self.stop_with_timeout(timeout)?;
+		}
+		match self.status()? {
+			Status::Stopped(s) if ExitKind::from(s).is_clean() => {}
+			Status::Stopped(s) => return Err(Error::Exited(s.into())),
+			s => return Err(Error::InvalidState(s)),
+		}
+		if utils::datadir_locked(&self.network_datadir())? {
+			return Err(Error::Custom("datadir is still locked after shutdown"));
+		}
+		Ok(())
+	}
+
+	/// Get a summary of the UTXO set using `gettxoutsetinfo`.
+	pub fn utxo_set_info(&self, hash_type: UtxoHashType) -> Result<UtxoSetInfo, Error> {
+		let version = if self.config.version > 0 {
+			self.config.version
+		} else {
+			DEFAULT_VERSION
+		};
+		utxo::query_utxo_set_info(&self.rpc()?, version, hash_type)
+	}
+
+	/// The consensus constants of the configured chain.
+	pub fn chain_params(&self) -> ChainParams {
+		let (interval, subsidy) = match self.config.chain.as_str() {
+			"liquidv1" => (chain_params::MAINNET_HALVING_INTERVAL, Amount::from_sat(0)),
+			_ => (
+				self.config
+					.con_nsubsidyhalvinginterval
+					.unwrap_or(chain_params::REGTEST_HALVING_INTERVAL),
+				Amount::from_sat(
+					self.config
+						.con_blocksubsidy
+						.unwrap_or(chain_params::DEFAULT_INITIAL_SUBSIDY_SAT),
+				),
+			),
+		};
+		ChainParams {
+			coinbase_maturity: chain_params::COINBASE_MATURITY,
+			subsidy_halving_interval: interval,
+			initial_subsidy: subsidy,
+		}
+	}
+
+	/// Mine the given number of blocks to the given address.
+	pub fn generate_to(
+		&self,
+		blocks: u64,
+		address: &str,
+	) -> Result<Vec<bitcoin::BlockHash>, Error> {
+		let rpc = self.rpc()?;
+		Ok(rpc::bitcoincore_rpc::RpcApi::call(
+			&rpc,
+			"generatetoaddress",
+			&[blocks.into(), address.into()],
+		)?)
+	}
+
+	/// Mine a block to the wallet and enough blocks on top of it
+	/// for its coinbase to be spendable.
+	///
+	/// Returns the hashes of the mined blocks.
+	pub fn mature_coinbase(&self) -> Result<Vec<bitcoin::BlockHash>, Error> {
+		let address = self.new_address(None)?;
+		self.generate_to(self.chain_params().coinbase_maturity + 1, &address)
+	}
+
+	/// List all unspent outputs in the wallet.
+	pub fn unspent(&self) -> Result<Vec<rpc::json::ListUnspentResultEntry>, Error> {
+		Ok(self.rpc()?.list_unspent(None, None, None, None, None)?)
+	}
+
+	/// Generate a new wallet address of the given type.
+	/// If no type is given, the daemon's default `addresstype` is used.
+	pub fn new_address(&self, kind: Option<rpc::btcjson::AddressType>) -> Result<String, Error> {
+		Ok(self.rpc()?.get_new_address(None, kind)?)
+	}
+
+	/// Check that the coinbase of the given block only pays to the
+	/// mandatory coinbase destination.
+	///
+	/// Outputs without value, like the witness commitment, are ignored.
+	/// Fails if [Config::con_mandatorycoinbase] is not set.
+	pub fn coinbase_pays_mandatory(&self, block: &bitcoin::BlockHash) -> Result<bool, Error> {
+		let script = match self.config.con_mandatorycoinbase {
+			Some(ref s) => Script::from(Vec::<u8>::from_hex(s).map_err(rpc::Error::from)?),
+			None => return Err(Error::Config("con_mandatorycoinbase is not set")),
+		};
+		let rpc = self.rpc()?;
+		let hex: String = rpc::bitcoincore_rpc::RpcApi::call(
+			&rpc,
+			"getblock",
+			&[block.to_string().into(), 0.into()],
+		)?;
+		let bytes = Vec::<u8>::from_hex(&hex).map_err(rpc::Error::from)?;
+		let block: rpc::elements::Block =
+			rpc::elements::encode::deserialize(&bytes).map_err(rpc::Error::from)?;
+		let coinbase = block.txdata.first().ok_or(Error::Custom("block without coinbase"))?;
+		Ok(coinbase.output.iter().all(|out| {
+			out.script_pubkey == script
+				|| out.value == rpc::elements::confidential::Value::Explicit(0)
+				|| out.value == rpc::elements::confidential::Value::Null
+		}))
+	}
+
+	/// Messages about blocks that were rejected because their coinbase
+	/// doesn't pay to the mandatory coinbase destination.
+	pub fn coinbase_destination_mismatches(&self) -> Vec<String> {
+		self.with_state(|s| s.coinbase_destination_mismatches.clone()).unwrap_or_default()
+	}
+
+	/// Issue an asset committing to the given registry contract.
+	///
+	/// The contract is validated first and the issued asset id is checked
+	/// against the one expected by the registry.
+	pub fn issue_asset_with_contract(
+		&self,
+		contract: &asset_registry::Contract,
+		asset_amount: Amount,
+		token_amount: Amount,
+		blind: bool,
+	) -> Result<rpc::json::IssueAssetResult, Error> {
+		contract.validate()?;
+
+		let rpc = self.rpc()?;
+		self.ensure_unlocked(&rpc)?;
+		let args = [
+			asset_amount.as_btc().into(),
+			token_amount.as_btc().into(),
+			blind.into(),
+			contract.contract_hash_rpc_hex().into(),
+		];
+		let res: rpc::json::IssueAssetResult = rpc.call("issueasset", &args)?;
+
+		let tx = rpc.get_raw_transaction(&res.txid, None)?;
+		let input =
+			tx.input.get(res.vin as usize).ok_or(Error::Custom("issuance input missing"))?;
+		if contract.asset_id(input.previous_output) != res.asset {
+			return Err(Error::Custom("issued asset id doesn't match the registry contract"));
+		}
+		Ok(res)
+	}
+
+	/// Encrypt the wallet with the given passphrase.
+	///
+	/// The passphrase is remembered so that the wallet can be unlocked
+	/// automatically before signing. See [unlock_wallet].
+	pub fn encrypt_wallet(&self, passphrase: &str) -> Result<(), Error> {
+		let rpc = self.rpc()?;
+		rpc::bitcoincore_rpc::RpcApi::encrypt_wallet(&rpc, passphrase)?;
+		self.with_state_mut(|s| {
+			s.wallet_unlock = Some((utils::Secret::new(passphrase), WALLET_UNLOCK_TIMEOUT));
+		});
+		Ok(())
+	}
+
+	/// Unlock the wallet for the given duration.
+	///
+	/// The passphrase is remembered so that the wallet is unlocked again
+	/// for the same duration before the helpers that sign.
+	pub fn unlock_wallet(&self, passphrase: &str, timeout: time::Duration) -> Result<(), Error> {
+		let pass = utils::Secret::new(passphrase);
+		self.unlock_with(&self.rpc()?, &pass, timeout)?;
+		self.with_state_mut(|s| s.wallet_unlock = Some((pass, timeout)));
+		Ok(())
+	}
+
+	/// Lock the wallet and forget the passphrase.
+	pub fn lock_wallet(&self) -> Result<(), Error> {
+		self.with_state_mut(|s| s.wallet_unlock = None);
+		let rpc = self.rpc()?;
+		rpc::bitcoincore_rpc::RpcApi::call::<()>(&rpc, "walletlock", &[])?;
+		Ok(())
+	}
+
+	fn unlock_with(
+		&self,
+		rpc: &utils::TimeoutClient,
+		pass: &utils::Secret,
+		timeout: time::Duration,
+	) -> Result<(), Error> {
+		let secs = cmp::max(timeout.as_secs(), 1);
+		rpc::bitcoincore_rpc::RpcApi::call::<()>(
+			rpc,
+			"walletpassphrase",
+			&[pass.expose().into(), secs.into()],
+		)?;
+		Ok(())
+	}
+
+	/// Unlock the wallet if a passphrase is known.
+	fn ensure_unlocked(&self, rpc: &utils::TimeoutClient) -> Result<(), Error> {
+		if let Some((pass, timeout)) = self.with_state(|s| s.wallet_unlock.clone()).flatten() {
+			self.unlock_with(rpc, &pass, timeout)?;
+		}
+		Ok(())
+	}
+
+	/// Send the given amount of the policy asset to the address from the wallet.
+	///
+	/// The wallet is unlocked first if a passphrase is known.
+	pub fn send_to_address(
+		&self,
+		address: &str,
+		amount: Amount,
+	) -> Result<bitcoin::hashes::sha256d::Hash, Error> {
+		let rpc = self.rpc()?;
+		self.ensure_unlocked(&rpc)?;
+		Ok(rpc.send_to_address(
+			address,
+			amount.as_btc(),
+			None,
+			None,
+			None,
+			None,
+			None,
+			None,
+			None,
+			None,
+		)?)
+	}
+
+	/// Take the retained stdout output.
+	///
+	/// This is empty unless [Config::max_stdout_bytes] is set.
+	pub fn take_stdout(&self) -> String {
+		self.with_state_mut(|s| {
+			s.stdout
+				.as_mut()
+				.map(|b| b.take())
+				.unwrap_or_default()
+				.into_iter()
+				.map(|l| l + "\n")
+				.collect()
+		})
+		.unwrap_or_default()
+	}
+
+	/// The lines of the retained stdout and stderr output that match the pattern.
+	///
+	/// The output is not taken. Stdout is only retained when
+	/// [Config::max_stdout_bytes] is set.
+	pub fn grep_logs(&self, pattern: &Regex) -> Vec<String> {
+		self.with_state(|s| {
+			s.stdout
+				.iter()
+				.flat_map(|b| b.iter())
+				.chain(s.output.stderr.iter())
+				.filter(|l| pattern.is_match(l))
+				.cloned()
+				.collect()
+		})
+		.unwrap_or_default()
+	}
+
+	/// Consider stderr lines matching the pattern benign.
+	///
+	/// See [Daemon::unexpected_stderr].
+	pub fn allow_stderr(&mut self, pattern: Regex) {
+		self.benign_stderr.push(pattern);
+	}
+
+	/// Classify a line of stderr output, taking into account the patterns
+	/// added with [Daemon::allow_stderr].
+	pub fn stderr_severity(&self, line: &str) -> logs::StderrSeverity {
+		if self.benign_stderr.iter().any(|p| p.is_match(line)) {
+			logs::StderrSeverity::Benign
+		} else {
+			logs::classify_stderr_line(line)
+		}
+	}
+
+	/// The lines of the retained stderr output with the given severity.
+	///
+	/// The output is not taken.
+	pub fn stderr_lines(&self, severity: logs::StderrSeverity) -> Vec<String> {
+		self.with_state(|s| {
+			let lines = s.output.stderr.iter();
+			lines.filter(|l| self.stderr_severity(l) == severity).cloned().collect()
+		})
+		.unwrap_or_default()
+	}
+
+	/// The lines of the retained stderr output that are not known to be benign.
+	///
+	/// Harnesses can check this is empty instead of checking all of stderr.
+	pub fn unexpected_stderr(&self) -> Vec<String> {
+		self.stderr_lines(logs::StderrSeverity::Unknown)
+	}
+
+	/// The number of stderr lines and error messages that were dropped
+	/// because their buffers were full.
+	///
+	/// See [RunnerConfig::max_stderr_bytes] and [RunnerConfig::max_error_msgs].
+	pub fn dropped_output(&self) -> (usize, usize) {
+		self.with_state(|s| (s.output.stderr.dropped(), s.output.error_msgs().dropped()))
 			.unwrap_or_default()
 	}
 
-	pub fn take_error_msgs(&self) -> Vec<String> {
-		self.runtime_data
-			.as_ref()
-			.map(|rt| mem::replace(&mut rt.lock().unwrap().state.error_msgs, Vec::new()))
+	/// The number of stderr lines and error messages that were moved
+	/// to disk by compaction.
+	pub fn compacted_output(&self) -> (usize, usize) {
+		self.with_state(|s| (s.output.stderr.flushed(), s.output.error_msgs().flushed()))
 			.unwrap_or_default()
 	}
+
+	/// Move the buffered stderr output and error messages to archive files
+	/// in the datadir.
+	///
+	/// See [logs::Output::compact].
+	pub fn compact_logs(&self) -> Result<(), Error> {
+		let rt = self.common.runtime_data.as_ref().ok_or(Error::InvalidState(Status::Init))?;
+		rt.lock().unwrap().state.output.compact(&self.config.datadir)?;
+		Ok(())
+	}
+
+	/// Compact the logs every [interval] for as long as the daemon exists.
+	///
+	/// This is useful for long-running tests to keep memory usage flat
+	/// while retaining the full history on disk.
+	pub fn compact_logs_every(&self, interval: time::Duration) -> Result<(), Error> {
+		let rt = self.common.runtime_data.clone().ok_or(Error::InvalidState(Status::Init))?;
+		let dir = self.config.datadir.clone();
+		let name = format!("{:?}", self);
+		thread::Builder::new()
+			.name(format!("{}_compact", name))
+			.spawn(move || loop {
+				thread::sleep(interval);
+				// When we hold the only reference, the daemon was dropped.
+				if Arc::strong_count(&rt) == 1 {
+					break;
+				}
+				if let Err(e) = rt.lock().unwrap().state.output.compact(&dir) {
+					warn!("Failed to compact logs of daemon {}: {}", name, e);
+				}
+			})
+			.expect("failed to start compaction thread");
+		Ok(())
+	}
+
+	/// The startup arguments the daemon reported to have picked up.
+	///
+	/// Arguments that are given multiple times have multiple values.
+	pub fn effective_args(&self) -> BTreeMap<String, Vec<String>> {
+		self.with_state(|s| s.effective_args.clone()).unwrap_or_default()
+	}
+
+	/// Warnings the daemon gave about options it doesn't understand.
+	pub fn unknown_options(&self) -> Vec<String> {
+		self.with_state(|s| s.unknown_options.clone()).unwrap_or_default()
+	}
 }
 
 impl RunnerHelper for Daemon {
 	type State = State;
 
 	fn _prepare(&mut self) -> Result<(), Error> {
+		// Open the log file here so that failing to do so fails the start.
+		if let Some(ref c) = self.config.log_file {
+			fs::create_dir_all(&self.config.datadir)?;
+			let path = self.config.datadir.join(&c.filename);
+			debug!("Writing {:?} output to {}", self, path.display());
+			self.log_file = Some(logs::RotatingFile::open(&path, c.max_bytes, c.keep)?);
+		}
+
 		if self.config_file.is_some() {
 			return Ok(());
 		}
@@ -403,8 +1938,31 @@ impl RunnerHelper for Daemon {
 		// Make sure the datadir exists.
 		fs::create_dir_all(&self.config.datadir)?;
 
+		if self.config.check_disk_space {
+			utils::check_disk_space(&self.config.datadir, self.config.required_disk_space())?;
+		}
+
+		// Make sure the directory of a custom cookie file exists.
+		if self.config.rpccookie.is_some() {
+			if let Some(dir) = self.cookie_path().as_ref().and_then(|p| p.parent()) {
+				fs::create_dir_all(dir)?;
+			}
+		}
+
+		// Allocate free ports for the ones that are not set so that
+		// the config always knows where to reach the daemon.
+		let missing =
+			[self.config.port, self.config.rpcport].iter().filter(|p| p.is_none()).count();
+		let mut ports = utils::find_free_ports(missing).into_iter();
+		if self.config.port.is_none() {
+			self.config.port = ports.next();
+		}
+		if self.config.rpcport.is_none() {
+			self.config.rpcport = ports.next();
+		}
+
 		// Write the config file once and store the path.
-		let mut path: PathBuf = self.config.datadir.clone().into();
+		let mut path: PathBuf = self.config.datadir.clone();
 		path.push(CONFIG_FILENAME);
 		let mut file = File::create(&path)?;
 		self.config.write_into(&mut file)?;
@@ -412,32 +1970,74 @@ impl RunnerHelper for Daemon {
 		Ok(())
 	}
 
+	fn _reset(&mut self) -> Result<(), Error> {
+		if self.config.datadir.exists() {
+			fs::remove_dir_all(&self.config.datadir)?;
+		}
+		self.config_file = None;
+		Ok(())
+	}
+
 	fn _command(&self) -> process::Command {
 		let mut cmd = process::Command::new(self.executable.clone());
-		cmd.args(&[
-			&format!("-conf={}", self.config_file.as_ref().unwrap().as_path().display()),
-			"-printtoconsole=1",
-		]);
+		cmd.envs(&self.env);
+		cmd.arg(format!("-conf={}", self.config_file.as_ref().unwrap().as_path().display()));
+		if self.config.tail_debug_log {
+			cmd.arg("-printtoconsole=0");
+		} else {
+			cmd.arg("-printtoconsole=1");
+		}
+		cmd.args(&self.args);
 		cmd
 	}
 
-	fn _init_state(&self) -> Self::State {
+	fn _tail_log_path(&self) -> Option<PathBuf> {
+		if self.config.tail_debug_log {
+			Some(self.network_datadir().join("debug.log"))
+		} else {
+			None
+		}
+	}
+
+	fn _init_state(&mut self) -> Self::State {
 		State {
 			last_update_tip: None,
-			stderr: String::new(),
+			last_update_tip_time: None,
+			sync_progress: None,
+			verification_progress: None,
+			block_files: count_block_files(&self.network_datadir().join("blocks")),
+			reindex_progress: None,
+			reindex_finished: false,
+			stdout: self.config.max_stdout_bytes.map(|max| logs::LineBuffer::new(None, Some(max))),
 			stdout_file: None,
-			error_msgs: Vec::new(),
+			log_file: self.log_file.take(),
+			effective_args: BTreeMap::new(),
+			unknown_options: Vec::new(),
+			coinbase_destination_mismatches: Vec::new(),
+			wallet_unlock: None,
+			output: self.common.new_output(&self.config.runner),
 		}
 	}
 
 	/// Notify that the daemon has started.
 	fn _notif_started(&mut self, runtime_data: Arc<Mutex<RuntimeData<Self::State>>>) {
-		self.runtime_data.replace(runtime_data);
+		self.common.runtime_data.replace(runtime_data.clone());
+
+		if let Some((url, auth)) = self.rpc_info() {
+			thread::Builder::new()
+				.name(format!("{:?}_init_tip", self))
+				.spawn(move || {
+					bitcoind::init_tip_from_rpc(url, auth, runtime_data, |s| {
+						(&mut s.last_update_tip, &mut s.last_update_tip_time)
+					})
+				})
+				.expect("failed to start tip init thread");
+		}
 	}
 
 	/// Get the current runtime data.
 	fn _get_runtime(&self) -> Option<Arc<Mutex<RuntimeData<Self::State>>>> {
-		self.runtime_data.clone()
+		self.common.runtime_data.clone()
 	}
 
 	fn _process_stdout(name: &str, state: &mut Self::State, line: &str) {
@@ -446,38 +2046,151 @@ impl RunnerHelper for Daemon {
 		if let Some(ref mut file) = state.stdout_file {
 			writeln!(file, "{}", line).unwrap();
 		}
+		if let Some(ref mut file) = state.log_file {
+			if let Err(e) = file.write_line(line) {
+				warn!("{}: failed to write to log file: {}", name, e);
+			}
+		}
+		if let Some(ref mut buf) = state.stdout {
+			buf.push(line.to_owned());
+		}
 
-		if let Some(tip) = parse_update_tip(&line) {
+		if let Some(tip) = parse_update_tip(line) {
 			trace!("Setting new elementsd tip: {:?}", tip);
 			state.last_update_tip = Some(tip);
+			state.last_update_tip_time = Some(time::Instant::now());
+			if let Some(progress) = logs::parse_sync_progress(line) {
+				state.sync_progress = Some(progress);
+			}
+			state.output.send(LogEvent::UpdateTip {
+				height: tip.0,
+				hash: tip.1,
+			});
+			return;
+		}
+
+		if let Some(progress) = logs::parse_verification_progress(line) {
+			state.verification_progress = Some(progress);
+		}
+
+		if let Some(file) = logs::parse_reindex_block_file(line) {
+			// The line is logged when the import of the file starts.
+			let progress = (file as f64 / state.block_files.max(1) as f64).min(1.0);
+			let percent = (progress * 100.0) as u8;
+			if state.reindex_progress.map(|p| (p * 100.0) as u8) != Some(percent) {
+				info!("{}: reindex at {}%", name, percent);
+			}
+			state.reindex_progress = Some(progress);
+			state.output.send(LogEvent::ReindexProgress(percent));
+			return;
+		}
+		if logs::is_reindex_finished(line) {
+			info!("{}: finished importing block files for reindex", name);
+			state.reindex_progress = Some(1.0);
+			state.reindex_finished = true;
+			state.output.send(LogEvent::ReindexProgress(100));
+			return;
+		}
+
+		if let Some(warning) = logs::parse_unknown_option_warning(line) {
+			warn!("{}: unknown option: {}", name, warning);
+			state.unknown_options.push(warning);
+			state.output.send(LogEvent::Warning(line.to_owned()));
+			return;
+		}
+
+		if let Some((name, value)) = logs::parse_startup_arg(line) {
+			let values = state.effective_args.entry(name).or_default();
+			// Arguments are logged again on restart.
+			if !values.contains(&value) {
+				values.push(value);
+			}
+			state.output.send(LogEvent::RawLine(line.to_owned()));
 			return;
 		}
 
 		lazy_static! {
 			/// Regular expression to match for error messages.
 			static ref ERROR_REGEX: Regex = Regex::new(r"(?i)ERROR").unwrap();
+			/// Regular expression to match for warning messages.
+			static ref WARNING_REGEX: Regex = Regex::new(r"(?i)WARNING").unwrap();
+		}
+		if let Some(msg) = parse_coinbase_destination_mismatch(line) {
+			debug!("{}: coinbase destination mismatch: {}", name, msg);
+			state.coinbase_destination_mismatches.push(msg);
 		}
 		if ERROR_REGEX.is_match(line) {
 			debug!("{}: found error: {}", name, line);
-			state.error_msgs.push(line.to_string());
-			return;
 		}
+		state.output.process_line(line, &ERROR_REGEX, &WARNING_REGEX);
 	}
 
 	fn _process_stderr(state: &mut Self::State, line: &str) {
 		trace!("stderr line of elementsd: {}", line);
-		writeln!(&mut state.stderr, "{}", line).unwrap();
+		if let Some(ref mut file) = state.log_file {
+			if let Err(e) = file.write_line(line) {
+				warn!("elementsd: failed to write to log file: {}", e);
+			}
+		}
+		state.output.stderr.push(line.to_owned());
+		if let Some(warning) = logs::parse_unknown_option_warning(line) {
+			warn!("elementsd: unknown option: {}", warning);
+			state.unknown_options.push(warning);
+		}
 	}
 }
 
 impl DaemonRunner for Daemon {}
 
+impl CommonDaemon for Daemon {
+	fn _common(&self) -> &Common<State> {
+		&self.common
+	}
+
+	fn _common_mut(&mut self) -> &mut Common<State> {
+		&mut self.common
+	}
+
+	fn _rpc_info(&self) -> Option<(String, rpc::Auth)> {
+		self.rpc_info()
+	}
+}
+
+impl Drop for Daemon {
+	fn drop(&mut self) {
+		if let Some(ref dir) = self.temp_datadir {
+			if let Some(ref rt) = self.common.runtime_data {
+				if let Ok(mut rt) = rt.lock() {
+					// Wait for the process so that the datadir is no longer in use.
+					rt.kill(true);
+				}
+			}
+			utils::remove_temp_dir(dir);
+		}
+	}
+}
+
 impl fmt::Debug for Daemon {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		if self.name.is_empty() {
-			write!(f, "<unnamed> elementsd")
-		} else {
-			write!(f, "elementsd \"{}\"", self.name)
+		self.common.fmt_header(f, "elementsd")?;
+		if f.alternate() {
+			let c = &self.config;
+			let version = if c.version > 0 {
+				c.version
+			} else {
+				DEFAULT_VERSION
+			};
+			utils::fmt_details(
+				f,
+				&[
+					("version", Some(utils::format_version(version))),
+					("chain", Some(c.chain.clone())),
+					("datadir", Some(c.datadir.display().to_string())),
+					("port", c.port.map(|p| p.to_string())),
+					("rpcport", c.rpcport.map(|p| p.to_string())),
+				],
+			)?;
 		}
+		Ok(())
 	}
 }
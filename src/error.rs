@@ -1,7 +1,5 @@
 use std::{error, fmt, io, process};
 
-use bitcoincore_rpc;
-
 #[derive(Debug)]
 pub enum Error {
 	/// An I/O error.
@@ -15,9 +13,35 @@ pub enum Error {
 	/// Any other error.
 	Custom(&'static str),
 	/// The daemon is not in the appropriate state for this action.
-	InvalidState(::Status),
+	InvalidState(crate::Status),
 	/// Error running a command.
-	RunCommand(io::Error, process::Command),
+	RunCommand(io::Error, Box<process::Command>),
+	/// The daemon exited unexpectedly.
+	Exited(crate::ExitKind),
+	/// Timed out while waiting for the given condition.
+	Timeout(&'static str),
+	/// The daemon warned about options it doesn't understand.
+	UnknownOptions(Vec<String>),
+	/// A JSON-RPC error response, with its code and message.
+	JsonRpc(i64, String),
+	/// Nodes that should be on the same chain have different genesis blocks.
+	/// Contains a report of the differences in their configs.
+	GenesisMismatch(String),
+	/// Nodes didn't agree on the chain tip in time.
+	/// Contains a report of the tip of every node.
+	NotSynced(String),
+	/// A config could not be converted from or to TOML or JSON.
+	ConfigFormat(String),
+	/// The filesystem of the datadir doesn't have enough free space.
+	/// The available and required space are in bytes.
+	InsufficientDiskSpace {
+		dir: std::path::PathBuf,
+		available: u64,
+		required: u64,
+	},
+	/// A ZMQ error.
+	#[cfg(feature = "zmq")]
+	Zmq(zmq::Error),
 }
 
 impl From<io::Error> for Error {
@@ -38,6 +62,13 @@ impl From<liquid_rpc::Error> for Error {
 	}
 }
 
+#[cfg(feature = "zmq")]
+impl From<zmq::Error> for Error {
+	fn from(e: zmq::Error) -> Error {
+		Error::Zmq(e)
+	}
+}
+
 impl fmt::Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		fmt::Debug::fmt(self, f)
@@ -51,7 +82,21 @@ impl error::Error for Error {
 			Error::BitcoinRpc(ref e) => Some(e),
 			Error::LiquidRpc(ref e) => Some(e),
 			Error::RunCommand(ref e, ..) => Some(e),
-			Error::Config(_) | Error::Custom(_) | Error::InvalidState(_) => None,
+			#[cfg(feature = "zmq")]
+			Error::Zmq(ref e) => Some(e),
+			Error::Config(_)
+			| Error::Custom(_)
+			| Error::InvalidState(_)
+			| Error::Exited(_)
+			| Error::Timeout(_)
+			| Error::UnknownOptions(_)
+			| Error::JsonRpc(..)
+			| Error::GenesisMismatch(_)
+			| Error::NotSynced(_)
+			| Error::ConfigFormat(_)
+			| Error::InsufficientDiskSpace {
+				..
+			} => None,
 		}
 	}
 }
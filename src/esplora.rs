@@ -0,0 +1,488 @@
+//! Running the esplora backend in HTTP mode.
+//!
+//! This is the Blockstream fork of electrs, which serves the esplora REST
+//! API over HTTP. It can index both a bitcoind and, in liquid mode, an
+//! elementsd. The binary is configured with command line arguments only.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{fmt, fs, process, time};
+
+use regex::Regex;
+
+use crate::error::Error;
+use crate::logs;
+use crate::runner::{
+	self, Common, CommonDaemon, DaemonRunner, RunnerConfig, RunnerHelper, RuntimeData, StartupStage,
+};
+use crate::{bitcoind, elementsd, utils};
+
+/// The environment variable that can be used to point to the esplora electrs executable.
+pub const EXECUTABLE_ENV_VAR: &str = "ESPLORA_ELECTRS_EXE";
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+	/// The directory to keep the index database in.
+	pub db_dir: PathBuf,
+	/// The network as named by esplora, f.e. `regtest` or `liquidregtest`.
+	pub network: Option<String>,
+	/// The network of the mainchain in liquid mode, f.e. `regtest`.
+	pub parent_network: Option<String>,
+	/// The number of `-v` flags to pass.
+	pub verbosity: u8,
+
+	/// The port to serve the HTTP API on.
+	/// When not set, a free port is allocated when the daemon is started.
+	pub http_port: Option<u16>,
+	/// The port to serve the Electrum protocol on.
+	/// When not set, a free port is allocated when the daemon is started.
+	pub electrum_rpc_port: Option<u16>,
+	/// The port to serve Prometheus metrics on.
+	/// When not set, a free port is allocated when the daemon is started.
+	pub monitoring_port: Option<u16>,
+	/// The value of the `Access-Control-Allow-Origin` header, f.e. `*`.
+	pub cors: Option<String>,
+	/// Fetch blocks over RPC instead of reading the block files.
+	pub jsonrpc_import: bool,
+	/// Don't index the history of all addresses, to save disk space.
+	pub lightmode: bool,
+
+	// Connection to the node:
+	/// The datadir of the node.
+	/// When no [Config::cookie] is set, the cookie file of the node is read
+	/// from the subdirectory of the network in this directory.
+	pub daemon_dir: Option<PathBuf>,
+	/// The address of the RPC interface, f.e. `127.0.0.1:18443`.
+	pub daemon_rpc_addr: Option<String>,
+	/// The RPC credentials in the `user:password` format.
+	pub cookie: Option<String>,
+
+	/// The options that are only used by the runner.
+	#[serde(flatten)]
+	pub runner: RunnerConfig,
+}
+
+/// The name esplora uses for the bitcoin network.
+fn network_name(network: bitcoin::Network) -> &'static str {
+	match network {
+		bitcoin::Network::Bitcoin => "mainnet",
+		bitcoin::Network::Testnet => "testnet",
+		bitcoin::Network::Regtest => "regtest",
+	}
+}
+
+impl Config {
+	/// The command line arguments for this config.
+	///
+	/// The RPC credentials are not included, see [Config::cookie].
+	pub fn args(&self) -> Vec<OsString> {
+		let mut args = Vec::<OsString>::new();
+		let mut arg = |name: &str, value: OsString| {
+			args.push(format!("--{}", name).into());
+			args.push(value);
+		};
+
+		arg("db-dir", self.db_dir.clone().into());
+		if let Some(ref v) = self.network {
+			arg("network", v.into());
+		}
+		if let Some(ref v) = self.parent_network {
+			arg("parent-network", v.into());
+		}
+		if let Some(p) = self.http_port {
+			arg("http-addr", format!("127.0.0.1:{}", p).into());
+		}
+		if let Some(p) = self.electrum_rpc_port {
+			arg("electrum-rpc-addr", format!("127.0.0.1:{}", p).into());
+		}
+		if let Some(p) = self.monitoring_port {
+			arg("monitoring-addr", format!("127.0.0.1:{}", p).into());
+		}
+		if let Some(ref v) = self.cors {
+			arg("cors", v.into());
+		}
+		if let Some(ref v) = self.daemon_dir {
+			arg("daemon-dir", v.clone().into());
+		}
+		if let Some(ref v) = self.daemon_rpc_addr {
+			arg("daemon-rpc-addr", v.into());
+		}
+
+		if self.jsonrpc_import {
+			args.push("--jsonrpc-import".into());
+		}
+		if self.lightmode {
+			args.push("--lightmode".into());
+		}
+		if self.verbosity > 0 {
+			args.push(format!("-{}", "v".repeat(self.verbosity as usize)).into());
+		}
+		args
+	}
+}
+
+impl utils::ConfigFile for Config {}
+
+/// A builder for [Config].
+///
+/// Use [ConfigBuilder::regtest] or [ConfigBuilder::liquid_regtest] to start
+/// from sensible defaults.
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+	config: Config,
+}
+
+impl ConfigBuilder {
+	/// Create a builder for a config without any options set.
+	pub fn new<P: Into<PathBuf>>(db_dir: P) -> ConfigBuilder {
+		ConfigBuilder {
+			config: Config {
+				db_dir: db_dir.into(),
+				..Default::default()
+			},
+		}
+	}
+
+	/// Create a builder with defaults for a regtest backend indexing the given bitcoind.
+	pub fn regtest<P: Into<PathBuf>>(db_dir: P, bitcoind: &bitcoind::Daemon) -> ConfigBuilder {
+		ConfigBuilder::new(db_dir)
+			.network(network_name(bitcoin::Network::Regtest))
+			.http_port(utils::find_free_port())
+			.electrum_rpc_port(utils::find_free_port())
+			.monitoring_port(utils::find_free_port())
+			.cors("*")
+			.jsonrpc_import(true)
+			.bitcoind(bitcoind)
+	}
+
+	/// Create a builder with defaults for a liquid regtest backend
+	/// indexing the given elementsd, pegged to a regtest mainchain.
+	pub fn liquid_regtest<P: Into<PathBuf>>(
+		db_dir: P,
+		elementsd: &elementsd::Daemon,
+	) -> ConfigBuilder {
+		ConfigBuilder::new(db_dir)
+			.network("liquidregtest")
+			.parent_network(network_name(bitcoin::Network::Regtest))
+			.http_port(utils::find_free_port())
+			.electrum_rpc_port(utils::find_free_port())
+			.monitoring_port(utils::find_free_port())
+			.cors("*")
+			.jsonrpc_import(true)
+			.elementsd(elementsd)
+	}
+
+	/// The network as named by esplora, f.e. `regtest` or `liquidregtest`.
+	pub fn network(mut self, v: impl Into<String>) -> Self {
+		self.config.network = Some(v.into());
+		self
+	}
+
+	/// The network of the mainchain in liquid mode, f.e. `regtest`.
+	pub fn parent_network(mut self, v: impl Into<String>) -> Self {
+		self.config.parent_network = Some(v.into());
+		self
+	}
+
+	pub fn verbosity(mut self, v: u8) -> Self {
+		self.config.verbosity = v;
+		self
+	}
+
+	pub fn http_port(mut self, v: u16) -> Self {
+		self.config.http_port = Some(v);
+		self
+	}
+
+	pub fn electrum_rpc_port(mut self, v: u16) -> Self {
+		self.config.electrum_rpc_port = Some(v);
+		self
+	}
+
+	pub fn monitoring_port(mut self, v: u16) -> Self {
+		self.config.monitoring_port = Some(v);
+		self
+	}
+
+	pub fn cors(mut self, v: impl Into<String>) -> Self {
+		self.config.cors = Some(v.into());
+		self
+	}
+
+	pub fn jsonrpc_import(mut self, v: bool) -> Self {
+		self.config.jsonrpc_import = v;
+		self
+	}
+
+	pub fn lightmode(mut self, v: bool) -> Self {
+		self.config.lightmode = v;
+		self
+	}
+
+	/// Connect to the node RPC at the given address with user and password.
+	pub fn daemon_rpc(
+		mut self,
+		addr: impl Into<String>,
+		user: impl AsRef<str>,
+		pass: impl AsRef<str>,
+	) -> Self {
+		self.config.daemon_rpc_addr = Some(addr.into());
+		self.config.cookie = Some(format!("{}:{}", user.as_ref(), pass.as_ref()));
+		self
+	}
+
+	/// Index the given local bitcoind.
+	pub fn bitcoind(mut self, bitcoind: &bitcoind::Daemon) -> Self {
+		let c = bitcoind.config();
+		self.config.daemon_dir = Some(c.datadir.clone());
+		self.config.daemon_rpc_addr = c.rpcport.map(|p| format!("127.0.0.1:{}", p));
+		self.set_auth(c.rpcuser.as_ref(), c.rpcpass.as_ref());
+		self
+	}
+
+	/// Index the given local elementsd.
+	pub fn elementsd(mut self, elementsd: &elementsd::Daemon) -> Self {
+		let c = elementsd.config();
+		self.config.daemon_dir = Some(c.datadir.clone());
+		self.config.daemon_rpc_addr = c.rpcport.map(|p| format!("127.0.0.1:{}", p));
+		self.set_auth(c.rpcuser.as_ref(), c.rpcpass.as_ref());
+		self
+	}
+
+	/// Use the given credentials, or the cookie file in the daemon_dir
+	/// when they are not set.
+	fn set_auth(&mut self, user: Option<&String>, pass: Option<&String>) {
+		self.config.cookie = match (user, pass) {
+			(Some(u), Some(p)) => Some(format!("{}:{}", u, p)),
+			_ => None,
+		};
+	}
+
+	/// See [RunnerConfig::block_on_drop].
+	pub fn block_on_drop(mut self, v: bool) -> Self {
+		self.config.runner.block_on_drop = v;
+		self
+	}
+
+	/// Validate the options and build the config.
+	pub fn build(self) -> Result<Config, Error> {
+		let c = self.config;
+		if !c.db_dir.is_absolute() {
+			return Err(Error::Config("db_dir should be an absolute path"));
+		}
+		let liquid = c.network.as_ref().map(|n| n.starts_with("liquid")).unwrap_or(false);
+		if c.parent_network.is_some() && !liquid {
+			return Err(Error::Config("parent_network is only used in liquid mode"));
+		}
+		Ok(c)
+	}
+}
+
+#[derive(Default)]
+pub struct State {
+	/// Whether the HTTP server is up.
+	pub http_ready: bool,
+
+	/// Whether the Electrum RPC server is up.
+	pub electrum_ready: bool,
+
+	/// The captured output of the daemon.
+	pub output: logs::Output,
+}
+
+impl AsRef<logs::Output> for State {
+	fn as_ref(&self) -> &logs::Output {
+		&self.output
+	}
+}
+
+impl AsMut<logs::Output> for State {
+	fn as_mut(&mut self) -> &mut logs::Output {
+		&mut self.output
+	}
+}
+
+pub struct Daemon {
+	common: Common<State>,
+	executable: PathBuf,
+	config: Config,
+}
+
+/// Check if the line is the one esplora logs when its HTTP server is up.
+pub fn parse_http_server_started(msg: &str) -> bool {
+	lazy_static! {
+		/// The regular expression for the HTTP server started message.
+		static ref HTTP_STARTED_REGEX: Regex = Regex::new(r"REST server running on").unwrap();
+	}
+
+	HTTP_STARTED_REGEX.is_match(msg)
+}
+
+/// Check if the line is the one esplora logs when its Electrum server is up.
+pub fn parse_electrum_server_started(msg: &str) -> bool {
+	lazy_static! {
+		/// The regular expression for the Electrum server started message.
+		static ref ELECTRUM_STARTED_REGEX: Regex =
+			Regex::new(r"Electrum RPC server running on").unwrap();
+	}
+
+	ELECTRUM_STARTED_REGEX.is_match(msg)
+}
+
+impl Daemon {
+	pub fn new<P: Into<PathBuf>>(executable: P, config: Config) -> Result<Daemon, Error> {
+		if !config.db_dir.is_absolute() {
+			return Err(Error::Config("db_dir should be an absolute path"));
+		}
+
+		Ok(Daemon {
+			common: Common::new(&config.runner),
+			executable: executable.into(),
+			config,
+		})
+	}
+
+	/// Wait until the daemon serves HTTP requests.
+	///
+	/// The HTTP server is started after the initial indexing is done.
+	/// This fails when the daemon exits or when it isn't ready after [timeout].
+	pub fn wait_for_ready(&self, timeout: time::Duration) -> Result<(), Error> {
+		runner::wait_until(self, "daemon to become ready", timeout, || {
+			self.with_state(|s| s.http_ready).unwrap_or(false)
+		})?;
+		self.record_startup(StartupStage::Ready);
+		Ok(())
+	}
+
+	pub fn config(&self) -> &Config {
+		&self.config
+	}
+
+	pub fn db_dir(&self) -> &Path {
+		self.config.db_dir.as_path()
+	}
+
+	/// The base URL of the HTTP API, f.e. `http://127.0.0.1:3002`.
+	pub fn http_url(&self) -> Option<String> {
+		self.config.http_port.map(|p| format!("http://127.0.0.1:{}", p))
+	}
+
+	/// The address of the Electrum server, f.e. `127.0.0.1:60401`.
+	pub fn electrum_address(&self) -> Option<String> {
+		self.config.electrum_rpc_port.map(|p| format!("127.0.0.1:{}", p))
+	}
+}
+
+/// Process a line of log output.
+///
+/// esplora logs to stderr, but stdout is processed as well.
+fn process_log_line(state: &mut State, line: &str) {
+	if parse_http_server_started(line) {
+		state.http_ready = true;
+	}
+	if parse_electrum_server_started(line) {
+		state.electrum_ready = true;
+	}
+
+	lazy_static! {
+		/// Regular expression to match for error messages.
+		static ref ERROR_REGEX: Regex = Regex::new(r"\bERROR\b").unwrap();
+		/// Regular expression to match for warning messages.
+		static ref WARNING_REGEX: Regex = Regex::new(r"\bWARN\b").unwrap();
+	}
+	state.output.process_line(line, &ERROR_REGEX, &WARNING_REGEX);
+}
+
+impl RunnerHelper for Daemon {
+	type State = State;
+
+	fn _prepare(&mut self) -> Result<(), Error> {
+		// Make sure the db dir exists.
+		fs::create_dir_all(&self.config.db_dir)?;
+
+		if self.config.http_port.is_none() {
+			self.config.http_port = Some(utils::find_free_port());
+		}
+		if self.config.electrum_rpc_port.is_none() {
+			self.config.electrum_rpc_port = Some(utils::find_free_port());
+		}
+		if self.config.monitoring_port.is_none() {
+			self.config.monitoring_port = Some(utils::find_free_port());
+		}
+		Ok(())
+	}
+
+	fn _reset(&mut self) -> Result<(), Error> {
+		if self.config.db_dir.exists() {
+			fs::remove_dir_all(&self.config.db_dir)?;
+		}
+		Ok(())
+	}
+
+	fn _command(&self) -> process::Command {
+		let mut cmd = process::Command::new(self.executable.clone());
+		cmd.args(self.config.args());
+		if let Some(ref cookie) = self.config.cookie {
+			cmd.arg("--cookie").arg(cookie);
+		}
+		cmd
+	}
+
+	fn _init_state(&mut self) -> Self::State {
+		State {
+			http_ready: false,
+			electrum_ready: false,
+			output: self.common.new_output(&self.config.runner),
+		}
+	}
+
+	fn _notif_started(&mut self, runtime_data: Arc<Mutex<RuntimeData<Self::State>>>) {
+		self.common.runtime_data.replace(runtime_data);
+	}
+
+	fn _get_runtime(&self) -> Option<Arc<Mutex<RuntimeData<Self::State>>>> {
+		self.common.runtime_data.clone()
+	}
+
+	fn _process_stdout(_name: &str, state: &mut Self::State, line: &str) {
+		process_log_line(state, line);
+	}
+
+	fn _process_stderr(state: &mut Self::State, line: &str) {
+		state.output.stderr.push(line.to_owned());
+		process_log_line(state, line);
+	}
+}
+
+impl DaemonRunner for Daemon {}
+
+impl CommonDaemon for Daemon {
+	fn _common(&self) -> &Common<State> {
+		&self.common
+	}
+
+	fn _common_mut(&mut self) -> &mut Common<State> {
+		&mut self.common
+	}
+}
+
+impl fmt::Debug for Daemon {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		self.common.fmt_header(f, "esplora")?;
+		if f.alternate() {
+			let c = &self.config;
+			utils::fmt_details(
+				f,
+				&[
+					("network", c.network.clone()),
+					("db_dir", Some(c.db_dir.display().to_string())),
+					("http_port", c.http_port.map(|p| p.to_string())),
+					("electrum_rpc_port", c.electrum_rpc_port.map(|p| p.to_string())),
+				],
+			)?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,186 @@
+//! A federation of elementsd nodes that sign blocks together.
+
+use std::path::Path;
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::secp256k1::{self, Secp256k1};
+use bitcoin::{BlockHash, PrivateKey, PublicKey, Script};
+use bitcoincore_rpc::RpcApi;
+
+use crate::bitcoind;
+use crate::cluster::{Cluster, Topology};
+use crate::elementsd;
+use crate::error::Error;
+use crate::runner::CommonDaemon;
+use crate::utils;
+
+/// The name of the wallet holding the signing key of a member.
+const WALLET_NAME: &str = "federation";
+
+/// The maximum size of a single signature in a block proof.
+const MAX_SIG_SIZE: usize = 74;
+
+/// Create a bare K-of-N multisig script to use as signblockscript.
+pub fn signblockscript(threshold: usize, pubkeys: &[PublicKey]) -> Script {
+	let mut builder = Builder::new().push_int(threshold as i64);
+	for pk in pubkeys {
+		builder = builder.push_key(pk);
+	}
+	builder.push_int(pubkeys.len() as i64).push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script()
+}
+
+/// Generate [n] random regtest private keys for the members of a federation.
+pub fn generate_keys(n: usize) -> Vec<PrivateKey> {
+	(0..n)
+		.map(|_| loop {
+			if let Ok(key) = secp256k1::SecretKey::from_slice(&rand::random::<[u8; 32]>()) {
+				break PrivateKey {
+					compressed: true,
+					network: bitcoin::Network::Regtest,
+					key,
+				};
+			}
+		})
+		.collect()
+}
+
+/// A set of elementsd nodes on a custom chain whose blocks are signed
+/// by a K-of-N multisig of the members.
+///
+/// The members are in a [Cluster], connected in a full mesh. Member `i`
+/// has the `i`th key in its wallet.
+#[derive(Debug)]
+pub struct Federation {
+	threshold: usize,
+	keys: Vec<PrivateKey>,
+	signblockscript: Script,
+	cluster: Cluster,
+}
+
+impl Federation {
+	/// Create a federation with one member for every key.
+	///
+	/// The members are named `member0`, `member1`, etc. and have their
+	/// datadirs in [dir]. They all have identical consensus parameters.
+	/// The elementsd executable is taken from the `ELEMENTSD_EXE` environment
+	/// variable or searched in `PATH`. Use [start] to start the members.
+	pub fn new(dir: &Path, keys: Vec<PrivateKey>, threshold: usize) -> Result<Federation, Error> {
+		if keys.is_empty() {
+			return Err(Error::Config("a federation needs at least one member"));
+		}
+		if threshold == 0 || threshold > keys.len() {
+			return Err(Error::Config("threshold should be between 1 and the number of keys"));
+		}
+		let executable = utils::find_executable("elementsd", elementsd::EXECUTABLE_ENV_VAR)
+			.ok_or(Error::Config("elementsd executable not found"))?;
+
+		let secp = Secp256k1::signing_only();
+		let pubkeys = keys.iter().map(|k| k.public_key(&secp)).collect::<Vec<_>>();
+		let script = signblockscript(threshold, &pubkeys);
+
+		let mut configs = (0..keys.len())
+			.map(|i| {
+				elementsd::ConfigBuilder::elementsregtest(dir.join(format!("member{}", i)))
+					.listen(true)
+					.hermetic(true)
+					.signblockscript(script.clone())
+					.con_max_block_sig_size(threshold * MAX_SIG_SIZE + 1)
+					.build()
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+		for (a, b) in Topology::FullMesh.edges(configs.len()) {
+			let port = configs[b].port.expect("elementsregtest config has a port");
+			configs[a].addnodes.push(format!("127.0.0.1:{}", port));
+		}
+
+		let mut cluster = Cluster::new();
+		for (i, config) in configs.into_iter().enumerate() {
+			let name = format!("member{}", i);
+			let mut daemon = elementsd::Daemon::named(name, executable.clone(), config)?;
+			daemon.set_label("group", "federation");
+			cluster.add(daemon);
+		}
+
+		Ok(Federation {
+			threshold,
+			keys,
+			signblockscript: script,
+			cluster,
+		})
+	}
+
+	/// Start all members and give them their signing keys.
+	///
+	/// This fails when the members don't agree on the genesis block.
+	pub fn start(&mut self) -> Result<(), Error> {
+		self.cluster.start_all()?;
+		self.cluster.check_genesis()?;
+		for (node, key) in self.cluster.nodes().iter().zip(&self.keys) {
+			let rpc = node.rpc_client().ok_or(Error::Config("RPC is not configured"))??;
+			bitcoind::load_or_create_wallet(&rpc, WALLET_NAME)?;
+			let args = [key.to_wif().into(), "".into(), false.into()];
+			rpc.call::<serde_json::Value>("importprivkey", &args)?;
+		}
+		Ok(())
+	}
+
+	/// Create a block, sign it with [threshold] members and submit it.
+	///
+	/// Returns the hash of the new block.
+	pub fn sign_and_submit_block(&self) -> Result<BlockHash, Error> {
+		let nodes = self.cluster.nodes();
+		let rpc_of = |i: usize| -> Result<bitcoincore_rpc::Client, Error> {
+			nodes[i].rpc_client().ok_or(Error::Config("RPC is not configured"))?
+		};
+
+		let proposer = rpc_of(0)?;
+		let block = proposer.call::<String>("getnewblockhex", &[])?;
+		let mut sigs = Vec::new();
+		for i in 0..self.threshold {
+			let member_sigs =
+				rpc_of(i)?.call::<Vec<serde_json::Value>>("signblock", &[block.clone().into()])?;
+			sigs.extend(member_sigs);
+		}
+
+		let combined =
+			proposer.call::<serde_json::Value>("combineblocksigs", &[block.into(), sigs.into()])?;
+		if combined["complete"].as_bool() != Some(true) {
+			return Err(Error::Custom("block signatures are incomplete"));
+		}
+		let signed = combined["hex"].as_str().ok_or(Error::Custom("no signed block returned"))?;
+		let res = proposer.call::<serde_json::Value>("submitblock", &[signed.into()])?;
+		if !res.is_null() {
+			warn!("Federation block was rejected: {}", res);
+			return Err(Error::Custom("signed block was rejected"));
+		}
+		Ok(proposer.get_best_block_hash()?)
+	}
+
+	/// The number of signatures needed for a block.
+	pub fn threshold(&self) -> usize {
+		self.threshold
+	}
+
+	/// The signing keys of the members.
+	pub fn keys(&self) -> &[PrivateKey] {
+		&self.keys
+	}
+
+	pub fn signblockscript(&self) -> &Script {
+		&self.signblockscript
+	}
+
+	/// The member at the given index.
+	pub fn member(&self, idx: usize) -> Option<&elementsd::Daemon> {
+		self.cluster.elementsd(idx)
+	}
+
+	pub fn cluster(&self) -> &Cluster {
+		&self.cluster
+	}
+
+	pub fn cluster_mut(&mut self) -> &mut Cluster {
+		&mut self.cluster
+	}
+}
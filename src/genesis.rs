@@ -0,0 +1,358 @@
+//! Calculation of the genesis block of custom elements chains.
+//!
+//! The genesis block of a custom chain commits to the chain name, the
+//! fedpegscript and the signblockscript. When the daemons of a network are
+//! configured with different values, they end up on different chains and
+//! silently refuse to talk to each other. Calculating the genesis block
+//! hash up front catches such mismatches early.
+//!
+//! The calculation follows the chain parameters of elements for custom chains
+//! with the default `con_elementsmode` and `con_blockheightinheader`, without
+//! dynamic federations active at genesis.
+
+use bitcoin::consensus::Encodable;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::hashes::{sha256, sha256d, Hash, HashEngine};
+use bitcoin::{BlockHash, Script};
+use liquid_rpc::elements::confidential::{Asset, Nonce, Value};
+use liquid_rpc::elements::issuance::AssetId;
+use liquid_rpc::elements::{AssetIssuance, OutPoint, Transaction, TxIn, TxOut};
+
+use crate::elementsd::{Config, GenesisStyle};
+use crate::error::Error;
+
+/// The timestamp of the genesis block of custom chains.
+pub const GENESIS_TIME: u32 = 1296688602;
+
+/// The nonce of the genesis block of proof of work chains.
+pub const GENESIS_NONCE: u32 = 2;
+
+/// The difficulty bits of the genesis block of proof of work chains.
+pub const GENESIS_BITS: u32 = 0x207fffff;
+
+/// The genesis block hash of bitcoin regtest, the default parent chain.
+pub const BITCOIN_REGTEST_GENESIS_HASH: &str =
+	"0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206";
+
+/// The coinbase script of the bitcoin genesis block, used for bitcoin style genesis blocks.
+const BITCOIN_GENESIS_SCRIPT_SIG: &str = "04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73";
+
+/// The output script of the bitcoin genesis block, used for bitcoin style genesis blocks.
+const BITCOIN_GENESIS_SCRIPT_PUBKEY: &str = "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac";
+
+/// The genesis reward of bitcoin style genesis blocks.
+const BITCOIN_GENESIS_REWARD: u64 = 50 * 100_000_000;
+
+/// The parameters of a custom chain that determine its genesis block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenesisParams {
+	/// The name of the chain.
+	pub chain: String,
+	/// The style of the genesis block.
+	pub style: GenesisStyle,
+	/// Whether blocks are signed instead of mined with proof of work.
+	pub signed_blocks: bool,
+	/// The script blocks are signed with.
+	/// It's also committed to by proof of work chains.
+	pub signblockscript: Script,
+	/// The script of the federation holding the peg.
+	pub fedpegscript: Script,
+	/// The genesis block hash of the parent chain.
+	pub parent_genesis_hash: BlockHash,
+	/// The amount of the pegged asset issued in the genesis block.
+	pub initial_free_coins: u64,
+	/// The amount of reissuance tokens of the pegged asset issued in the genesis block.
+	pub initial_reissuance_tokens: u64,
+}
+
+/// The default of both the signblockscript and the fedpegscript: `OP_TRUE`.
+fn op_true() -> Script {
+	Script::from(vec![0x51])
+}
+
+impl GenesisParams {
+	/// The default parameters of a custom chain with the given name.
+	pub fn new(chain: impl Into<String>) -> GenesisParams {
+		GenesisParams {
+			chain: chain.into(),
+			style: GenesisStyle::Elements,
+			signed_blocks: true,
+			signblockscript: op_true(),
+			fedpegscript: op_true(),
+			parent_genesis_hash: BlockHash::from_hex(BITCOIN_REGTEST_GENESIS_HASH).unwrap(),
+			initial_free_coins: 0,
+			initial_reissuance_tokens: 0,
+		}
+	}
+
+	/// The parameters the given config results in.
+	///
+	/// Fails for the pre-defined `liquidv1` chain, which has fixed parameters.
+	pub fn from_config(config: &Config) -> Result<GenesisParams, Error> {
+		if config.chain == "liquidv1" {
+			return Err(Error::Config("the genesis block of liquidv1 is fixed"));
+		}
+		let default = GenesisParams::new(config.chain.clone());
+		Ok(GenesisParams {
+			style: config.con_genesis_style.unwrap_or(default.style),
+			signed_blocks: config.con_signed_blocks.unwrap_or(default.signed_blocks),
+			signblockscript: config.signblockscript.clone().unwrap_or(default.signblockscript),
+			fedpegscript: config.fedpegscript.clone().unwrap_or(default.fedpegscript),
+			parent_genesis_hash: config
+				.parentgenesisblockhash
+				.unwrap_or(default.parent_genesis_hash),
+			initial_free_coins: config.initialfreecoins.unwrap_or(0),
+			initial_reissuance_tokens: config.initialreissuancetokens.unwrap_or(0),
+			chain: default.chain,
+		})
+	}
+
+	/// Check that the parameters can be expressed in a config.
+	pub fn validate(&self) -> Result<(), Error> {
+		if self.chain.is_empty() {
+			return Err(Error::Config("chain should be set"));
+		}
+		if self.chain == "liquidv1" {
+			return Err(Error::Config("the genesis block of liquidv1 is fixed"));
+		}
+		if !self.signed_blocks && self.signblockscript != op_true() {
+			return Err(Error::Config("signblockscript is set for a chain without signed blocks"));
+		}
+		if self.style == GenesisStyle::Bitcoin
+			&& (self.initial_free_coins != 0 || self.initial_reissuance_tokens != 0)
+		{
+			return Err(Error::Config("bitcoin style genesis blocks have no initial issuance"));
+		}
+		Ok(())
+	}
+
+	/// The commitment to the chain parameters in the genesis block.
+	pub fn commitment(&self) -> sha256::Hash {
+		let mut engine = sha256::Hash::engine();
+		engine.input(self.chain.as_bytes());
+		engine.input(format!("{:x}", self.fedpegscript).as_bytes());
+		engine.input(format!("{:x}", self.signblockscript).as_bytes());
+		sha256::Hash::from_engine(engine)
+	}
+
+	/// The outpoint the initial issuance of the pegged asset spends.
+	fn issuance_prevout(&self) -> OutPoint {
+		OutPoint {
+			txid: bitcoin::Txid::from_inner(self.commitment().into_inner()),
+			vout: 0,
+		}
+	}
+
+	/// The entropy of the pegged asset.
+	fn pegged_asset_entropy(&self) -> sha256::Midstate {
+		let contract = sha256::Hash::from_inner(self.parent_genesis_hash.into_inner());
+		AssetId::generate_asset_entropy(self.issuance_prevout(), contract)
+	}
+
+	/// The id of the pegged asset.
+	pub fn pegged_asset(&self) -> AssetId {
+		AssetId::from_entropy(self.pegged_asset_entropy())
+	}
+
+	/// The transactions in the genesis block.
+	pub fn genesis_transactions(&self) -> Vec<Transaction> {
+		let (script_sig, script_pubkey, reward) = match self.style {
+			// The commitment is used raw, it's not pushed.
+			GenesisStyle::Elements => {
+				(Script::from(self.commitment()[..].to_vec()), Script::from(vec![0x6a]), 0)
+			}
+			GenesisStyle::Bitcoin => (
+				Script::from(Vec::<u8>::from_hex(BITCOIN_GENESIS_SCRIPT_SIG).unwrap()),
+				Script::from(Vec::<u8>::from_hex(BITCOIN_GENESIS_SCRIPT_PUBKEY).unwrap()),
+				BITCOIN_GENESIS_REWARD,
+			),
+		};
+		let coinbase = Transaction {
+			version: 1,
+			lock_time: 0,
+			input: vec![TxIn {
+				previous_output: OutPoint {
+					txid: Default::default(),
+					vout: u32::MAX,
+				},
+				is_pegin: false,
+				has_issuance: false,
+				script_sig,
+				sequence: u32::MAX,
+				asset_issuance: Default::default(),
+				witness: Default::default(),
+			}],
+			output: vec![TxOut {
+				asset: Asset::Null,
+				value: Value::Explicit(reward),
+				nonce: Nonce::Null,
+				script_pubkey,
+				witness: Default::default(),
+			}],
+		};
+
+		let mut txs = vec![coinbase];
+		if self.style == GenesisStyle::Elements
+			&& (self.initial_free_coins != 0 || self.initial_reissuance_tokens != 0)
+		{
+			txs.push(self.initial_issuance());
+		}
+		txs
+	}
+
+	/// The transaction issuing the pegged asset and its reissuance tokens.
+	fn initial_issuance(&self) -> Transaction {
+		let entropy = self.pegged_asset_entropy();
+		let explicit_asset =
+			|id: AssetId| Asset::Explicit(sha256d::Hash::from_inner(id.into_inner().into_inner()));
+		let mut output = Vec::new();
+		if self.initial_free_coins > 0 {
+			output.push(TxOut {
+				asset: explicit_asset(AssetId::from_entropy(entropy)),
+				value: Value::Explicit(self.initial_free_coins),
+				nonce: Nonce::Null,
+				script_pubkey: op_true(),
+				witness: Default::default(),
+			});
+		}
+		if self.initial_reissuance_tokens > 0 {
+			output.push(TxOut {
+				asset: explicit_asset(AssetId::reissuance_token_from_entropy(entropy, false)),
+				value: Value::Explicit(self.initial_reissuance_tokens),
+				nonce: Nonce::Null,
+				script_pubkey: op_true(),
+				witness: Default::default(),
+			});
+		}
+		Transaction {
+			version: 1,
+			lock_time: 0,
+			input: vec![TxIn {
+				previous_output: self.issuance_prevout(),
+				is_pegin: false,
+				has_issuance: true,
+				script_sig: Script::new(),
+				sequence: u32::MAX,
+				asset_issuance: AssetIssuance {
+					asset_blinding_nonce: [0; 32],
+					asset_entropy: [0; 32],
+					amount: Value::Explicit(self.initial_free_coins),
+					inflation_keys: Value::Explicit(self.initial_reissuance_tokens),
+				},
+				witness: Default::default(),
+			}],
+			output,
+		}
+	}
+
+	/// The hash of the genesis block.
+	pub fn genesis_hash(&self) -> BlockHash {
+		let txids = self
+			.genesis_transactions()
+			.iter()
+			.map(|tx| sha256d::Hash::from_inner(tx.txid().into_inner()))
+			.collect::<Vec<_>>();
+		let merkle_root = bitcoin::util::hash::bitcoin_merkle_root(txids.into_iter());
+
+		let mut engine = BlockHash::engine();
+		1u32.consensus_encode(&mut engine).unwrap();
+		BlockHash::default().consensus_encode(&mut engine).unwrap();
+		merkle_root.consensus_encode(&mut engine).unwrap();
+		GENESIS_TIME.consensus_encode(&mut engine).unwrap();
+		// The block height.
+		0u32.consensus_encode(&mut engine).unwrap();
+		if self.signed_blocks {
+			// Only the challenge of the block proof is part of the hash.
+			self.signblockscript.consensus_encode(&mut engine).unwrap();
+		} else {
+			GENESIS_BITS.consensus_encode(&mut engine).unwrap();
+			GENESIS_NONCE.consensus_encode(&mut engine).unwrap();
+		}
+		BlockHash::from_engine(engine)
+	}
+
+	/// Check that the config results in these parameters.
+	pub fn check_config(&self, config: &Config) -> Result<(), Error> {
+		let actual = GenesisParams::from_config(config)?;
+		if actual.chain != self.chain {
+			return Err(Error::Config("config has a different chain name"));
+		}
+		if actual.genesis_hash() != self.genesis_hash() {
+			return Err(Error::Config("config results in a different genesis block"));
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// The id elementsd reports for the pegged asset of a default elementsregtest chain.
+	const ELEMENTSREGTEST_PEGGED_ASSET: &str =
+		"b2e15d0d7a0c94e4e2ce0fe6e8691b9e451377f6e46e8045a86f7c4b5d4f0f23";
+
+	/// The id elementsd reports for the pegged asset of a default liquidregtest chain.
+	const LIQUIDREGTEST_PEGGED_ASSET: &str =
+		"5ac9f65c0efcc4775e0baec4ec03abdde22473cd3cf33c0419ca290e0751b225";
+
+	#[test]
+	fn commitment() {
+		// sha256("elementsregtest" || "51" || "51")
+		assert_eq!(
+			GenesisParams::new("elementsregtest").commitment().to_string(),
+			"78fdfddeafc3bac34abe63efee0d64f7d817cee508ded08746ba4ae6df5349cb",
+		);
+	}
+
+	#[test]
+	fn pegged_asset() {
+		let params = GenesisParams::new("elementsregtest");
+		assert_eq!(params.pegged_asset().to_string(), ELEMENTSREGTEST_PEGGED_ASSET);
+		let params = GenesisParams::new("liquidregtest");
+		assert_eq!(params.pegged_asset().to_string(), LIQUIDREGTEST_PEGGED_ASSET);
+
+		// The initial issuance doesn't change the asset.
+		let mut params = GenesisParams::new("elementsregtest");
+		params.initial_free_coins = 21_000_000 * 100_000_000;
+		assert_eq!(params.pegged_asset().to_string(), ELEMENTSREGTEST_PEGGED_ASSET);
+	}
+
+	#[test]
+	fn genesis_hash_elements_style() {
+		let mut params = GenesisParams::new("elementsregtest");
+		assert_eq!(
+			params.genesis_hash().to_string(),
+			"f1c7aeeb38d19793e6e2d7d70deaf85fe97267b64459d3889b0f418742639679",
+		);
+		params.signed_blocks = false;
+		assert_eq!(
+			params.genesis_hash().to_string(),
+			"d1bcf801e913c206d9bde2d18ec940032181c851079c0c8834dd2481609d5876",
+		);
+	}
+
+	#[test]
+	fn genesis_hash_bitcoin_style() {
+		let mut params = GenesisParams::new("elementsregtest");
+		params.style = GenesisStyle::Bitcoin;
+		assert_eq!(
+			params.genesis_hash().to_string(),
+			"ecb985c56def76fffe084c1c4ae4d8311824716129c67f89aaaba506c11b4a55",
+		);
+		params.signed_blocks = false;
+		assert_eq!(
+			params.genesis_hash().to_string(),
+			"39230fa5ba97781e0365802e5439cd1123cf916ab032d519ebbba1adebe9231d",
+		);
+	}
+
+	#[test]
+	fn initial_issuance_changes_genesis_hash() {
+		let default = GenesisParams::new("elementsregtest");
+		let mut params = default.clone();
+		params.initial_free_coins = 21_000_000 * 100_000_000;
+		assert_eq!(params.genesis_transactions().len(), 2);
+		assert_ne!(params.genesis_hash(), default.genesis_hash());
+	}
+}
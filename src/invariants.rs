@@ -0,0 +1,251 @@
+//! Invariants that are checked periodically across a cluster.
+//!
+//! Instead of finding out at the final assertion of a test that something
+//! went wrong somewhere along the way, an [InvariantMonitor] evaluates a set
+//! of [Invariants] in the background and reports the first violation.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::{fmt, thread, time};
+
+use bitcoincore_rpc::RpcApi;
+
+use crate::cluster::NodeObserver;
+
+/// A predicate over all nodes that returns a message when it's violated.
+pub type Check = Box<dyn Fn(&[NodeObserver]) -> Result<(), String> + Send + Sync>;
+
+/// A violation of an invariant.
+#[derive(Debug, Clone)]
+pub struct Violation {
+	/// The name of the violated invariant.
+	pub invariant: String,
+	/// The message describing the violation.
+	pub message: String,
+	/// The status of all nodes when the violation was detected.
+	pub report: String,
+}
+
+impl fmt::Display for Violation {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "invariant \"{}\" violated: {}", self.invariant, self.message)?;
+		f.write_str(&self.report)
+	}
+}
+
+/// A set of named invariants.
+#[derive(Default)]
+pub struct Invariants {
+	checks: Vec<(String, Check)>,
+}
+
+impl Invariants {
+	pub fn new() -> Invariants {
+		Invariants::default()
+	}
+
+	/// Add an invariant with the given name.
+	pub fn add(
+		mut self,
+		name: impl Into<String>,
+		check: impl Fn(&[NodeObserver]) -> Result<(), String> + Send + Sync + 'static,
+	) -> Self {
+		self.checks.push((name.into(), Box::new(check)));
+		self
+	}
+
+	/// Add an invariant that the block heights of all nodes are at most
+	/// [max_diff] blocks apart.
+	pub fn tips_within(self, max_diff: u64) -> Self {
+		self.add(format!("all tips within {} blocks", max_diff), move |nodes| {
+			let mut heights = Vec::with_capacity(nodes.len());
+			for node in nodes {
+				let rpc = match node.rpc_client() {
+					Some(Ok(rpc)) => rpc,
+					_ => continue,
+				};
+				let height = rpc
+					.get_block_count()
+					.map_err(|e| format!("{}: failed to get block count: {}", node.name(), e))?;
+				heights.push(height);
+			}
+			let min = heights.iter().min().cloned().unwrap_or(0);
+			let max = heights.iter().max().cloned().unwrap_or(0);
+			if max - min > max_diff {
+				return Err(format!("block heights range from {} to {}", min, max));
+			}
+			Ok(())
+		})
+	}
+
+	/// Add an invariant that no node has produced error messages.
+	pub fn no_errors(self) -> Self {
+		self.add("no error messages", |nodes| {
+			for node in nodes {
+				let count = node.error_count();
+				if count > 0 {
+					return Err(format!("{} has {} error messages", node.name(), count));
+				}
+			}
+			Ok(())
+		})
+	}
+
+	/// Add an invariant that all nodes are running.
+	pub fn all_running(self) -> Self {
+		self.add("all nodes running", |nodes| {
+			for node in nodes {
+				match node.status() {
+					Ok(crate::Status::Running) => {}
+					Ok(s) => return Err(format!("{} is not running: {:?}", node.name(), s)),
+					Err(e) => {
+						return Err(format!("{}: failed to get status: {:?}", node.name(), e))
+					}
+				}
+			}
+			Ok(())
+		})
+	}
+
+	/// Evaluate all invariants once and return the first violation.
+	pub fn evaluate(&self, nodes: &[NodeObserver]) -> Result<(), Violation> {
+		for (name, check) in &self.checks {
+			if let Err(message) = check(nodes) {
+				return Err(Violation {
+					invariant: name.clone(),
+					message,
+					report: report(nodes),
+				});
+			}
+		}
+		Ok(())
+	}
+
+	/// Evaluate the invariants every [interval] in a background thread.
+	///
+	/// The monitor stops evaluating after the first violation.
+	pub fn monitor(self, nodes: Vec<NodeObserver>, interval: time::Duration) -> InvariantMonitor {
+		let violation = Arc::new(Mutex::new(None));
+		let stop = Arc::new((Mutex::new(false), Condvar::new()));
+
+		let violation_cloned = violation.clone();
+		let stop_cloned = stop.clone();
+		let thread = thread::Builder::new()
+			.name("invariant_monitor".into())
+			.spawn(move || {
+				let (ref stopped, ref wakeup) = *stop_cloned;
+				let mut next = time::Instant::now();
+				loop {
+					// Wait until the next evaluation is due, unless stopped.
+					let mut lock = stopped.lock().unwrap();
+					loop {
+						if *lock {
+							return;
+						}
+						let now = time::Instant::now();
+						if next <= now {
+							break;
+						}
+						lock = wakeup.wait_timeout(lock, next - now).unwrap().0;
+					}
+					drop(lock);
+
+					if let Err(v) = self.evaluate(&nodes) {
+						error!("{}", v);
+						*violation_cloned.lock().unwrap() = Some(v);
+						return;
+					}
+					next = time::Instant::now() + interval;
+				}
+			})
+			.expect("failed to start invariant monitor thread");
+
+		InvariantMonitor {
+			violation,
+			stop,
+			thread: Some(thread),
+		}
+	}
+}
+
+/// Create a short report of the status of all nodes.
+fn report(nodes: &[NodeObserver]) -> String {
+	let mut ret = String::new();
+	for node in nodes {
+		let status = match node.status() {
+			Ok(s) => format!("{:?}", s),
+			Err(e) => format!("unknown ({:?})", e),
+		};
+		ret.push_str(&format!("{}: {}, {} errors\n", node.name(), status, node.error_count()));
+	}
+	ret
+}
+
+/// Evaluates invariants in the background. See [Invariants::monitor].
+///
+/// The background thread is stopped when the monitor is dropped.
+pub struct InvariantMonitor {
+	violation: Arc<Mutex<Option<Violation>>>,
+	/// Whether the monitor is stopped, notified when it is.
+	stop: Arc<(Mutex<bool>, Condvar)>,
+	thread: Option<thread::JoinHandle<()>>,
+}
+
+impl InvariantMonitor {
+	/// The violation that was detected, if any.
+	pub fn violation(&self) -> Option<Violation> {
+		self.violation.lock().unwrap().clone()
+	}
+
+	/// Fail if a violation was detected.
+	pub fn check(&self) -> Result<(), Violation> {
+		match self.violation() {
+			Some(v) => Err(v),
+			None => Ok(()),
+		}
+	}
+
+	/// Stop the monitor and fail if a violation was detected.
+	pub fn stop(mut self) -> Result<(), Violation> {
+		self.stop_thread();
+		self.check()
+	}
+
+	fn stop_thread(&mut self) {
+		*self.stop.0.lock().unwrap() = true;
+		self.stop.1.notify_all();
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+impl Drop for InvariantMonitor {
+	fn drop(&mut self) {
+		self.stop_thread();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn monitor_stops_promptly() {
+		let monitor = Invariants::new().monitor(Vec::new(), time::Duration::from_secs(3600));
+		thread::sleep(time::Duration::from_millis(50));
+		let start = time::Instant::now();
+		assert!(monitor.stop().is_ok());
+		assert!(start.elapsed() < time::Duration::from_secs(1));
+
+		let invariants = Invariants::new().add("never", |_| Err("violated".into()));
+		let monitor = invariants.monitor(Vec::new(), time::Duration::from_secs(3600));
+		let start = time::Instant::now();
+		while monitor.violation().is_none() {
+			assert!(start.elapsed() < time::Duration::from_secs(5));
+			thread::sleep(time::Duration::from_millis(10));
+		}
+		let violation = monitor.stop().unwrap_err();
+		assert_eq!(violation.invariant, "never");
+		assert_eq!(violation.message, "violated");
+	}
+}
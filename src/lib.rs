@@ -4,20 +4,53 @@ pub extern crate bitcoin;
 pub extern crate bitcoincore_rpc;
 pub extern crate liquid_rpc;
 
+extern crate base64;
+#[cfg(unix)]
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate rand;
 extern crate regex;
 #[macro_use]
 extern crate serde;
+extern crate serde_json;
+extern crate toml;
 #[macro_use]
 extern crate lazy_static;
+#[cfg(feature = "async")]
+extern crate tokio;
 
+pub mod asset_registry;
+#[cfg(feature = "async")]
+pub mod async_runner;
 pub mod bitcoind;
+pub mod chain_params;
+pub mod cluster;
+pub mod electrs;
 pub mod elementsd;
 mod error;
+pub mod esplora;
+pub mod federation;
+pub mod genesis;
+pub mod invariants;
+#[cfg(unix)]
+pub mod lightningd;
+pub mod lnd;
+pub mod logs;
+pub mod matrix;
+pub mod miner;
+pub mod observer;
+pub mod peg;
 pub mod runner;
+#[cfg(unix)]
+pub mod signer;
+pub mod template;
+pub mod tor;
 pub mod utils;
+pub mod utxo;
 
-pub use error::Error;
-pub use runner::{DaemonRunner, Status};
+pub use crate::error::Error;
+pub use crate::runner::{
+	CommonDaemon, DaemonRunner, ExitKind, RestartPolicy, StartupStage, StartupTiming, Status,
+};
+pub use crate::utils::ConfigFile;
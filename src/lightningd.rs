@@ -0,0 +1,492 @@
+//! Running Core Lightning daemons.
+//!
+//! The RPC interface of lightningd is only available over a unix socket,
+//! so this module is only available on unix platforms.
+
+use std::fs::File;
+use std::io::Write as _;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{fmt, fs, io, process, time};
+
+use regex::Regex;
+
+use crate::bitcoind;
+use crate::error::Error;
+use crate::logs;
+use crate::runner::{
+	self, Common, CommonDaemon, DaemonRunner, RunnerConfig, RunnerHelper, RuntimeData, StartupStage,
+};
+use crate::utils;
+
+pub const CONFIG_FILENAME: &str = "config";
+
+/// The name of the RPC socket in the network directory.
+pub const RPC_SOCKET_FILENAME: &str = "lightning-rpc";
+
+/// The environment variable that can be used to point to the lightningd executable.
+pub const EXECUTABLE_ENV_VAR: &str = "LIGHTNINGD_EXE";
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+	/// The directory lightningd keeps all its data in.
+	pub lightning_dir: PathBuf,
+	pub network: Option<bitcoin::Network>,
+	pub alias: Option<String>,
+	pub log_level: Option<String>,
+
+	/// The address to listen on for peers.
+	/// Defaults to 127.0.0.1 when a [port] is set.
+	pub bind_addr: Option<String>,
+	/// The port to listen on for peers.
+	/// When not set, a free port is allocated when the daemon is started.
+	pub port: Option<u16>,
+	/// The address to announce to peers.
+	pub announce_addr: Option<String>,
+
+	// Connection to bitcoind:
+	pub bitcoin_cli: Option<PathBuf>,
+	pub bitcoin_datadir: Option<PathBuf>,
+	pub bitcoin_rpcconnect: Option<String>,
+	pub bitcoin_rpcport: Option<u16>,
+	pub bitcoin_rpcuser: Option<String>,
+	pub bitcoin_rpcpassword: Option<String>,
+
+	/// The options that are only used by the runner.
+	#[serde(flatten)]
+	pub runner: RunnerConfig,
+}
+
+/// The name lightningd uses for the network.
+fn network_name(network: bitcoin::Network) -> &'static str {
+	match network {
+		bitcoin::Network::Bitcoin => "bitcoin",
+		bitcoin::Network::Testnet => "testnet",
+		bitcoin::Network::Regtest => "regtest",
+	}
+}
+
+impl Config {
+	pub fn write_into<W: io::Write>(&self, mut w: W) -> Result<(), io::Error> {
+		if let Some(n) = self.network {
+			writeln!(w, "network={}", network_name(n))?;
+		}
+		if let Some(ref v) = self.alias {
+			writeln!(w, "alias={}", v)?;
+		}
+		if let Some(ref v) = self.log_level {
+			writeln!(w, "log-level={}", v)?;
+		}
+
+		if let Some(p) = self.port {
+			let addr = self.bind_addr.as_deref().unwrap_or("127.0.0.1");
+			writeln!(w, "bind-addr={}:{}", addr, p)?;
+		} else if let Some(ref v) = self.bind_addr {
+			writeln!(w, "bind-addr={}", v)?;
+		}
+		if let Some(ref v) = self.announce_addr {
+			writeln!(w, "announce-addr={}", v)?;
+		}
+
+		if let Some(ref v) = self.bitcoin_cli {
+			writeln!(w, "bitcoin-cli={}", utils::config_path(v))?;
+		}
+		if let Some(ref v) = self.bitcoin_datadir {
+			writeln!(w, "bitcoin-datadir={}", utils::config_path(v))?;
+		}
+		if let Some(ref v) = self.bitcoin_rpcconnect {
+			writeln!(w, "bitcoin-rpcconnect={}", v)?;
+		}
+		if let Some(v) = self.bitcoin_rpcport {
+			writeln!(w, "bitcoin-rpcport={}", v)?;
+		}
+		if let Some(ref v) = self.bitcoin_rpcuser {
+			writeln!(w, "bitcoin-rpcuser={}", v)?;
+		}
+		if let Some(ref v) = self.bitcoin_rpcpassword {
+			writeln!(w, "bitcoin-rpcpassword={}", v)?;
+		}
+		Ok(())
+	}
+}
+
+impl utils::ConfigFile for Config {}
+
+/// A builder for [Config].
+///
+/// Use [ConfigBuilder::regtest] to start from sensible regtest defaults.
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+	config: Config,
+}
+
+impl ConfigBuilder {
+	/// Create a builder for a config without any options set.
+	pub fn new<P: Into<PathBuf>>(lightning_dir: P) -> ConfigBuilder {
+		ConfigBuilder {
+			config: Config {
+				lightning_dir: lightning_dir.into(),
+				..Default::default()
+			},
+		}
+	}
+
+	/// Create a builder with defaults for a regtest node connected to the given bitcoind.
+	pub fn regtest<P: Into<PathBuf>>(
+		lightning_dir: P,
+		bitcoind: &bitcoind::Daemon,
+	) -> ConfigBuilder {
+		ConfigBuilder::new(lightning_dir)
+			.network(bitcoin::Network::Regtest)
+			.port(utils::find_free_port())
+			.bitcoind(bitcoind)
+	}
+
+	pub fn network(mut self, network: bitcoin::Network) -> Self {
+		self.config.network = Some(network);
+		self
+	}
+
+	pub fn alias(mut self, v: impl Into<String>) -> Self {
+		self.config.alias = Some(v.into());
+		self
+	}
+
+	pub fn log_level(mut self, v: impl Into<String>) -> Self {
+		self.config.log_level = Some(v.into());
+		self
+	}
+
+	pub fn bind_addr(mut self, v: impl Into<String>) -> Self {
+		self.config.bind_addr = Some(v.into());
+		self
+	}
+
+	pub fn port(mut self, v: u16) -> Self {
+		self.config.port = Some(v);
+		self
+	}
+
+	pub fn announce_addr(mut self, v: impl Into<String>) -> Self {
+		self.config.announce_addr = Some(v.into());
+		self
+	}
+
+	pub fn bitcoin_cli(mut self, v: impl Into<PathBuf>) -> Self {
+		self.config.bitcoin_cli = Some(v.into());
+		self
+	}
+
+	pub fn bitcoin_rpc(
+		mut self,
+		host: impl Into<String>,
+		port: u16,
+		user: impl Into<String>,
+		pass: impl Into<String>,
+	) -> Self {
+		self.config.bitcoin_rpcconnect = Some(host.into());
+		self.config.bitcoin_rpcport = Some(port);
+		self.config.bitcoin_rpcuser = Some(user.into());
+		self.config.bitcoin_rpcpassword = Some(pass.into());
+		self
+	}
+
+	/// Use the given local bitcoind as chain backend.
+	///
+	/// The datadir of the bitcoind is used so that `bitcoin-cli` picks up
+	/// its config, which makes cookie authentication work as well.
+	pub fn bitcoind(mut self, bitcoind: &bitcoind::Daemon) -> Self {
+		let c = bitcoind.config();
+		self.config.bitcoin_datadir = Some(c.datadir.clone());
+		self.config.bitcoin_rpcconnect = Some("127.0.0.1".into());
+		self.config.bitcoin_rpcport = c.rpcport;
+		self.config.bitcoin_rpcuser = c.rpcuser.clone();
+		self.config.bitcoin_rpcpassword = c.rpcpass.clone();
+		self
+	}
+
+	/// See [RunnerConfig::block_on_drop].
+	pub fn block_on_drop(mut self, v: bool) -> Self {
+		self.config.runner.block_on_drop = v;
+		self
+	}
+
+	/// Validate the options and build the config.
+	pub fn build(self) -> Result<Config, Error> {
+		let c = self.config;
+		if !c.lightning_dir.is_absolute() {
+			return Err(Error::Config("lightning_dir should be an absolute path"));
+		}
+		if c.bitcoin_rpcuser.is_some() != c.bitcoin_rpcpassword.is_some() {
+			return Err(Error::Config(
+				"bitcoin_rpcuser and bitcoin_rpcpassword should be set together",
+			));
+		}
+		Ok(c)
+	}
+}
+
+/// A client for the JSON-RPC interface of lightningd over its unix socket.
+#[derive(Debug)]
+pub struct RpcClient {
+	path: PathBuf,
+	next_id: AtomicUsize,
+}
+
+impl RpcClient {
+	/// Create a client for the RPC socket at the given path.
+	pub fn new<P: Into<PathBuf>>(path: P) -> RpcClient {
+		RpcClient {
+			path: path.into(),
+			next_id: AtomicUsize::new(0),
+		}
+	}
+
+	/// The path of the RPC socket.
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Call the given method with the given parameters.
+	///
+	/// The parameters should be either an array or an object.
+	pub fn call<T: serde::de::DeserializeOwned>(
+		&self,
+		method: &str,
+		params: serde_json::Value,
+	) -> Result<T, Error> {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		let request = serde_json::json!({
+			"jsonrpc": "2.0",
+			"id": id,
+			"method": method,
+			"params": params,
+		});
+
+		let mut stream = UnixStream::connect(&self.path)?;
+		serde_json::to_writer(&mut stream, &request).map_err(io::Error::from)?;
+		stream.flush()?;
+
+		let mut responses = serde_json::Deserializer::from_reader(&stream).into_iter();
+		let mut response: serde_json::Value = match responses.next() {
+			Some(r) => r.map_err(io::Error::from)?,
+			None => return Err(Error::Custom("no response from lightningd")),
+		};
+		if let Some(e) = response.get("error") {
+			let code = e["code"].as_i64().unwrap_or(0);
+			let message = e["message"].as_str().unwrap_or_default().to_owned();
+			return Err(Error::JsonRpc(code, message));
+		}
+		Ok(serde_json::from_value(response["result"].take()).map_err(io::Error::from)?)
+	}
+}
+
+#[derive(Default)]
+pub struct State {
+	/// The public key of the node, once the daemon logged it.
+	pub node_id: Option<String>,
+
+	/// The captured output of the daemon.
+	pub output: logs::Output,
+}
+
+impl AsRef<logs::Output> for State {
+	fn as_ref(&self) -> &logs::Output {
+		&self.output
+	}
+}
+
+impl AsMut<logs::Output> for State {
+	fn as_mut(&mut self) -> &mut logs::Output {
+		&mut self.output
+	}
+}
+
+pub struct Daemon {
+	common: Common<State>,
+	executable: PathBuf,
+	config: Config,
+
+	/// The path of the written config file.
+	/// [None] before it has been written.
+	config_file: Option<PathBuf>,
+}
+
+/// Parse the node public key from the line lightningd logs when it's ready.
+pub fn parse_server_started(msg: &str) -> Option<String> {
+	lazy_static! {
+		/// The regular expression for the server started message.
+		static ref SERVER_STARTED_REGEX: Regex =
+			Regex::new(r"Server started with public key ([0-9a-f]{66})").unwrap();
+	}
+
+	SERVER_STARTED_REGEX.captures(msg).map(|c| c[1].to_owned())
+}
+
+impl Daemon {
+	pub fn new<P: Into<PathBuf>>(executable: P, config: Config) -> Result<Daemon, Error> {
+		if !config.lightning_dir.is_absolute() {
+			return Err(Error::Config("lightning_dir should be an absolute path"));
+		}
+
+		Ok(Daemon {
+			common: Common::new(&config.runner),
+			executable: executable.into(),
+			config,
+
+			config_file: None,
+		})
+	}
+
+	/// Wait until the daemon is ready to handle RPC requests.
+	///
+	/// The daemon is ready once it logged its public key and the RPC
+	/// socket answers. This fails when the daemon exits or when it isn't
+	/// ready after [timeout].
+	pub fn wait_for_ready(&self, timeout: time::Duration) -> Result<(), Error> {
+		runner::wait_until(self, "daemon to become ready", timeout, || {
+			self.node_id().is_some()
+				&& self
+					.rpc_client()
+					.call::<serde_json::Value>("getinfo", serde_json::json!([]))
+					.is_ok()
+		})?;
+		self.record_startup(StartupStage::Ready);
+		Ok(())
+	}
+
+	pub fn config(&self) -> &Config {
+		&self.config
+	}
+
+	pub fn lightning_dir(&self) -> &Path {
+		self.config.lightning_dir.as_path()
+	}
+
+	/// The directory where the daemon stores network-specific data.
+	pub fn network_dir(&self) -> PathBuf {
+		let network = self.config.network.unwrap_or(bitcoin::Network::Bitcoin);
+		self.config.lightning_dir.join(network_name(network))
+	}
+
+	/// The path of the RPC socket.
+	pub fn rpc_socket_path(&self) -> PathBuf {
+		self.network_dir().join(RPC_SOCKET_FILENAME)
+	}
+
+	/// Get an RPC client.
+	pub fn rpc_client(&self) -> RpcClient {
+		RpcClient::new(self.rpc_socket_path())
+	}
+
+	/// The public key of the node, once the daemon logged it.
+	pub fn node_id(&self) -> Option<String> {
+		self.with_state(|s| s.node_id.clone()).flatten()
+	}
+}
+
+impl RunnerHelper for Daemon {
+	type State = State;
+
+	fn _prepare(&mut self) -> Result<(), Error> {
+		if self.config_file.is_some() {
+			return Ok(());
+		}
+
+		// Make sure the lightning dir exists.
+		fs::create_dir_all(&self.config.lightning_dir)?;
+
+		if self.config.port.is_none() {
+			self.config.port = Some(utils::find_free_port());
+		}
+
+		// Write the config file once and store the path.
+		let path = self.config.lightning_dir.join(CONFIG_FILENAME);
+		let mut file = File::create(&path)?;
+		self.config.write_into(&mut file)?;
+		self.config_file = Some(path);
+		Ok(())
+	}
+
+	fn _reset(&mut self) -> Result<(), Error> {
+		if self.config.lightning_dir.exists() {
+			fs::remove_dir_all(&self.config.lightning_dir)?;
+		}
+		self.config_file = None;
+		Ok(())
+	}
+
+	fn _command(&self) -> process::Command {
+		let mut cmd = process::Command::new(self.executable.clone());
+		cmd.arg(format!("--lightning-dir={}", self.config.lightning_dir.display()));
+		cmd.arg(format!("--conf={}", self.config_file.as_ref().unwrap().display()));
+		cmd
+	}
+
+	fn _init_state(&mut self) -> Self::State {
+		State {
+			node_id: None,
+			output: self.common.new_output(&self.config.runner),
+		}
+	}
+
+	fn _notif_started(&mut self, runtime_data: Arc<Mutex<RuntimeData<Self::State>>>) {
+		self.common.runtime_data.replace(runtime_data);
+	}
+
+	fn _get_runtime(&self) -> Option<Arc<Mutex<RuntimeData<Self::State>>>> {
+		self.common.runtime_data.clone()
+	}
+
+	fn _process_stdout(name: &str, state: &mut Self::State, line: &str) {
+		if let Some(node_id) = parse_server_started(line) {
+			debug!("{}: started with node id {}", name, node_id);
+			state.node_id = Some(node_id);
+		}
+
+		lazy_static! {
+			/// Regular expression to match for error messages.
+			static ref ERROR_REGEX: Regex = Regex::new(r"\bBROKEN\b|(?i)ERROR").unwrap();
+			/// Regular expression to match for warning messages.
+			static ref WARNING_REGEX: Regex = Regex::new(r"\bUNUSUAL\b|(?i)WARNING").unwrap();
+		}
+		state.output.process_line(line, &ERROR_REGEX, &WARNING_REGEX);
+	}
+
+	fn _process_stderr(state: &mut Self::State, line: &str) {
+		state.output.stderr.push(line.to_owned());
+	}
+}
+
+impl DaemonRunner for Daemon {}
+
+impl CommonDaemon for Daemon {
+	fn _common(&self) -> &Common<State> {
+		&self.common
+	}
+
+	fn _common_mut(&mut self) -> &mut Common<State> {
+		&mut self.common
+	}
+}
+
+impl fmt::Debug for Daemon {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		self.common.fmt_header(f, "lightningd")?;
+		if f.alternate() {
+			let c = &self.config;
+			utils::fmt_details(
+				f,
+				&[
+					("network", c.network.map(network_name).map(str::to_owned)),
+					("lightning_dir", Some(c.lightning_dir.display().to_string())),
+					("port", c.port.map(|p| p.to_string())),
+				],
+			)?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,537 @@
+//! Running lnd daemons.
+//!
+//! Only the bitcoind chain backend is supported. lnd is controlled over
+//! gRPC, for which no client is provided. Instead, the paths of the TLS
+//! certificate and the admin macaroon are available so that tests can
+//! construct a client with the gRPC library of their choice.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{fmt, fs, io, process, time};
+
+use regex::Regex;
+
+use crate::bitcoind;
+use crate::error::Error;
+use crate::logs;
+use crate::runner::{
+	self, Common, CommonDaemon, DaemonRunner, RunnerConfig, RunnerHelper, RuntimeData, StartupStage,
+};
+use crate::utils;
+
+pub const CONFIG_FILENAME: &str = "lnd.conf";
+
+/// The name of the TLS certificate in the lnd dir.
+pub const TLS_CERT_FILENAME: &str = "tls.cert";
+
+/// The name of the admin macaroon in the network directory.
+pub const ADMIN_MACAROON_FILENAME: &str = "admin.macaroon";
+
+/// The environment variable that can be used to point to the lnd executable.
+pub const EXECUTABLE_ENV_VAR: &str = "LND_EXE";
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+	/// The directory lnd keeps all its data in.
+	pub lnd_dir: PathBuf,
+	pub network: Option<bitcoin::Network>,
+	pub alias: Option<String>,
+	pub debuglevel: Option<String>,
+
+	/// The port to listen on for peers.
+	/// When not set, a free port is allocated when the daemon is started.
+	pub port: Option<u16>,
+	/// The port of the gRPC interface.
+	/// When not set, a free port is allocated when the daemon is started.
+	pub rpcport: Option<u16>,
+	/// The port of the REST interface.
+	/// When not set, a free port is allocated when the daemon is started.
+	pub restport: Option<u16>,
+
+	/// Disable macaroon authentication of the RPC interfaces.
+	pub no_macaroons: bool,
+	/// Create a wallet without seed backup on startup, so that
+	/// no wallet has to be created or unlocked over RPC.
+	pub noseedbackup: bool,
+
+	// Connection to bitcoind:
+	/// The host and port of the RPC interface, f.e. `127.0.0.1:18443`.
+	pub bitcoind_rpchost: Option<String>,
+	pub bitcoind_rpcuser: Option<String>,
+	pub bitcoind_rpcpass: Option<String>,
+	pub bitcoind_rpccookie: Option<PathBuf>,
+	pub bitcoind_zmqpubrawblock: Option<String>,
+	pub bitcoind_zmqpubrawtx: Option<String>,
+	/// Poll bitcoind over RPC instead of using ZMQ notifications.
+	pub bitcoind_rpcpolling: bool,
+
+	/// The options that are only used by the runner.
+	#[serde(flatten)]
+	pub runner: RunnerConfig,
+}
+
+/// The name lnd uses for the network.
+fn network_name(network: bitcoin::Network) -> &'static str {
+	match network {
+		bitcoin::Network::Bitcoin => "mainnet",
+		bitcoin::Network::Testnet => "testnet",
+		bitcoin::Network::Regtest => "regtest",
+	}
+}
+
+impl Config {
+	pub fn write_into<W: io::Write>(&self, mut w: W) -> Result<(), io::Error> {
+		writeln!(w, "[Application Options]")?;
+		if let Some(ref v) = self.alias {
+			writeln!(w, "alias={}", v)?;
+		}
+		if let Some(ref v) = self.debuglevel {
+			writeln!(w, "debuglevel={}", v)?;
+		}
+		if let Some(p) = self.port {
+			writeln!(w, "listen=127.0.0.1:{}", p)?;
+		}
+		if let Some(p) = self.rpcport {
+			writeln!(w, "rpclisten=127.0.0.1:{}", p)?;
+		}
+		if let Some(p) = self.restport {
+			writeln!(w, "restlisten=127.0.0.1:{}", p)?;
+		}
+		if self.no_macaroons {
+			writeln!(w, "no-macaroons=1")?;
+		}
+		if self.noseedbackup {
+			writeln!(w, "noseedbackup=1")?;
+		}
+
+		writeln!(w, "\n[Bitcoin]")?;
+		writeln!(w, "bitcoin.active=1")?;
+		writeln!(w, "bitcoin.node=bitcoind")?;
+		if let Some(n) = self.network {
+			writeln!(w, "bitcoin.{}=1", network_name(n))?;
+		}
+
+		writeln!(w, "\n[Bitcoind]")?;
+		if let Some(ref v) = self.bitcoind_rpchost {
+			writeln!(w, "bitcoind.rpchost={}", v)?;
+		}
+		if let Some(ref v) = self.bitcoind_rpcuser {
+			writeln!(w, "bitcoind.rpcuser={}", v)?;
+		}
+		if let Some(ref v) = self.bitcoind_rpcpass {
+			writeln!(w, "bitcoind.rpcpass={}", v)?;
+		}
+		if let Some(ref v) = self.bitcoind_rpccookie {
+			writeln!(w, "bitcoind.rpccookie={}", utils::config_path(v))?;
+		}
+		if let Some(ref v) = self.bitcoind_zmqpubrawblock {
+			writeln!(w, "bitcoind.zmqpubrawblock={}", v)?;
+		}
+		if let Some(ref v) = self.bitcoind_zmqpubrawtx {
+			writeln!(w, "bitcoind.zmqpubrawtx={}", v)?;
+		}
+		if self.bitcoind_rpcpolling {
+			writeln!(w, "bitcoind.rpcpolling=1")?;
+		}
+		Ok(())
+	}
+}
+
+impl utils::ConfigFile for Config {}
+
+/// A builder for [Config].
+///
+/// Use [ConfigBuilder::regtest] to start from sensible regtest defaults.
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+	config: Config,
+}
+
+impl ConfigBuilder {
+	/// Create a builder for a config without any options set.
+	pub fn new<P: Into<PathBuf>>(lnd_dir: P) -> ConfigBuilder {
+		ConfigBuilder {
+			config: Config {
+				lnd_dir: lnd_dir.into(),
+				..Default::default()
+			},
+		}
+	}
+
+	/// Create a builder with defaults for a regtest node connected to the given bitcoind.
+	///
+	/// The wallet is created without seed backup, so the node is usable
+	/// right after startup.
+	pub fn regtest<P: Into<PathBuf>>(lnd_dir: P, bitcoind: &bitcoind::Daemon) -> ConfigBuilder {
+		ConfigBuilder::new(lnd_dir)
+			.network(bitcoin::Network::Regtest)
+			.port(utils::find_free_port())
+			.rpcport(utils::find_free_port())
+			.restport(utils::find_free_port())
+			.noseedbackup(true)
+			.bitcoind(bitcoind)
+	}
+
+	pub fn network(mut self, network: bitcoin::Network) -> Self {
+		self.config.network = Some(network);
+		self
+	}
+
+	pub fn alias(mut self, v: impl Into<String>) -> Self {
+		self.config.alias = Some(v.into());
+		self
+	}
+
+	pub fn debuglevel(mut self, v: impl Into<String>) -> Self {
+		self.config.debuglevel = Some(v.into());
+		self
+	}
+
+	pub fn port(mut self, v: u16) -> Self {
+		self.config.port = Some(v);
+		self
+	}
+
+	pub fn rpcport(mut self, v: u16) -> Self {
+		self.config.rpcport = Some(v);
+		self
+	}
+
+	pub fn restport(mut self, v: u16) -> Self {
+		self.config.restport = Some(v);
+		self
+	}
+
+	pub fn no_macaroons(mut self, v: bool) -> Self {
+		self.config.no_macaroons = v;
+		self
+	}
+
+	pub fn noseedbackup(mut self, v: bool) -> Self {
+		self.config.noseedbackup = v;
+		self
+	}
+
+	/// Use user and password authentication for the bitcoind RPC.
+	pub fn bitcoind_rpc(
+		mut self,
+		host: impl Into<String>,
+		user: impl Into<String>,
+		pass: impl Into<String>,
+	) -> Self {
+		self.config.bitcoind_rpchost = Some(host.into());
+		self.config.bitcoind_rpcuser = Some(user.into());
+		self.config.bitcoind_rpcpass = Some(pass.into());
+		self.config.bitcoind_rpccookie = None;
+		self
+	}
+
+	/// Use the given local bitcoind as chain backend.
+	///
+	/// Block and transaction notifications are received over ZMQ if the
+	/// bitcoind publishes them, otherwise bitcoind is polled.
+	pub fn bitcoind(mut self, bitcoind: &bitcoind::Daemon) -> Self {
+		let c = bitcoind.config();
+		self.config.bitcoind_rpchost = c.rpcport.map(|p| format!("127.0.0.1:{}", p));
+		self.config.bitcoind_rpcuser = c.rpcuser.clone();
+		self.config.bitcoind_rpcpass = c.rpcpass.clone();
+		self.config.bitcoind_rpccookie = c.rpccookie.as_ref().map(PathBuf::from);
+		if c.zmqpubrawblock.is_some() && c.zmqpubrawtx.is_some() {
+			self.config.bitcoind_zmqpubrawblock = c.zmqpubrawblock.clone();
+			self.config.bitcoind_zmqpubrawtx = c.zmqpubrawtx.clone();
+			self.config.bitcoind_rpcpolling = false;
+		} else {
+			self.config.bitcoind_zmqpubrawblock = None;
+			self.config.bitcoind_zmqpubrawtx = None;
+			self.config.bitcoind_rpcpolling = true;
+		}
+		self
+	}
+
+	/// See [RunnerConfig::block_on_drop].
+	pub fn block_on_drop(mut self, v: bool) -> Self {
+		self.config.runner.block_on_drop = v;
+		self
+	}
+
+	/// Validate the options and build the config.
+	pub fn build(self) -> Result<Config, Error> {
+		let c = self.config;
+		if !c.lnd_dir.is_absolute() {
+			return Err(Error::Config("lnd_dir should be an absolute path"));
+		}
+		if c.bitcoind_rpcuser.is_some() != c.bitcoind_rpcpass.is_some() {
+			return Err(Error::Config(
+				"bitcoind_rpcuser and bitcoind_rpcpass should be set together",
+			));
+		}
+		if c.bitcoind_rpcpolling
+			&& (c.bitcoind_zmqpubrawblock.is_some() || c.bitcoind_zmqpubrawtx.is_some())
+		{
+			return Err(Error::Config("bitcoind_rpcpolling can't be combined with ZMQ"));
+		}
+		Ok(c)
+	}
+}
+
+#[derive(Default)]
+pub struct State {
+	/// Whether the daemon logged that its RPC server is up.
+	pub rpc_ready: bool,
+
+	/// Whether the daemon logged that it's listening for peers.
+	/// This only happens after the wallet has been unlocked.
+	pub server_ready: bool,
+
+	/// The captured output of the daemon.
+	pub output: logs::Output,
+}
+
+impl AsRef<logs::Output> for State {
+	fn as_ref(&self) -> &logs::Output {
+		&self.output
+	}
+}
+
+impl AsMut<logs::Output> for State {
+	fn as_mut(&mut self) -> &mut logs::Output {
+		&mut self.output
+	}
+}
+
+pub struct Daemon {
+	common: Common<State>,
+	executable: PathBuf,
+	config: Config,
+
+	/// The path of the written config file.
+	/// [None] before it has been written.
+	config_file: Option<PathBuf>,
+}
+
+/// Check if the line is the one lnd logs when its gRPC server is up.
+pub fn parse_rpc_server_started(msg: &str) -> bool {
+	lazy_static! {
+		/// The regular expression for the RPC server started message.
+		static ref RPC_STARTED_REGEX: Regex =
+			Regex::new(r"RPC server listening on|gRPC proxy started at").unwrap();
+	}
+
+	RPC_STARTED_REGEX.is_match(msg)
+}
+
+/// Check if the line is the one lnd logs when it's accepting peers.
+pub fn parse_server_started(msg: &str) -> bool {
+	lazy_static! {
+		/// The regular expression for the server started message.
+		static ref SERVER_STARTED_REGEX: Regex =
+			Regex::new(r"\bServer listening on").unwrap();
+	}
+
+	SERVER_STARTED_REGEX.is_match(msg)
+}
+
+impl Daemon {
+	pub fn new<P: Into<PathBuf>>(executable: P, config: Config) -> Result<Daemon, Error> {
+		if !config.lnd_dir.is_absolute() {
+			return Err(Error::Config("lnd_dir should be an absolute path"));
+		}
+
+		Ok(Daemon {
+			common: Common::new(&config.runner),
+			executable: executable.into(),
+			config,
+
+			config_file: None,
+		})
+	}
+
+	/// Wait until the daemon is ready to handle RPC requests.
+	///
+	/// With [Config::noseedbackup], the daemon is ready once it's listening
+	/// for peers, which means the wallet is unlocked. Otherwise, it's ready
+	/// once the RPC server is up so that a wallet can be created.
+	/// In both cases the TLS certificate, and the admin macaroon when
+	/// macaroons are enabled, should have been written.
+	///
+	/// This fails when the daemon exits or when it isn't ready after [timeout].
+	pub fn wait_for_ready(&self, timeout: time::Duration) -> Result<(), Error> {
+		runner::wait_until(self, "daemon to become ready", timeout, || {
+			let logged_ready = self
+				.with_state(|s| {
+					if self.config.noseedbackup {
+						s.server_ready
+					} else {
+						s.rpc_ready
+					}
+				})
+				.unwrap_or(false);
+			let macaroon_ready = self.config.no_macaroons
+				|| !self.config.noseedbackup
+				|| self.admin_macaroon_path().exists();
+			logged_ready && self.tls_cert_path().exists() && macaroon_ready
+		})?;
+		self.record_startup(StartupStage::Ready);
+		Ok(())
+	}
+
+	pub fn config(&self) -> &Config {
+		&self.config
+	}
+
+	pub fn lnd_dir(&self) -> &Path {
+		self.config.lnd_dir.as_path()
+	}
+
+	/// The directory where the daemon stores the wallet and macaroons.
+	pub fn network_dir(&self) -> PathBuf {
+		let network = self.config.network.unwrap_or(bitcoin::Network::Bitcoin);
+		self.config.lnd_dir.join("data/chain/bitcoin").join(network_name(network))
+	}
+
+	/// The path of the TLS certificate of the RPC interfaces.
+	pub fn tls_cert_path(&self) -> PathBuf {
+		self.config.lnd_dir.join(TLS_CERT_FILENAME)
+	}
+
+	/// The path of the admin macaroon.
+	///
+	/// The macaroon is only written when macaroons are enabled and
+	/// after the wallet has been created.
+	pub fn admin_macaroon_path(&self) -> PathBuf {
+		self.network_dir().join(ADMIN_MACAROON_FILENAME)
+	}
+
+	/// The address of the gRPC interface, f.e. `127.0.0.1:10009`.
+	pub fn rpc_address(&self) -> Option<String> {
+		self.config.rpcport.map(|p| format!("127.0.0.1:{}", p))
+	}
+
+	/// The address of the REST interface, f.e. `127.0.0.1:8080`.
+	pub fn rest_address(&self) -> Option<String> {
+		self.config.restport.map(|p| format!("127.0.0.1:{}", p))
+	}
+
+	/// The address to connect to as a peer.
+	pub fn p2p_address(&self) -> Option<String> {
+		self.config.port.map(|p| format!("127.0.0.1:{}", p))
+	}
+}
+
+impl RunnerHelper for Daemon {
+	type State = State;
+
+	fn _prepare(&mut self) -> Result<(), Error> {
+		if self.config_file.is_some() {
+			return Ok(());
+		}
+
+		// Make sure the lnd dir exists.
+		fs::create_dir_all(&self.config.lnd_dir)?;
+
+		if self.config.port.is_none() {
+			self.config.port = Some(utils::find_free_port());
+		}
+		if self.config.rpcport.is_none() {
+			self.config.rpcport = Some(utils::find_free_port());
+		}
+		if self.config.restport.is_none() {
+			self.config.restport = Some(utils::find_free_port());
+		}
+
+		// Write the config file once and store the path.
+		let path = self.config.lnd_dir.join(CONFIG_FILENAME);
+		let mut file = File::create(&path)?;
+		self.config.write_into(&mut file)?;
+		self.config_file = Some(path);
+		Ok(())
+	}
+
+	fn _reset(&mut self) -> Result<(), Error> {
+		if self.config.lnd_dir.exists() {
+			fs::remove_dir_all(&self.config.lnd_dir)?;
+		}
+		self.config_file = None;
+		Ok(())
+	}
+
+	fn _command(&self) -> process::Command {
+		let mut cmd = process::Command::new(self.executable.clone());
+		cmd.arg(format!("--lnddir={}", self.config.lnd_dir.display()));
+		cmd.arg(format!("--configfile={}", self.config_file.as_ref().unwrap().display()));
+		cmd
+	}
+
+	fn _init_state(&mut self) -> Self::State {
+		State {
+			rpc_ready: false,
+			server_ready: false,
+			output: self.common.new_output(&self.config.runner),
+		}
+	}
+
+	fn _notif_started(&mut self, runtime_data: Arc<Mutex<RuntimeData<Self::State>>>) {
+		self.common.runtime_data.replace(runtime_data);
+	}
+
+	fn _get_runtime(&self) -> Option<Arc<Mutex<RuntimeData<Self::State>>>> {
+		self.common.runtime_data.clone()
+	}
+
+	fn _process_stdout(name: &str, state: &mut Self::State, line: &str) {
+		if parse_rpc_server_started(line) {
+			debug!("{}: RPC server started", name);
+			state.rpc_ready = true;
+		}
+		if parse_server_started(line) {
+			debug!("{}: server started", name);
+			state.server_ready = true;
+		}
+
+		lazy_static! {
+			/// Regular expression to match for error messages.
+			static ref ERROR_REGEX: Regex = Regex::new(r"\[(?:ERR|CRT)\]").unwrap();
+			/// Regular expression to match for warning messages.
+			static ref WARNING_REGEX: Regex = Regex::new(r"\[WRN\]").unwrap();
+		}
+		state.output.process_line(line, &ERROR_REGEX, &WARNING_REGEX);
+	}
+
+	fn _process_stderr(state: &mut Self::State, line: &str) {
+		state.output.stderr.push(line.to_owned());
+	}
+}
+
+impl DaemonRunner for Daemon {}
+
+impl CommonDaemon for Daemon {
+	fn _common(&self) -> &Common<State> {
+		&self.common
+	}
+
+	fn _common_mut(&mut self) -> &mut Common<State> {
+		&mut self.common
+	}
+}
+
+impl fmt::Debug for Daemon {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		self.common.fmt_header(f, "lnd")?;
+		if f.alternate() {
+			let c = &self.config;
+			utils::fmt_details(
+				f,
+				&[
+					("network", c.network.map(network_name).map(str::to_owned)),
+					("lnd_dir", Some(c.lnd_dir.display().to_string())),
+					("port", c.port.map(|p| p.to_string())),
+					("rpcport", c.rpcport.map(|p| p.to_string())),
+					("restport", c.restport.map(|p| p.to_string())),
+				],
+			)?;
+		}
+		Ok(())
+	}
+}
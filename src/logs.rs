@@ -0,0 +1,914 @@
+//! Parsing of log lines that are common to the supported daemons.
+
+use std::any::Any;
+use std::collections::{vec_deque, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::{fmt, mem, time};
+
+use bitcoin::hashes::hex::FromHex;
+use regex::Regex;
+
+use crate::observer::Observer;
+use crate::runner::{RunnerConfig, Status};
+
+/// Parse an UpdateTip line into the new height and block hash.
+pub fn parse_update_tip(msg: &str) -> Option<(u64, bitcoin::BlockHash)> {
+	lazy_static! {
+		/// The regular expression for UpdateTip messages.
+		static ref UPDATE_TIP_REGEX: Regex = Regex::new(
+			r".*UpdateTip: new best=([0-9a-f]+) height=([0-9]+) version=.*$"
+		).unwrap();
+	}
+
+	UPDATE_TIP_REGEX.captures(msg).map(|c| {
+		let blockhash = bitcoin::BlockHash::from_hex(
+			c.get(1).expect("blockhash missing in UpdateTip").as_str(),
+		)
+		.expect("invalid blockhash in UpdateTip");
+		let height = u64::from_str(c.get(2).expect("height missing in UpdateTip").as_str())
+			.expect("invalid height in UpdateTip");
+		(height, blockhash)
+	})
+}
+
+/// Parse the estimated sync progress from an UpdateTip line.
+///
+/// Returns a fraction between 0 and 1.
+pub fn parse_sync_progress(msg: &str) -> Option<f64> {
+	lazy_static! {
+		/// The regular expression for the progress field of UpdateTip messages.
+		static ref SYNC_PROGRESS_REGEX: Regex =
+			Regex::new(r"UpdateTip: .* progress=([0-9.]+)").unwrap();
+	}
+
+	SYNC_PROGRESS_REGEX.captures(msg).and_then(|c| f64::from_str(&c[1]).ok())
+}
+
+/// Parse the progress of the block verification at startup.
+///
+/// Both the `Verification progress: 50%` lines of newer versions and the
+/// `[50%]...` progress markers of older versions are supported.
+/// Returns a fraction between 0 and 1.
+pub fn parse_verification_progress(msg: &str) -> Option<f64> {
+	lazy_static! {
+		/// The regular expression for the start of the verification.
+		static ref VERIFY_START_REGEX: Regex = Regex::new(r"Verifying last \d+ blocks").unwrap();
+		/// The regular expression for verification progress markers.
+		static ref VERIFY_PROGRESS_REGEX: Regex =
+			Regex::new(r"(?:\[|Verification progress: )(\d+)%").unwrap();
+		/// The regular expression for the end of the verification.
+		static ref VERIFY_DONE_REGEX: Regex =
+			Regex::new(r"\[DONE\]|No coin database inconsistencies").unwrap();
+	}
+
+	if VERIFY_DONE_REGEX.is_match(msg) {
+		return Some(1.0);
+	}
+	// Older versions print all markers on a single line.
+	if let Some(c) = VERIFY_PROGRESS_REGEX.captures_iter(msg).last() {
+		return u8::from_str(&c[1]).ok().map(|p| f64::from(p) / 100.0);
+	}
+	if VERIFY_START_REGEX.is_match(msg) {
+		return Some(0.0);
+	}
+	None
+}
+
+/// Parse the start of the block verification at startup.
+///
+/// These are lines like `Verifying last 6 blocks at level 3`.
+/// Returns the number of blocks and the check level.
+pub fn parse_verification_start(msg: &str) -> Option<(u64, u8)> {
+	lazy_static! {
+		/// The regular expression for the start of the verification.
+		static ref VERIFY_START_REGEX: Regex =
+			Regex::new(r"Verifying last (\d+) blocks at level (\d+)").unwrap();
+	}
+
+	VERIFY_START_REGEX
+		.captures(msg)
+		.and_then(|c| Some((u64::from_str(&c[1]).ok()?, u8::from_str(&c[2]).ok()?)))
+}
+
+/// Parse a line logging that a block file is being imported during a reindex.
+///
+/// Returns the number of the block file, f.e. 12 for `blk00012.dat`.
+pub fn parse_reindex_block_file(msg: &str) -> Option<u32> {
+	lazy_static! {
+		/// The regular expression for reindex block file messages.
+		static ref REINDEX_FILE_REGEX: Regex =
+			Regex::new(r"Reindexing block file blk(\d+)\.dat").unwrap();
+	}
+
+	REINDEX_FILE_REGEX.captures(msg).and_then(|c| u32::from_str(&c[1]).ok())
+}
+
+/// Whether the line logs that importing the block files of a reindex finished.
+pub fn is_reindex_finished(msg: &str) -> bool {
+	msg.contains("Reindexing finished")
+}
+
+/// Parse a line logging a startup argument.
+///
+/// These are lines like `Config file arg: [regtest] txindex="1"`
+/// or `Command-line arg: conf="/path/to/bitcoin.conf"`.
+/// Returns the name of the argument and its value.
+pub fn parse_startup_arg(msg: &str) -> Option<(String, String)> {
+	lazy_static! {
+		/// The regular expression for startup argument messages.
+		static ref STARTUP_ARG_REGEX: Regex = Regex::new(
+			r#"(?:Config file|Command-line|Setting file) arg: (?:\[[^\]]*\] )?([^=\s]+)=(.*)$"#
+		).unwrap();
+	}
+
+	STARTUP_ARG_REGEX.captures(msg).map(|c| {
+		let name = c.get(1).unwrap().as_str().to_owned();
+		let value = c.get(2).unwrap().as_str();
+		let value = value.trim_start_matches('"').trim_end_matches('"');
+		(name, value.to_owned())
+	})
+}
+
+/// Parse the timestamp at the start of a log line.
+///
+/// These are timestamps like `2020-01-01T12:00:00Z`, optionally with
+/// microseconds when `logtimemicros` is set. Returns the date and time as
+/// the number `YYYYMMDDhhmmss` and the microseconds, so that timestamps
+/// can be compared.
+pub fn parse_log_timestamp(msg: &str) -> Option<(u64, u32)> {
+	lazy_static! {
+		/// The regular expression for log timestamps.
+		static ref TIMESTAMP_REGEX: Regex = Regex::new(
+			r"^(\d{4})-(\d{2})-(\d{2})T(\d{2}):(\d{2}):(\d{2})(?:\.(\d{1,6}))?Z"
+		).unwrap();
+	}
+
+	TIMESTAMP_REGEX.captures(msg).map(|c| {
+		let datetime = (1..7).map(|i| &c[i]).collect::<String>();
+		let micros = c.get(7).map(|m| format!("{:0<6}", m.as_str())).unwrap_or_default();
+		(u64::from_str(&datetime).unwrap(), u32::from_str(&micros).unwrap_or(0))
+	})
+}
+
+/// Parse a line warning about an option the daemon doesn't understand.
+///
+/// This happens when options are written that are not supported by the
+/// version of the daemon that is run. Returns the warning message.
+pub fn parse_unknown_option_warning(msg: &str) -> Option<String> {
+	lazy_static! {
+		/// The regular expression for unknown option warnings.
+		static ref UNKNOWN_OPTION_REGEX: Regex = Regex::new(
+			r"(?:Ignoring unknown configuration value|Warning: Unknown network|Warning: Section \[[^\]]*\] is not recognized|Warning: Config setting for \S+ only applied on).*$"
+		).unwrap();
+	}
+
+	UNKNOWN_OPTION_REGEX.find(msg).map(|m| m.as_str().trim().to_owned())
+}
+
+/// The severity of a line of stderr output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StderrSeverity {
+	/// A known warning that doesn't indicate a problem, like a fee rate that
+	/// is considered high or a datadir on a small disk.
+	Benign,
+	/// Any other output.
+	Unknown,
+}
+
+/// Classify a line of stderr output of bitcoind or elementsd.
+///
+/// Warnings about unknown options are not considered benign,
+/// see [parse_unknown_option_warning].
+pub fn classify_stderr_line(line: &str) -> StderrSeverity {
+	lazy_static! {
+		/// The regular expression for known benign stderr output.
+		static ref BENIGN_STDERR_REGEX: Regex = Regex::new(
+			r"^\s*$|Warning: (?:Disk space for .* may not accommodate|-\w+ is set very high|Unsupported logging category|Option -\w+ is deprecated|.* is deprecated and will be removed)"
+		).unwrap();
+	}
+
+	if BENIGN_STDERR_REGEX.is_match(line) {
+		StderrSeverity::Benign
+	} else {
+		StderrSeverity::Unknown
+	}
+}
+
+/// Parse a line logging a disconnect because the upload target was reached.
+///
+/// These lines are only logged with the `net` debug category enabled.
+/// Returns the id of the disconnected peer.
+pub fn parse_upload_limit_disconnect(msg: &str) -> Option<u64> {
+	lazy_static! {
+		/// The regular expression for upload limit disconnect messages.
+		static ref UPLOAD_LIMIT_REGEX: Regex = Regex::new(
+			r"(?i)(?:historical block serving|upload) limit reached, disconnect(?:ing)? peer=(\d+)"
+		).unwrap();
+	}
+
+	UPLOAD_LIMIT_REGEX.captures(msg).and_then(|c| u64::from_str(&c[1]).ok())
+}
+
+/// An event parsed from the log output of a daemon.
+///
+/// Every line of output produces exactly one event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogEvent {
+	/// The daemon updated its tip to the given height and block.
+	UpdateTip {
+		height: u64,
+		hash: bitcoin::BlockHash,
+	},
+	/// The progress of importing the block files during a reindex,
+	/// as a percentage.
+	ReindexProgress(u8),
+	/// A line containing an error.
+	Error(String),
+	/// A line containing a warning.
+	Warning(String),
+	/// Any other line.
+	RawLine(String),
+}
+
+/// The subscribers to the log events of a daemon.
+#[derive(Clone, Default)]
+pub(crate) struct Subscribers(Arc<Mutex<Vec<mpsc::Sender<LogEvent>>>>);
+
+impl Subscribers {
+	/// Add a new subscriber.
+	pub fn subscribe(&self) -> mpsc::Receiver<LogEvent> {
+		let (tx, rx) = mpsc::channel();
+		self.0.lock().unwrap().push(tx);
+		rx
+	}
+
+	/// Send the event to all subscribers.
+	///
+	/// Subscribers that dropped their receiver are removed.
+	pub fn send(&self, event: LogEvent) {
+		self.0.lock().unwrap().retain(|s| s.send(event.clone()).is_ok());
+	}
+}
+
+/// The interval at which [Blocks] checks whether the daemon is still running.
+const BLOCKS_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// Where [Blocks] gets its blocks from.
+enum BlockSource {
+	/// The UpdateTip log events.
+	Events(mpsc::Receiver<LogEvent>),
+	/// A ZMQ socket subscribed to `hashblock`, with an RPC client to look up
+	/// the heights.
+	#[cfg(feature = "zmq")]
+	Zmq(zmq::Socket, Box<bitcoincore_rpc::Client>),
+}
+
+/// The outcome of polling a [BlockSource] once.
+enum Poll {
+	/// A block was connected.
+	Block(u64, bitcoin::BlockHash),
+	/// A message arrived that wasn't a connected block.
+	Skipped,
+	/// No message arrived in time.
+	Empty,
+	/// The source was closed.
+	Closed,
+}
+
+/// An iterator over the heights and hashes of the blocks a daemon connects.
+///
+/// The iterator blocks until the next block is connected and ends when the
+/// daemon stops. Blocks connected before the iterator was created are not
+/// included.
+pub struct Blocks<S> {
+	source: BlockSource,
+	observer: Observer<S>,
+}
+
+impl<S> Blocks<S> {
+	/// Iterate over the UpdateTip events of the daemon.
+	pub(crate) fn from_events(
+		events: mpsc::Receiver<LogEvent>,
+		observer: Observer<S>,
+	) -> Blocks<S> {
+		Blocks {
+			source: BlockSource::Events(events),
+			observer,
+		}
+	}
+
+	/// Iterate over the `hashblock` notifications of the socket.
+	#[cfg(feature = "zmq")]
+	pub(crate) fn from_zmq(
+		socket: zmq::Socket,
+		rpc: bitcoincore_rpc::Client,
+		observer: Observer<S>,
+	) -> Blocks<S> {
+		Blocks {
+			source: BlockSource::Zmq(socket, Box::new(rpc)),
+			observer,
+		}
+	}
+
+	fn is_running(&self) -> bool {
+		self.observer.status().map(|s| s == Status::Running).unwrap_or(false)
+	}
+
+	/// Read the next message from the source, if one arrives in time.
+	fn poll(&self, timeout: time::Duration) -> Poll {
+		match self.source {
+			BlockSource::Events(ref rx) => match rx.recv_timeout(timeout) {
+				Ok(LogEvent::UpdateTip {
+					height,
+					hash,
+				}) => Poll::Block(height, hash),
+				Ok(_) => Poll::Skipped,
+				Err(mpsc::RecvTimeoutError::Timeout) => Poll::Empty,
+				Err(mpsc::RecvTimeoutError::Disconnected) => Poll::Closed,
+			},
+			#[cfg(feature = "zmq")]
+			BlockSource::Zmq(ref socket, ref rpc) => {
+				use bitcoin::hashes::Hash;
+				use bitcoincore_rpc::RpcApi;
+
+				match socket.poll(zmq::POLLIN, timeout.as_millis() as i64) {
+					Ok(0) => return Poll::Empty,
+					Ok(_) => {}
+					Err(_) => return Poll::Closed,
+				}
+				let msg = match socket.recv_multipart(0) {
+					Ok(msg) => msg,
+					Err(_) => return Poll::Closed,
+				};
+				if msg.len() < 2 || msg[0] != b"hashblock" || msg[1].len() != 32 {
+					return Poll::Skipped;
+				}
+				// The hash is sent in the reversed order that it's displayed in.
+				let mut bytes = msg[1].clone();
+				bytes.reverse();
+				let hash = match bitcoin::BlockHash::from_slice(&bytes) {
+					Ok(hash) => hash,
+					Err(_) => return Poll::Skipped,
+				};
+				match rpc.get_block_header_info(&hash) {
+					Ok(header) => Poll::Block(header.height as u64, hash),
+					Err(e) => {
+						debug!(
+							"{}: failed to look up block {}: {:?}",
+							self.observer.name(),
+							hash,
+							e
+						);
+						Poll::Skipped
+					}
+				}
+			}
+		}
+	}
+}
+
+impl<S> Iterator for Blocks<S> {
+	type Item = (u64, bitcoin::BlockHash);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			match self.poll(BLOCKS_POLL_INTERVAL) {
+				Poll::Block(height, hash) => return Some((height, hash)),
+				Poll::Skipped | Poll::Empty if self.is_running() => continue,
+				Poll::Skipped | Poll::Empty => break,
+				Poll::Closed => return None,
+			}
+		}
+
+		// Deliver the blocks that were connected right before stopping.
+		loop {
+			match self.poll(time::Duration::from_millis(0)) {
+				Poll::Block(height, hash) => return Some((height, hash)),
+				Poll::Skipped => continue,
+				Poll::Empty | Poll::Closed => return None,
+			}
+		}
+	}
+}
+
+impl<S> fmt::Debug for Blocks<S> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Blocks({})", self.observer.name())
+	}
+}
+
+/// A [LogHook] callback taking the type-erased user state.
+type HookCallback = Box<dyn FnMut(&mut dyn Any, &str) + Send>;
+
+/// The callback of a [LogHook] with its user state.
+struct HookInner {
+	state: Box<dyn Any + Send>,
+	callback: HookCallback,
+}
+
+/// A user callback that derives custom state from the log lines of a daemon.
+///
+/// The hook is shared between a daemon and its runtime state, so it can be
+/// set both before and after the daemon is started.
+#[derive(Clone, Default)]
+pub(crate) struct LogHook(Arc<Mutex<Option<HookInner>>>);
+
+impl LogHook {
+	/// Set the callback that is called for every line with the given state.
+	///
+	/// This replaces any callback that was set before.
+	pub fn set<T, F>(&self, state: T, mut callback: F)
+	where
+		T: Any + Send,
+		F: FnMut(&mut T, &str) + Send + 'static,
+	{
+		*self.0.lock().unwrap() = Some(HookInner {
+			state: Box::new(state),
+			callback: Box::new(move |state, line| {
+				callback(state.downcast_mut().expect("hook state of wrong type"), line)
+			}),
+		});
+	}
+
+	/// Remove the callback and return its state.
+	pub fn clear<T: Any>(&self) -> Option<T> {
+		let inner = self.0.lock().unwrap().take()?;
+		inner.state.downcast().ok().map(|s| *s)
+	}
+
+	/// Call the callback, if any, for the given line.
+	pub fn process(&self, line: &str) {
+		if let Some(ref mut inner) = *self.0.lock().unwrap() {
+			(inner.callback)(&mut *inner.state, line);
+		}
+	}
+
+	/// Run the closure with the state of the callback.
+	///
+	/// Returns [None] if no callback was set or if the state has another type.
+	pub fn with_state<T: Any, R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+		let mut lock = self.0.lock().unwrap();
+		let state = lock.as_mut()?.state.downcast_mut()?;
+		Some(f(state))
+	}
+}
+
+/// The default capacity in bytes of the stderr buffer of a daemon.
+pub const DEFAULT_MAX_STDERR_BYTES: usize = 1024 * 1024;
+
+/// The default capacity in lines of the error message buffer of a daemon.
+pub const DEFAULT_MAX_ERROR_MSGS: usize = 1000;
+
+/// A ring buffer of log lines.
+///
+/// When adding a line would exceed the capacity, the oldest lines are dropped.
+/// The number of dropped lines is kept so that users know output was truncated.
+#[derive(Debug, Clone, Default)]
+pub struct LineBuffer {
+	lines: VecDeque<String>,
+	bytes: usize,
+	max_lines: Option<usize>,
+	max_bytes: Option<usize>,
+	dropped: usize,
+	flushed: usize,
+}
+
+impl LineBuffer {
+	/// Create a new buffer holding at most [max_lines] lines and [max_bytes] bytes.
+	pub fn new(max_lines: Option<usize>, max_bytes: Option<usize>) -> LineBuffer {
+		LineBuffer {
+			max_lines,
+			max_bytes,
+			..Default::default()
+		}
+	}
+
+	/// Add a line, dropping the oldest lines if the buffer is full.
+	///
+	/// A line that is longer than the byte capacity is dropped right away.
+	pub fn push(&mut self, line: String) {
+		self.bytes += line.len();
+		self.lines.push_back(line);
+		while self.max_lines.map(|m| self.lines.len() > m).unwrap_or(false)
+			|| self.max_bytes.map(|m| self.bytes > m).unwrap_or(false)
+		{
+			let dropped = self.lines.pop_front().expect("buffer can't be empty");
+			self.bytes -= dropped.len();
+			self.dropped += 1;
+		}
+	}
+
+	/// The number of lines in the buffer.
+	pub fn len(&self) -> usize {
+		self.lines.len()
+	}
+
+	/// Whether the buffer holds no lines.
+	pub fn is_empty(&self) -> bool {
+		self.lines.is_empty()
+	}
+
+	/// The total size of the lines in the buffer in bytes.
+	pub fn bytes(&self) -> usize {
+		self.bytes
+	}
+
+	/// The number of lines that were dropped because the buffer was full.
+	pub fn dropped(&self) -> usize {
+		self.dropped
+	}
+
+	/// The number of lines that were moved to disk with [flush_to].
+	pub fn flushed(&self) -> usize {
+		self.flushed
+	}
+
+	/// Append all lines to the file at [path] and clear the buffer.
+	///
+	/// Returns the number of lines written.
+	pub fn flush_to(&mut self, path: &Path) -> Result<usize, io::Error> {
+		if self.lines.is_empty() {
+			return Ok(0);
+		}
+		let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+		for line in &self.lines {
+			writeln!(file, "{}", line)?;
+		}
+		let n = self.take().len();
+		self.flushed += n;
+		Ok(n)
+	}
+
+	/// Iterate over the lines in the buffer, oldest first.
+	pub fn iter(&self) -> vec_deque::Iter<'_, String> {
+		self.lines.iter()
+	}
+
+	/// Take all lines out of the buffer.
+	///
+	/// The capacity and the drop count are kept.
+	pub fn take(&mut self) -> Vec<String> {
+		self.bytes = 0;
+		self.lines.drain(..).collect()
+	}
+}
+
+impl fmt::Display for LineBuffer {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for line in &self.lines {
+			writeln!(f, "{}", line)?;
+		}
+		Ok(())
+	}
+}
+
+/// The output of a daemon that is kept in its runtime state.
+#[derive(Default)]
+pub struct Output {
+	/// Buffer holding the most recent stderr output.
+	pub stderr: LineBuffer,
+
+	/// Error messages produced during runtime.
+	error_msgs: LineBuffer,
+
+	/// The subscribers to log events.
+	events: Subscribers,
+}
+
+impl Output {
+	pub(crate) fn new(config: &RunnerConfig, events: Subscribers) -> Output {
+		Output {
+			stderr: LineBuffer::new(
+				None,
+				Some(config.max_stderr_bytes.unwrap_or(DEFAULT_MAX_STDERR_BYTES)),
+			),
+			error_msgs: LineBuffer::new(
+				Some(config.max_error_msgs.unwrap_or(DEFAULT_MAX_ERROR_MSGS)),
+				None,
+			),
+			events,
+		}
+	}
+
+	/// Error messages produced during runtime that have not been taken yet.
+	pub fn error_msgs(&self) -> &LineBuffer {
+		&self.error_msgs
+	}
+
+	/// Take the buffered stderr output.
+	pub(crate) fn take_stderr(&mut self) -> String {
+		self.stderr.take().into_iter().map(|l| l + "\n").collect()
+	}
+
+	/// Take the error messages that were not taken yet.
+	pub(crate) fn take_error_msgs(&mut self) -> Vec<String> {
+		self.error_msgs.take()
+	}
+
+	/// Move the buffered stderr output and error messages to archive files
+	/// in the given directory, so that memory usage stays flat.
+	///
+	/// See [STDERR_ARCHIVE_FILENAME] and [ERRORS_ARCHIVE_FILENAME].
+	pub fn compact(&mut self, dir: &Path) -> Result<(), io::Error> {
+		self.stderr.flush_to(&dir.join(STDERR_ARCHIVE_FILENAME))?;
+		self.error_msgs.flush_to(&dir.join(ERRORS_ARCHIVE_FILENAME))?;
+		Ok(())
+	}
+
+	/// Send the event to the subscribers.
+	pub(crate) fn send(&self, event: LogEvent) {
+		self.events.send(event);
+	}
+
+	/// Keep the line if it's an error message and send it to the
+	/// subscribers as an error, a warning or a raw line.
+	pub(crate) fn process_line(&mut self, line: &str, error: &Regex, warning: &Regex) {
+		if error.is_match(line) {
+			self.error_msgs.push(line.to_owned());
+			self.send(LogEvent::Error(line.to_owned()));
+		} else if warning.is_match(line) {
+			self.send(LogEvent::Warning(line.to_owned()));
+		} else {
+			self.send(LogEvent::RawLine(line.to_owned()));
+		}
+	}
+}
+
+/// The file name in the datadir that compacted stderr output is moved to.
+pub const STDERR_ARCHIVE_FILENAME: &str = "daemon-runner-stderr.log";
+
+/// The file name in the datadir that compacted error messages are moved to.
+pub const ERRORS_ARCHIVE_FILENAME: &str = "daemon-runner-errors.log";
+
+/// The default file name of the log file in the datadir.
+pub const DEFAULT_LOG_FILENAME: &str = "daemon-runner.log";
+
+/// Configuration of a log file holding all captured output of a daemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogFileConfig {
+	/// The path of the log file, relative to the datadir.
+	pub filename: String,
+	/// The size in bytes after which the file is rotated.
+	pub max_bytes: u64,
+	/// The number of rotated files to keep, in addition to the current one.
+	pub keep: usize,
+}
+
+/// A log file that is rotated when it grows too large.
+///
+/// Rotated files get a numbered suffix, `.1` being the most recent.
+#[derive(Debug)]
+pub struct RotatingFile {
+	path: PathBuf,
+	max_bytes: u64,
+	keep: usize,
+	file: File,
+	size: u64,
+}
+
+impl RotatingFile {
+	/// Open the log file at [path], appending to it if it exists.
+	pub fn open(path: &Path, max_bytes: u64, keep: usize) -> Result<RotatingFile, io::Error> {
+		let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+		Ok(RotatingFile {
+			path: path.to_owned(),
+			max_bytes,
+			keep,
+			size: file.metadata()?.len(),
+			file,
+		})
+	}
+
+	/// The path of the rotated file with the given number.
+	fn rotated_path(&self, n: usize) -> PathBuf {
+		let mut name = self.path.as_os_str().to_owned();
+		name.push(format!(".{}", n));
+		name.into()
+	}
+
+	/// Rotate the files and start a new one.
+	fn rotate(&mut self) -> Result<(), io::Error> {
+		if self.keep == 0 {
+			self.file.set_len(0)?;
+		} else {
+			let _ = fs::remove_file(self.rotated_path(self.keep));
+			for n in (1..self.keep).rev() {
+				let from = self.rotated_path(n);
+				if from.exists() {
+					fs::rename(from, self.rotated_path(n + 1))?;
+				}
+			}
+			fs::rename(&self.path, self.rotated_path(1))?;
+		}
+		let file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+		drop(mem::replace(&mut self.file, file));
+		self.size = 0;
+		Ok(())
+	}
+
+	/// Write a line to the log file, rotating first if it would grow too large.
+	pub fn write_line(&mut self, line: &str) -> Result<(), io::Error> {
+		let len = line.len() as u64 + 1;
+		if self.size > 0 && self.size + len > self.max_bytes {
+			self.rotate()?;
+		}
+		writeln!(self.file, "{}", line)?;
+		self.size += len;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::utils;
+
+	const UPDATE_TIP: &str = "2020-01-01T12:00:00Z UpdateTip: new best=0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206 height=0 version=0x00000001 log2_work=1 tx=1 date='2011-02-02T23:16:42Z' progress=0.250000 cache=0.0MiB(0txo)";
+
+	#[test]
+	fn update_tip() {
+		let (height, hash) = parse_update_tip(UPDATE_TIP).unwrap();
+		assert_eq!(height, 0);
+		assert_eq!(
+			hash.to_string(),
+			"0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+		);
+		assert_eq!(parse_sync_progress(UPDATE_TIP), Some(0.25));
+		assert_eq!(parse_update_tip("UpdateTip: something else"), None);
+	}
+
+	#[test]
+	fn verification_progress() {
+		assert_eq!(parse_verification_progress("Verifying last 6 blocks at level 3"), Some(0.0));
+		assert_eq!(parse_verification_progress("Verification progress: 50%"), Some(0.5));
+		assert_eq!(parse_verification_progress("[0%]...[33%]...[66%]..."), Some(0.66));
+		assert_eq!(parse_verification_progress("[0%]...[99%]...[DONE]."), Some(1.0));
+		assert_eq!(parse_verification_progress("Loading wallet..."), None);
+	}
+
+	#[test]
+	fn verification_start() {
+		assert_eq!(parse_verification_start("Verifying last 6 blocks at level 3"), Some((6, 3)));
+		assert_eq!(parse_verification_start("Verifying last 6 blocks"), None);
+	}
+
+	#[test]
+	fn reindex() {
+		assert_eq!(parse_reindex_block_file("Reindexing block file blk00012.dat..."), Some(12));
+		assert!(is_reindex_finished("Reindexing finished"));
+		assert!(!is_reindex_finished("Reindexing block file blk00012.dat..."));
+	}
+
+	#[test]
+	fn startup_arg() {
+		assert_eq!(
+			parse_startup_arg(r#"Config file arg: [regtest] txindex="1""#),
+			Some(("txindex".to_owned(), "1".to_owned())),
+		);
+		assert_eq!(
+			parse_startup_arg(r#"Command-line arg: conf="/path/to/bitcoin.conf""#),
+			Some(("conf".to_owned(), "/path/to/bitcoin.conf".to_owned())),
+		);
+		assert_eq!(parse_startup_arg("Using config file /path/to/bitcoin.conf"), None);
+	}
+
+	#[test]
+	fn log_timestamp() {
+		assert_eq!(parse_log_timestamp(UPDATE_TIP), Some((20200101120000, 0)));
+		assert_eq!(
+			parse_log_timestamp("2020-01-01T12:00:00.0125Z Loading wallet"),
+			Some((20200101120000, 12500)),
+		);
+		assert_eq!(parse_log_timestamp("Loading wallet"), None);
+	}
+
+	#[test]
+	fn unknown_option_warning() {
+		assert_eq!(
+			parse_unknown_option_warning(
+				"Warning: Section [foo] is not recognized and will be ignored.",
+			),
+			Some("Warning: Section [foo] is not recognized and will be ignored.".to_owned()),
+		);
+		assert_eq!(parse_unknown_option_warning("Warning: -paytxfee is set very high!"), None);
+	}
+
+	#[test]
+	fn stderr_severity() {
+		assert_eq!(classify_stderr_line(""), StderrSeverity::Benign);
+		assert_eq!(
+			classify_stderr_line("Warning: -maxtxfee is set very high! Fees this large could be paid on a single transaction."),
+			StderrSeverity::Benign,
+		);
+		assert_eq!(
+			classify_stderr_line("Warning: Section [foo] is not recognized and will be ignored."),
+			StderrSeverity::Unknown,
+		);
+	}
+
+	#[test]
+	fn upload_limit_disconnect() {
+		assert_eq!(
+			parse_upload_limit_disconnect(
+				"historical block serving limit reached, disconnect peer=5",
+			),
+			Some(5),
+		);
+		assert_eq!(
+			parse_upload_limit_disconnect("Upload limit reached, disconnecting peer=12"),
+			Some(12)
+		);
+		assert_eq!(parse_upload_limit_disconnect("disconnecting peer=5"), None);
+	}
+
+	#[test]
+	fn line_buffer() {
+		let mut buf = LineBuffer::new(Some(2), None);
+		for l in &["a", "b", "c"] {
+			buf.push(l.to_string());
+		}
+		assert_eq!(buf.iter().collect::<Vec<_>>(), vec!["b", "c"]);
+		assert_eq!(buf.dropped(), 1);
+		assert_eq!(buf.to_string(), "b\nc\n");
+
+		let mut buf = LineBuffer::new(None, Some(4));
+		buf.push("ab".into());
+		buf.push("cd".into());
+		assert_eq!((buf.len(), buf.bytes()), (2, 4));
+		buf.push("e".into());
+		assert_eq!((buf.len(), buf.bytes(), buf.dropped()), (2, 3, 1));
+		// A line that doesn't fit is dropped right away.
+		buf.push("fghij".into());
+		assert!(buf.is_empty());
+		assert_eq!((buf.bytes(), buf.dropped()), (0, 4));
+
+		buf.push("k".into());
+		assert_eq!(buf.take(), vec!["k".to_owned()]);
+		assert_eq!((buf.len(), buf.bytes(), buf.dropped()), (0, 0, 4));
+	}
+
+	#[test]
+	fn line_buffer_flush() {
+		let dir = utils::create_temp_dir("logs-test").unwrap();
+		let path = dir.join("flushed.log");
+		let mut buf = LineBuffer::new(None, None);
+		assert_eq!(buf.flush_to(&path).unwrap(), 0);
+		assert!(!path.exists());
+		buf.push("a".into());
+		buf.push("b".into());
+		assert_eq!(buf.flush_to(&path).unwrap(), 2);
+		buf.push("c".into());
+		assert_eq!(buf.flush_to(&path).unwrap(), 1);
+		assert!(buf.is_empty());
+		assert_eq!(buf.flushed(), 3);
+		assert_eq!(fs::read_to_string(&path).unwrap(), "a\nb\nc\n");
+		utils::remove_temp_dir(&dir);
+	}
+
+	#[test]
+	fn rotating_file() {
+		let dir = utils::create_temp_dir("logs-test").unwrap();
+		let path = dir.join("daemon.log");
+		let read = |n: usize| {
+			let p = if n == 0 {
+				path.clone()
+			} else {
+				dir.join(format!("daemon.log.{}", n))
+			};
+			fs::read_to_string(p).ok()
+		};
+
+		let mut file = RotatingFile::open(&path, 4, 2).unwrap();
+		for l in &["a", "b", "c", "d", "e"] {
+			file.write_line(l).unwrap();
+		}
+		assert_eq!(read(0).as_deref(), Some("e\n"));
+		assert_eq!(read(1).as_deref(), Some("c\nd\n"));
+		assert_eq!(read(2).as_deref(), Some("a\nb\n"));
+		assert_eq!(read(3), None);
+		drop(file);
+
+		// Appends to the existing file and takes its size into account.
+		let mut file = RotatingFile::open(&path, 4, 2).unwrap();
+		file.write_line("f").unwrap();
+		file.write_line("g").unwrap();
+		assert_eq!(read(0).as_deref(), Some("g\n"));
+		assert_eq!(read(1).as_deref(), Some("e\nf\n"));
+		assert_eq!(read(2).as_deref(), Some("c\nd\n"));
+
+		// Without rotated files, the file is truncated.
+		let path = dir.join("truncated.log");
+		let mut file = RotatingFile::open(&path, 4, 0).unwrap();
+		for l in &["a", "b", "c"] {
+			file.write_line(l).unwrap();
+		}
+		assert_eq!(fs::read_to_string(&path).unwrap(), "c\n");
+		assert!(!dir.join("truncated.log.1").exists());
+		utils::remove_temp_dir(&dir);
+	}
+}
@@ -0,0 +1,142 @@
+//! Running the same scenario against multiple daemon versions.
+//!
+//! Projects that support multiple versions of bitcoind and elementsd can
+//! describe a scenario once and run it for every pair of versions in a
+//! [Matrix].
+
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use crate::error::Error;
+use crate::utils;
+
+/// A pair of bitcoind and elementsd executables in a [Matrix].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cell {
+	pub bitcoind: PathBuf,
+	/// The detected version of the bitcoind executable.
+	pub bitcoind_version: u64,
+	pub elementsd: PathBuf,
+	/// The detected version of the elementsd executable.
+	pub elementsd_version: u64,
+}
+
+impl Cell {
+	/// A short description of the versions, like `bitcoind 0.21.0 / elementsd 0.18.1.12`.
+	pub fn label(&self) -> String {
+		format!(
+			"bitcoind {} / elementsd {}",
+			utils::format_version(self.bitcoind_version),
+			utils::format_version(self.elementsd_version),
+		)
+	}
+}
+
+/// The result of a scenario in a single [Cell].
+#[derive(Debug)]
+pub struct CellResult<R> {
+	pub cell: Cell,
+	pub result: Result<R, Error>,
+}
+
+/// A list of version pairs to run a scenario against.
+#[derive(Debug, Clone, Default)]
+pub struct Matrix {
+	cells: Vec<Cell>,
+}
+
+impl Matrix {
+	pub fn new() -> Matrix {
+		Matrix::default()
+	}
+
+	/// Add a cell for the given executables.
+	///
+	/// The versions of the executables are detected with `--version`.
+	pub fn cell(
+		mut self,
+		bitcoind: impl Into<PathBuf>,
+		elementsd: impl Into<PathBuf>,
+	) -> Result<Self, Error> {
+		let (bitcoind, elementsd) = (bitcoind.into(), elementsd.into());
+		self.cells.push(Cell {
+			bitcoind_version: utils::detect_version(&bitcoind)?,
+			bitcoind,
+			elementsd_version: utils::detect_version(&elementsd)?,
+			elementsd,
+		});
+		Ok(self)
+	}
+
+	pub fn cells(&self) -> &[Cell] {
+		&self.cells
+	}
+
+	/// Run the scenario for every cell, one after the other.
+	pub fn run<F, R>(&self, scenario: F) -> Vec<CellResult<R>>
+	where
+		F: Fn(&Cell) -> Result<R, Error>,
+	{
+		self.cells
+			.iter()
+			.map(|cell| {
+				info!("Running scenario for {}", cell.label());
+				CellResult {
+					cell: cell.clone(),
+					result: scenario(cell),
+				}
+			})
+			.collect()
+	}
+
+	/// Run the scenario for all cells at the same time, each in its own thread.
+	///
+	/// The scenario should use separate datadirs and ports for every cell.
+	/// A panic in a scenario is reported as an error for its cell.
+	pub fn run_parallel<F, R>(&self, scenario: F) -> Vec<CellResult<R>>
+	where
+		F: Fn(&Cell) -> Result<R, Error> + Send + Sync + 'static,
+		R: Send + 'static,
+	{
+		let scenario = Arc::new(scenario);
+		let threads = self
+			.cells
+			.iter()
+			.map(|cell| {
+				let (cell, scenario) = (cell.clone(), scenario.clone());
+				thread::Builder::new()
+					.name(format!("matrix_{}_{}", cell.bitcoind_version, cell.elementsd_version))
+					.spawn(move || {
+						info!("Running scenario for {}", cell.label());
+						scenario(&cell)
+					})
+					.expect("failed to start matrix thread")
+			})
+			.collect::<Vec<_>>();
+
+		self.cells
+			.iter()
+			.zip(threads)
+			.map(|(cell, thread)| CellResult {
+				cell: cell.clone(),
+				result: thread.join().unwrap_or(Err(Error::Custom("scenario panicked"))),
+			})
+			.collect()
+	}
+}
+
+/// Create a table with the outcome of every cell.
+pub fn report<R>(results: &[CellResult<R>]) -> String {
+	let width = results.iter().map(|r| r.cell.label().len()).max().unwrap_or(0);
+	let mut ret = String::new();
+	for r in results {
+		let outcome = match r.result {
+			Ok(_) => "ok".to_owned(),
+			Err(ref e) => format!("FAILED: {}", e),
+		};
+		writeln!(ret, "{:<w$}  {}", r.cell.label(), outcome, w = width).unwrap();
+	}
+	ret
+}
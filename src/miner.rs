@@ -0,0 +1,252 @@
+//! Continuous block production for regtest networks.
+//!
+//! A [Miner] mines blocks on a node in a background thread, either at a
+//! fixed interval or only when requested.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::{fmt, thread, time};
+
+use bitcoin::BlockHash;
+use bitcoincore_rpc::RpcApi;
+
+use crate::bitcoind;
+use crate::cluster::NodeObserver;
+use crate::elementsd;
+use crate::error::Error;
+use crate::runner::CommonDaemon;
+
+/// When a [Miner] produces blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+	/// Mine a block every interval.
+	Interval(time::Duration),
+	/// Only mine blocks requested with [Miner::mine].
+	OnDemand,
+}
+
+/// A callback called with the hash of every mined block.
+pub type BlockCallback = Box<dyn FnMut(&BlockHash) + Send>;
+
+/// The state shared between a [Miner] and its thread.
+#[derive(Default)]
+struct Shared {
+	stop: AtomicBool,
+	paused: AtomicBool,
+	blocks_mined: AtomicU64,
+	failures: AtomicU64,
+	/// The number of requested blocks that were not mined yet.
+	requested: Mutex<u64>,
+	/// Notified when blocks are requested or the miner is stopped.
+	wakeup: Condvar,
+	/// The address to mine to. When not set, a wallet address is used.
+	address: Mutex<Option<String>>,
+	callback: Mutex<Option<BlockCallback>>,
+	last_block: Mutex<Option<BlockHash>>,
+}
+
+/// Mines blocks on a node in a background thread.
+///
+/// The thread is stopped when the miner is dropped.
+pub struct Miner {
+	node_name: String,
+	schedule: Schedule,
+	shared: Arc<Shared>,
+	thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Miner {
+	/// Start mining on the given node.
+	///
+	/// Blocks are mined with `generatetoaddress`, so the node should not
+	/// require signed blocks.
+	pub fn start(node: NodeObserver, schedule: Schedule) -> Miner {
+		let shared = Arc::new(Shared::default());
+		let node_name = node.name().to_owned();
+
+		let shared_cloned = shared.clone();
+		let thread = thread::Builder::new()
+			.name(format!("{}_miner", node_name))
+			.spawn(move || run(node, schedule, shared_cloned))
+			.expect("failed to start miner thread");
+
+		Miner {
+			node_name,
+			schedule,
+			shared,
+			thread: Some(thread),
+		}
+	}
+
+	/// Start mining on the given bitcoind.
+	pub fn for_bitcoind(daemon: &bitcoind::Daemon, schedule: Schedule) -> Result<Miner, Error> {
+		Ok(Miner::start(NodeObserver::Bitcoind(daemon.observer()?), schedule))
+	}
+
+	/// Start mining on the given elementsd.
+	pub fn for_elementsd(daemon: &elementsd::Daemon, schedule: Schedule) -> Result<Miner, Error> {
+		Ok(Miner::start(NodeObserver::Elementsd(daemon.observer()?), schedule))
+	}
+
+	pub fn schedule(&self) -> Schedule {
+		self.schedule
+	}
+
+	/// Mine to the given address instead of to a new wallet address.
+	pub fn set_address(&self, address: impl Into<String>) {
+		*self.shared.address.lock().unwrap() = Some(address.into());
+	}
+
+	/// Call the callback with the hash of every mined block.
+	///
+	/// The callback is called from the miner thread.
+	pub fn set_callback(&self, callback: impl FnMut(&BlockHash) + Send + 'static) {
+		*self.shared.callback.lock().unwrap() = Some(Box::new(callback));
+	}
+
+	/// Request the given number of blocks to be mined as soon as possible.
+	///
+	/// This works with any schedule, but blocks are not mined while paused.
+	pub fn mine(&self, blocks: u64) {
+		*self.shared.requested.lock().unwrap() += blocks;
+		self.shared.wakeup.notify_all();
+	}
+
+	/// Stop producing blocks until [resume] is called.
+	pub fn pause(&self) {
+		self.shared.paused.store(true, Ordering::SeqCst);
+	}
+
+	pub fn resume(&self) {
+		self.shared.paused.store(false, Ordering::SeqCst);
+		self.shared.wakeup.notify_all();
+	}
+
+	pub fn is_paused(&self) -> bool {
+		self.shared.paused.load(Ordering::SeqCst)
+	}
+
+	/// The number of blocks mined so far.
+	pub fn blocks_mined(&self) -> u64 {
+		self.shared.blocks_mined.load(Ordering::SeqCst)
+	}
+
+	/// The number of times mining a block failed.
+	pub fn failures(&self) -> u64 {
+		self.shared.failures.load(Ordering::SeqCst)
+	}
+
+	/// The number of requested blocks that were not mined yet.
+	pub fn pending(&self) -> u64 {
+		*self.shared.requested.lock().unwrap()
+	}
+
+	/// The hash of the last mined block.
+	pub fn last_block(&self) -> Option<BlockHash> {
+		*self.shared.last_block.lock().unwrap()
+	}
+
+	/// Wait until at least [blocks] blocks were mined in total.
+	pub fn wait_for_blocks(&self, blocks: u64, timeout: time::Duration) -> Result<(), Error> {
+		let start = time::Instant::now();
+		while self.blocks_mined() < blocks {
+			if start.elapsed() > timeout {
+				return Err(Error::Timeout("blocks to be mined"));
+			}
+			thread::sleep(time::Duration::from_millis(50));
+		}
+		Ok(())
+	}
+
+	/// Stop the miner and wait for its thread to exit.
+	///
+	/// Returns the number of blocks that were mined.
+	pub fn stop(mut self) -> u64 {
+		self.stop_thread();
+		self.blocks_mined()
+	}
+
+	fn stop_thread(&mut self) {
+		self.shared.stop.store(true, Ordering::SeqCst);
+		self.shared.wakeup.notify_all();
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+impl fmt::Debug for Miner {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Miner({}, {:?}, {} blocks)", self.node_name, self.schedule, self.blocks_mined())
+	}
+}
+
+impl Drop for Miner {
+	fn drop(&mut self) {
+		self.stop_thread();
+	}
+}
+
+/// The main loop of the miner thread.
+fn run(node: NodeObserver, schedule: Schedule, shared: Arc<Shared>) {
+	let mut next = match schedule {
+		Schedule::Interval(i) => Some(time::Instant::now() + i),
+		Schedule::OnDemand => None,
+	};
+	loop {
+		// Wait until a block is due.
+		let mut requested = shared.requested.lock().unwrap();
+		let due = loop {
+			if shared.stop.load(Ordering::SeqCst) {
+				return;
+			}
+			let paused = shared.paused.load(Ordering::SeqCst);
+			if !paused && *requested > 0 {
+				*requested -= 1;
+				break false;
+			}
+			let now = time::Instant::now();
+			match next {
+				Some(n) if !paused && n <= now => break true,
+				Some(n) if !paused => {
+					requested = shared.wakeup.wait_timeout(requested, n - now).unwrap().0;
+				}
+				_ => requested = shared.wakeup.wait(requested).unwrap(),
+			}
+		};
+		drop(requested);
+		if due {
+			if let Schedule::Interval(i) = schedule {
+				next = Some(time::Instant::now() + i);
+			}
+		}
+
+		match mine_block(&node, &shared) {
+			Ok(hash) => {
+				trace!("{}: mined block {}", node.name(), hash);
+				shared.blocks_mined.fetch_add(1, Ordering::SeqCst);
+				*shared.last_block.lock().unwrap() = Some(hash);
+				if let Some(ref mut cb) = *shared.callback.lock().unwrap() {
+					cb(&hash);
+				}
+			}
+			Err(e) => {
+				warn!("{}: failed to mine block: {:?}", node.name(), e);
+				shared.failures.fetch_add(1, Ordering::SeqCst);
+			}
+		}
+	}
+}
+
+/// Mine a single block on the node.
+fn mine_block(node: &NodeObserver, shared: &Shared) -> Result<BlockHash, Error> {
+	let rpc = node.rpc_client().ok_or(Error::Config("RPC is not configured"))??;
+	let mut address = shared.address.lock().unwrap();
+	if address.is_none() {
+		*address = Some(rpc.call::<String>("getnewaddress", &[])?);
+	}
+	let args = [1.into(), address.clone().unwrap().into()];
+	drop(address);
+	let hashes = rpc.call::<Vec<BlockHash>>("generatetoaddress", &args)?;
+	hashes.first().cloned().ok_or(Error::Custom("no block was mined"))
+}
@@ -0,0 +1,133 @@
+//! Read-only handles to daemons that are owned elsewhere.
+//!
+//! An [Observer] can be handed to auxiliary components like monitors or
+//! invariant checkers. It gives access to the daemon's state and RPC
+//! interface, but can't be used to start or stop the daemon.
+
+use std::fmt;
+use std::sync::{mpsc, Arc, Mutex, Weak};
+
+use bitcoincore_rpc::Auth;
+
+use crate::error::Error;
+use crate::logs::{self, LogEvent};
+use crate::runner::{RuntimeData, Status};
+use crate::utils;
+
+/// A read-only handle to a running daemon.
+///
+/// The handle observes the process the daemon was running when it was
+/// created. If the daemon is started again with a fresh state, a new
+/// observer should be created.
+///
+/// The handle doesn't keep the daemon's state alive. Once the daemon is
+/// dropped, accessing the state returns an error.
+pub struct Observer<S> {
+	name: String,
+	rpc_info: Option<(String, Auth)>,
+	/// The subscribers to log events, shared with the daemon.
+	events: logs::Subscribers,
+	runtime_data: Weak<Mutex<RuntimeData<S>>>,
+}
+
+impl<S> Observer<S> {
+	pub(crate) fn new(
+		name: String,
+		rpc_info: Option<(String, Auth)>,
+		events: logs::Subscribers,
+		runtime_data: &Arc<Mutex<RuntimeData<S>>>,
+	) -> Observer<S> {
+		Observer {
+			name,
+			rpc_info,
+			events,
+			runtime_data: Arc::downgrade(runtime_data),
+		}
+	}
+
+	/// The runtime data of the daemon, if it wasn't dropped yet.
+	fn runtime_data(&self) -> Result<Arc<Mutex<RuntimeData<S>>>, Error> {
+		self.runtime_data.upgrade().ok_or(Error::Custom("the observed daemon was dropped"))
+	}
+
+	/// The debug name of the observed daemon.
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// The running status of the daemon.
+	pub fn status(&self) -> Result<Status, Error> {
+		Ok(self.runtime_data()?.lock().unwrap().status()?)
+	}
+
+	/// Get the OS process ID of the daemon.
+	pub fn pid(&self) -> Option<u32> {
+		self.runtime_data().ok()?.lock().unwrap().pid()
+	}
+
+	/// Run the closure with a reference to the runtime state of the daemon.
+	pub fn with_state<R>(&self, f: impl FnOnce(&S) -> R) -> Result<R, Error> {
+		Ok(f(&self.runtime_data()?.lock().unwrap().state))
+	}
+
+	/// Subscribe to the events parsed from the log output of the daemon.
+	///
+	/// The subscription lasts across restarts of the daemon.
+	pub fn subscribe_events(&self) -> mpsc::Receiver<LogEvent> {
+		self.events.subscribe()
+	}
+
+	/// Get the RPC info.
+	pub fn rpc_info(&self) -> Option<(String, Auth)> {
+		self.rpc_info.clone()
+	}
+
+	/// Get an RPC client whose calls time out after
+	/// [utils::HEALTH_CHECK_RPC_TIMEOUT].
+	///
+	/// The client supports the elementsd calls as well, see [utils::TimeoutClient].
+	pub fn rpc_client(&self) -> Option<Result<utils::TimeoutClient, Error>> {
+		let (url, auth) = self.rpc_info()?;
+		let timeout = Some(utils::HEALTH_CHECK_RPC_TIMEOUT);
+		Some(utils::TimeoutClient::new(&url, auth, timeout).map_err(Error::from))
+	}
+}
+
+impl<S> Clone for Observer<S> {
+	fn clone(&self) -> Observer<S> {
+		Observer {
+			name: self.name.clone(),
+			rpc_info: self.rpc_info.clone(),
+			events: self.events.clone(),
+			runtime_data: self.runtime_data.clone(),
+		}
+	}
+}
+
+impl<S> fmt::Debug for Observer<S> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "observer of {}", self.name)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn subscribe_events() {
+		let events = logs::Subscribers::default();
+		let rt = Arc::new(Mutex::new(RuntimeData::new(())));
+		let observer = Observer::new("test".into(), None, events.clone(), &rt);
+
+		let rx = observer.clone().subscribe_events();
+		events.send(LogEvent::RawLine("line".into()));
+		assert!(matches!(rx.try_recv(), Ok(LogEvent::RawLine(ref l)) if l == "line"));
+
+		// The subscription doesn't need the daemon's state.
+		drop(rt);
+		events.send(LogEvent::Warning("warning".into()));
+		assert!(matches!(rx.try_recv(), Ok(LogEvent::Warning(_))));
+		assert!(observer.status().is_err());
+	}
+}
@@ -0,0 +1,287 @@
+//! Helpers for testing pegs between a bitcoind and an elementsd.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{fmt, fs, thread, time};
+
+use bitcoin::Txid;
+use bitcoincore_rpc::{Auth, RpcApi};
+
+use crate::bitcoind;
+use crate::elementsd;
+use crate::error::Error;
+use crate::observer::Observer;
+use crate::runner::{CommonDaemon, DaemonRunner, Status};
+
+/// The default of the `peginconfirmationdepth` option of elementsd.
+pub const DEFAULT_PEGIN_CONFIRMATION_DEPTH: u64 = 100;
+
+/// The interval at which a [PeginTracker] checks the confirmations.
+const PEGIN_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// A bitcoind with an elementsd that validates peg-ins against it.
+///
+/// The mainchain RPC details of the elementsd are taken from the bitcoind,
+/// so that they can't get out of sync.
+#[derive(Debug)]
+pub struct PegEnvironment {
+	bitcoind: bitcoind::Daemon,
+	elementsd: elementsd::Daemon,
+}
+
+impl PegEnvironment {
+	/// Start the bitcoind and then an elementsd with the given config.
+	///
+	/// The bitcoind is only started when it isn't running yet. The config of the
+	/// elementsd gets `validatepegin` set and its mainchain RPC fields filled in
+	/// from the [bitcoind::Daemon::rpc_info] of the bitcoind. When the bitcoind
+	/// uses cookie authentication, the current cookie is used, so the
+	/// environment should be recreated when the bitcoind is restarted.
+	///
+	/// This method returns once both daemons are ready. The [timeout] applies
+	/// to each daemon separately.
+	pub fn start(
+		mut bitcoind: bitcoind::Daemon,
+		elementsd_executable: impl Into<PathBuf>,
+		mut config: elementsd::Config,
+		timeout: time::Duration,
+	) -> Result<PegEnvironment, Error> {
+		if bitcoind.status()? != Status::Running {
+			bitcoind.start()?;
+		}
+		bitcoind.wait_for_ready(timeout)?;
+
+		let (_, auth) =
+			bitcoind.rpc_info().ok_or(Error::Config("bitcoind has no RPC configured"))?;
+		let (user, pass) = match auth {
+			Auth::UserPass(user, pass) => (user, pass),
+			Auth::CookieFile(path) => {
+				let cookie = fs::read_to_string(&path)?;
+				let mut parts = cookie.trim().splitn(2, ':');
+				match (parts.next(), parts.next()) {
+					(Some(user), Some(pass)) => (user.to_owned(), pass.to_owned()),
+					_ => return Err(Error::Custom("invalid bitcoind cookie file")),
+				}
+			}
+			Auth::None => return Err(Error::Config("bitcoind has no RPC authentication")),
+		};
+		config.validatepegin = true;
+		config.mainchain_rpchost = Some("127.0.0.1".into());
+		config.mainchain_rpcport = bitcoind.config().rpcport;
+		config.mainchain_rpcuser = Some(user);
+		config.mainchain_rpcpass = Some(pass);
+
+		let name = match bitcoind.name() {
+			"" => String::new(),
+			n => format!("{}-elementsd", n),
+		};
+		let mut elementsd = elementsd::Daemon::named(name, elementsd_executable, config)?;
+		elementsd.start()?;
+		elementsd.wait_for_ready(timeout)?;
+		Ok(PegEnvironment {
+			bitcoind,
+			elementsd,
+		})
+	}
+
+	pub fn bitcoind(&self) -> &bitcoind::Daemon {
+		&self.bitcoind
+	}
+
+	pub fn bitcoind_mut(&mut self) -> &mut bitcoind::Daemon {
+		&mut self.bitcoind
+	}
+
+	pub fn elementsd(&self) -> &elementsd::Daemon {
+		&self.elementsd
+	}
+
+	pub fn elementsd_mut(&mut self) -> &mut elementsd::Daemon {
+		&mut self.elementsd
+	}
+
+	/// Stop the elementsd and then the bitcoind.
+	pub fn stop(&self) -> Result<(), Error> {
+		self.elementsd.stop()?;
+		self.bitcoind.stop()?;
+		Ok(())
+	}
+
+	/// The number of mainchain confirmations the elementsd requires for peg-ins.
+	///
+	/// This is taken from `getsidechaininfo`, falling back to the config.
+	pub fn pegin_confirmation_depth(&self) -> Result<u64, Error> {
+		let rpc = self.elementsd.rpc_client().ok_or(Error::Config("RPC is not configured"))??;
+		let info = rpc.call::<serde_json::Value>("getsidechaininfo", &[])?;
+		Ok(info["pegin_confirmation_depth"].as_u64().unwrap_or_else(|| {
+			self.elementsd
+				.config()
+				.peginconfirmationdepth
+				.map(|d| d as u64)
+				.unwrap_or(DEFAULT_PEGIN_CONFIRMATION_DEPTH)
+		}))
+	}
+
+	/// Start tracking the mainchain confirmations of a peg-in transaction.
+	///
+	/// The tracker is done when the transaction has the number of
+	/// confirmations the elementsd requires to claim the peg-in.
+	pub fn track_pegin(&self, txid: Txid) -> Result<PeginTracker, Error> {
+		let depth = self.pegin_confirmation_depth()?;
+		Ok(PeginTracker::start(self.bitcoind.observer()?, txid, depth))
+	}
+
+	/// Take both daemons out of the environment.
+	pub fn into_inner(self) -> (bitcoind::Daemon, elementsd::Daemon) {
+		(self.bitcoind, self.elementsd)
+	}
+}
+
+/// A callback called with the txid once a peg-in transaction is deep enough.
+pub type PeginCallback = Box<dyn FnOnce(&Txid) + Send>;
+
+/// The state shared between a [PeginTracker] and its thread.
+#[derive(Default)]
+struct Shared {
+	stop: AtomicBool,
+	confirmed: AtomicBool,
+	confirmations: AtomicU64,
+	callback: Mutex<Option<PeginCallback>>,
+}
+
+/// Tracks the mainchain confirmations of a peg-in transaction in a background
+/// thread, until it reaches the peg-in confirmation depth.
+///
+/// The thread is stopped when the tracker is dropped.
+pub struct PeginTracker {
+	txid: Txid,
+	depth: u64,
+	shared: Arc<Shared>,
+	thread: Option<thread::JoinHandle<()>>,
+}
+
+impl PeginTracker {
+	/// Start tracking the transaction on the given bitcoind.
+	///
+	/// The confirmations are taken from the bitcoind wallet if it knows the
+	/// transaction, otherwise from `getrawtransaction`, which only finds
+	/// transactions outside of the wallet when `txindex` is enabled.
+	pub fn start(bitcoind: Observer<bitcoind::State>, txid: Txid, depth: u64) -> PeginTracker {
+		let shared = Arc::new(Shared::default());
+		let shared_cloned = shared.clone();
+		let thread = thread::Builder::new()
+			.name(format!("{}_pegin_tracker", bitcoind.name()))
+			.spawn(move || run(bitcoind, txid, depth, shared_cloned))
+			.expect("failed to start pegin tracker thread");
+
+		PeginTracker {
+			txid,
+			depth,
+			shared,
+			thread: Some(thread),
+		}
+	}
+
+	pub fn txid(&self) -> Txid {
+		self.txid
+	}
+
+	/// The number of confirmations the transaction needs.
+	pub fn depth(&self) -> u64 {
+		self.depth
+	}
+
+	/// Call the callback once the transaction is deep enough.
+	///
+	/// The callback is called from the tracker thread, or right away if the
+	/// transaction is already deep enough.
+	pub fn on_confirmed(&self, callback: impl FnOnce(&Txid) + Send + 'static) {
+		let mut cb = self.shared.callback.lock().unwrap();
+		if self.is_confirmed() {
+			callback(&self.txid);
+		} else {
+			*cb = Some(Box::new(callback));
+		}
+	}
+
+	/// The number of confirmations seen last.
+	pub fn confirmations(&self) -> u64 {
+		self.shared.confirmations.load(Ordering::SeqCst)
+	}
+
+	/// Whether the transaction reached the confirmation depth.
+	pub fn is_confirmed(&self) -> bool {
+		self.shared.confirmed.load(Ordering::SeqCst)
+	}
+
+	/// Wait until the transaction reached the confirmation depth.
+	pub fn wait(&self, timeout: time::Duration) -> Result<(), Error> {
+		let start = time::Instant::now();
+		while !self.is_confirmed() {
+			if start.elapsed() > timeout {
+				return Err(Error::Timeout("peg-in to be confirmed"));
+			}
+			thread::sleep(PEGIN_POLL_INTERVAL);
+		}
+		Ok(())
+	}
+
+	fn stop_thread(&mut self) {
+		self.shared.stop.store(true, Ordering::SeqCst);
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+impl fmt::Debug for PeginTracker {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "PeginTracker({}, {}/{} confs)", self.txid, self.confirmations(), self.depth)
+	}
+}
+
+impl Drop for PeginTracker {
+	fn drop(&mut self) {
+		self.stop_thread();
+	}
+}
+
+/// The main loop of the tracker thread.
+fn run(bitcoind: Observer<bitcoind::State>, txid: Txid, depth: u64, shared: Arc<Shared>) {
+	while !shared.stop.load(Ordering::SeqCst) {
+		match confirmations(&bitcoind, &txid) {
+			Ok(confs) => {
+				shared.confirmations.store(confs, Ordering::SeqCst);
+				if confs >= depth {
+					info!("{}: peg-in {} has {} confirmations", bitcoind.name(), txid, confs);
+					// Hold the callback lock so that a callback can't be missed.
+					let mut cb = shared.callback.lock().unwrap();
+					shared.confirmed.store(true, Ordering::SeqCst);
+					if let Some(cb) = cb.take() {
+						cb(&txid);
+					}
+					return;
+				}
+			}
+			Err(e) => {
+				debug!("{}: failed to get confirmations of {}: {:?}", bitcoind.name(), txid, e)
+			}
+		}
+		thread::sleep(PEGIN_POLL_INTERVAL);
+	}
+}
+
+/// The number of confirmations of the transaction, 0 when it is unconfirmed.
+fn confirmations(bitcoind: &Observer<bitcoind::State>, txid: &Txid) -> Result<u64, Error> {
+	let rpc = bitcoind.rpc_client().ok_or(Error::Config("RPC is not configured"))??;
+	let args = [txid.to_string().into()];
+	let tx = match rpc.call::<serde_json::Value>("gettransaction", &args) {
+		Ok(tx) => tx,
+		Err(_) => {
+			rpc.call::<serde_json::Value>("getrawtransaction", &[args[0].clone(), true.into()])?
+		}
+	};
+	// Conflicted wallet transactions have negative confirmations.
+	Ok(tx["confirmations"].as_i64().unwrap_or(0).max(0) as u64)
+}
@@ -1,8 +1,13 @@
-use std::io::{BufRead, Read};
-use std::sync::{Arc, Mutex};
-use std::{fmt, io, mem, ops, process, thread, time};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Read, Seek};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::{cmp, fmt, fs, io, mem, ops, process, thread, time};
 
-use error::Error;
+use crate::error::Error;
+use crate::logs::{self, LogEvent};
+use crate::observer::Observer;
+use crate::utils;
 
 /// An wrapper for child that is killed when it's dropped.
 struct KillOnDropChild(process::Child);
@@ -24,12 +29,168 @@ impl ops::Drop for KillOnDropChild {
 	}
 }
 
+/// The process of a daemon, spawned either by [DaemonRunner] or by
+/// [crate::AsyncRunner].
+enum Process {
+	Std(KillOnDropChild),
+	/// The child is spawned with `kill_on_drop` set.
+	#[cfg(feature = "async")]
+	Tokio(tokio::process::Child),
+}
+
+impl Process {
+	/// The OS process ID, [None] once the exit of a tokio child was seen.
+	fn id(&self) -> Option<u32> {
+		match *self {
+			Process::Std(ref c) => Some(c.get().id()),
+			#[cfg(feature = "async")]
+			Process::Tokio(ref c) => c.id(),
+		}
+	}
+
+	fn try_wait(&mut self) -> Result<Option<process::ExitStatus>, io::Error> {
+		match *self {
+			Process::Std(ref mut c) => c.get_mut().try_wait(),
+			#[cfg(feature = "async")]
+			Process::Tokio(ref mut c) => c.try_wait(),
+		}
+	}
+
+	/// Kill the process without waiting for it to exit.
+	fn kill(&mut self) -> Result<(), io::Error> {
+		match *self {
+			Process::Std(ref mut c) => c.get_mut().kill(),
+			#[cfg(feature = "async")]
+			Process::Tokio(ref mut c) => c.start_kill(),
+		}
+	}
+
+	/// Block until the process has exited.
+	fn wait(&mut self) -> Result<process::ExitStatus, io::Error> {
+		match *self {
+			Process::Std(ref mut c) => c.get_mut().wait(),
+			// Waiting on a tokio child requires an async context.
+			#[cfg(feature = "async")]
+			Process::Tokio(ref mut c) => loop {
+				if let Some(s) = c.try_wait()? {
+					return Ok(s);
+				}
+				thread::sleep(time::Duration::from_millis(10));
+			},
+		}
+	}
+}
+
 pub struct RuntimeData<S> {
 	pub state: S,
 
-	process: Option<KillOnDropChild>,
+	process: Option<Process>,
 	stdout_thread: Option<thread::JoinHandle<()>>,
 	stderr_thread: Option<thread::JoinHandle<()>>,
+
+	/// The policy the watchdog uses to restart the daemon.
+	restart_policy: RestartPolicy,
+	/// Whether a watchdog thread is running.
+	supervised: bool,
+	/// The number of times the daemon was restarted by the watchdog.
+	restarts: usize,
+
+	/// Callbacks to notify of status changes.
+	status_callbacks: Vec<Box<dyn Fn(Status) + Send>>,
+	/// Whether a status monitoring thread is running.
+	monitored: bool,
+
+	/// The timings of every start of the daemon, the last one is the latest.
+	startup_timings: Vec<StartupTiming>,
+}
+
+impl<S> RuntimeData<S> {
+	pub(crate) fn new(state: S) -> RuntimeData<S> {
+		RuntimeData {
+			state,
+			process: None,
+			stdout_thread: None,
+			stderr_thread: None,
+			restart_policy: RestartPolicy::Never,
+			supervised: false,
+			restarts: 0,
+			status_callbacks: Vec::new(),
+			monitored: false,
+			startup_timings: Vec::new(),
+		}
+	}
+
+	/// Record that a new process of the daemon was spawned.
+	fn started(&mut self, process: Process) {
+		self.process = Some(process);
+		self.startup_timings.push(StartupTiming {
+			spawned: time::Instant::now(),
+			first_log_line: None,
+			rpc_listening: None,
+			ready: None,
+		});
+	}
+
+	/// Record that a new process of the daemon was spawned on a tokio runtime.
+	#[cfg(feature = "async")]
+	pub(crate) fn started_async(&mut self, child: tokio::process::Child) {
+		self.started(Process::Tokio(child));
+	}
+
+	/// Whether the daemon process is currently running.
+	pub fn is_running(&mut self) -> bool {
+		match self.process.as_mut() {
+			Some(p) => p.try_wait().map(|s| s.is_none()).unwrap_or(false),
+			None => false,
+		}
+	}
+
+	/// The running status of the daemon process.
+	pub fn status(&mut self) -> Result<Status, io::Error> {
+		match self.process.as_mut() {
+			None => Ok(Status::Init),
+			Some(p) => match p.try_wait()? {
+				None => Ok(Status::Running),
+				Some(c) => Ok(Status::Stopped(c)),
+			},
+		}
+	}
+
+	/// The OS process ID of the daemon process.
+	pub fn pid(&self) -> Option<u32> {
+		self.process.as_ref().and_then(|p| p.id())
+	}
+
+	/// Record that the latest start of the daemon reached the given stage.
+	///
+	/// Only the first time a stage is reached is recorded.
+	pub fn record_startup(&mut self, stage: StartupStage) {
+		if let Some(t) = self.startup_timings.last_mut() {
+			let elapsed = t.spawned.elapsed();
+			let slot = match stage {
+				StartupStage::FirstLogLine => &mut t.first_log_line,
+				StartupStage::RpcListening => &mut t.rpc_listening,
+				StartupStage::Ready => &mut t.ready,
+			};
+			if slot.is_none() {
+				*slot = Some(elapsed);
+			}
+		}
+	}
+
+	/// Kill the daemon process if it's still running.
+	///
+	/// If [wait] is set, this blocks until the process has actually exited.
+	pub fn kill(&mut self, wait: bool) {
+		self.restart_policy = RestartPolicy::Never;
+		if let Some(ref mut process) = self.process {
+			// The process might have already exited.
+			let _ = process.kill();
+			if wait {
+				let _ = process.wait();
+			}
+		}
+	}
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -39,6 +200,270 @@ pub enum Status {
 	Stopped(process::ExitStatus),
 }
 
+impl Status {
+	/// How the daemon exited, if it stopped.
+	pub fn exit_kind(&self) -> Option<ExitKind> {
+		match *self {
+			Status::Stopped(s) => Some(s.into()),
+			Status::Init | Status::Running => None,
+		}
+	}
+}
+
+impl fmt::Display for Status {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Status::Init => write!(f, "not started"),
+			Status::Running => write!(f, "running"),
+			Status::Stopped(s) => write!(f, "stopped: {}", ExitKind::from(s)),
+		}
+	}
+}
+
+/// Serialized as a map with a `state` of `init`, `running` or `stopped`.
+/// When stopped, the fields of [ExitKind] are included.
+impl serde::Serialize for Status {
+	fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+		use serde::ser::SerializeMap;
+
+		let mut map = s.serialize_map(None)?;
+		match *self {
+			Status::Init => map.serialize_entry("state", "init")?,
+			Status::Running => map.serialize_entry("state", "running")?,
+			Status::Stopped(status) => {
+				map.serialize_entry("state", "stopped")?;
+				ExitKind::from(status).serialize_fields(&mut map)?;
+			}
+		}
+		map.end()
+	}
+}
+
+/// A stage in the startup of a daemon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupStage {
+	/// The daemon printed its first line of output.
+	FirstLogLine,
+	/// The RPC interface accepted a request, even if it wasn't ready yet.
+	RpcListening,
+	/// The daemon is ready.
+	///
+	/// bitcoind and elementsd record this as soon as an RPC request succeeds.
+	/// Other daemons only record it from their `wait_for_ready` method, so
+	/// it's not recorded for them if that method isn't used.
+	Ready,
+}
+
+/// How long it took a daemon to reach the stages of its startup.
+///
+/// The durations are measured from when the process was spawned.
+/// Stages that were not reached (yet) are [None].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StartupTiming {
+	/// When the process was spawned.
+	pub spawned: time::Instant,
+	pub first_log_line: Option<time::Duration>,
+	pub rpc_listening: Option<time::Duration>,
+	/// See [StartupStage::Ready] for when this is recorded.
+	pub ready: Option<time::Duration>,
+}
+
+/// The way in which a daemon process terminated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitKind {
+	/// The process exited with a zero exit code.
+	Clean,
+	/// The process exited with the given non-zero exit code.
+	/// Daemons usually use `EXIT_FAILURE`, which is 1.
+	Failure(i32),
+	/// The process was terminated by the given signal.
+	Signal(i32),
+}
+
+impl ExitKind {
+	/// Whether the process exited cleanly.
+	pub fn is_clean(&self) -> bool {
+		*self == ExitKind::Clean
+	}
+
+	/// Write the `exit` field and the `code` or `signal` field.
+	fn serialize_fields<M: serde::ser::SerializeMap>(&self, map: &mut M) -> Result<(), M::Error> {
+		match *self {
+			ExitKind::Clean => map.serialize_entry("exit", "clean"),
+			ExitKind::Failure(c) => {
+				map.serialize_entry("exit", "failure")?;
+				map.serialize_entry("code", &c)
+			}
+			ExitKind::Signal(s) => {
+				map.serialize_entry("exit", "signal")?;
+				map.serialize_entry("signal", &s)
+			}
+		}
+	}
+}
+
+impl From<process::ExitStatus> for ExitKind {
+	fn from(s: process::ExitStatus) -> ExitKind {
+		#[cfg(unix)]
+		{
+			use std::os::unix::process::ExitStatusExt;
+			if let Some(signal) = s.signal() {
+				return ExitKind::Signal(signal);
+			}
+		}
+
+		match s.code() {
+			Some(0) => ExitKind::Clean,
+			Some(c) => ExitKind::Failure(c),
+			// Only happens for signals on unix.
+			None => ExitKind::Failure(-1),
+		}
+	}
+}
+
+impl fmt::Display for ExitKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ExitKind::Clean => write!(f, "exited cleanly"),
+			ExitKind::Failure(c) => write!(f, "exited with code {}", c),
+			ExitKind::Signal(s) => write!(f, "killed by signal {}", s),
+		}
+	}
+}
+
+/// Serialized as a map with an `exit` of `clean`, `failure` or `signal`,
+/// and the exit `code` or the `signal` number.
+impl serde::Serialize for ExitKind {
+	fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+		use serde::ser::SerializeMap;
+
+		let mut map = s.serialize_map(None)?;
+		self.serialize_fields(&mut map)?;
+		map.end()
+	}
+}
+
+/// Ask the process with the given PID to shut down cleanly.
+#[cfg(unix)]
+fn request_shutdown(pid: u32) -> Result<(), io::Error> {
+	if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(())
+}
+
+/// Ask the process with the given PID to shut down cleanly.
+///
+/// The daemon runs in its own process group with the PID as its id,
+/// so that the event doesn't reach our own process.
+#[cfg(windows)]
+fn request_shutdown(pid: u32) -> Result<(), io::Error> {
+	const CTRL_BREAK_EVENT: u32 = 1;
+	extern "system" {
+		fn GenerateConsoleCtrlEvent(ctrl_event: u32, process_group_id: u32) -> i32;
+	}
+
+	if unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, pid) } == 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn request_shutdown(_pid: u32) -> Result<(), io::Error> {
+	Ok(())
+}
+
+/// What to do when a supervised daemon exits without being stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RestartPolicy {
+	/// Never restart the daemon.
+	#[default]
+	Never,
+	/// Restart the daemon when it exits with a failure, at most [max] times.
+	/// Before every restart, wait for [backoff].
+	OnFailure {
+		max: usize,
+		backoff: time::Duration,
+	},
+	/// Always restart the daemon when it exits.
+	Always,
+}
+
+/// The options of a daemon that are only used by the runner.
+///
+/// None of these are passed to the daemon.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct RunnerConfig {
+	/// When set, dropping the daemon blocks until the process has exited.
+	pub block_on_drop: bool,
+	/// The maximum number of bytes of stderr output to keep.
+	/// Defaults to [logs::DEFAULT_MAX_STDERR_BYTES].
+	pub max_stderr_bytes: Option<usize>,
+	/// The maximum number of error messages to keep.
+	/// Defaults to [logs::DEFAULT_MAX_ERROR_MSGS].
+	pub max_error_msgs: Option<usize>,
+}
+
+/// The name, labels and runtime data of a daemon that implements [CommonDaemon].
+///
+/// The daemon is killed when this is dropped.
+pub struct Common<S> {
+	name: String,
+	/// Arbitrary labels to identify the daemon in orchestrations.
+	labels: BTreeMap<String, String>,
+	/// The subscribers to log events, shared with the runtime state.
+	events: logs::Subscribers,
+	block_on_drop: bool,
+
+	pub(crate) runtime_data: Option<Arc<Mutex<RuntimeData<S>>>>,
+}
+
+impl<S> Common<S> {
+	pub(crate) fn new(config: &RunnerConfig) -> Common<S> {
+		Common {
+			name: "".into(),
+			labels: BTreeMap::new(),
+			events: logs::Subscribers::default(),
+			block_on_drop: config.block_on_drop,
+			runtime_data: None,
+		}
+	}
+
+	/// The output to keep in the state of a new run of the daemon.
+	pub(crate) fn new_output(&self, config: &RunnerConfig) -> logs::Output {
+		logs::Output::new(config, self.events.clone())
+	}
+
+	/// Write the kind of daemon and its name, and its labels in the
+	/// alternate format.
+	///
+	/// The plain format is used in thread names and log lines, so the
+	/// labels are left out there.
+	pub(crate) fn fmt_header(&self, f: &mut fmt::Formatter, kind: &str) -> fmt::Result {
+		if self.name.is_empty() {
+			write!(f, "<unnamed> {}", kind)?;
+		} else {
+			write!(f, "{} \"{}\"", kind, self.name)?;
+		}
+		if f.alternate() {
+			utils::fmt_labels(f, &self.labels)?;
+		}
+		Ok(())
+	}
+}
+
+impl<S> Drop for Common<S> {
+	fn drop(&mut self) {
+		if let Some(ref rt) = self.runtime_data {
+			if let Ok(mut rt) = rt.lock() {
+				rt.kill(self.block_on_drop);
+			}
+		}
+	}
+}
+
 /// Methods in this trait are intended to be used only
 /// by the [DaemonRunner] implementation.
 #[doc(hidden)]
@@ -50,13 +475,18 @@ pub trait RunnerHelper {
 	/// This is called before the [_init_state] method is called.
 	fn _prepare(&mut self) -> Result<(), Error>;
 
+	/// Remove all data of the daemon so that it can be prepared again.
+	///
+	/// This is only called when the daemon is not running.
+	fn _reset(&mut self) -> Result<(), Error>;
+
 	/// The command to run.
 	fn _command(&self) -> process::Command;
 
 	/// Create the initial state.
 	///
 	/// This is called after the [_prepare] method is called.
-	fn _init_state(&self) -> Self::State;
+	fn _init_state(&mut self) -> Self::State;
 
 	/// Notify that the daemon has started.
 	fn _notif_started(&mut self, runtime_data: Arc<Mutex<RuntimeData<Self::State>>>);
@@ -71,6 +501,263 @@ pub trait RunnerHelper {
 	/// Process some lines of stderr output.
 	/// All lines not processed will be discarded.
 	fn _process_stderr(state: &mut Self::State, line: &str);
+
+	/// The path of a log file to follow.
+	///
+	/// Lines appended to the file are processed by [_process_stdout].
+	fn _tail_log_path(&self) -> Option<PathBuf> {
+		None
+	}
+}
+
+/// Launch the daemon process and start the threads processing its output.
+///
+/// Returns the PID of the new process.
+fn launch<H: RunnerHelper>(
+	name: &str,
+	mut cmd: process::Command,
+	rt: &Arc<Mutex<RuntimeData<H::State>>>,
+) -> Result<u32, Error>
+where
+	H::State: 'static + Send,
+{
+	cmd.stdout(process::Stdio::piped());
+	cmd.stderr(process::Stdio::piped());
+	#[cfg(windows)]
+	{
+		// Run in a new process group so that we can send CTRL_BREAK to it.
+		use std::os::windows::process::CommandExt;
+		const CREATE_NEW_PROCESS_GROUP: u32 = 0x00000200;
+		cmd.creation_flags(CREATE_NEW_PROCESS_GROUP);
+	}
+	debug!("Launching daemon {} with command: {:?}", name, utils::redacted_command(&cmd));
+	let mut process = KillOnDropChild(
+		cmd.spawn().map_err(|e| Error::RunCommand(e, Box::new(utils::redacted_command(&cmd))))?,
+	);
+	let pid = process.get().id();
+
+	let mut stdout = process.0.stdout.take().unwrap();
+	let mut stderr = process.0.stderr.take().unwrap();
+
+	let mut rt_lock = rt.lock().unwrap();
+	rt_lock.started(Process::Std(process));
+
+	// Start stdout processing thread.
+	let rt_cloned = rt.clone();
+	rt_lock.stdout_thread.replace(
+		thread::Builder::new()
+			.name(format!("{}_stdout", name))
+			.spawn(move || {
+				let mut buf_read = io::BufReader::new(stdout);
+				for line in buf_read.lines() {
+					let mut lock = rt_cloned.lock().unwrap();
+					lock.record_startup(StartupStage::FirstLogLine);
+					H::_process_stdout(
+						thread::current().name().unwrap(),
+						&mut lock.state,
+						&line.unwrap(),
+					);
+				}
+				trace!("Thread {} stopped", thread::current().name().unwrap());
+			})
+			.expect("failed to start stdout read thread"),
+	);
+
+	// Start stderr processing thread.
+	let rt_cloned = rt.clone();
+	rt_lock.stderr_thread.replace(
+		thread::Builder::new()
+			.name(format!("{}_stderr", name))
+			.spawn(move || {
+				let mut buf_read = io::BufReader::new(stderr);
+				for line in buf_read.lines() {
+					let mut lock = rt_cloned.lock().unwrap();
+					lock.record_startup(StartupStage::FirstLogLine);
+					H::_process_stderr(&mut lock.state, &line.unwrap());
+				}
+				trace!("Thread {} stopped", thread::current().name().unwrap());
+			})
+			.expect("failed to start stderr read thread"),
+	);
+
+	Ok(pid)
+}
+
+/// Create a new command with the same program, arguments and environment.
+fn clone_command(cmd: &process::Command) -> process::Command {
+	let mut ret = process::Command::new(cmd.get_program());
+	ret.args(cmd.get_args());
+	for (key, value) in cmd.get_envs() {
+		match value {
+			Some(v) => ret.env(key, v),
+			None => ret.env_remove(key),
+		};
+	}
+	if let Some(dir) = cmd.get_current_dir() {
+		ret.current_dir(dir);
+	}
+	ret
+}
+
+/// Relaunch the daemon according to its restart policy when it exits.
+fn watchdog<H: RunnerHelper>(
+	name: String,
+	cmd: process::Command,
+	rt: Arc<Mutex<RuntimeData<H::State>>>,
+) where
+	H::State: 'static + Send,
+{
+	loop {
+		thread::sleep(time::Duration::from_millis(100));
+
+		// When we hold the only reference, the daemon was dropped.
+		if Arc::strong_count(&rt) == 1 {
+			break;
+		}
+
+		let mut lock = rt.lock().unwrap();
+		let policy = lock.restart_policy;
+		if policy == RestartPolicy::Never {
+			break;
+		}
+		let exit = match lock.process.as_mut().map(|p| p.try_wait()) {
+			Some(Ok(Some(exit))) => ExitKind::from(exit),
+			_ => continue,
+		};
+		let backoff = match policy {
+			RestartPolicy::Never => unreachable!(),
+			RestartPolicy::Always => time::Duration::from_secs(0),
+			RestartPolicy::OnFailure {
+				..
+			} if exit.is_clean() => break,
+			RestartPolicy::OnFailure {
+				max,
+				..
+			} if lock.restarts >= max => {
+				error!("Daemon {} {}, but it was restarted {} times already", name, exit, max);
+				break;
+			}
+			RestartPolicy::OnFailure {
+				backoff,
+				..
+			} => backoff,
+		};
+		drop(lock);
+
+		warn!("Daemon {} {}, restarting in {:?}...", name, exit, backoff);
+		thread::sleep(backoff);
+
+		// The daemon might have been stopped or restarted in the meantime.
+		{
+			let mut lock = rt.lock().unwrap();
+			if lock.restart_policy == RestartPolicy::Never || lock.is_running() {
+				continue;
+			}
+			lock.restarts += 1;
+		}
+		match launch::<H>(&name, clone_command(&cmd), &rt) {
+			Ok(pid) => info!("Daemon {} restarted. PID: {}", name, pid),
+			Err(e) => {
+				error!("Failed to restart daemon {}: {:?}", name, e);
+				break;
+			}
+		}
+	}
+	rt.lock().unwrap().supervised = false;
+	trace!("Thread {}_watchdog stopped", name);
+}
+
+/// Follow the log file at [path] from [offset] and process new lines like stdout.
+///
+/// The file doesn't have to exist yet. When it's truncated or replaced,
+/// it is followed from the start again.
+fn tail<H: RunnerHelper>(path: PathBuf, mut offset: u64, rt: Arc<Mutex<RuntimeData<H::State>>>) {
+	let name = thread::current().name().unwrap().to_owned();
+	let mut partial = Vec::new();
+	loop {
+		thread::sleep(time::Duration::from_millis(100));
+
+		// When we hold the only reference, the daemon was dropped.
+		if Arc::strong_count(&rt) == 1 {
+			break;
+		}
+
+		let mut file = match fs::File::open(&path) {
+			Ok(f) => f,
+			Err(_) => continue,
+		};
+		let len = match file.metadata() {
+			Ok(m) => m.len(),
+			Err(_) => continue,
+		};
+		if len < offset {
+			offset = 0;
+			partial.clear();
+		}
+		if len == offset {
+			continue;
+		}
+		if let Err(e) = file.seek(io::SeekFrom::Start(offset)) {
+			warn!("Failed to read log file {}: {}", path.display(), e);
+			continue;
+		}
+		match file.take(len - offset).read_to_end(&mut partial) {
+			Ok(n) => offset += n as u64,
+			Err(e) => {
+				warn!("Failed to read log file {}: {}", path.display(), e);
+				continue;
+			}
+		}
+
+		let mut lock = rt.lock().unwrap();
+		if partial.contains(&b'\n') {
+			lock.record_startup(StartupStage::FirstLogLine);
+		}
+		while let Some(i) = partial.iter().position(|b| *b == b'\n') {
+			let line = partial.drain(..=i).collect::<Vec<_>>();
+			let line = String::from_utf8_lossy(&line);
+			H::_process_stdout(&name, &mut lock.state, line.trim_end());
+		}
+	}
+	trace!("Thread {} stopped", name);
+}
+
+/// Call the status callbacks when the status of the daemon changes.
+fn monitor<S>(name: String, mut last: Status, rt: Arc<Mutex<RuntimeData<S>>>) {
+	loop {
+		thread::sleep(time::Duration::from_millis(100));
+
+		// When we hold the only reference, the daemon was dropped.
+		if Arc::strong_count(&rt) == 1 {
+			break;
+		}
+
+		let mut lock = rt.lock().unwrap();
+		let status = match lock.status() {
+			Ok(s) => s,
+			Err(e) => {
+				warn!("Failed to get status of daemon {}: {}", name, e);
+				continue;
+			}
+		};
+		if status == last {
+			continue;
+		}
+		last = status;
+		debug!("Status of daemon {} changed to {:?}", name, status);
+
+		// Call the callbacks without holding the lock.
+		let mut callbacks = mem::take(&mut lock.status_callbacks);
+		drop(lock);
+		for callback in &callbacks {
+			callback(status);
+		}
+		let mut lock = rt.lock().unwrap();
+		callbacks.append(&mut lock.status_callbacks);
+		lock.status_callbacks = callbacks;
+	}
+	rt.lock().unwrap().monitored = false;
+	trace!("Thread {}_monitor stopped", name);
 }
 
 pub trait DaemonRunner: RunnerHelper + fmt::Debug + Sized
@@ -81,56 +768,7 @@ where
 	/// This intended for internal use only, use [start] and [restart] instead.
 	fn _start_up(&self, rt: Arc<Mutex<RuntimeData<Self::State>>>) -> Result<(), Error> {
 		info!("Starting daemon {:?}...", self);
-
-		let mut cmd = self._command();
-		cmd.stdout(process::Stdio::piped());
-		cmd.stderr(process::Stdio::piped());
-		debug!("Launching daemon {:?} with command: {:?}", self, cmd);
-		let mut process = KillOnDropChild(cmd.spawn().map_err(|e| Error::RunCommand(e, cmd))?);
-		let pid = process.get().id();
-
-		let mut stdout = process.0.stdout.take().unwrap();
-		let mut stderr = process.0.stderr.take().unwrap();
-
-		let mut rt_lock = rt.lock().unwrap();
-		rt_lock.process = Some(process);
-
-		// Start stdout processing thread.
-		let rt_cloned = rt.clone();
-		rt_lock.stdout_thread.replace(
-			thread::Builder::new()
-				.name(format!("{:?}_stdout", self))
-				.spawn(move || {
-					thread::sleep(time::Duration::from_secs(1));
-					let mut buf_read = io::BufReader::new(stdout);
-					for line in buf_read.lines() {
-						Self::_process_stdout(
-							thread::current().name().unwrap(),
-							&mut rt_cloned.lock().unwrap().state,
-							&line.unwrap(),
-						);
-					}
-					trace!("Thread {} stopped", thread::current().name().unwrap());
-				})
-				.expect(&format!("failed to start stdout read thread")),
-		);
-
-		// Start stderr processing thread.
-		let rt_cloned = rt.clone();
-		rt_lock.stderr_thread.replace(
-			thread::Builder::new()
-				.name(format!("{:?}_stderr", self))
-				.spawn(move || {
-					thread::sleep(time::Duration::from_secs(1));
-					let mut buf_read = io::BufReader::new(stderr);
-					for line in buf_read.lines() {
-						Self::_process_stderr(&mut rt_cloned.lock().unwrap().state, &line.unwrap());
-					}
-					trace!("Thread {} stopped", thread::current().name().unwrap());
-				})
-				.expect(&format!("failed to start stderr read thread")),
-		);
-
+		let pid = launch::<Self>(&format!("{:?}", self), self._command(), &rt)?;
 		info!("Daemon {:?} started. PID: {}", self, pid);
 		Ok(())
 	}
@@ -138,6 +776,7 @@ where
 	/// Start the daemon for the first time.
 	///
 	/// If the daemon was previously stopped, this method simply restarts it.
+	/// Use [start_fresh] to start with a fresh state instead.
 	fn start(&mut self) -> Result<(), Error> {
 		match self.status()? {
 			Status::Running => return Ok(()), // already running
@@ -148,20 +787,48 @@ where
 			Status::Init => {} // fall through
 		}
 
+		self._start_new()
+	}
+
+	/// Prepare the daemon and start it with new runtime data.
+	/// This intended for internal use only, use [start] and [start_fresh] instead.
+	fn _start_new(&mut self) -> Result<(), Error> {
 		self._prepare()?;
 
-		let rt = Arc::new(Mutex::new(RuntimeData {
-			process: None,
-			stdout_thread: None,
-			stderr_thread: None,
-			state: self._init_state(),
-		}));
+		let rt = Arc::new(Mutex::new(RuntimeData::new(self._init_state())));
+
+		if let Some(path) = self._tail_log_path() {
+			// Only follow what is logged from now on.
+			let offset = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+			let rt_cloned = rt.clone();
+			thread::Builder::new()
+				.name(format!("{:?}_tail", self))
+				.spawn(move || tail::<Self>(path, offset, rt_cloned))
+				.expect("failed to start log tail thread");
+		}
 
 		self._start_up(rt.clone())?;
 		self._notif_started(rt);
 		Ok(())
 	}
 
+	/// Start the daemon with a wiped datadir and fresh state.
+	///
+	/// If the daemon is running, it is stopped first.
+	/// The config is kept and written again.
+	fn start_fresh(&mut self) -> Result<(), Error> {
+		if self._get_runtime().is_some() {
+			if self.status()? == Status::Running {
+				self.stop()?;
+			}
+			self.wait_for_exit(None)?;
+		}
+
+		info!("Wiping data of daemon {:?}...", self);
+		self._reset()?;
+		self._start_new()
+	}
+
 	/// Stop the daemon.
 	/// State is preserved so that it can be restarted with [restart].
 	/// If the daemon already stopped, this is a no-op.
@@ -174,16 +841,139 @@ where
 
 		let rt_ref = self._get_runtime().unwrap();
 		let mut rt = rt_ref.lock().unwrap();
+		rt.restart_policy = RestartPolicy::Never;
 
 		info!("Stopping daemon {:?}...", self);
-		let proc = rt.process.as_mut().unwrap().get_mut();
+		let proc = rt.process.as_mut().unwrap();
 		proc.kill()?;
-		proc.wait()?;
+		let exit = proc.wait()?;
 
-		info!("Daemon {:?} stopped", self);
+		info!("Daemon {:?} stopped: {}", self, ExitKind::from(exit));
 		Ok(())
 	}
 
+	/// Stop the daemon gracefully.
+	///
+	/// The daemon is first asked to shut down and is given [timeout] to do so
+	/// cleanly. If it's still running after that, it's killed.
+	/// On Unix, the daemon is sent a SIGTERM; on Windows, a CTRL_BREAK event.
+	/// On other platforms, this is equivalent to [stop].
+	/// If the daemon already stopped, this is a no-op.
+	fn stop_with_timeout(&self, timeout: time::Duration) -> Result<(), Error> {
+		match self.status()? {
+			Status::Init => return Err(Error::InvalidState(Status::Init)),
+			Status::Running => {}
+			Status::Stopped(_) => return Ok(()),
+		}
+
+		if let Some(rt) = self._get_runtime() {
+			rt.lock().unwrap().restart_policy = RestartPolicy::Never;
+		}
+
+		if cfg!(any(unix, windows)) {
+			info!("Requesting daemon {:?} to shut down...", self);
+			request_shutdown(self.pid().unwrap())?;
+
+			let start = time::Instant::now();
+			while start.elapsed() < timeout {
+				if let Status::Stopped(exit) = self.status()? {
+					info!("Daemon {:?} stopped: {}", self, ExitKind::from(exit));
+					return Ok(());
+				}
+				thread::sleep(time::Duration::from_millis(50));
+			}
+			warn!("Daemon {:?} didn't stop within {:?}", self, timeout);
+		}
+
+		self.stop()
+	}
+
+	/// Supervise the daemon with the given restart policy.
+	///
+	/// A watchdog thread notices when the daemon exits unexpectedly and
+	/// relaunches it according to the policy. Stopping the daemon through
+	/// this runner ends the supervision.
+	fn supervise(&self, policy: RestartPolicy) -> Result<(), Error> {
+		let rt = self._get_runtime().ok_or(Error::InvalidState(Status::Init))?;
+		let mut lock = rt.lock().unwrap();
+		lock.restart_policy = policy;
+		if lock.supervised || policy == RestartPolicy::Never {
+			return Ok(());
+		}
+		lock.supervised = true;
+		drop(lock);
+
+		let name = format!("{:?}", self);
+		let cmd = self._command();
+		thread::Builder::new()
+			.name(format!("{}_watchdog", name))
+			.spawn(move || watchdog::<Self>(name, cmd, rt))
+			.expect("failed to start watchdog thread");
+		Ok(())
+	}
+
+	/// The number of times the daemon was restarted by its watchdog.
+	fn restart_count(&self) -> usize {
+		self._get_runtime().map(|rt| rt.lock().unwrap().restarts).unwrap_or(0)
+	}
+
+	/// Register a callback that is called when the status of the daemon changes.
+	///
+	/// A monitoring thread checks the status of the daemon and calls the
+	/// callback from that thread when the daemon stops or is started again.
+	/// The callback should not block for long.
+	fn on_status_change(&self, callback: impl Fn(Status) + Send + 'static) -> Result<(), Error> {
+		let rt = self._get_runtime().ok_or(Error::InvalidState(Status::Init))?;
+		let mut lock = rt.lock().unwrap();
+		lock.status_callbacks.push(Box::new(callback));
+		if lock.monitored {
+			return Ok(());
+		}
+		lock.monitored = true;
+		let status = lock.status()?;
+		drop(lock);
+
+		let name = format!("{:?}", self);
+		thread::Builder::new()
+			.name(format!("{}_monitor", name))
+			.spawn(move || monitor(name, status, rt))
+			.expect("failed to start monitor thread");
+		Ok(())
+	}
+
+	/// Wait for the daemon to exit.
+	///
+	/// If a [timeout] is given, this fails with [Error::Timeout] when the
+	/// daemon is still running after it. Once the daemon has exited, this also
+	/// waits until all its output has been processed.
+	fn wait_for_exit(&self, timeout: Option<time::Duration>) -> Result<process::ExitStatus, Error> {
+		let rt = self._get_runtime().ok_or(Error::InvalidState(Status::Init))?;
+
+		// We poll instead of waiting on the process so that we don't hold
+		// the lock the output threads need to process output.
+		let start = time::Instant::now();
+		let exit = loop {
+			if let Status::Stopped(exit) = self.status()? {
+				break exit;
+			}
+			if timeout.map(|t| start.elapsed() > t).unwrap_or(false) {
+				return Err(Error::Timeout("daemon to exit"));
+			}
+			thread::sleep(time::Duration::from_millis(50));
+		};
+
+		let threads = {
+			let mut lock = rt.lock().unwrap();
+			(lock.stdout_thread.take(), lock.stderr_thread.take())
+		};
+		for thread in threads.0.into_iter().chain(threads.1) {
+			if thread.join().is_err() {
+				warn!("Output processing thread of daemon {:?} panicked", self);
+			}
+		}
+		Ok(exit)
+	}
+
 	/// The the running status of the daemon.
 	fn status(&self) -> Result<Status, Error> {
 		let rt = match self._get_runtime() {
@@ -191,18 +981,223 @@ where
 			None => return Ok(Status::Init),
 		};
 
-		let mut lock = rt.lock().unwrap();
-		match lock.process.as_mut().unwrap().0.try_wait()? {
-			None => Ok(Status::Running),
-			Some(c) => Ok(Status::Stopped(c)),
-		}
+		let status = rt.lock().unwrap().status()?;
+		Ok(status)
 	}
 
 	/// Get the OS process ID of the daemon.
 	fn pid(&self) -> Option<u32> {
-		self._get_runtime().map(|rt| rt.lock().unwrap().process.as_ref().unwrap().get().id())
+		self._get_runtime().and_then(|rt| rt.lock().unwrap().pid())
+	}
+
+	/// Run the closure with a reference to the runtime state.
+	///
+	/// Returns [None] if the daemon was never started.
+	fn with_state<R>(&self, f: impl FnOnce(&Self::State) -> R) -> Option<R> {
+		self._get_runtime().map(|rt| f(&rt.lock().unwrap().state))
+	}
+
+	/// Run the closure with a mutable reference to the runtime state.
+	///
+	/// Returns [None] if the daemon was never started.
+	fn with_state_mut<R>(&self, f: impl FnOnce(&mut Self::State) -> R) -> Option<R> {
+		self._get_runtime().map(|rt| f(&mut rt.lock().unwrap().state))
+	}
+
+	/// The timings of every start of the daemon, oldest first.
+	///
+	/// Timings are kept across restarts, but not when started with [start_fresh].
+	fn startup_timings(&self) -> Vec<StartupTiming> {
+		match self._get_runtime() {
+			Some(rt) => rt.lock().unwrap().startup_timings.clone(),
+			None => Vec::new(),
+		}
 	}
 
-	//TODO(stevenroose) try make a generic method
-	// fn state(&self) -> Option<XXX> where XXX has Deref<Self::State> somehow
+	/// Record that the latest start of the daemon reached the given stage.
+	fn record_startup(&self, stage: StartupStage) {
+		if let Some(rt) = self._get_runtime() {
+			rt.lock().unwrap().record_startup(stage);
+		}
+	}
+}
+
+/// The accessors of daemons that keep their name, labels and runtime data
+/// in a [Common] and their output in a [logs::Output].
+pub trait CommonDaemon: DaemonRunner
+where
+	<Self as RunnerHelper>::State: 'static + Send + AsRef<logs::Output> + AsMut<logs::Output>,
+{
+	#[doc(hidden)]
+	fn _common(&self) -> &Common<Self::State>;
+
+	#[doc(hidden)]
+	fn _common_mut(&mut self) -> &mut Common<Self::State>;
+
+	/// The RPC info to give to observers, see [Observer::rpc_info].
+	#[doc(hidden)]
+	fn _rpc_info(&self) -> Option<(String, bitcoincore_rpc::Auth)> {
+		None
+	}
+
+	fn set_name(&mut self, name: String) {
+		self._common_mut().name = name;
+	}
+
+	fn name(&self) -> &str {
+		&self._common().name
+	}
+
+	/// Set a label, like `role=miner`, to identify the daemon in orchestrations.
+	fn set_label(&mut self, key: impl Into<String>, value: impl Into<String>) {
+		self._common_mut().labels.insert(key.into(), value.into());
+	}
+
+	fn label(&self, key: &str) -> Option<&str> {
+		self._common().labels.get(key).map(|v| v.as_str())
+	}
+
+	fn labels(&self) -> &BTreeMap<String, String> {
+		&self._common().labels
+	}
+
+	fn labels_mut(&mut self) -> &mut BTreeMap<String, String> {
+		&mut self._common_mut().labels
+	}
+
+	/// Subscribe to the events parsed from the log output of the daemon.
+	///
+	/// The subscription lasts across restarts of the daemon.
+	fn subscribe_events(&self) -> mpsc::Receiver<LogEvent> {
+		self._common().events.subscribe()
+	}
+
+	/// Create a read-only handle to the running daemon.
+	///
+	/// The handle can't be used to start or stop the daemon.
+	fn observer(&self) -> Result<Observer<Self::State>, Error> {
+		let rt = self._get_runtime().ok_or(Error::InvalidState(Status::Init))?;
+		let events = self._common().events.clone();
+		Ok(Observer::new(format!("{:?}", self), self._rpc_info(), events, &rt))
+	}
+
+	fn take_stderr(&self) -> String {
+		self.with_state_mut(|s| s.as_mut().take_stderr()).unwrap_or_default()
+	}
+
+	fn take_error_msgs(&self) -> Vec<String> {
+		self.with_state_mut(|s| s.as_mut().take_error_msgs()).unwrap_or_default()
+	}
+
+	/// The number of error messages that have not been taken yet.
+	fn error_count(&self) -> usize {
+		self.with_state(|s| s.as_ref().error_msgs().len()).unwrap_or(0)
+	}
+}
+
+/// Wait until the condition holds, polling with an increasing interval.
+///
+/// This fails when the daemon exits or when the condition doesn't hold
+/// after [timeout]. [what] describes the condition in logs and errors.
+pub(crate) fn wait_until<D>(
+	daemon: &D,
+	what: &'static str,
+	timeout: time::Duration,
+	mut cond: impl FnMut() -> bool,
+) -> Result<(), Error>
+where
+	D: DaemonRunner,
+	D::State: 'static + Send,
+{
+	let start = time::Instant::now();
+	let mut interval = time::Duration::from_millis(50);
+	loop {
+		if let Status::Stopped(s) = daemon.status()? {
+			return Err(Error::Exited(s.into()));
+		}
+		if cond() {
+			debug!("Daemon {:?}: {} after {:?}", daemon, what, start.elapsed());
+			return Ok(());
+		}
+		if start.elapsed() > timeout {
+			return Err(Error::Timeout(what));
+		}
+		thread::sleep(interval);
+		interval = cmp::min(interval * 2, time::Duration::from_secs(1));
+	}
+}
+
+#[cfg(all(test, unix))]
+pub(crate) mod tests {
+	use super::*;
+
+	/// A daemon that prints a line and sleeps.
+	#[derive(Default)]
+	pub(crate) struct Sleeper {
+		runtime_data: Option<Arc<Mutex<RuntimeData<Vec<String>>>>>,
+	}
+
+	impl RunnerHelper for Sleeper {
+		type State = Vec<String>;
+
+		fn _prepare(&mut self) -> Result<(), Error> {
+			Ok(())
+		}
+
+		fn _reset(&mut self) -> Result<(), Error> {
+			Ok(())
+		}
+
+		fn _command(&self) -> process::Command {
+			let mut cmd = process::Command::new("sh");
+			cmd.args(["-c", "echo started; exec sleep 30"]);
+			cmd
+		}
+
+		fn _init_state(&mut self) -> Self::State {
+			Vec::new()
+		}
+
+		fn _notif_started(&mut self, runtime_data: Arc<Mutex<RuntimeData<Self::State>>>) {
+			self.runtime_data.replace(runtime_data);
+		}
+
+		fn _get_runtime(&self) -> Option<Arc<Mutex<RuntimeData<Self::State>>>> {
+			self.runtime_data.clone()
+		}
+
+		fn _process_stdout(_name: &str, state: &mut Self::State, line: &str) {
+			state.push(line.to_owned());
+		}
+
+		fn _process_stderr(_state: &mut Self::State, _line: &str) {}
+	}
+
+	impl DaemonRunner for Sleeper {}
+
+	impl fmt::Debug for Sleeper {
+		fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+			write!(f, "sleeper")
+		}
+	}
+
+	#[test]
+	fn start_stop() {
+		let mut daemon = Sleeper::default();
+		assert_eq!(daemon.status().unwrap(), Status::Init);
+		assert_eq!(daemon.pid(), None);
+
+		daemon.start().unwrap();
+		assert_eq!(daemon.status().unwrap(), Status::Running);
+		assert!(daemon.pid().is_some());
+		wait_until(&daemon, "output", time::Duration::from_secs(5), || {
+			daemon.with_state(|s| !s.is_empty()).unwrap()
+		})
+		.unwrap();
+		daemon.stop().unwrap();
+		assert!(matches!(daemon.status().unwrap(), Status::Stopped(_)));
+		daemon.wait_for_exit(None).unwrap();
+		assert_eq!(daemon.with_state(|s| s.clone()).unwrap(), vec!["started".to_owned()]);
+		assert!(daemon.startup_timings()[0].first_log_line.is_some());
+	}
 }
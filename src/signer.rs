@@ -0,0 +1,435 @@
+//! A simulated hardware signer for external signer wallets.
+//!
+//! bitcoind can use a hardware wallet through the HWI command interface,
+//! configured with `-signer=<cmd>`. The [Signer] emulates this interface with
+//! an in-memory key so that external signer flows can be tested without any
+//! hardware.
+//!
+//! The key lives in a background thread of the current process. The command
+//! given to bitcoind is a small bash script that forwards its arguments and
+//! input to that thread over a loopback socket. This module is only available
+//! on unix platforms.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use bitcoin::consensus::encode;
+use bitcoin::secp256k1::{self, Secp256k1};
+use bitcoin::util::bip143;
+use bitcoin::util::bip32::{
+	ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey, Fingerprint,
+};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{Address, SigHashType};
+
+use crate::error::Error;
+
+/// The file name of the signer command in the signer directory.
+pub const COMMAND_FILENAME: &str = "hwi-sim";
+
+/// The model the signer reports in `enumerate`.
+pub const MODEL: &str = "daemon-runner-sim";
+
+/// The name HWI uses for the network in the `--chain` argument.
+fn chain_name(network: bitcoin::Network) -> &'static str {
+	match network {
+		bitcoin::Network::Bitcoin => "main",
+		bitcoin::Network::Testnet => "test",
+		bitcoin::Network::Regtest => "regtest",
+	}
+}
+
+/// The key material of the signer, shared with the server thread.
+struct Keys {
+	network: bitcoin::Network,
+	master: ExtendedPrivKey,
+	fingerprint: Fingerprint,
+	secp: Secp256k1<secp256k1::All>,
+	/// The commands that were handled, without the global options.
+	commands: Mutex<Vec<Vec<String>>>,
+}
+
+/// The response to a command, in the JSON format of HWI.
+type Response = serde_json::Value;
+
+/// An error response in the JSON format of HWI.
+fn error_response(msg: impl Into<String>) -> Response {
+	serde_json::json!({ "error": msg.into(), "code": -1 })
+}
+
+impl Keys {
+	/// The BIP84 account path for the given account.
+	///
+	/// Returns [None] if the account is not below 2^31, so that it can't be hardened.
+	fn account_path(&self, account: u32) -> Option<DerivationPath> {
+		let coin = match self.network {
+			bitcoin::Network::Bitcoin => 0,
+			_ => 1,
+		};
+		Some(DerivationPath::from(vec![
+			ChildNumber::from_hardened_idx(84).unwrap(),
+			ChildNumber::from_hardened_idx(coin).unwrap(),
+			ChildNumber::from_hardened_idx(account).ok()?,
+		]))
+	}
+
+	/// The receive and change descriptors of the given account.
+	fn descriptors(&self, account: u32) -> Option<(String, String)> {
+		let path = self.account_path(account)?;
+		let xpriv = self.master.derive_priv(&self.secp, &path).expect("derivation can't fail");
+		let xpub = ExtendedPubKey::from_private(&self.secp, &xpriv);
+		let origin = path.to_string().replacen("m", &self.fingerprint.to_string(), 1);
+		Some((format!("wpkh([{}]{}/0/*)", origin, xpub), format!("wpkh([{}]{}/1/*)", origin, xpub)))
+	}
+
+	/// Derive the address of a descriptor with a single key, like
+	/// `wpkh([d34db33f/84'/1'/0']tpub.../0/5)`.
+	fn descriptor_address(&self, desc: &str) -> Option<Address> {
+		let desc = desc.split('#').next()?;
+		let inner = desc.strip_prefix("wpkh(")?.strip_suffix(')')?;
+		let key = match inner.find(']') {
+			Some(i) => &inner[i + 1..],
+			None => inner,
+		};
+		let mut parts = key.split('/');
+		let xpub = ExtendedPubKey::from_str(parts.next()?).ok()?;
+		let path = parts
+			.map(|p| u32::from_str(p).ok().and_then(|i| ChildNumber::from_normal_idx(i).ok()))
+			.collect::<Option<Vec<_>>>()?;
+		let pubkey = xpub.derive_pub(&self.secp, &path).ok()?.public_key;
+		Some(Address::p2wpkh(&pubkey, self.network))
+	}
+
+	/// Sign all P2WPKH inputs of the PSBT that belong to our key.
+	fn sign_psbt(&self, psbt: &mut PartiallySignedTransaction) -> Result<usize, &'static str> {
+		let tx = psbt.global.unsigned_tx.clone();
+		let sighashes = bip143::SighashComponents::new(&tx);
+		let mut signed = 0;
+		for (idx, input) in psbt.inputs.iter_mut().enumerate() {
+			let value = match input.witness_utxo {
+				Some(ref utxo) => utxo.value,
+				None => continue,
+			};
+			let ours = input
+				.hd_keypaths
+				.iter()
+				.filter(|(_, (fp, _))| *fp == self.fingerprint)
+				.map(|(pk, (_, path))| (*pk, path.clone()))
+				.collect::<Vec<_>>();
+			for (pubkey, path) in ours {
+				let xpriv = self.master.derive_priv(&self.secp, &path).map_err(|_| "bad path")?;
+				if xpriv.private_key.public_key(&self.secp) != pubkey {
+					return Err("derived key doesn't match PSBT key");
+				}
+				let script_code = Address::p2pkh(&pubkey, self.network).script_pubkey();
+				let sighash = sighashes.sighash_all(&tx.input[idx], &script_code, value);
+				let msg = secp256k1::Message::from_slice(&sighash[..]).expect("32 bytes");
+				let sig = self.secp.sign(&msg, &xpriv.private_key.key);
+				let mut sig = sig.serialize_der().to_vec();
+				sig.push(SigHashType::All as u8);
+				input.partial_sigs.insert(pubkey, sig);
+				signed += 1;
+			}
+		}
+		Ok(signed)
+	}
+
+	/// Handle a single invocation of the signer command.
+	fn handle(&self, args: &[String], stdin: &str) -> Response {
+		let mut args = args.to_vec();
+		if args.iter().any(|a| a == "--stdin") {
+			args.retain(|a| a != "--stdin");
+			args.extend(stdin.split_whitespace().map(|a| a.trim_matches('"').to_owned()));
+		}
+
+		// Split the options from the command and its arguments.
+		let mut options = Vec::new();
+		let mut command = Vec::new();
+		let mut iter = args.into_iter();
+		while let Some(arg) = iter.next() {
+			if !arg.starts_with("--") {
+				command.push(arg);
+				continue;
+			}
+			let (name, value) = match arg.find('=') {
+				Some(i) => (arg[2..i].to_owned(), arg[i + 1..].to_owned()),
+				None => (arg[2..].to_owned(), iter.next().unwrap_or_default()),
+			};
+			if command.is_empty() {
+				options.push((name, value));
+			} else {
+				command.push(format!("--{}={}", name, value));
+			}
+		}
+		self.commands.lock().unwrap().push(command.clone());
+		let option = |name: &str| options.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+		let cmd_option = |name: &str| {
+			let prefix = format!("--{}=", name);
+			command.iter().find_map(|a| a.strip_prefix(prefix.as_str()).map(|v| v.to_owned()))
+		};
+
+		if let Some(fp) = option("fingerprint") {
+			if fp != self.fingerprint.to_string() {
+				return error_response(format!("unknown fingerprint {}", fp));
+			}
+		}
+		if let Some(chain) = option("chain") {
+			if chain != chain_name(self.network) {
+				return error_response(format!("signer is not configured for chain {}", chain));
+			}
+		}
+
+		match command.first().map(|c| c.as_str()) {
+			Some("enumerate") => serde_json::json!([{
+				"type": MODEL,
+				"model": MODEL,
+				"label": null,
+				"path": MODEL,
+				"fingerprint": self.fingerprint.to_string(),
+				"needs_pin_sent": false,
+				"needs_passphrase_sent": false,
+			}]),
+			Some("getdescriptors") => {
+				let account = match cmd_option("account").as_deref().map(u32::from_str) {
+					None => 0,
+					Some(Ok(a)) => a,
+					Some(Err(_)) => return error_response("invalid account"),
+				};
+				match self.descriptors(account) {
+					Some((receive, internal)) => {
+						serde_json::json!({ "receive": [receive], "internal": [internal] })
+					}
+					None => error_response("invalid account"),
+				}
+			}
+			Some("displayaddress") => {
+				match cmd_option("desc").and_then(|d| self.descriptor_address(&d)) {
+					Some(addr) => serde_json::json!({ "address": addr.to_string() }),
+					None => error_response("unsupported descriptor"),
+				}
+			}
+			Some("signtx") => {
+				let psbt = command
+					.get(1)
+					.and_then(|p| base64::decode(p).ok())
+					.and_then(|b| encode::deserialize::<PartiallySignedTransaction>(&b).ok());
+				let mut psbt = match psbt {
+					Some(p) => p,
+					None => return error_response("invalid PSBT"),
+				};
+				match self.sign_psbt(&mut psbt) {
+					Ok(_) => serde_json::json!({
+						"psbt": base64::encode(&encode::serialize(&psbt)),
+						"signed": true,
+					}),
+					Err(e) => error_response(e),
+				}
+			}
+			Some(c) => error_response(format!("unsupported command {}", c)),
+			None => error_response("no command given"),
+		}
+	}
+}
+
+/// The bash script that forwards an invocation to the signer thread.
+///
+/// Arguments are sent NUL-terminated, followed by an empty argument.
+/// Then the input is sent if `--stdin` is given, followed by a NUL byte.
+fn command_script(port: u16) -> String {
+	format!(
+		r#"#!/usr/bin/env bash
+# HWI signer simulation of daemon-runner.
+set -e
+exec 3<>/dev/tcp/127.0.0.1/{port}
+for arg in "$@"; do printf '%s\0' "$arg" >&3; done
+printf '\0' >&3
+for arg in "$@"; do
+	if [ "$arg" = "--stdin" ]; then cat >&3; fi
+done
+printf '\0' >&3
+cat <&3
+"#,
+		port = port
+	)
+}
+
+/// Read a single request of the command script and answer it.
+fn serve(keys: &Keys, stream: TcpStream) -> Result<(), Error> {
+	let mut reader = BufReader::new(stream.try_clone()?);
+	let mut args = Vec::new();
+	loop {
+		let mut buf = Vec::new();
+		if reader.read_until(0, &mut buf)? == 0 {
+			return Err(Error::Custom("signer request ended early"));
+		}
+		buf.pop();
+		if buf.is_empty() {
+			break;
+		}
+		args.push(String::from_utf8_lossy(&buf).into_owned());
+	}
+	let mut stdin = Vec::new();
+	reader.read_until(0, &mut stdin)?;
+	if stdin.last() == Some(&0) {
+		stdin.pop();
+	}
+
+	let response = keys.handle(&args, &String::from_utf8_lossy(&stdin));
+	let mut stream = stream;
+	writeln!(stream, "{}", response)?;
+	Ok(())
+}
+
+/// A simulated hardware signer.
+///
+/// The signer serves requests until it is dropped.
+pub struct Signer {
+	keys: Arc<Keys>,
+	command: PathBuf,
+	port: u16,
+	stop: Arc<AtomicBool>,
+	thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Signer {
+	/// Start a signer with a random key for the given network.
+	///
+	/// The signer command is written into [dir].
+	pub fn new(dir: &Path, network: bitcoin::Network) -> Result<Signer, Error> {
+		Signer::from_seed(dir, network, &rand::random::<[u8; 32]>())
+	}
+
+	/// Start a signer with the master key derived from the given BIP32 seed.
+	///
+	/// The signer command is written into [dir].
+	pub fn from_seed(dir: &Path, network: bitcoin::Network, seed: &[u8]) -> Result<Signer, Error> {
+		let master = ExtendedPrivKey::new_master(network, seed)
+			.map_err(|_| Error::Config("invalid signer seed"))?;
+		let secp = Secp256k1::new();
+		let keys = Arc::new(Keys {
+			network,
+			fingerprint: master.fingerprint(&secp),
+			master,
+			secp,
+			commands: Mutex::new(Vec::new()),
+		});
+
+		let listener = TcpListener::bind("127.0.0.1:0")?;
+		let port = listener.local_addr()?.port();
+
+		fs::create_dir_all(dir)?;
+		let command = dir.join(COMMAND_FILENAME);
+		fs::write(&command, command_script(port))?;
+		fs::set_permissions(&command, fs::Permissions::from_mode(0o755))?;
+
+		let stop = Arc::new(AtomicBool::new(false));
+		let stop_cloned = stop.clone();
+		let keys_cloned = keys.clone();
+		let thread = thread::Builder::new()
+			.name(format!("signer_{}", keys.fingerprint))
+			.spawn(move || {
+				for stream in listener.incoming() {
+					if stop_cloned.load(Ordering::SeqCst) {
+						break;
+					}
+					match stream {
+						Ok(s) => {
+							if let Err(e) = serve(&keys_cloned, s) {
+								warn!("Error serving signer request: {}", e);
+							}
+						}
+						Err(e) => warn!("Error accepting signer connection: {}", e),
+					}
+				}
+			})
+			.expect("failed to start signer thread");
+
+		Ok(Signer {
+			keys,
+			command,
+			port,
+			stop,
+			thread: Some(thread),
+		})
+	}
+
+	/// The path of the command to configure as `-signer=` in bitcoind.
+	pub fn command(&self) -> &Path {
+		&self.command
+	}
+
+	/// The fingerprint of the master key.
+	pub fn fingerprint(&self) -> Fingerprint {
+		self.keys.fingerprint
+	}
+
+	/// The network the signer is configured for.
+	pub fn network(&self) -> bitcoin::Network {
+		self.keys.network
+	}
+
+	/// The receive and change descriptors the signer reports for the account.
+	///
+	/// Fails if the account is not below 2^31.
+	pub fn descriptors(&self, account: u32) -> Result<(String, String), Error> {
+		self.keys.descriptors(account).ok_or(Error::Config("account should be below 2^31"))
+	}
+
+	/// Handle a command directly, without going through the signer command.
+	///
+	/// Takes the arguments and input the signer command would get.
+	pub fn handle(&self, args: &[String], stdin: &str) -> serde_json::Value {
+		self.keys.handle(args, stdin)
+	}
+
+	/// The commands that were handled so far, without the global options.
+	pub fn commands(&self) -> Vec<Vec<String>> {
+		self.keys.commands.lock().unwrap().clone()
+	}
+}
+
+impl Drop for Signer {
+	fn drop(&mut self) {
+		self.stop.store(true, Ordering::SeqCst);
+		// Wake up the server thread so that it notices it has to stop.
+		let _ = TcpStream::connect(("127.0.0.1", self.port));
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::utils;
+
+	#[test]
+	fn descriptors_account_range() {
+		let dir = utils::create_temp_dir("signer").unwrap();
+		let signer = Signer::from_seed(&dir, bitcoin::Network::Regtest, &[1; 32]).unwrap();
+
+		let (receive, change) = signer.descriptors((1 << 31) - 1).unwrap();
+		let origin = format!("[{}/84'/1'/2147483647']", signer.fingerprint());
+		assert!(receive.starts_with(&format!("wpkh({}", origin)) && receive.ends_with("/0/*)"));
+		assert!(change.starts_with(&format!("wpkh({}", origin)) && change.ends_with("/1/*)"));
+		assert!(signer.descriptors(1 << 31).is_err());
+
+		let args =
+			|account: &str| vec!["getdescriptors".into(), "--account".into(), account.into()];
+		assert_eq!(signer.handle(&args("2147483648"), "")["error"], "invalid account");
+		assert_eq!(signer.handle(&args("x"), "")["error"], "invalid account");
+		assert_eq!(signer.handle(&args("0"), "")["receive"][0], signer.descriptors(0).unwrap().0);
+
+		drop(signer);
+		utils::remove_temp_dir(&dir);
+	}
+}
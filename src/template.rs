@@ -0,0 +1,220 @@
+//! Cached regtest datadirs to speed up test startup.
+//!
+//! Mining a chain and funding wallets from genesis for every test takes most
+//! of the time of many test suites. A [RegtestTemplate] is built once, with
+//! the result cached on disk. Later daemons start from a copy of it.
+
+use std::path::{Path, PathBuf};
+use std::{env, fs, io, time};
+
+use bitcoin::blockdata::script::Script;
+use bitcoincore_rpc::RpcApi;
+
+use crate::bitcoind;
+use crate::error::Error;
+use crate::runner::DaemonRunner;
+use crate::utils;
+
+/// The environment variable to override the directory templates are cached in.
+pub const CACHE_DIR_ENV_VAR: &str = "DAEMON_RUNNER_TEMPLATE_DIR";
+
+/// The name of the default cache directory in the temporary directory.
+pub const DEFAULT_CACHE_DIRNAME: &str = "daemon-runner-templates";
+
+/// Templates older than this are rebuilt.
+///
+/// The tip of an older template is too old for the node to consider itself
+/// synced, which some RPCs depend on.
+pub const MAX_TEMPLATE_AGE: time::Duration = time::Duration::from_secs(12 * 60 * 60);
+
+/// The time the daemon building a template is given to start and stop.
+const BUILD_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// The files in a datadir that belong to a specific process.
+const PROCESS_FILES: &[&str] = &[utils::DATADIR_LOCK_FILENAME, ".cookie", "debug.log"];
+
+/// A regtest chain with a number of mined blocks and funded wallets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegtestTemplate {
+	/// The number of blocks to mine.
+	pub blocks: u64,
+	/// The wallets to create. The blocks are mined to these wallets in turn.
+	/// Without wallets, the blocks are mined to an anyone-can-spend address.
+	pub wallets: Vec<String>,
+}
+
+impl RegtestTemplate {
+	pub fn new(blocks: u64) -> RegtestTemplate {
+		RegtestTemplate {
+			blocks,
+			wallets: Vec::new(),
+		}
+	}
+
+	/// Add a wallet to fund.
+	pub fn wallet(mut self, name: impl Into<String>) -> Self {
+		self.wallets.push(name.into());
+		self
+	}
+
+	/// The directory templates are cached in.
+	///
+	/// This is the directory in the `DAEMON_RUNNER_TEMPLATE_DIR` environment
+	/// variable, or [DEFAULT_CACHE_DIRNAME] in the temporary directory.
+	pub fn cache_dir() -> PathBuf {
+		match env::var_os(CACHE_DIR_ENV_VAR) {
+			Some(dir) => PathBuf::from(dir),
+			None => env::temp_dir().join(DEFAULT_CACHE_DIRNAME),
+		}
+	}
+
+	/// The name of the cached template for the given bitcoind version.
+	fn key(&self, version: u64) -> String {
+		let mut key = format!("bitcoind-{}-{}", version, self.blocks);
+		for wallet in &self.wallets {
+			key.push('-');
+			key.extend(wallet.chars().map(|c| {
+				if c.is_ascii_alphanumeric() {
+					c
+				} else {
+					'_'
+				}
+			}));
+		}
+		key
+	}
+
+	/// Prepare the datadir of the config with a copy of the template.
+	///
+	/// The template is built first with the given executable if it isn't
+	/// cached yet. The config should be for regtest and the datadir should
+	/// not contain a chain yet. The wallets of the template are added to
+	/// [bitcoind::Config::wallets] so that they are loaded at startup.
+	pub fn prepare_bitcoind(
+		&self,
+		executable: &Path,
+		config: &mut bitcoind::Config,
+	) -> Result<(), Error> {
+		if config.network != Some(bitcoin::Network::Regtest) || config.signet {
+			return Err(Error::Config("templates are only supported on regtest"));
+		}
+		let version = utils::detect_version(executable)?;
+		let template = self.ensure_built(executable, version)?;
+
+		let network_dir = config.datadir.join("regtest");
+		if network_dir.join("blocks").exists() {
+			return Err(Error::Config("datadir already contains a chain"));
+		}
+		utils::copy_dir(&template, &network_dir)?;
+		for wallet in &self.wallets {
+			if !config.wallets.contains(wallet) {
+				config.wallets.push(wallet.clone());
+			}
+		}
+		Ok(())
+	}
+
+	/// Return the directory of the built template, building it if necessary.
+	fn ensure_built(&self, executable: &Path, version: u64) -> Result<PathBuf, Error> {
+		let cache = RegtestTemplate::cache_dir();
+		fs::create_dir_all(&cache)?;
+		let key = self.key(version);
+		let dir = cache.join(&key);
+
+		// Other processes might be building the same template.
+		let _lock = lock_file(&cache.join(format!("{}.lock", key)))?;
+		if let Ok(meta) = fs::metadata(&dir) {
+			let age = meta.modified()?.elapsed().unwrap_or_default();
+			if age < MAX_TEMPLATE_AGE {
+				return Ok(dir);
+			}
+			info!("Rebuilding template {} that is {:?} old", key, age);
+			fs::remove_dir_all(&dir)?;
+		}
+
+		info!("Building template {}...", key);
+		let datadir = utils::create_temp_dir("template")?;
+		let res = self.build(executable, version, &datadir).and_then(|_| {
+			let network_dir = datadir.join("regtest");
+			for file in PROCESS_FILES {
+				match fs::remove_file(network_dir.join(file)) {
+					Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+					_ => {}
+				}
+			}
+			// Build next to the final location so that the move is atomic.
+			let tmp = cache.join(format!("{}.tmp", key));
+			let _ = fs::remove_dir_all(&tmp);
+			utils::copy_dir(&network_dir, &tmp)?;
+			fs::rename(&tmp, &dir)?;
+			Ok(())
+		});
+		let _ = fs::remove_dir_all(&datadir);
+		res?;
+		Ok(dir)
+	}
+
+	/// Mine the chain and fund the wallets in the given datadir.
+	fn build(&self, executable: &Path, version: u64, datadir: &Path) -> Result<(), Error> {
+		let ports = utils::find_free_ports(2);
+		let config = bitcoind::Config {
+			version,
+			datadir: datadir.to_owned(),
+			network: Some(bitcoin::Network::Regtest),
+			port: Some(ports[0]),
+			rpcport: Some(ports[1]),
+			rpcuser: Some("user".into()),
+			rpcpass: Some(format!("{:016x}", rand::random::<u64>())),
+			hermetic: true,
+			disablewallet: Some(self.wallets.is_empty()),
+			..Default::default()
+		};
+		let mut daemon = bitcoind::Daemon::new(executable, config)?;
+		daemon.start()?;
+		daemon.wait_for_ready(BUILD_TIMEOUT)?;
+
+		let mut addresses = Vec::with_capacity(self.wallets.len());
+		for wallet in &self.wallets {
+			let rpc = daemon.rpc_client().ok_or(Error::Config("RPC is not configured"))??;
+			rpc.call::<serde_json::Value>("createwallet", &[wallet.as_str().into()])?;
+			let rpc = daemon
+				.rpc_client_for_wallet(wallet)
+				.ok_or(Error::Config("RPC is not configured"))??;
+			addresses.push(rpc.call::<String>("getnewaddress", &[])?);
+		}
+		if addresses.is_empty() {
+			let op_true = Script::from(vec![0x51]);
+			addresses
+				.push(bitcoin::Address::p2wsh(&op_true, bitcoin::Network::Regtest).to_string());
+		}
+
+		let rpc = daemon.rpc_client().ok_or(Error::Config("RPC is not configured"))??;
+		for i in 0..self.blocks {
+			let address = &addresses[i as usize % addresses.len()];
+			rpc.call::<serde_json::Value>(
+				"generatetoaddress",
+				&[1.into(), address.as_str().into()],
+			)?;
+		}
+		daemon.stop_for_snapshot(BUILD_TIMEOUT)
+	}
+}
+
+/// Open and exclusively lock the file, creating it if necessary.
+///
+/// The lock is released when the file is closed.
+#[cfg(unix)]
+fn lock_file(path: &Path) -> Result<fs::File, io::Error> {
+	use std::os::unix::io::AsRawFd;
+
+	let file = fs::OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+	if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(file)
+}
+
+#[cfg(not(unix))]
+fn lock_file(path: &Path) -> Result<fs::File, io::Error> {
+	fs::OpenOptions::new().write(true).create(true).truncate(false).open(path)
+}
@@ -0,0 +1,402 @@
+//! Running tor daemons.
+//!
+//! The tor daemon provides a SOCKS proxy and a control port, and can host
+//! an onion service for the p2p port of a bitcoind or elementsd. Use
+//! [Daemon::configure_bitcoind] and [Daemon::configure_elementsd] to make
+//! a node use the tor daemon.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::{fmt, fs, io, process, time};
+
+use regex::Regex;
+
+use crate::error::Error;
+use crate::logs;
+use crate::runner::{
+	self, Common, CommonDaemon, DaemonRunner, RunnerConfig, RunnerHelper, RuntimeData, StartupStage,
+};
+use crate::{bitcoind, elementsd, utils};
+
+pub const CONFIG_FILENAME: &str = "torrc";
+
+/// The name of the onion service directory in the data dir.
+pub const HIDDEN_SERVICE_DIRNAME: &str = "hidden_service";
+
+/// The name of the file holding the onion address in the onion service directory.
+pub const HOSTNAME_FILENAME: &str = "hostname";
+
+/// The environment variable that can be used to point to the tor executable.
+pub const EXECUTABLE_ENV_VAR: &str = "TOR_EXE";
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+	/// The directory tor keeps all its data in.
+	pub data_dir: PathBuf,
+
+	/// The port of the SOCKS proxy.
+	/// When not set, a free port is allocated when the daemon is started.
+	pub socks_port: Option<u16>,
+	/// The port of the control interface, which uses cookie authentication.
+	/// When not set, a free port is allocated when the daemon is started.
+	pub control_port: Option<u16>,
+	/// The ports of the onion service, as the virtual port and the local
+	/// port it's forwarded to. No onion service is created when empty.
+	pub hidden_service_ports: Vec<(u16, u16)>,
+
+	/// The options that are only used by the runner.
+	#[serde(flatten)]
+	pub runner: RunnerConfig,
+}
+
+impl Config {
+	pub fn write_into<W: io::Write>(&self, mut w: W) -> Result<(), io::Error> {
+		writeln!(w, "DataDirectory {}", self.data_dir.display())?;
+		writeln!(w, "Log notice stdout")?;
+		if let Some(p) = self.socks_port {
+			writeln!(w, "SocksPort 127.0.0.1:{}", p)?;
+		}
+		if let Some(p) = self.control_port {
+			writeln!(w, "ControlPort 127.0.0.1:{}", p)?;
+			writeln!(w, "CookieAuthentication 1")?;
+		}
+		if !self.hidden_service_ports.is_empty() {
+			let dir = self.data_dir.join(HIDDEN_SERVICE_DIRNAME);
+			writeln!(w, "HiddenServiceDir {}", dir.display())?;
+			for (virt, target) in &self.hidden_service_ports {
+				writeln!(w, "HiddenServicePort {} 127.0.0.1:{}", virt, target)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl utils::ConfigFile for Config {}
+
+/// A builder for [Config].
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+	config: Config,
+}
+
+impl ConfigBuilder {
+	/// Create a builder for a config without any options set.
+	pub fn new<P: Into<PathBuf>>(data_dir: P) -> ConfigBuilder {
+		ConfigBuilder {
+			config: Config {
+				data_dir: data_dir.into(),
+				..Default::default()
+			},
+		}
+	}
+
+	pub fn socks_port(mut self, v: u16) -> Self {
+		self.config.socks_port = Some(v);
+		self
+	}
+
+	pub fn control_port(mut self, v: u16) -> Self {
+		self.config.control_port = Some(v);
+		self
+	}
+
+	/// Forward the given virtual port of the onion service to the local port.
+	pub fn hidden_service_port(mut self, virt: u16, target: u16) -> Self {
+		self.config.hidden_service_ports.push((virt, target));
+		self
+	}
+
+	/// Create an onion service for the p2p port of the given bitcoind.
+	///
+	/// The p2p port of the bitcoind should be set.
+	pub fn onion_service_for_bitcoind(self, config: &bitcoind::Config) -> Self {
+		match config.port {
+			Some(p) => self.hidden_service_port(p, p),
+			None => self,
+		}
+	}
+
+	/// Create an onion service for the p2p port of the given elementsd.
+	///
+	/// The p2p port of the elementsd should be set.
+	pub fn onion_service_for_elementsd(self, config: &elementsd::Config) -> Self {
+		match config.port {
+			Some(p) => self.hidden_service_port(p, p),
+			None => self,
+		}
+	}
+
+	/// See [RunnerConfig::block_on_drop].
+	pub fn block_on_drop(mut self, v: bool) -> Self {
+		self.config.runner.block_on_drop = v;
+		self
+	}
+
+	/// Validate the options and build the config.
+	pub fn build(self) -> Result<Config, Error> {
+		let c = self.config;
+		if !c.data_dir.is_absolute() {
+			return Err(Error::Config("data_dir should be an absolute path"));
+		}
+		Ok(c)
+	}
+}
+
+#[derive(Default)]
+pub struct State {
+	/// The bootstrap progress in percent from the last bootstrap line.
+	pub bootstrap_progress: Option<u8>,
+
+	/// The captured output of the daemon.
+	pub output: logs::Output,
+}
+
+impl AsRef<logs::Output> for State {
+	fn as_ref(&self) -> &logs::Output {
+		&self.output
+	}
+}
+
+impl AsMut<logs::Output> for State {
+	fn as_mut(&mut self) -> &mut logs::Output {
+		&mut self.output
+	}
+}
+
+pub struct Daemon {
+	common: Common<State>,
+	executable: PathBuf,
+	config: Config,
+
+	/// The path of the written config file.
+	/// [None] before it has been written.
+	config_file: Option<PathBuf>,
+}
+
+/// Parse the bootstrap progress in percent from a log line.
+pub fn parse_bootstrap_progress(msg: &str) -> Option<u8> {
+	lazy_static! {
+		/// The regular expression for bootstrap messages.
+		static ref BOOTSTRAP_REGEX: Regex = Regex::new(r"Bootstrapped (\d+)%").unwrap();
+	}
+
+	BOOTSTRAP_REGEX.captures(msg).and_then(|c| u8::from_str(&c[1]).ok())
+}
+
+impl Daemon {
+	pub fn new<P: Into<PathBuf>>(executable: P, config: Config) -> Result<Daemon, Error> {
+		if !config.data_dir.is_absolute() {
+			return Err(Error::Config("data_dir should be an absolute path"));
+		}
+
+		Ok(Daemon {
+			common: Common::new(&config.runner),
+			executable: executable.into(),
+			config,
+
+			config_file: None,
+		})
+	}
+
+	/// Wait until the daemon is bootstrapped into the tor network and
+	/// the onion service, if any, has its address.
+	///
+	/// This fails when the daemon exits or when it isn't ready after [timeout].
+	pub fn wait_for_ready(&self, timeout: time::Duration) -> Result<(), Error> {
+		runner::wait_until(self, "daemon to become ready", timeout, || {
+			let bootstrapped = self.bootstrap_progress() == Some(100);
+			let onion =
+				self.config.hidden_service_ports.is_empty() || self.onion_address().is_some();
+			bootstrapped && onion
+		})?;
+		self.record_startup(StartupStage::Ready);
+		Ok(())
+	}
+
+	pub fn config(&self) -> &Config {
+		&self.config
+	}
+
+	pub fn data_dir(&self) -> &Path {
+		self.config.data_dir.as_path()
+	}
+
+	/// The address of the SOCKS proxy, f.e. `127.0.0.1:9050`.
+	pub fn socks_address(&self) -> Option<String> {
+		self.config.socks_port.map(|p| format!("127.0.0.1:{}", p))
+	}
+
+	/// The address of the control port, f.e. `127.0.0.1:9051`.
+	pub fn control_address(&self) -> Option<String> {
+		self.config.control_port.map(|p| format!("127.0.0.1:{}", p))
+	}
+
+	/// The address of the onion service, once tor created it.
+	pub fn onion_address(&self) -> Option<String> {
+		let path = self.config.data_dir.join(HIDDEN_SERVICE_DIRNAME).join(HOSTNAME_FILENAME);
+		let hostname = fs::read_to_string(path).ok()?;
+		let hostname = hostname.trim();
+		if hostname.ends_with(".onion") {
+			Some(hostname.to_owned())
+		} else {
+			None
+		}
+	}
+
+	/// The bootstrap progress in percent.
+	pub fn bootstrap_progress(&self) -> Option<u8> {
+		self.with_state(|s| s.bootstrap_progress).flatten()
+	}
+
+	/// Configure the bitcoind to connect through this tor daemon.
+	///
+	/// Outgoing connections use the SOCKS proxy. When an onion service was
+	/// configured, it's advertised as external address. Otherwise bitcoind
+	/// creates its own onion service through the control port.
+	/// Note that onion services are disabled for hermetic configs.
+	pub fn configure_bitcoind(&self, config: &mut bitcoind::Config) -> Result<(), Error> {
+		config.proxy = Some(self.socks_address().ok_or(Error::Config("socks_port not set"))?);
+		match self.onion_address() {
+			Some(onion) => {
+				config.externalip = Some(onion);
+				config.listenonion = false;
+			}
+			None => {
+				config.torcontrol = self.control_address();
+				config.listenonion = config.torcontrol.is_some();
+			}
+		}
+		Ok(())
+	}
+
+	/// Configure the elementsd to connect through this tor daemon.
+	///
+	/// See [Daemon::configure_bitcoind].
+	pub fn configure_elementsd(&self, config: &mut elementsd::Config) -> Result<(), Error> {
+		config.proxy = Some(self.socks_address().ok_or(Error::Config("socks_port not set"))?);
+		match self.onion_address() {
+			Some(onion) => {
+				config.externalip = Some(onion);
+				config.listenonion = false;
+			}
+			None => {
+				config.torcontrol = self.control_address();
+				config.listenonion = config.torcontrol.is_some();
+			}
+		}
+		Ok(())
+	}
+}
+
+impl RunnerHelper for Daemon {
+	type State = State;
+
+	fn _prepare(&mut self) -> Result<(), Error> {
+		if self.config_file.is_some() {
+			return Ok(());
+		}
+
+		// tor refuses data dirs that are accessible by others.
+		fs::create_dir_all(&self.config.data_dir)?;
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			fs::set_permissions(&self.config.data_dir, fs::Permissions::from_mode(0o700))?;
+		}
+
+		if self.config.socks_port.is_none() {
+			self.config.socks_port = Some(utils::find_free_port());
+		}
+		if self.config.control_port.is_none() {
+			self.config.control_port = Some(utils::find_free_port());
+		}
+
+		// Write the config file once and store the path.
+		let path = self.config.data_dir.join(CONFIG_FILENAME);
+		let mut file = File::create(&path)?;
+		self.config.write_into(&mut file)?;
+		self.config_file = Some(path);
+		Ok(())
+	}
+
+	fn _reset(&mut self) -> Result<(), Error> {
+		if self.config.data_dir.exists() {
+			fs::remove_dir_all(&self.config.data_dir)?;
+		}
+		self.config_file = None;
+		Ok(())
+	}
+
+	fn _command(&self) -> process::Command {
+		let mut cmd = process::Command::new(self.executable.clone());
+		cmd.arg("-f").arg(self.config_file.as_ref().unwrap());
+		cmd
+	}
+
+	fn _init_state(&mut self) -> Self::State {
+		State {
+			bootstrap_progress: None,
+			output: self.common.new_output(&self.config.runner),
+		}
+	}
+
+	fn _notif_started(&mut self, runtime_data: Arc<Mutex<RuntimeData<Self::State>>>) {
+		self.common.runtime_data.replace(runtime_data);
+	}
+
+	fn _get_runtime(&self) -> Option<Arc<Mutex<RuntimeData<Self::State>>>> {
+		self.common.runtime_data.clone()
+	}
+
+	fn _process_stdout(name: &str, state: &mut Self::State, line: &str) {
+		if let Some(progress) = parse_bootstrap_progress(line) {
+			debug!("{}: bootstrapped {}%", name, progress);
+			state.bootstrap_progress = Some(progress);
+		}
+
+		lazy_static! {
+			/// Regular expression to match for error messages.
+			static ref ERROR_REGEX: Regex = Regex::new(r"\[err\]").unwrap();
+			/// Regular expression to match for warning messages.
+			static ref WARNING_REGEX: Regex = Regex::new(r"\[warn\]").unwrap();
+		}
+		state.output.process_line(line, &ERROR_REGEX, &WARNING_REGEX);
+	}
+
+	fn _process_stderr(state: &mut Self::State, line: &str) {
+		state.output.stderr.push(line.to_owned());
+	}
+}
+
+impl DaemonRunner for Daemon {}
+
+impl CommonDaemon for Daemon {
+	fn _common(&self) -> &Common<State> {
+		&self.common
+	}
+
+	fn _common_mut(&mut self) -> &mut Common<State> {
+		&mut self.common
+	}
+}
+
+impl fmt::Debug for Daemon {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		self.common.fmt_header(f, "tor")?;
+		if f.alternate() {
+			let c = &self.config;
+			utils::fmt_details(
+				f,
+				&[
+					("data_dir", Some(c.data_dir.display().to_string())),
+					("socks_port", c.socks_port.map(|p| p.to_string())),
+					("control_port", c.control_port.map(|p| p.to_string())),
+				],
+			)?;
+		}
+		Ok(())
+	}
+}
@@ -1,14 +1,817 @@
-use std::net;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{env, fs, io, net, process, time};
 
+use bitcoin::hashes::hex::ToHex;
 use rand::{thread_rng, Rng};
+use regex::Regex;
+
+use crate::error::Error;
+
+/// The environment variable that can be set to the path of a port registry file.
+///
+/// See [enable_port_registry].
+pub const PORT_REGISTRY_ENV_VAR: &str = "DAEMON_RUNNER_PORT_REGISTRY";
+
+/// The file name of the default port registry in the temp directory.
+pub const DEFAULT_PORT_REGISTRY_FILENAME: &str = "daemon-runner-ports";
+
+lazy_static! {
+	/// The path of the port registry, if enabled.
+	static ref PORT_REGISTRY: Mutex<Option<PathBuf>> =
+		Mutex::new(env::var_os(PORT_REGISTRY_ENV_VAR).map(PathBuf::from));
+}
+
+/// Coordinate port allocations with other processes using a registry file.
+///
+/// When several processes allocate ports at the same time, they can pick the
+/// same port before any of them binds to it. With the registry enabled,
+/// [find_free_port] records every allocated port in the file at [path] while
+/// holding a lock on it, so that processes sharing the registry never hand out
+/// the same port. Ports are released when the process that allocated them exits.
+///
+/// The registry can also be enabled by setting the [PORT_REGISTRY_ENV_VAR]
+/// environment variable. Use [default_port_registry] for a shared default.
+/// The registry is only supported on unix platforms.
+pub fn enable_port_registry<P: Into<PathBuf>>(path: P) {
+	*PORT_REGISTRY.lock().unwrap() = Some(path.into());
+}
+
+/// The default port registry path.
+pub fn default_port_registry() -> PathBuf {
+	env::temp_dir().join(DEFAULT_PORT_REGISTRY_FILENAME)
+}
+
+/// Check if nothing is bound to the port.
+fn is_port_free(port: u16) -> bool {
+	let addr: net::SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+	net::UdpSocket::bind(addr).is_ok()
+}
 
 /// Find a free IP port.
+///
+/// If a port registry is enabled, the port is also reserved in the registry.
+/// See [enable_port_registry].
 pub fn find_free_port() -> u16 {
+	let registry = PORT_REGISTRY.lock().unwrap().clone();
+	if let Some(path) = registry {
+		match reserve_registry_port(&path) {
+			Ok(port) => return port,
+			Err(e) => warn!("Failed to use port registry at {}: {}", path.display(), e),
+		}
+	}
+
 	loop {
 		let port = thread_rng().gen_range(49152, 65535);
-		let addr: net::SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-		if net::UdpSocket::bind(addr).is_ok() {
+		if is_port_free(port) {
 			return port;
 		}
 	}
 }
+
+/// Find the given number of distinct free IP ports.
+///
+/// Separate calls to [find_free_port] can return the same port, so the ports
+/// are kept bound until all of them are found.
+pub fn find_free_ports(n: usize) -> Vec<u16> {
+	let mut bound = Vec::with_capacity(n);
+	while bound.len() < n {
+		let port = find_free_port();
+		if let Ok(socket) = net::UdpSocket::bind(("127.0.0.1", port)) {
+			bound.push((port, socket));
+		}
+	}
+	bound.into_iter().map(|(port, _)| port).collect()
+}
+
+/// Check if a process with the given PID exists.
+#[cfg(unix)]
+fn process_exists(pid: libc::pid_t) -> bool {
+	let ret = unsafe { libc::kill(pid, 0) };
+	ret == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+/// Reserve a free port in the registry file at [path].
+///
+/// The file has a line `<port> <pid>` for every reserved port.
+#[cfg(unix)]
+fn reserve_registry_port(path: &Path) -> Result<u16, io::Error> {
+	use std::io::{Read, Seek, Write};
+	use std::os::unix::io::AsRawFd;
+
+	let mut file =
+		fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+	if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	// The lock is released when the file is closed.
+	let mut content = String::new();
+	file.read_to_string(&mut content)?;
+
+	// Drop the reservations of processes that no longer exist.
+	let mut reserved = content
+		.lines()
+		.filter_map(|l| {
+			let mut parts = l.split_whitespace();
+			let port = parts.next()?.parse::<u16>().ok()?;
+			let pid = parts.next()?.parse::<libc::pid_t>().ok()?;
+			Some((port, pid))
+		})
+		.filter(|&(_, pid)| process_exists(pid))
+		.collect::<Vec<_>>();
+
+	let port = loop {
+		let port = thread_rng().gen_range(49152, 65535);
+		if !reserved.iter().any(|&(p, _)| p == port) && is_port_free(port) {
+			break port;
+		}
+	};
+	reserved.push((port, process::id() as libc::pid_t));
+
+	let mut content = String::new();
+	for (port, pid) in reserved {
+		content.push_str(&format!("{} {}\n", port, pid));
+	}
+	file.seek(io::SeekFrom::Start(0))?;
+	file.set_len(0)?;
+	file.write_all(content.as_bytes())?;
+	Ok(port)
+}
+
+#[cfg(not(unix))]
+fn reserve_registry_port(_path: &Path) -> Result<u16, io::Error> {
+	Err(io::Error::new(io::ErrorKind::Other, "port registry is only supported on unix"))
+}
+
+/// Recursively copy the directory at [src] to [dst].
+///
+/// The [dst] directory is created if it doesn't exist.
+pub fn copy_dir(src: &Path, dst: &Path) -> Result<(), io::Error> {
+	fs::create_dir_all(dst)?;
+	for entry in fs::read_dir(src)? {
+		let entry = entry?;
+		let target = dst.join(entry.file_name());
+		if entry.file_type()?.is_dir() {
+			copy_dir(&entry.path(), &target)?;
+		} else {
+			fs::copy(entry.path(), target)?;
+		}
+	}
+	Ok(())
+}
+
+/// Format a path to be written in a config file.
+///
+/// On Windows, backslashes are replaced with forward slashes,
+/// which are accepted as separators as well.
+pub fn config_path(path: &Path) -> String {
+	let path = path.to_string_lossy();
+	if cfg!(windows) {
+		path.replace('\\', "/")
+	} else {
+		path.into_owned()
+	}
+}
+
+/// Find an executable with the given name.
+///
+/// If the environment variable [env_var] is set, its value is used.
+/// Otherwise, the directories in `PATH` are searched.
+pub fn find_executable(name: &str, env_var: &str) -> Option<PathBuf> {
+	if let Some(path) = env::var_os(env_var) {
+		return Some(path.into());
+	}
+
+	env::split_paths(&env::var_os("PATH")?).map(|dir| dir.join(name)).find(|p| p.is_file())
+}
+
+/// Create a new uniquely named directory in the system's temporary directory.
+pub fn create_temp_dir(prefix: &str) -> Result<PathBuf, io::Error> {
+	loop {
+		let name = format!("{}-{:016x}", prefix, thread_rng().gen::<u64>());
+		let path = env::temp_dir().join(name);
+		match fs::create_dir(&path) {
+			Ok(()) => return Ok(path),
+			Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+			Err(e) => return Err(e),
+		}
+	}
+}
+
+/// Remove a temporary directory, logging a failure instead of returning it.
+pub fn remove_temp_dir(path: &Path) {
+	match fs::remove_dir_all(path) {
+		Ok(()) => debug!("Removed temporary directory {}", path.display()),
+		Err(e) => warn!("Failed to remove temporary directory {}: {}", path.display(), e),
+	}
+}
+
+/// Format a version in the encoding used by the `version` config fields.
+///
+/// Two digits per section, 4 sections: 18_01_00 => "0.18.1".
+/// Starting from version 22, the leading zero is dropped: 22_00_00 => "22.0".
+pub fn format_version(version: u64) -> String {
+	let (major, minor, patch) = (version / 1_00_00, version / 1_00 % 100, version % 100);
+	let mut ret = if major < 22 {
+		format!("0.{}.{}", major, minor)
+	} else {
+		format!("{}.{}", major, minor)
+	};
+	if patch > 0 {
+		ret.push_str(&format!(".{}", patch));
+	}
+	ret
+}
+
+/// Parse a version as printed by the daemons into the `Config::version` encoding.
+///
+/// Both the old `0.x.y.z` and the new `x.y.z` formats are supported,
+/// f.e. `v0.21.1` => 21_01_00, `elements-0.18.1.12` => 18_01_12 and `v22.0` => 22_00_00.
+pub fn parse_version(s: &str) -> Option<u64> {
+	lazy_static! {
+		/// The regular expression for a version number.
+		static ref VERSION_REGEX: Regex =
+			Regex::new(r"version (?:v|elements-)?(\d+)\.(\d+)(?:\.(\d+))?(?:\.(\d+))?").unwrap();
+	}
+
+	let caps = VERSION_REGEX.captures(s)?;
+	let nums = (1..5)
+		.map(|i| caps.get(i).map(|m| m.as_str().parse::<u64>().ok()).unwrap_or(Some(0)))
+		.collect::<Option<Vec<_>>>()?;
+	let sections = if nums[0] == 0 {
+		&nums[1..4]
+	} else {
+		&nums[0..3]
+	};
+	if sections.iter().any(|s| *s > 99) {
+		return None;
+	}
+	Some(sections[0] * 1_00_00 + sections[1] * 1_00 + sections[2])
+}
+
+/// Detect the version of the daemon executable by running it with `--version`.
+pub fn detect_version(executable: &Path) -> Result<u64, Error> {
+	let mut cmd = process::Command::new(executable);
+	cmd.arg("--version");
+	let output = cmd.output().map_err(|e| Error::RunCommand(e, Box::new(cmd)))?;
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	parse_version(&stdout).ok_or(Error::Custom("failed to parse daemon version"))
+}
+
+/// Command line arguments whose value is a secret.
+const SECRET_ARGS: &[&str] = &["--cookie"];
+
+/// Copy the command with the values of secret arguments replaced, so that
+/// it can be logged.
+///
+/// The environment and working directory of the command are not copied.
+pub(crate) fn redacted_command(cmd: &process::Command) -> process::Command {
+	let mut ret = process::Command::new(cmd.get_program());
+	let mut redact_next = false;
+	for arg in cmd.get_args() {
+		let s = arg.to_string_lossy();
+		if redact_next {
+			ret.arg("<redacted>");
+			redact_next = false;
+		} else if let Some(name) = SECRET_ARGS.iter().find(|n| s.starts_with(&format!("{}=", n))) {
+			ret.arg(format!("{}=<redacted>", name));
+		} else {
+			redact_next = SECRET_ARGS.contains(&s.as_ref());
+			ret.arg(arg);
+		}
+	}
+	ret
+}
+
+/// Generate hashed RPC credentials for the given user.
+///
+/// This follows the `rpcauth.py` script of Bitcoin Core: the password is
+/// hashed with HMAC-SHA256 keyed with a random salt. Returns the value for
+/// an `rpcauth=` config line and the password for the clients.
+pub fn generate_rpcauth(user: &str) -> (String, String) {
+	use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+
+	let salt = thread_rng().gen::<[u8; 16]>().to_hex();
+	let password = base64::encode_config(&thread_rng().gen::<[u8; 32]>(), base64::URL_SAFE);
+	let mut engine = hmac::HmacEngine::<sha256::Hash>::new(salt.as_bytes());
+	engine.input(password.as_bytes());
+	let hash = hmac::Hmac::<sha256::Hash>::from_engine(engine);
+	(format!("{}:{}${}", user, salt, hash.to_hex()), password)
+}
+
+/// Write labels like ` {group=federation, role=miner}` if there are any.
+pub(crate) fn fmt_labels(
+	f: &mut impl fmt::Write,
+	labels: &BTreeMap<String, String>,
+) -> fmt::Result {
+	if labels.is_empty() {
+		return Ok(());
+	}
+	f.write_str(" {")?;
+	for (i, (key, value)) in labels.iter().enumerate() {
+		if i > 0 {
+			f.write_str(", ")?;
+		}
+		write!(f, "{}={}", key, value)?;
+	}
+	f.write_str("}")
+}
+
+/// Write details like ` [version=22.0, datadir=/tmp/node, port=18444]`.
+///
+/// Details without a value are skipped. This is used for the alternate
+/// [fmt::Debug] format of the daemons, like `{:#?}`.
+pub(crate) fn fmt_details(
+	f: &mut impl fmt::Write,
+	details: &[(&str, Option<String>)],
+) -> fmt::Result {
+	let mut first = true;
+	for (key, value) in details {
+		if let Some(ref value) = value {
+			f.write_str(if first {
+				" ["
+			} else {
+				", "
+			})?;
+			write!(f, "{}={}", key, value)?;
+			first = false;
+		}
+	}
+	if !first {
+		f.write_str("]")?;
+	}
+	Ok(())
+}
+
+/// A secret string, like a wallet passphrase.
+///
+/// The [fmt::Debug] implementation doesn't reveal the secret, so that it
+/// doesn't end up in logs. The secret is not wiped from memory.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+	pub fn new(secret: impl Into<String>) -> Secret {
+		Secret(secret.into())
+	}
+
+	/// Access the secret value.
+	pub fn expose(&self) -> &str {
+		&self.0
+	}
+}
+
+impl fmt::Debug for Secret {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("Secret(<redacted>)")
+	}
+}
+
+/// The RPC timeout used for the health checks of [crate::DaemonRunner] implementations.
+pub const HEALTH_CHECK_RPC_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// An RPC client that gives up on calls that take longer than a timeout.
+///
+/// The HTTP client of [bitcoincore_rpc::Client] doesn't support timeouts, so
+/// this client makes its own HTTP requests, with the timeout set on the socket.
+/// A call that times out closes its connection, so nothing is left blocked.
+#[derive(Clone)]
+pub struct TimeoutClient {
+	/// The address of the RPC server, like `127.0.0.1:18443`.
+	addr: String,
+	/// The HTTP path of the requests, like `/` or `/wallet/name`.
+	path: String,
+	/// The value of the `Authorization` header, if any.
+	authorization: Option<String>,
+	timeout: Option<time::Duration>,
+	nonce: Arc<AtomicU64>,
+}
+
+impl TimeoutClient {
+	/// Create a client for the given `http://` URL.
+	///
+	/// Without a timeout, calls wait for as long as it takes. A cookie file
+	/// is read right away, like [bitcoincore_rpc::Client::new] does.
+	pub fn new(
+		url: &str,
+		auth: bitcoincore_rpc::Auth,
+		timeout: Option<time::Duration>,
+	) -> Result<TimeoutClient, bitcoincore_rpc::Error> {
+		let rest = url.strip_prefix("http://").ok_or_else(|| {
+			io::Error::new(io::ErrorKind::InvalidInput, "only http:// RPC URLs are supported")
+		})?;
+		let (addr, path) = match rest.find('/') {
+			Some(i) => (&rest[..i], &rest[i..]),
+			None => (rest, "/"),
+		};
+		let credentials = match auth {
+			bitcoincore_rpc::Auth::None => None,
+			bitcoincore_rpc::Auth::UserPass(user, pass) => Some(format!("{}:{}", user, pass)),
+			bitcoincore_rpc::Auth::CookieFile(path) => {
+				let cookie = fs::read_to_string(path)?;
+				if !cookie.contains(':') {
+					return Err(bitcoincore_rpc::Error::InvalidCookieFile);
+				}
+				Some(cookie.trim().to_owned())
+			}
+		};
+		Ok(TimeoutClient {
+			addr: addr.to_owned(),
+			path: path.to_owned(),
+			authorization: credentials.map(|c| format!("Basic {}", base64::encode(c.as_bytes()))),
+			timeout,
+			nonce: Arc::new(AtomicU64::new(0)),
+		})
+	}
+
+	/// The same client with a different timeout.
+	pub fn with_timeout(&self, timeout: Option<time::Duration>) -> TimeoutClient {
+		TimeoutClient {
+			timeout,
+			..self.clone()
+		}
+	}
+
+	pub fn timeout(&self) -> Option<time::Duration> {
+		self.timeout
+	}
+
+	/// Connect to the server, within the timeout if there is one.
+	fn connect(&self, deadline: Option<time::Instant>) -> Result<net::TcpStream, io::Error> {
+		use std::net::ToSocketAddrs;
+
+		let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to");
+		for addr in self.addr.to_socket_addrs()? {
+			let res = match deadline {
+				Some(d) => net::TcpStream::connect_timeout(&addr, remaining(d)?),
+				None => net::TcpStream::connect(addr),
+			};
+			match res {
+				Ok(stream) => return Ok(stream),
+				Err(e) => last_err = e,
+			}
+		}
+		Err(last_err)
+	}
+
+	/// Send the request and return the body of the response.
+	fn send(&self, body: &[u8]) -> Result<Vec<u8>, io::Error> {
+		use std::io::{Read, Write};
+
+		let deadline = self.timeout.map(|t| time::Instant::now() + t);
+		let mut stream = self.connect(deadline)?;
+		let mut request = format!(
+			"POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+			self.path,
+			self.addr,
+			body.len(),
+		);
+		if let Some(ref auth) = self.authorization {
+			request.push_str(&format!("Authorization: {}\r\n", auth));
+		}
+		request.push_str("\r\n");
+		if let Some(d) = deadline {
+			stream.set_write_timeout(Some(remaining(d)?))?;
+		}
+		stream.write_all(request.as_bytes())?;
+		stream.write_all(body)?;
+
+		// The server closes the connection after the response.
+		let mut response = Vec::new();
+		let mut buf = [0u8; 8192];
+		loop {
+			if let Some(d) = deadline {
+				stream.set_read_timeout(Some(remaining(d)?))?;
+			}
+			match stream.read(&mut buf) {
+				Ok(0) => break,
+				Ok(n) => response.extend_from_slice(&buf[..n]),
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+					return Err(io::ErrorKind::TimedOut.into())
+				}
+				Err(e) => return Err(e),
+			}
+		}
+		parse_http_response(&response)
+	}
+}
+
+/// The time left until the deadline, or a timeout error if it passed.
+fn remaining(deadline: time::Instant) -> Result<time::Duration, io::Error> {
+	let now = time::Instant::now();
+	if now >= deadline {
+		return Err(io::ErrorKind::TimedOut.into());
+	}
+	Ok(deadline - now)
+}
+
+/// Get the body of an HTTP response.
+///
+/// Fails for responses without a body, like when authentication failed.
+/// Error statuses with a body are accepted, because the body contains the
+/// JSON-RPC error.
+fn parse_http_response(response: &[u8]) -> Result<Vec<u8>, io::Error> {
+	let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+	let header_end = response
+		.windows(4)
+		.position(|w| w == b"\r\n\r\n")
+		.ok_or_else(|| invalid("incomplete HTTP response"))?;
+	let head = String::from_utf8_lossy(&response[..header_end]);
+	let mut body = &response[header_end + 4..];
+
+	let mut lines = head.split("\r\n");
+	let status = lines
+		.next()
+		.and_then(|l| l.split_whitespace().nth(1))
+		.and_then(|c| c.parse::<u16>().ok())
+		.ok_or_else(|| invalid("invalid HTTP status line"))?;
+	let mut chunked = false;
+	for line in lines {
+		let mut parts = line.splitn(2, ':');
+		let (name, value) = match (parts.next(), parts.next()) {
+			(Some(n), Some(v)) => (n.trim().to_ascii_lowercase(), v.trim()),
+			_ => continue,
+		};
+		if name == "content-length" {
+			let len = value.parse::<usize>().map_err(|_| invalid("invalid Content-Length"))?;
+			body = body.get(..len).ok_or_else(|| invalid("incomplete HTTP body"))?;
+		} else if name == "transfer-encoding" && value.eq_ignore_ascii_case("chunked") {
+			chunked = true;
+		}
+	}
+	let body = if chunked {
+		decode_chunked(body).ok_or_else(|| invalid("invalid chunked HTTP body"))?
+	} else {
+		body.to_vec()
+	};
+
+	if body.is_empty() && status != 200 {
+		return Err(match status {
+			401 => io::Error::new(io::ErrorKind::PermissionDenied, "RPC authentication failed"),
+			_ => invalid(&format!("HTTP status {} without body", status)),
+		});
+	}
+	Ok(body)
+}
+
+/// Decode a body with chunked transfer encoding.
+fn decode_chunked(mut data: &[u8]) -> Option<Vec<u8>> {
+	let mut ret = Vec::new();
+	loop {
+		let line_end = data.windows(2).position(|w| w == b"\r\n")?;
+		let size = std::str::from_utf8(&data[..line_end]).ok()?;
+		let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+		data = &data[line_end + 2..];
+		if size == 0 {
+			return Some(ret);
+		}
+		ret.extend_from_slice(data.get(..size)?);
+		data = data.get(size + 2..)?;
+	}
+}
+
+impl fmt::Debug for TimeoutClient {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "TimeoutClient(http://{}{}, timeout: {:?})", self.addr, self.path, self.timeout)
+	}
+}
+
+impl bitcoincore_rpc::RpcApi for TimeoutClient {
+	fn call<T: for<'a> serde::de::Deserialize<'a>>(
+		&self,
+		cmd: &str,
+		args: &[serde_json::Value],
+	) -> bitcoincore_rpc::Result<T> {
+		use bitcoincore_rpc::jsonrpc;
+
+		let id = self.nonce.fetch_add(1, Ordering::SeqCst);
+		let request = serde_json::json!({
+			"jsonrpc": "2.0",
+			"id": id,
+			"method": cmd,
+			"params": args,
+		});
+		let body = self.send(&serde_json::to_vec(&request)?).map_err(|e| {
+			if e.kind() == io::ErrorKind::TimedOut {
+				let timeout = self.timeout.unwrap_or_default();
+				io::Error::new(e.kind(), format!("RPC call {} timed out after {:?}", cmd, timeout))
+			} else {
+				e
+			}
+		})?;
+
+		let mut response: serde_json::Value = serde_json::from_slice(&body)?;
+		if !response["error"].is_null() {
+			let error = &response["error"];
+			return Err(jsonrpc::Error::Rpc(jsonrpc::error::RpcError {
+				code: error["code"].as_i64().unwrap_or_default() as i32,
+				message: error["message"].as_str().unwrap_or_default().to_owned(),
+				data: error.get("data").cloned(),
+			})
+			.into());
+		}
+		Ok(serde_json::from_value(response["result"].take())?)
+	}
+}
+
+impl liquid_rpc::LiquidRpcApi for TimeoutClient {
+	fn call<T: for<'a> serde::de::Deserialize<'a>>(
+		&self,
+		cmd: &str,
+		args: &[serde_json::Value],
+	) -> liquid_rpc::Result<T> {
+		Ok(bitcoincore_rpc::RpcApi::call(self, cmd, args)?)
+	}
+}
+
+/// Loading and storing configs as TOML or JSON.
+///
+/// The field names are those of the config struct, which are the names of the
+/// options in the config file of the daemon. Fields that are not set can be
+/// left out. Implemented for the `Config` types of all daemons.
+pub trait ConfigFile: serde::Serialize + serde::de::DeserializeOwned {
+	fn from_toml_str(s: &str) -> Result<Self, Error> {
+		toml::from_str(s).map_err(|e| Error::ConfigFormat(e.to_string()))
+	}
+
+	fn from_toml_file(path: &Path) -> Result<Self, Error> {
+		Self::from_toml_str(&fs::read_to_string(path)?)
+	}
+
+	fn to_toml_string(&self) -> Result<String, Error> {
+		// Go through a value so that tables are written after plain values.
+		toml::Value::try_from(self)
+			.and_then(|v| toml::to_string_pretty(&v))
+			.map_err(|e| Error::ConfigFormat(e.to_string()))
+	}
+
+	fn from_json_str(s: &str) -> Result<Self, Error> {
+		serde_json::from_str(s).map_err(|e| Error::ConfigFormat(e.to_string()))
+	}
+
+	fn from_json_file(path: &Path) -> Result<Self, Error> {
+		Self::from_json_str(&fs::read_to_string(path)?)
+	}
+
+	fn to_json_string(&self) -> Result<String, Error> {
+		serde_json::to_string_pretty(self).map_err(|e| Error::ConfigFormat(e.to_string()))
+	}
+}
+
+/// One megabyte (MiB) in bytes.
+pub const MB: u64 = 1024 * 1024;
+
+/// One gigabyte (GiB) in bytes.
+pub const GB: u64 = 1024 * MB;
+
+/// The free space in bytes available to unprivileged users on the
+/// filesystem of the given path.
+#[cfg(unix)]
+pub fn free_disk_space(path: &Path) -> Result<u64, io::Error> {
+	use std::ffi::CString;
+	use std::os::unix::ffi::OsStrExt;
+
+	let cpath = CString::new(path.as_os_str().as_bytes())
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+	let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+	if unsafe { libc::statvfs(cpath.as_ptr(), &mut stat) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_disk_space(_path: &Path) -> Result<u64, io::Error> {
+	Err(io::Error::new(io::ErrorKind::Other, "disk space checks are only supported on unix"))
+}
+
+/// Check that the filesystem of the directory has at least [required]
+/// bytes of free space.
+pub fn check_disk_space(dir: &Path, required: u64) -> Result<(), Error> {
+	let available = free_disk_space(dir)?;
+	if available < required {
+		return Err(Error::InsufficientDiskSpace {
+			dir: dir.to_owned(),
+			available,
+			required,
+		});
+	}
+	debug!("{} has {} MiB of free disk space", dir.display(), available / MB);
+	Ok(())
+}
+
+/// The name of the file daemons lock in their network-specific datadir.
+pub const DATADIR_LOCK_FILENAME: &str = ".lock";
+
+/// Check whether a process holds the lock on the datadir at [dir].
+///
+/// Daemons keep a POSIX record lock on [DATADIR_LOCK_FILENAME] for as long
+/// as they're running.
+#[cfg(unix)]
+pub fn datadir_locked(dir: &Path) -> Result<bool, io::Error> {
+	use std::os::unix::io::AsRawFd;
+
+	let file = match fs::File::open(dir.join(DATADIR_LOCK_FILENAME)) {
+		Ok(f) => f,
+		Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+		Err(e) => return Err(e),
+	};
+	// Ask which lock would prevent us from taking a write lock.
+	let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+	lock.l_type = libc::F_WRLCK as libc::c_short;
+	lock.l_whence = libc::SEEK_SET as libc::c_short;
+	if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut lock) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(lock.l_type != libc::F_UNLCK as libc::c_short)
+}
+
+#[cfg(not(unix))]
+pub fn datadir_locked(_dir: &Path) -> Result<bool, io::Error> {
+	Err(io::Error::new(io::ErrorKind::Other, "datadir lock checks are only supported on unix"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+
+	#[test]
+	fn rpcauth() {
+		let (rpcauth, password) = generate_rpcauth("user");
+		let (user, rest) = rpcauth.split_at(rpcauth.find(':').unwrap());
+		let mut parts = rest[1..].split('$');
+		let (salt, hash) = (parts.next().unwrap(), parts.next().unwrap());
+		assert_eq!(user, "user");
+		assert_eq!(salt.len(), 32);
+		assert!(parts.next().is_none());
+
+		let mut engine = hmac::HmacEngine::<sha256::Hash>::new(salt.as_bytes());
+		engine.input(password.as_bytes());
+		assert_eq!(hash, hmac::Hmac::<sha256::Hash>::from_engine(engine).to_hex());
+
+		// Every call generates new credentials.
+		assert_ne!(generate_rpcauth("user").1, password);
+	}
+
+	#[test]
+	fn http_response() {
+		let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n{\"id\":1}\r\nextra";
+		assert_eq!(parse_http_response(ok).unwrap(), b"{\"id\":1}\r\n");
+
+		let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+			4\r\n{\"id\r\n5;ext=1\r\n\":1}\n\r\n0\r\n\r\n";
+		assert_eq!(parse_http_response(chunked).unwrap(), b"{\"id\":1}\n");
+
+		// The JSON-RPC error is in the body of error statuses.
+		let error = b"HTTP/1.1 500 Internal Server Error\r\ncontent-length: 2\r\n\r\n{}";
+		assert_eq!(parse_http_response(error).unwrap(), b"{}");
+
+		let unauthorized = b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n";
+		let err = parse_http_response(unauthorized).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+		let not_found = b"HTTP/1.1 404 Not Found\r\n\r\n";
+		assert_eq!(parse_http_response(not_found).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+		for invalid in &[
+			&b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n"[..],
+			b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n{}",
+			b"HTTP/1.1 200 OK\r\nContent-Length: x\r\n\r\n{}",
+			b"garbage\r\n\r\n{}",
+			b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\n{}",
+		] {
+			assert_eq!(
+				parse_http_response(invalid).unwrap_err().kind(),
+				io::ErrorKind::InvalidData
+			);
+		}
+	}
+
+	#[test]
+	fn chunked_body() {
+		assert_eq!(decode_chunked(b"0\r\n\r\n").unwrap(), b"");
+		assert_eq!(
+			decode_chunked(b"a\r\n0123456789\r\n1\r\n!\r\n0\r\n\r\n").unwrap(),
+			b"0123456789!"
+		);
+		// Missing the terminating chunk.
+		assert_eq!(decode_chunked(b"1\r\n!\r\n"), None);
+		// Chunk shorter than its size.
+		assert_eq!(decode_chunked(b"5\r\nab\r\n0\r\n\r\n"), None);
+		assert_eq!(decode_chunked(b"zz\r\nab\r\n0\r\n\r\n"), None);
+	}
+
+	#[test]
+	fn free_ports() {
+		let mut ports = find_free_ports(10);
+		ports.sort();
+		ports.dedup();
+		assert_eq!(ports.len(), 10);
+	}
+}
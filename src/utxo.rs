@@ -0,0 +1,105 @@
+//! Inspection of the UTXO sets of nodes.
+
+use bitcoincore_rpc::RpcApi;
+
+use crate::cluster::Node;
+use crate::error::Error;
+
+/// The hash to calculate over the UTXO set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UtxoHashType {
+	/// The hash of the serialized UTXO set.
+	HashSerialized,
+	/// The MuHash of the UTXO set, which needs `coinstatsindex` to be fast.
+	MuHash,
+	/// Don't calculate a hash.
+	None,
+}
+
+impl UtxoHashType {
+	/// The name of the hash type for the given daemon version.
+	fn name(self, version: u64) -> &'static str {
+		match self {
+			UtxoHashType::HashSerialized if version >= 26_00_00 => "hash_serialized_3",
+			UtxoHashType::HashSerialized => "hash_serialized_2",
+			UtxoHashType::MuHash => "muhash",
+			UtxoHashType::None => "none",
+		}
+	}
+}
+
+/// A summary of the UTXO set of a node, as returned by `gettxoutsetinfo`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UtxoSetInfo {
+	/// The height of the block the UTXO set is at.
+	pub height: u64,
+	/// The hash of the block the UTXO set is at.
+	pub best_block: bitcoin::BlockHash,
+	/// The number of unspent outputs.
+	pub txouts: u64,
+	/// The requested hash of the UTXO set, if any.
+	pub hash: Option<String>,
+	/// The full result of the call.
+	pub raw: serde_json::Value,
+}
+
+impl UtxoSetInfo {
+	/// Whether both summaries describe the same UTXO set.
+	///
+	/// Fields that depend on the storage of the node, like the disk size, are ignored.
+	pub fn same_set(&self, other: &UtxoSetInfo) -> bool {
+		self.height == other.height
+			&& self.best_block == other.best_block
+			&& self.txouts == other.txouts
+			&& self.hash == other.hash
+	}
+}
+
+/// Query a summary of the UTXO set over RPC.
+///
+/// The version of elementsd follows the version of bitcoind it's based on.
+/// Versions before 0.21 don't support selecting the hash type
+/// and always use [UtxoHashType::HashSerialized].
+pub(crate) fn query_utxo_set_info(
+	rpc: &impl bitcoincore_rpc::RpcApi,
+	version: u64,
+	hash_type: UtxoHashType,
+) -> Result<UtxoSetInfo, Error> {
+	let name = hash_type.name(version);
+	let raw: serde_json::Value = if version < 21_00_00 {
+		if hash_type != UtxoHashType::HashSerialized {
+			return Err(Error::Config("hash type not supported by daemon version"));
+		}
+		rpc.call("gettxoutsetinfo", &[])?
+	} else {
+		rpc.call("gettxoutsetinfo", &[name.into()])?
+	};
+
+	let invalid = || Error::Custom("invalid gettxoutsetinfo response");
+	Ok(UtxoSetInfo {
+		height: raw["height"].as_u64().ok_or_else(invalid)?,
+		best_block: raw["bestblock"].as_str().and_then(|s| s.parse().ok()).ok_or_else(invalid)?,
+		txouts: raw["txouts"].as_u64().ok_or_else(invalid)?,
+		hash: raw[name].as_str().map(|s| s.to_owned()),
+		raw,
+	})
+}
+
+/// Assert that both nodes have the same UTXO set.
+///
+/// Fails if the UTXO sets can't be queried and panics if they differ.
+pub fn assert_utxo_set_equal(a: &Node, b: &Node, hash_type: UtxoHashType) -> Result<(), Error> {
+	let info_a = a.utxo_set_info(hash_type)?;
+	let info_b = b.utxo_set_info(hash_type)?;
+	assert!(
+		info_a.same_set(&info_b),
+		"UTXO sets of {} and {} differ:\n{}: {:?}\n{}: {:?}",
+		a.name(),
+		b.name(),
+		a.name(),
+		info_a,
+		b.name(),
+		info_b,
+	);
+	Ok(())
+}